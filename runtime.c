@@ -1,13 +1,104 @@
 #include <inttypes.h>
 #include <stdio.h>
 #include <stdlib.h>
+#include <string.h>
 
 int64_t *heap;
+int64_t *heap_end;
 int64_t *rootstack;
 int64_t *free_ptr;
 
 const int64_t TRUE  = 0xffffffffffffffff;
 const int64_t FALSE = 0x7fffffffffffffff;
+const int64_t NIL   = 0x3fffffffffffffff;
+// the value of `set!` and `while`, which are run for their effect
+const int64_t VOID  = 0x1fffffffffffffff;
+
+// A character is its code shifted left by 8, with CHAR_TAG in the low
+// byte.
+#define CHAR_TAG 0x0f
+// And a symbol is its index in `symbols`.
+#define SYMBOL_TAG 0x1f
+
+// Boxed objects are tagged with 0b011 in their low bits, and their
+// first word says what kind of object they are.
+#define BOXED_TAG 0x3
+#define HASH_KIND 1
+#define STRING_KIND 2
+#define FLOAT_KIND 3
+#define VECTOR_KIND 4
+
+// Strings are allocated by the compiled code, with their bytes packed
+// after the length.
+struct string {
+  int64_t kind;
+  int64_t length;
+  char bytes[];
+};
+
+struct hash_entry {
+  int64_t key;
+  int64_t val;
+  struct hash_entry *next;
+};
+
+struct hash_table {
+  int64_t kind;
+  int64_t count;
+  int64_t nbuckets;
+  struct hash_entry **buckets;
+  struct hash_table *next_table;  // every table, for the collector
+};
+
+static struct hash_table *tables = NULL;
+
+// The names of the symbols, as strings, which the compiled program
+// passes to initialize
+static int64_t *symbols;
+
+void print_string(int64_t s) {
+  struct string *str = (struct string*)(s - BOXED_TAG);
+  fwrite(str->bytes, 1, str->length, stdout);
+}
+
+// #\ and the character, in the one to four bytes of its UTF-8.
+void print_char(int64_t val) {
+  uint32_t c = (uint32_t)(val >> 8);
+  printf("#\\");
+  if(c < 0x80) {
+    putchar(c);
+  }
+  else if(c < 0x800) {
+    putchar(0xc0 | (c >> 6));
+    putchar(0x80 | (c & 0x3f));
+  }
+  else if(c < 0x10000) {
+    putchar(0xe0 | (c >> 12));
+    putchar(0x80 | ((c >> 6) & 0x3f));
+    putchar(0x80 | (c & 0x3f));
+  }
+  else {
+    putchar(0xf0 | (c >> 18));
+    putchar(0x80 | ((c >> 12) & 0x3f));
+    putchar(0x80 | ((c >> 6) & 0x3f));
+    putchar(0x80 | (c & 0x3f));
+  }
+}
+
+// The shortest of %.15g and %.17g that reads back as the same double,
+// with a ".0" if it would otherwise look like an integer.
+void print_float(int64_t f) {
+  double d = *(double*)(f - BOXED_TAG + 8);
+  char buf[32];
+  snprintf(buf, sizeof buf, "%.15g", d);
+  if(strtod(buf, NULL) != d) {
+    snprintf(buf, sizeof buf, "%.17g", d);
+  }
+  if(strspn(buf, "-0123456789") == strlen(buf)) {
+    strcat(buf, ".0");
+  }
+  printf("%s", buf);
+}
 
 int rec_print(int64_t val) {
   if(val & 0x00000001 ^ 0x00000001) {
@@ -19,6 +110,18 @@ int rec_print(int64_t val) {
   else if(val == FALSE) {
     printf("#f");
   }
+  else if(val == NIL) {
+    printf("()");
+  }
+  else if(val == VOID) {
+    printf("#<void>");
+  }
+  else if((val & 0xff) == CHAR_TAG) {
+    print_char(val);
+  }
+  else if((val & 0xff) == SYMBOL_TAG) {
+    print_string(symbols[val >> 8]);
+  }
   else if((val & 0x00000003) == 0x00000001) {
     int64_t *tup_base = (int64_t*)(val - 1);
     int tup_count = *tup_base;
@@ -33,19 +136,433 @@ int rec_print(int64_t val) {
     }
     printf(")");
   }
+  else if((val & 0x00000007) == BOXED_TAG
+          && *(int64_t*)(val - BOXED_TAG) == HASH_KIND) {
+    printf("#<hash-table>");
+  }
+  else if((val & 0x00000007) == BOXED_TAG
+          && *(int64_t*)(val - BOXED_TAG) == STRING_KIND) {
+    print_string(val);
+  }
+  else if((val & 0x00000007) == BOXED_TAG
+          && *(int64_t*)(val - BOXED_TAG) == FLOAT_KIND) {
+    print_float(val);
+  }
+  else if((val & 0x00000007) == BOXED_TAG
+          && *(int64_t*)(val - BOXED_TAG) == VECTOR_KIND) {
+    int64_t *vec = (int64_t*)(val - BOXED_TAG);
+    printf("#(");
+    for (int64_t i = 0; i < vec[1]; i++) {
+      rec_print(vec[i + 2]);
+      if (i != vec[1] - 1) {
+        printf(", ");
+      }
+    }
+    printf(")");
+  }
   else {
     printf("Unknown value: %#010x", val);
   }
 }
 
-int print(int64_t val) {
+// A program that ends with `set!` or the like prints nothing. Also
+// `print` in the program, which gives `val` back.
+int64_t print(int64_t val) {
+  if(val == VOID) {
+    return val;
+  }
   rec_print(val);
   printf("\n");
   return val;
 }
 
-void initialize() {
-  heap = malloc(200);
-  rootstack = malloc(200);
+// `print_hex` and `print_binary`: an integer in base 16 or 2, with a
+// 0x or 0b prefix and its sign before that, eg. -0xff. Like print, they
+// give `val` back.
+static void check_integer(const char *prim, int64_t val) {
+  if(val & 1) {
+    fprintf(stderr, "error: %s: argument is not an integer\n", prim);
+    exit(1);
+  }
+}
+
+int64_t print_hex(int64_t val) {
+  check_integer("print_hex", val);
+  int64_t n = val >> 1;
+  printf("%s0x%" PRIx64 "\n", n < 0 ? "-" : "", (uint64_t)(n < 0 ? -n : n));
+  return val;
+}
+
+int64_t print_binary(int64_t val) {
+  check_integer("print_binary", val);
+  int64_t n = val >> 1;
+  uint64_t m = n < 0 ? -n : n;
+  char digits[65];
+  int i = 64;
+  digits[i] = '\0';
+  do {
+    digits[--i] = '0' + (m & 1);
+    m >>= 1;
+  } while(m != 0);
+  printf("%s0b%s\n", n < 0 ? "-" : "", &digits[i]);
+  return val;
+}
+
+// `equal?`: tuples are equal if their elements are, and strings if
+// their bytes are. Anything else is only equal to itself.
+int64_t deep_equal(int64_t a, int64_t b) {
+  if(a == b) {
+    return TRUE;
+  }
+  if((a & 0x00000003) == 0x00000001 && (b & 0x00000003) == 0x00000001) {
+    int64_t *a_base = (int64_t*)(a - 1);
+    int64_t *b_base = (int64_t*)(b - 1);
+    if(*a_base != *b_base) {
+      return FALSE;
+    }
+    for (int64_t i = 1; i < *a_base + 1; i++) {
+      if(deep_equal(a_base[i], b_base[i]) == FALSE) {
+        return FALSE;
+      }
+    }
+    return TRUE;
+  }
+  if((a & 0x00000007) == BOXED_TAG && (b & 0x00000007) == BOXED_TAG) {
+    struct string *a_str = (struct string*)(a - BOXED_TAG);
+    struct string *b_str = (struct string*)(b - BOXED_TAG);
+    if(a_str->kind == FLOAT_KIND && b_str->kind == FLOAT_KIND) {
+      return *(double*)(a - BOXED_TAG + 8) == *(double*)(b - BOXED_TAG + 8) ? TRUE : FALSE;
+    }
+    if(a_str->kind == STRING_KIND && b_str->kind == STRING_KIND
+       && a_str->length == b_str->length
+       && memcmp(a_str->bytes, b_str->bytes, a_str->length) == 0) {
+      return TRUE;
+    }
+  }
+  return FALSE;
+}
+
+static int is_float(int64_t val) {
+  return (val & 0x00000007) == BOXED_TAG && *(int64_t*)(val - BOXED_TAG) == FLOAT_KIND;
+}
+
+// `<`, `=` and the rest, for the compiled code when a side isn't an
+// integer: numbers are compared by value, and anything else by its
+// bits, as the compiled code does for two integers. Gives -1, 0 or 1,
+// which is then compared with 0 the same way.
+int64_t rusl_compare(int64_t a, int64_t b) {
+  if(((a & 1) == 0 || is_float(a)) && ((b & 1) == 0 || is_float(b))) {
+    double x = (a & 1) == 0 ? (double)(a >> 1) : *(double*)(a - BOXED_TAG + 8);
+    double y = (b & 1) == 0 ? (double)(b >> 1) : *(double*)(b - BOXED_TAG + 8);
+    return (x > y) - (x < y);
+  }
+  return (a > b) - (a < b);
+}
+
+// Errors found by the compiled code at run time. `code` is an index
+// into error_messages, which has to agree with the ERROR_ constants in
+// the compiler.
+static const char *error_messages[] = {
+  "car: argument is not a pair",
+  "cdr: argument is not a pair",
+  "tuple-ref: index out of range",
+  "vector: index out of range",
+  "division by zero",
+  "symbol->string: argument is not a symbol",
+  "arithmetic: argument is not a number",
+  "match: no clause matches the value",
+  "let-values: wrong number of values",
+};
+
+void rusl_error(int64_t code) {
+  fprintf(stderr, "error: %s\n", error_messages[code]);
+  exit(1);
+}
+
+// Called by the overflow trap of code compiled with --checked-arith
+void overflow_error() {
+  fprintf(stderr, "error: integer overflow\n");
+  exit(1);
+}
+
+// Called by `assert` when its argument is #f
+void assert_failed() {
+  fprintf(stderr, "error: assertion failed\n");
+  exit(1);
+}
+
+// Called by collect when there still isn't enough room after
+// collecting
+void heap_overflow() {
+  fprintf(stderr, "error: out of heap space\n");
+  exit(1);
+}
+
+// The heap is split in two halves of heap_size bytes. Objects are
+// allocated from `heap`, and collect copies the live ones to
+// `tospace`, then the two swap.
+static int64_t heap_size;
+static int64_t *tospace;
+
+// The variables of define-dynamic, which the collector treats as roots
+static int64_t *dynamics;
+static int64_t *dynamics_end;
+
+// The tuples and vectors copied to tospace whose elements haven't
+// been forwarded yet, in the order they were copied.
+static int64_t *scan_queue;
+
+// Enough for a deep recursion, each frame pushes only the variables
+// live at the allocation or call it's in
+#define ROOTSTACK_SIZE (8 << 20)
+
+// Called at the start of main with the heap size the program was
+// compiled with, the number of its dynamic variables followed by
+// them, and the same for its symbols' names
+void initialize(int64_t size, int64_t *dynamics_table, int64_t *symbol_table) {
+  heap_size = size;
+  heap = malloc(heap_size);
+  tospace = malloc(heap_size);
+  // every object takes at least two words
+  scan_queue = malloc(heap_size / 16 * sizeof(int64_t));
+  rootstack = malloc(ROOTSTACK_SIZE);
+  if(heap == NULL || tospace == NULL || scan_queue == NULL || rootstack == NULL) {
+    fprintf(stderr, "error: can't allocate a heap of %" PRId64 " bytes\n", heap_size);
+    exit(1);
+  }
+  heap_end = (int64_t*)((char*)heap + heap_size);
   free_ptr = heap;
+  dynamics = dynamics_table + 1;
+  dynamics_end = dynamics + dynamics_table[0];
+  symbols = symbol_table + 1;
+}
+
+// Hash tables compare keys with `eq?`, ie. by their raw 64-bit value.
+
+static struct hash_table *hash_table_of(int64_t h) {
+  struct hash_table *table = (struct hash_table*)(h - BOXED_TAG);
+  if((h & 0x00000007) != BOXED_TAG || table->kind != HASH_KIND) {
+    fprintf(stderr, "error: not a hash table\n");
+    exit(1);
+  }
+  return table;
+}
+
+static uint64_t hash_index(int64_t key, int64_t nbuckets) {
+  uint64_t x = key;
+  x ^= x >> 33;
+  x *= 0xff51afd7ed558ccdULL;
+  x ^= x >> 33;
+  return x % nbuckets;
+}
+
+int64_t make_hash() {
+  struct hash_table *table = malloc(sizeof(struct hash_table));
+  table->kind = HASH_KIND;
+  table->count = 0;
+  table->nbuckets = 16;
+  table->buckets = calloc(table->nbuckets, sizeof(struct hash_entry*));
+  table->next_table = tables;
+  tables = table;
+  return (int64_t)table + BOXED_TAG;
+}
+
+// Put every entry back in the bucket its key hashes to, out of
+// `nbuckets`. Needed when the table grows, and when the collector has
+// moved its keys.
+static void hash_rebuild(struct hash_table *table, int64_t nbuckets) {
+  struct hash_entry **buckets = calloc(nbuckets, sizeof(struct hash_entry*));
+  for (int64_t i = 0; i < table->nbuckets; i++) {
+    struct hash_entry *e = table->buckets[i];
+    while (e) {
+      struct hash_entry *next = e->next;
+      uint64_t j = hash_index(e->key, nbuckets);
+      e->next = buckets[j];
+      buckets[j] = e;
+      e = next;
+    }
+  }
+  free(table->buckets);
+  table->buckets = buckets;
+  table->nbuckets = nbuckets;
+}
+
+static void hash_grow(struct hash_table *table) {
+  hash_rebuild(table, table->nbuckets * 2);
+}
+
+int64_t hash_set(int64_t h, int64_t key, int64_t val) {
+  struct hash_table *table = hash_table_of(h);
+  uint64_t i = hash_index(key, table->nbuckets);
+  for (struct hash_entry *e = table->buckets[i]; e; e = e->next) {
+    if (e->key == key) {
+      e->val = val;
+      return val;
+    }
+  }
+
+  struct hash_entry *e = malloc(sizeof(struct hash_entry));
+  e->key = key;
+  e->val = val;
+  e->next = table->buckets[i];
+  table->buckets[i] = e;
+  table->count++;
+  if (table->count > 2 * table->nbuckets) {
+    hash_grow(table);
+  }
+  return val;
+}
+
+int64_t hash_ref(int64_t h, int64_t key, int64_t dflt) {
+  struct hash_table *table = hash_table_of(h);
+  uint64_t i = hash_index(key, table->nbuckets);
+  for (struct hash_entry *e = table->buckets[i]; e; e = e->next) {
+    if (e->key == key) {
+      return e->val;
+    }
+  }
+  return dflt;
+}
+
+// The garbage collector. collect is called by the compiled code when
+// an allocation of `bytes` wouldn't fit before heap_end. The compiled
+// code has pushed every variable that is live at that point onto the
+// root stack, which starts at `rootstack` and ends below `roots_top`,
+// and reads them back afterwards.
+//
+// It's a Cheney-style copying collector: the objects the roots point
+// to are copied to tospace, then the objects those point to, breadth
+// first. A copied object's first word is overwritten with its new
+// address.
+
+static int64_t *to_free;
+static int64_t nqueued;
+
+static int in_space(int64_t addr, int64_t *space) {
+  return addr >= (int64_t)space && addr < (int64_t)((char*)space + heap_size);
+}
+
+// The number of words taken by the object at `obj`, including the
+// padding that keeps objects two-word aligned
+static int64_t object_words(int64_t *obj, int64_t tag) {
+  int64_t words;
+  if(tag == 1) {
+    words = obj[0] + 1;
+  } else if(obj[0] == STRING_KIND) {
+    words = 2 + (obj[1] + 7) / 8;
+  } else if(obj[0] == VECTOR_KIND) {
+    words = 2 + obj[1];
+  } else {
+    words = 2;
+  }
+  return words + words % 2;
+}
+
+// The new value of `val`: where the object it points to was copied,
+// or `val` itself if it isn't a pointer into the heap
+static int64_t forward(int64_t val) {
+  int64_t tag;
+  if((val & 0x00000003) == 0x00000001) {
+    tag = 1;
+  } else if((val & 0x00000007) == BOXED_TAG) {
+    tag = BOXED_TAG;
+  } else {
+    return val;
+  }
+  if(!in_space(val - tag, heap)) {
+    return val;
+  }
+
+  int64_t *obj = (int64_t*)(val - tag);
+  if(in_space(obj[0], tospace)) {
+    return obj[0];
+  }
+  int64_t words = object_words(obj, tag);
+  if(tag == 1 || obj[0] == VECTOR_KIND) {
+    scan_queue[nqueued++] = (int64_t)to_free + tag;
+  }
+  memcpy(to_free, obj, words * sizeof(int64_t));
+  int64_t moved = (int64_t)to_free + tag;
+  to_free += words;
+  obj[0] = moved;
+  return moved;
+}
+
+void collect(int64_t *roots_top, int64_t bytes) {
+  to_free = tospace;
+  nqueued = 0;
+
+  for(int64_t *root = rootstack; root < roots_top; root++) {
+    *root = forward(*root);
+  }
+  for(int64_t *root = dynamics; root < dynamics_end; root++) {
+    *root = forward(*root);
+  }
+  for(struct hash_table *table = tables; table; table = table->next_table) {
+    for(int64_t i = 0; i < table->nbuckets; i++) {
+      for(struct hash_entry *e = table->buckets[i]; e; e = e->next) {
+        e->key = forward(e->key);
+        e->val = forward(e->val);
+      }
+    }
+  }
+
+  // a vector's elements may have been stored after it was made, so
+  // they're forwarded like a tuple's
+  for(int64_t scan = 0; scan < nqueued; scan++) {
+    if((scan_queue[scan] & 0x00000007) == BOXED_TAG) {
+      int64_t *vec = (int64_t*)(scan_queue[scan] - BOXED_TAG);
+      for(int64_t i = 2; i < vec[1] + 2; i++) {
+        vec[i] = forward(vec[i]);
+      }
+      continue;
+    }
+    int64_t *tup = (int64_t*)(scan_queue[scan] - 1);
+    for(int64_t i = 1; i < tup[0] + 1; i++) {
+      tup[i] = forward(tup[i]);
+    }
+  }
+
+  int64_t *from = heap;
+  heap = tospace;
+  tospace = from;
+  heap_end = (int64_t*)((char*)heap + heap_size);
+  free_ptr = to_free;
+
+  // keys are hashed by their address
+  for(struct hash_table *table = tables; table; table = table->next_table) {
+    hash_rebuild(table, table->nbuckets);
+  }
+
+  if((char*)free_ptr + bytes > (char*)heap_end) {
+    heap_overflow();
+  }
+}
+
+// `(make-vector n init)`, a vector of `n` elements that are all
+// `init`. The compiled code has pushed its live variables onto the
+// root stack, which ends below `roots_top`, as it does for collect.
+// `init` is pushed too, since it may move.
+int64_t make_vector(int64_t n, int64_t init, int64_t *roots_top) {
+  int64_t len = n >> 1;
+  if(len < 0) {
+    fprintf(stderr, "error: make-vector: negative length\n");
+    exit(1);
+  }
+  int64_t words = 2 + len;
+  words += words % 2;
+  if((char*)(free_ptr + words) > (char*)heap_end) {
+    *roots_top = init;
+    collect(roots_top + 1, words * sizeof(int64_t));
+    init = *roots_top;
+  }
+
+  int64_t *vec = free_ptr;
+  free_ptr += words;
+  vec[0] = VECTOR_KIND;
+  vec[1] = len;
+  for(int64_t i = 0; i < len; i++) {
+    vec[i + 2] = init;
+  }
+  return (int64_t)vec + BOXED_TAG;
 }
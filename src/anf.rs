@@ -1,16 +1,25 @@
 use util::get_unique_varname;
-use parser::{SExpr, CC};
+use parser::{SExpr, CC, NO_SPAN};
+use error::CompileError;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Flat {
     Symbol(String),
     FuncName(String),           // for closure-conversion
     Number(i64),
+    Float(f64),
     Bool(bool),
+    Char(char),
+    Nil,                        // the empty list
+    Void,                       // the value of forms run for their effect
+    Str(String),
+    QuotedSymbol(String),       // its index is found by intern_symbol
     Tuple(Vec<Flat>),
     Assign(String, Box<Flat>),
     Return(Box<Flat>),
     If(Box<Flat>, Vec<Flat>, Vec<Flat>),
+    // loops while the test, computed by the instructions, isn't #f
+    While(Vec<Flat>, Box<Flat>, Vec<Flat>),
     Cmp(CC, Box<Flat>, Box<Flat>),
     App(String, Vec<Flat>),
     Prim(String, Vec<Flat>),
@@ -44,11 +53,78 @@ fn flatten_args(args: &Vec<SExpr>)
 }
 
 
+// The number of arguments the primitive `f` takes, or None if `f`
+// isn't one that flatten knows.
+fn prim_arity(f: &str) -> Option<usize> {
+    match f {
+        "make-hash" | "%match-error" | "%values-error" => Some(0),
+        "-" | "tuple-length" | "car" | "cdr" | "pair?" | "string-length" |
+        "vector-length" | "assert" | "print" | "print_hex" | "print_binary" | "exit" |
+        "abs" | "symbol->string" | "%unbox" | "%rest-args" | "%tuple?" => Some(1),
+        "+" | "tuple-ref" | "min" | "max" | "quotient" | "remainder" | "modulo" |
+        "cons" | "eq?" | "equal?" | "make-vector" | "vector-ref" | "%set-box!" => Some(2),
+        "hash-set!" | "hash-ref" | "vector-set!" => Some(3),
+        _ => None,
+    }
+}
+
+// Check that every primitive in `expr` is called with as many
+// arguments as it takes. After uniquify, a symbol named like a
+// primitive is the primitive, as any variable has been renamed.
+pub fn check_arity(expr: &SExpr) -> Result<(), CompileError> {
+    match expr {
+        &SExpr::App(ref f, ref args) => {
+            if let &box SExpr::Symbol(ref name, span) = f {
+                match prim_arity(name) {
+                    Some(arity) if arity != args.len() =>
+                        return Err(CompileError::Arity(name.clone(), arity, args.len(), span)),
+                    _ => (),
+                }
+            }
+            try!(check_arity(f));
+            for a in args {
+                try!(check_arity(a));
+            }
+        },
+        &SExpr::Define(_, _, ref e) | &SExpr::DefineVal(_, ref e) |
+        &SExpr::Lambda(_, ref e) | &SExpr::DefineDynamic(_, ref e) |
+        &SExpr::Set(_, _, ref e) | &SExpr::DynamicSet(_, ref e) => try!(check_arity(e)),
+        &SExpr::Let(ref bindings, ref body) | &SExpr::Parameterize(ref bindings, ref body, _) => {
+            for &(_, ref v) in bindings {
+                try!(check_arity(v));
+            }
+            try!(check_arity(body));
+        },
+        &SExpr::If(ref cnd, ref thn, ref els) => {
+            try!(check_arity(cnd));
+            try!(check_arity(thn));
+            try!(check_arity(els));
+        },
+        &SExpr::Cmp(_, ref left, ref right) | &SExpr::While(ref left, ref right) => {
+            try!(check_arity(left));
+            try!(check_arity(right));
+        },
+        &SExpr::Begin(ref es) | &SExpr::Tuple(ref es) => {
+            for e in es {
+                try!(check_arity(e));
+            }
+        },
+        &SExpr::Prog(ref defs, ref main) => {
+            for def in defs {
+                try!(check_arity(def));
+            }
+            try!(check_arity(main));
+        },
+        _ => (),
+    }
+    return Ok(());
+}
+
 // This function does and ANF transformation. The output is a Flat
 // expression.
 pub fn flatten(expr: SExpr) -> FlatResult {
     match expr {
-        SExpr::Symbol(name) => FlatResult::Flat(Flat::Symbol(name.clone()),
+        SExpr::Symbol(name, _) => FlatResult::Flat(Flat::Symbol(name.clone()),
                                                 vec![],
                                                 vec![name]),
         SExpr::FuncName(name) => FlatResult::Flat(Flat::FuncName(name.clone()),
@@ -60,6 +136,27 @@ pub fn flatten(expr: SExpr) -> FlatResult {
         SExpr::Bool(b) => FlatResult::Flat(Flat::Bool(b),
                                            vec![],
                                            vec![]),
+        SExpr::Char(c) => FlatResult::Flat(Flat::Char(c),
+                                           vec![],
+                                           vec![]),
+        SExpr::QuotedSymbol(name) => FlatResult::Flat(Flat::QuotedSymbol(name),
+                                                      vec![],
+                                                      vec![]),
+        SExpr::Void => FlatResult::Flat(Flat::Void, vec![], vec![]),
+        // floats and strings are allocated on the heap, like tuples
+        SExpr::Float(bits) => {
+            let float_temp = get_unique_varname("float");
+            return FlatResult::Flat(Flat::Symbol(float_temp.clone()),
+                                    vec![Flat::Assign(float_temp.clone(),
+                                                      box Flat::Float(f64::from_bits(bits)))],
+                                    vec![float_temp]);
+        },
+        SExpr::Str(s) => {
+            let str_temp = get_unique_varname("str");
+            return FlatResult::Flat(Flat::Symbol(str_temp.clone()),
+                                    vec![Flat::Assign(str_temp.clone(), box Flat::Str(s))],
+                                    vec![str_temp]);
+        },
         SExpr::Lambda(_, _) =>
             panic!("closure conversion should happen before flatten"),
         SExpr::Tuple(elts) => {
@@ -121,8 +218,84 @@ pub fn flatten(expr: SExpr) -> FlatResult {
                                     bindings_assigns,
                                     bindings_vars);
         },
+        SExpr::Begin(body) => {
+            // evaluate everything for effect, the value is the last
+            // expression's
+            let mut body_assigns = vec![];
+            let mut body_vars = vec![];
+            let mut flat_last = None;
+            for e in body {
+                let (flat_e, e_assigns, e_vars) =
+                    match flatten(e) {
+                        FlatResult::Flat(flat_e, e_assigns, e_vars) => (flat_e, e_assigns, e_vars),
+                        _ => panic!("unreachable"),
+                    };
+                body_assigns.extend_from_slice(&e_assigns);
+                body_vars.extend_from_slice(&e_vars);
+                flat_last = Some(flat_e);
+            }
+
+            match flat_last {
+                Some(flat_last) => FlatResult::Flat(flat_last, body_assigns, body_vars),
+                None => panic!("empty begin"),
+            }
+        },
+        // a quoted list, built from its end as a chain of pairs
         SExpr::List(elts) => {
-            panic!("NYI");
+            let mut list_assigns = vec![];
+            let mut list_vars = vec![];
+            let mut list = Flat::Nil;
+            for elt in elts.into_iter().rev() {
+                let (flat_elt, elt_assigns, elt_vars) =
+                    match flatten(elt) {
+                        FlatResult::Flat(flat, assigns, vars) => (flat, assigns, vars),
+                        _ => panic!("unreachable"),
+                    };
+                list_assigns.extend_from_slice(&elt_assigns);
+                list_vars.extend_from_slice(&elt_vars);
+
+                let pair = get_unique_varname("pair");
+                list_assigns.push(Flat::Assign(pair.clone(),
+                                               box Flat::Tuple(vec![flat_elt, list])));
+                list_vars.push(pair.clone());
+                list = Flat::Symbol(pair);
+            }
+
+            return FlatResult::Flat(list, list_assigns, list_vars);
+        },
+        SExpr::DefineVal(_, _) =>
+            panic!("toplevel_prog should have turned defines into lets"),
+        SExpr::DefineDynamic(_, _) | SExpr::Parameterize(_, _, _) =>
+            panic!("resolve_dynamics should have removed {:?}", expr),
+        SExpr::DynamicRef(label) => {
+            let tmp = get_unique_varname("tmp");
+            return FlatResult::Flat(Flat::Symbol(tmp.clone()),
+                                    vec![Flat::Assign(tmp.clone(),
+                                                      box Flat::Prim("dynamic-ref".to_string(),
+                                                                     vec![Flat::FuncName(label)]))],
+                                    vec![tmp]);
+        },
+        SExpr::Set(name, _, val) => {
+            let (flat_val, mut val_assigns, val_vars) =
+                match flatten(*val) {
+                    FlatResult::Flat(flat, assigns, vars) => (flat, assigns, vars),
+                    _ => panic!("unreachable"),
+                };
+            val_assigns.push(Flat::Assign(name, box flat_val));
+            return FlatResult::Flat(Flat::Void, val_assigns, val_vars);
+        },
+        SExpr::DynamicSet(label, val) => {
+            let (flat_val, mut val_assigns, mut val_vars) =
+                match flatten(*val) {
+                    FlatResult::Flat(flat, assigns, vars) => (flat, assigns, vars),
+                    _ => panic!("unreachable"),
+                };
+            let tmp = get_unique_varname("tmp");
+            val_assigns.push(Flat::Assign(tmp.clone(),
+                                          box Flat::Prim("dynamic-set!".to_string(),
+                                                         vec![Flat::FuncName(label), flat_val])));
+            val_vars.push(tmp.clone());
+            return FlatResult::Flat(Flat::Void, val_assigns, val_vars);
         },
         SExpr::Define(name, args, body) => {
             let (flat_body, mut body_assigns, mut body_vars) =
@@ -146,12 +319,24 @@ pub fn flatten(expr: SExpr) -> FlatResult {
                                       body_vars);
         },
         SExpr::If(cnd, thn, els) => {
-            let (flat_cnd, mut cnd_assigns, mut cnd_vars) =
-                match flatten(*cnd) {
+            let (flat_cnd, mut cnd_assigns, mut cnd_vars) = match *cnd {
+                // a comparison is branched on directly, rather than
+                // turned into a boolean first
+                SExpr::Cmp(cc, left, right) => {
+                    let (flat_args, args_assigns, args_vars) =
+                        flatten_args(&vec![*left, *right]);
+                    let (flat_left, flat_right) = match &flat_args[..] {
+                        &[ref left, ref right] => (left.clone(), right.clone()),
+                        _ => panic!("unreachable"),
+                    };
+                    (Flat::Cmp(cc, box flat_left, box flat_right), args_assigns, args_vars)
+                },
+                cnd => match flatten(cnd) {
                     FlatResult::Flat(flat_cnd, cnd_assigns, cnd_vars) =>
                         (flat_cnd, cnd_assigns, cnd_vars),
                     _ => panic!("unreachable"),
-                };
+                },
+            };
             let (flat_thn, mut thn_assigns, mut thn_vars) =
                 match flatten(*thn) {
                     FlatResult::Flat(flat_thn, thn_assigns, thn_vars) =>
@@ -184,6 +369,36 @@ pub fn flatten(expr: SExpr) -> FlatResult {
                                     cnd_vars);
 
         },
+        SExpr::While(test, body) => {
+            let (flat_test, mut test_assigns, mut test_vars) =
+                match flatten(*test) {
+                    FlatResult::Flat(flat, assigns, vars) => (flat, assigns, vars),
+                    _ => panic!("unreachable"),
+                };
+            let (_, body_assigns, mut body_vars) =
+                match flatten(*body) {
+                    FlatResult::Flat(flat, assigns, vars) => (flat, assigns, vars),
+                    _ => panic!("unreachable"),
+                };
+
+            // the test is compared with #f on every iteration, so it
+            // has to be in a variable
+            let flat_test = match flat_test {
+                Flat::Symbol(_) => flat_test,
+                _ => {
+                    let test_temp = get_unique_varname("test");
+                    test_assigns.push(Flat::Assign(test_temp.clone(), box flat_test));
+                    test_vars.push(test_temp.clone());
+                    Flat::Symbol(test_temp)
+                },
+            };
+
+            test_vars.append(&mut body_vars);
+            // `while` has no useful value
+            return FlatResult::Flat(Flat::Void,
+                                    vec![Flat::While(test_assigns, box flat_test, body_assigns)],
+                                    test_vars);
+        },
         SExpr::Cmp(cc, left, right) => {
             let (flat_left, mut left_assigns, mut left_vars) =
                 match flatten(*left) {
@@ -211,12 +426,12 @@ pub fn flatten(expr: SExpr) -> FlatResult {
         },
         SExpr::App(f, args) => {
             match *f {
-                SExpr::Symbol(fname) => {
+                SExpr::Symbol(fname, _) => {
                     match &fname[..] {
                         "-" => {
                             let arg1 = match &args[..] {
                                 &[ref arg1] => arg1,
-                                _ => panic!("Wrong no. of args to `-`: {:?}", args),
+                                _ => panic!("check_arity should have rejected {:?}", args),
                             };
                             let (flat_e, mut e_assigns, mut e_vars) =
                                 match flatten(arg1.clone()) {
@@ -236,7 +451,7 @@ pub fn flatten(expr: SExpr) -> FlatResult {
                         "+" => {
                             let (arg1, arg2) = match &args[..] {
                                 &[ref arg1, ref arg2] => (arg1, arg2),
-                                _ => panic!("Wrong no. of args to `+`"),
+                                _ => panic!("check_arity should have rejected {:?}", args),
                             };
                             let (flat_e1, mut e1_assigns, mut e1_vars) =
                                 match flatten(arg1.clone()) {
@@ -266,25 +481,14 @@ pub fn flatten(expr: SExpr) -> FlatResult {
                                                     e1_vars);
                         },
                         "tuple-ref" => {
-                            let (tuple, index) = match &args[..] {
-                                &[ref tuple, ref index] => (tuple, index),
-                                _ => panic!("Wrong no. of args to `tuple-ref`: {:?}", args),
-                            };
-                            let index = match index {
-                                &SExpr::Number(n) => Flat::Number(n),
-                                &_ => panic!("index to tuple-ref must be a literal number"),
-                            };
-                            let (flat_tuple, mut tup_assigns, mut tup_vars) =
-                                match flatten(tuple.clone()) {
-                                    FlatResult::Flat(flat, assigns, vars) =>
-                                        (flat, assigns, vars),
-                                    _ => panic!("unreachable"),
-                                };
+                            // the index may be computed, select_flat
+                            // checks it against the length then
+                            let (flat_args, mut tup_assigns, mut tup_vars) = flatten_args(&args);
 
                             let ref_temp = get_unique_varname("tmp");
                             let flat_ref = Flat::Assign(ref_temp.clone(),
                                                         Box::new(Flat::Prim("tuple-ref".to_string(),
-                                                                            vec![flat_tuple, index])));
+                                                                            flat_args)));
                             tup_assigns.extend_from_slice(&[flat_ref]);
 
                             tup_vars.extend_from_slice(&[ref_temp.clone()]);
@@ -293,14 +497,35 @@ pub fn flatten(expr: SExpr) -> FlatResult {
                                                     tup_assigns,
                                                     tup_vars);
                         },
+                        "min" | "max" | "abs" | "quotient" | "remainder" | "modulo" |
+                        "tuple-length" | "make-hash" | "hash-set!" | "hash-ref" |
+                        "cons" | "car" | "cdr" | "pair?" | "string-length" |
+                        "eq?" | "equal?" | "assert" | "print" | "exit" |
+                        "print_hex" | "print_binary" |
+                        "make-vector" | "vector-ref" | "vector-set!" | "vector-length" |
+                        "symbol->string" | "%unbox" | "%set-box!" | "%rest-args" |
+                        "%tuple?" | "%match-error" | "%values-error" => {
+                            let (flat_args, mut args_assigns, mut args_vars) =
+                                flatten_args(&args);
+
+                            let prim_temp = get_unique_varname("tmp");
+                            args_assigns.push(Flat::Assign(prim_temp.clone(),
+                                                           box Flat::Prim(fname.clone(),
+                                                                          flat_args)));
+                            args_vars.push(prim_temp.clone());
+
+                            return FlatResult::Flat(Flat::Symbol(prim_temp),
+                                                    args_assigns,
+                                                    args_vars);
+                        },
                         f => {
-                            return flatten(SExpr::App(box SExpr::Symbol("tuple-ref".to_string()),
+                            return flatten(SExpr::App(box SExpr::Symbol("tuple-ref".to_string(), NO_SPAN),
                                                       vec![SExpr::Tuple(vec![SExpr::FuncName(f.to_string())]),
                                                            SExpr::Number(0)]));
                         },
                     }
                 },
-                SExpr::App(_, _) => {
+                SExpr::App(_, _) | SExpr::FuncName(_) => {
                     let (flat_fref, mut fref_assigns, mut fref_vars) =
                         match flatten(*f) {
                             FlatResult::Flat(flat, assigns, vars) =>
@@ -309,6 +534,14 @@ pub fn flatten(expr: SExpr) -> FlatResult {
                         };
                     let flat_fref = match flat_fref {
                         Flat::Symbol(fname) => fname,
+                        // a direct call of a top-level function
+                        Flat::FuncName(label) => {
+                            let fref_temp = get_unique_varname("tmp");
+                            fref_assigns.push(Flat::Assign(fref_temp.clone(),
+                                                           box Flat::FuncName(label)));
+                            fref_vars = vec![fref_temp.clone()];
+                            fref_temp
+                        },
                         _ => panic!("unreachable: {:?}", flat_fref),
                     };
 
@@ -360,20 +593,15 @@ pub fn flatten(expr: SExpr) -> FlatResult {
 
 #[test]
 fn test_flatten() {
-    use lexer::LexerState;
+    use lexer::lexer_for;
     use parser::read;
+    use util::reset_var_counter;
 
-    let mut input = String::from("(+ 12 (+ 13 14))");
-    let mut lexer = LexerState {
-        s: input,
-        pos: 0,
-        col: 1,
-        line_num: 1,
-        tok_buf: None,
-    };
+    reset_var_counter();
+    let mut lexer = lexer_for("(+ 12 (+ 13 14))");
 
     assert_eq!(
-        flatten(SExpr::Prog(vec![], Box::new(read(&mut lexer)))),
+        flatten(SExpr::Prog(vec![], Box::new(read(&mut lexer).unwrap()))),
         FlatResult::Prog(vec![],
                          vec![Flat::Assign("tmp1".to_string(), Box::new(Flat::Prim("+".to_string(),
                                                                                    vec![Flat::Number(13), Flat::Number(14)]))),
@@ -384,17 +612,35 @@ fn test_flatten() {
                          vec!["tmp1".to_string(), "tmp2".to_string()])
     );
 
-    // TODO: Reset start(var counter) so that these asserts are
-    // independent.
+    reset_var_counter();
     assert_eq!(
         flatten(SExpr::Define("foo".to_string(), vec!["x".to_string(), "y".to_string(), "z".to_string()],
-                              Box::new(SExpr::App(Box::new(SExpr::Symbol("+".to_string())),
-                                                  vec![SExpr::Symbol("x".to_string()), SExpr::Number(10)])))),
+                              Box::new(SExpr::App(Box::new(SExpr::Symbol("+".to_string(), NO_SPAN)),
+                                                  vec![SExpr::Symbol("x".to_string(), NO_SPAN), SExpr::Number(10)])))),
         FlatResult::Define("foo".to_string(),
                            vec!["x".to_string(), "y".to_string(), "z".to_string()],
-                           vec![Flat::Assign("tmp3".to_string(),
+                           vec![Flat::Assign("tmp1".to_string(),
                                              Box::new(Flat::Prim("+".to_string(), vec![Flat::Symbol("x".to_string()), Flat::Number(10)]))),
-                                Flat::Return(Box::new(Flat::Symbol("tmp3".to_string())))],
-                           vec!["tmp3".to_string()])
+                                Flat::Return(Box::new(Flat::Symbol("tmp1".to_string())))],
+                           vec!["tmp1".to_string()])
     );
 }
+
+#[test]
+fn test_reset_var_counter() {
+    use lexer::lexer_for;
+    use parser::read;
+    use util::reset_var_counter;
+
+    let flatten_src = || {
+        let mut lexer = lexer_for("(let ((x (tuple 1 2))) (if (< (tuple-ref x 0) 2) x (tuple 3)))");
+        flatten(SExpr::Prog(vec![], box read(&mut lexer).unwrap()))
+    };
+
+    reset_var_counter();
+    let first = flatten_src();
+    reset_var_counter();
+    assert_eq!(first, flatten_src());
+    // without a reset, the names differ
+    assert!(first != flatten_src());
+}
@@ -0,0 +1,239 @@
+use std::collections::HashSet;
+
+use parser::SExpr;
+use anf::{Flat, FlatResult};
+use {X86, X86Arg};
+
+// Invariants the passes rely on, checked between them when
+// `Config::check_invariants` is set. A violation is a bug in the pass
+// that produced the program, not in the program being compiled.
+
+fn bind(name: &String, bound: &mut HashSet<String>) -> Result<(), String> {
+    if !bound.insert(name.clone()) {
+        return Err(format!("`{}` is bound more than once", name));
+    }
+    return Ok(());
+}
+
+fn unique_bindings(expr: &SExpr, bound: &mut HashSet<String>) -> Result<(), String> {
+    match expr {
+        &SExpr::Define(ref name, ref args, ref body) => {
+            try!(bind(name, bound));
+            for arg in args {
+                try!(bind(arg, bound));
+            }
+            unique_bindings(body, bound)
+        },
+        &SExpr::Let(ref bindings, ref body) => {
+            for &(ref k, ref v) in bindings {
+                try!(bind(k, bound));
+                try!(unique_bindings(v, bound));
+            }
+            unique_bindings(body, bound)
+        },
+        &SExpr::Lambda(ref args, ref body) => {
+            for arg in args {
+                try!(bind(arg, bound));
+            }
+            unique_bindings(body, bound)
+        },
+        &SExpr::If(ref cnd, ref thn, ref els) => {
+            try!(unique_bindings(cnd, bound));
+            try!(unique_bindings(thn, bound));
+            unique_bindings(els, bound)
+        },
+        &SExpr::Cmp(_, ref left, ref right) | &SExpr::While(ref left, ref right) => {
+            try!(unique_bindings(left, bound));
+            unique_bindings(right, bound)
+        },
+        &SExpr::DynamicSet(_, ref val) | &SExpr::Set(_, _, ref val) =>
+            unique_bindings(val, bound),
+        &SExpr::App(ref f, ref es) => {
+            try!(unique_bindings(f, bound));
+            for e in es {
+                try!(unique_bindings(e, bound));
+            }
+            Ok(())
+        },
+        &SExpr::Tuple(ref es) | &SExpr::Begin(ref es) | &SExpr::List(ref es) => {
+            for e in es {
+                try!(unique_bindings(e, bound));
+            }
+            Ok(())
+        },
+        &SExpr::Prog(ref defs, ref main) => {
+            for def in defs {
+                try!(unique_bindings(def, bound));
+            }
+            unique_bindings(main, bound)
+        },
+        _ => Ok(()),
+    }
+}
+
+// After uniquify, no two bindings share a name.
+pub fn check_uniquified(prog: &SExpr) -> Result<(), String> {
+    unique_bindings(prog, &mut HashSet::new())
+}
+
+fn assigned_before_use(v: &Flat, assigned: &HashSet<String>) -> Result<(), String> {
+    match v {
+        &Flat::Symbol(ref name) => {
+            if !assigned.contains(name) {
+                return Err(format!("`{}` is used before it is assigned", name));
+            }
+            Ok(())
+        },
+        &Flat::App(ref f, ref args) => {
+            try!(assigned_before_use(&Flat::Symbol(f.clone()), assigned));
+            for arg in args {
+                try!(assigned_before_use(arg, assigned));
+            }
+            Ok(())
+        },
+        &Flat::Tuple(ref args) | &Flat::Prim(_, ref args) => {
+            for arg in args {
+                try!(assigned_before_use(arg, assigned));
+            }
+            Ok(())
+        },
+        &Flat::Cmp(_, ref left, ref right) => {
+            try!(assigned_before_use(left, assigned));
+            assigned_before_use(right, assigned)
+        },
+        &Flat::Return(ref v) => assigned_before_use(v, assigned),
+        _ => Ok(()),
+    }
+}
+
+// Adds the variables assigned by `instrs` to `assigned`. After an
+// `if`, only the variables assigned in both branches are, and after a
+// loop only those assigned by its test.
+fn check_assigns(instrs: &Vec<Flat>, assigned: &mut HashSet<String>) -> Result<(), String> {
+    for instr in instrs {
+        match instr {
+            &Flat::Assign(ref dest, ref e) => {
+                try!(assigned_before_use(e, assigned));
+                assigned.insert(dest.clone());
+            },
+            &Flat::If(ref cnd, ref thn, ref els) => {
+                try!(assigned_before_use(cnd, assigned));
+                let mut thn_assigned = assigned.clone();
+                let mut els_assigned = assigned.clone();
+                try!(check_assigns(thn, &mut thn_assigned));
+                try!(check_assigns(els, &mut els_assigned));
+                *assigned = thn_assigned.intersection(&els_assigned).cloned().collect();
+            },
+            // the test runs at least once, the body maybe never
+            &Flat::While(ref test, ref cnd, ref body) => {
+                try!(check_assigns(test, assigned));
+                try!(assigned_before_use(cnd, assigned));
+                try!(check_assigns(body, &mut assigned.clone()));
+            },
+            _ => try!(assigned_before_use(instr, assigned)),
+        }
+    }
+    return Ok(());
+}
+
+// After flatten, every variable is assigned before it is used.
+pub fn check_flattened(prog: &FlatResult) -> Result<(), String> {
+    match prog {
+        &FlatResult::Prog(ref defs, ref assigns, _) => {
+            for def in defs {
+                try!(check_flattened(def));
+            }
+            check_assigns(assigns, &mut HashSet::new())
+        },
+        &FlatResult::Define(ref name, ref args, ref assigns, _) => {
+            let mut assigned = args.iter().cloned().collect();
+            check_assigns(assigns, &mut assigned).map_err(|e| format!("in {}: {}", name, e))
+        },
+        &FlatResult::Flat(_, _, _) => Err("not a Prog".to_string()),
+    }
+}
+
+fn no_vars(instrs: &Vec<X86>) -> Result<(), String> {
+    for instr in instrs {
+        let args = match instr {
+            &X86::Mov(ref a, ref b) | &X86::Add(ref a, ref b) | &X86::Sub(ref a, ref b) |
+            &X86::And(ref a, ref b) | &X86::Or(ref a, ref b) | &X86::Xor(ref a, ref b) |
+            &X86::Sar(ref a, ref b) | &X86::MovSd(ref a, ref b) | &X86::AddSd(ref a, ref b) | &X86::SubSd(ref a, ref b) |
+            &X86::XorPd(ref a, ref b) | &X86::CvtSi2Sd(ref a, ref b) |
+            &X86::Cmp(ref a, ref b) | &X86::MovZx(ref a, ref b) | &X86::EqP(ref a, ref b) |
+            &X86::CmpP(_, ref a, ref b) | &X86::CMov(_, ref a, ref b) => vec![a, b],
+            &X86::Neg(ref a) | &X86::IDiv(ref a) | &X86::Call(ref a) | &X86::TailJmp(ref a) |
+            &X86::Set(ref a, _) => vec![a],
+            &X86::If(ref cnd, ref thn, ref els) => {
+                try!(no_vars(&vec![(**cnd).clone()]));
+                try!(no_vars(thn));
+                try!(no_vars(els));
+                vec![]
+            },
+            &X86::While(ref test, ref cnd, ref body) => {
+                try!(no_vars(test));
+                try!(no_vars(&vec![(**cnd).clone()]));
+                try!(no_vars(body));
+                vec![]
+            },
+            _ => vec![],
+        };
+        for arg in args {
+            if let &X86Arg::Var(ref v) = arg {
+                return Err(format!("`{}` has no home in {:?}", v, instr));
+            }
+        }
+    }
+    return Ok(());
+}
+
+// After assign_homes, no instruction refers to a variable.
+pub fn check_homes_assigned(prog: &X86) -> Result<(), String> {
+    match prog {
+        &X86::ProgWithStackSize(ref defs, ref instrs, _, _) => {
+            for def in defs {
+                try!(check_homes_assigned(def));
+            }
+            no_vars(instrs)
+        },
+        &X86::DefineWithStackSize(ref name, _, _, ref instrs) =>
+            no_vars(instrs).map_err(|e| format!("in {}: {}", name, e)),
+        _ => Err("homes have not been assigned".to_string()),
+    }
+}
+
+#[test]
+fn test_broken_invariants() {
+    use Reg;
+    use parser::NO_SPAN;
+
+    let sym = |s: &str| SExpr::Symbol(s.to_string(), NO_SPAN);
+    let shadowed = SExpr::Let(vec![("x".to_string(), SExpr::Number(1))],
+                              box SExpr::Let(vec![("x".to_string(), SExpr::Number(2))],
+                                             box sym("x")));
+    assert!(check_uniquified(&shadowed).is_err());
+    let renamed = SExpr::Let(vec![("x1".to_string(), SExpr::Number(1))],
+                             box SExpr::Let(vec![("x2".to_string(), SExpr::Number(2))],
+                                            box sym("x2")));
+    assert_eq!(Ok(()), check_uniquified(&renamed));
+
+    // `y` is only assigned in one branch
+    let one_branch = FlatResult::Prog(vec![], vec![
+        Flat::If(box Flat::Bool(true),
+                 vec![Flat::Assign("y".to_string(), box Flat::Number(1))],
+                 vec![]),
+        Flat::Return(box Flat::Symbol("y".to_string())),
+    ], vec!["y".to_string()]);
+    assert!(check_flattened(&one_branch).is_err());
+    let args_assigned = FlatResult::Define("f".to_string(), vec!["a".to_string()],
+                                           vec![Flat::Return(box Flat::Symbol("a".to_string()))],
+                                           vec![]);
+    assert_eq!(Ok(()), check_flattened(&args_assigned));
+
+    let homeless = X86::ProgWithStackSize(vec![], vec![
+        X86::If(box X86::EqP(X86Arg::Reg(Reg::RAX), X86Arg::Imm(0)),
+                vec![X86::Mov(X86Arg::Reg(Reg::RAX), X86Arg::Var("z".to_string()))],
+                vec![]),
+    ], 0, vec![]);
+    assert!(check_homes_assigned(&homeless).is_err());
+}
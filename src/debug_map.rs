@@ -0,0 +1,67 @@
+// What `--emit-map` writes: for each function, its name in the source,
+// the label it was compiled to and the homes of its variables, as
+// JSON:
+//
+//   {"functions": [
+//       {"name": "add1", "label": "add11", "homes": {"x2": "r10"}},
+//       {"name": "inc", "label": null, "homes": {}},
+//       {"name": null, "label": "main", "homes": {}}
+//   ]}
+//
+// A function that was inlined everywhere has no label, and lambdas and
+// `main` have no name. Homes are printed as in the assembly.
+#[derive(Debug)]
+pub struct FunctionMap {
+    pub name: Option<String>,
+    pub label: Option<String>,
+    // by variable, sorted
+    pub homes: Vec<(String, String)>,
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    return json;
+}
+
+fn json_option(s: &Option<String>) -> String {
+    match s {
+        &Some(ref s) => json_string(s),
+        &None => "null".to_string(),
+    }
+}
+
+pub fn debug_map_json(functions: &[FunctionMap]) -> String {
+    let entries : Vec<String> = functions.iter().map(|f| {
+        let homes : Vec<String> = f.homes.iter()
+            .map(|&(ref var, ref home)| format!("{}: {}", json_string(var), json_string(home)))
+            .collect();
+        format!("    {{\"name\": {}, \"label\": {}, \"homes\": {{{}}}}}",
+                json_option(&f.name), json_option(&f.label), homes.join(", "))
+    }).collect();
+    return format!("{{\"functions\": [\n{}\n]}}\n", entries.join(",\n"));
+}
+
+#[test]
+fn test_debug_map_json() {
+    let functions = vec![
+        FunctionMap { name: Some("f\"".to_string()), label: Some("f1".to_string()),
+                      homes: vec![("x2".to_string(), "QWORD [rbp-8]".to_string()),
+                                  ("y3".to_string(), "rbx".to_string())] },
+        FunctionMap { name: None, label: Some("main".to_string()), homes: vec![] },
+    ];
+    assert_eq!("{\"functions\": [
+    {\"name\": \"f\\\"\", \"label\": \"f1\", \"homes\": {\"x2\": \"QWORD [rbp-8]\", \"y3\": \"rbx\"}},
+    {\"name\": null, \"label\": \"main\", \"homes\": {}}
+]}
+", debug_map_json(&functions));
+}
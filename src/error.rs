@@ -0,0 +1,130 @@
+use std::fmt;
+
+use lexer::LexError;
+use parser::{ParseError, SExpr, Span, MAX_FIXNUM};
+
+#[derive(Debug)]
+pub enum CompileError {
+    // the source contains no top-level expression to evaluate
+    EmptyProgram,
+    Parse(ParseError),
+    // a symbol that no definition or binding is in scope for, and
+    // where it is used
+    UnboundVariable(String, Span),
+    // a variable a `parameterize` rebinds that isn't defined by
+    // `define-dynamic`, and where the `parameterize` is
+    NotDynamic(String, Span),
+    // a primitive called with the wrong number of arguments: its
+    // name, how many it takes, how many it was given and where it is
+    // named in the call
+    Arity(String, usize, usize, Span),
+    // nasm couldn't be run, or rejected the generated assembly
+    Assemble(String),
+    // gcc couldn't link the program with the runtime
+    Link(String),
+    // the assembler or linker isn't installed
+    MissingTool(String),
+    // the file of `Config::emit_map` couldn't be written
+    WriteMap(String),
+    // found by `Config::typecheck`: what is wrong, and the form it is
+    // wrong in
+    Type(String, SExpr),
+}
+
+impl From<ParseError> for CompileError {
+    fn from(err: ParseError) -> CompileError {
+        CompileError::Parse(err)
+    }
+}
+
+impl From<LexError> for CompileError {
+    fn from(err: LexError) -> CompileError {
+        CompileError::Parse(ParseError::Lex(err))
+    }
+}
+
+// "line 3:7 ", or nothing for code the compiler made up.
+fn position(span: &Span) -> String {
+    if span.is_known() {
+        format!("line {}:{} ", span.line, span.col)
+    } else {
+        String::new()
+    }
+}
+
+fn plural(n: usize, word: &str) -> String {
+    format!("{} {}{}", n, word, if n == 1 { "" } else { "s" })
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &CompileError::EmptyProgram => write!(f, "no expression to evaluate"),
+            &CompileError::Parse(ParseError::UnknownForm(ref form)) =>
+                write!(f, "unsupported form `{}`", form),
+            &CompileError::Parse(ParseError::NonExhaustiveMatch) =>
+                write!(f, "`match` has values no clause matches"),
+            &CompileError::Parse(ParseError::LetrecUninitialized(ref name)) =>
+                write!(f, "`{}` is used in `letrec` before it is initialized", name),
+            &CompileError::Parse(ParseError::BadArity(ref form)) =>
+                write!(f, "`{}` has the wrong number of parts", form),
+            &CompileError::Parse(ParseError::MismatchedTypes(ref cmp, ref left, ref right)) =>
+                write!(f, "`{}` compares a {} with a {}, use `eq?` instead", cmp, left, right),
+            &CompileError::Parse(ParseError::NumberTooLarge(n)) =>
+                write!(f, "{} is too large for an integer, which is at most {}", n, MAX_FIXNUM),
+            &CompileError::Parse(ParseError::Lex(LexError::NumberTooLarge(line, col, ref n))) =>
+                write!(f, "line {}:{} {} doesn't fit in 64 bits", line, col, n),
+            &CompileError::Parse(ParseError::Lex(LexError::UnknownChar(line, col, ref name))) =>
+                write!(f, "line {}:{} unknown character #\\{}", line, col, name),
+            &CompileError::Parse(ParseError::Lex(LexError::InvalidCodePoint(line, col, ref name))) =>
+                write!(f, "line {}:{} #\\{} isn't a valid code point", line, col, name),
+            &CompileError::Parse(ParseError::Lex(LexError::UnterminatedString(line, col))) =>
+                write!(f, "line {}:{} unterminated string", line, col),
+            &CompileError::Parse(ParseError::Lex(LexError::UnknownEscape(line, col, c))) =>
+                write!(f, "line {}:{} unknown escape \\{}", line, col, c),
+            &CompileError::Parse(ParseError::Lex(LexError::UnexpectedChar(line, col, c))) =>
+                write!(f, "line {}:{} unexpected character '{}'", line, col, c),
+            &CompileError::Parse(ParseError::UnmatchedParen(line, col)) =>
+                write!(f, "line {}:{} unmatched ')'", line, col),
+            &CompileError::Parse(ParseError::UnclosedParen(line, col)) =>
+                write!(f, "line {}:{} '(' is never closed", line, col),
+            &CompileError::Parse(ParseError::BadParameter(ref param)) =>
+                write!(f, "`{}` can't be a parameter", param),
+            &CompileError::Parse(ParseError::BadName(ref name)) =>
+                write!(f, "`{}` can't be bound, it isn't a name", name),
+            &CompileError::Parse(ParseError::EmptyDatumComment(line, col)) =>
+                write!(f, "line {}:{} `#;` isn't followed by an expression", line, col),
+            &CompileError::Parse(ParseError::ValuesMismatch(names, values)) =>
+                write!(f, "`let-values` binds {} to {}", plural(names, "name"),
+                       plural(values, "value")),
+            &CompileError::Parse(ParseError::BadPattern(ref pat)) =>
+                write!(f, "`{}` can't be matched", pat),
+            &CompileError::Parse(ParseError::BadMatchClause(ref clause)) =>
+                write!(f, "`{}` isn't a `match` clause, which is (pattern body)", clause),
+            &CompileError::Parse(ParseError::BadBinding(ref binding)) =>
+                write!(f, "`{}` isn't a binding, which is (name value)", binding),
+            &CompileError::Parse(ParseError::BadQuote(ref expr)) =>
+                write!(f, "`{}` can't be quoted", expr),
+            &CompileError::Parse(ParseError::EmptyQuote(line, col)) =>
+                write!(f, "line {}:{} `'` isn't followed by an expression", line, col),
+            &CompileError::Parse(ParseError::EmptyApplication) =>
+                write!(f, "`()` isn't an expression, the empty list is `'()`"),
+            &CompileError::Parse(ParseError::NotTopLevel(ref form)) =>
+                write!(f, "`{}` is only allowed at the top level", form),
+            &CompileError::UnboundVariable(ref name, ref span) =>
+                write!(f, "{}unbound variable '{}'", position(span), name),
+            &CompileError::NotDynamic(ref name, ref span) =>
+                write!(f, "{}`parameterize` of '{}', which isn't a dynamic variable",
+                       position(span), name),
+            &CompileError::Arity(ref prim, expected, given, ref span) =>
+                write!(f, "{}`{}` takes {}, not {}", position(span), prim,
+                       plural(expected, "argument"), given),
+            &CompileError::Assemble(ref msg) => write!(f, "assembling failed: {}", msg),
+            &CompileError::Link(ref msg) => write!(f, "linking failed: {}", msg),
+            &CompileError::MissingTool(ref tool) =>
+                write!(f, "`{}` isn't installed, or isn't on the PATH", tool),
+            &CompileError::WriteMap(ref msg) => write!(f, "couldn't write the map: {}", msg),
+            &CompileError::Type(ref msg, ref form) => write!(f, "type error: {} in `{}`", msg, form),
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use parser::SExpr;
+use uniquify;
+
+// A function called from more places than this is left alone, however
+// small, so that inlining doesn't make the program much bigger.
+const INLINE_MAX_CALLS : usize = 4;
+
+// The expressions directly inside `expr`.
+fn children(expr: &SExpr) -> Vec<&SExpr> {
+    match expr {
+        &SExpr::List(ref es) | &SExpr::Begin(ref es) | &SExpr::Tuple(ref es) =>
+            es.iter().collect(),
+        &SExpr::Define(_, _, ref e) | &SExpr::DefineVal(_, ref e) |
+        &SExpr::Lambda(_, ref e) | &SExpr::Set(_, _, ref e) |
+        &SExpr::DefineDynamic(_, ref e) | &SExpr::DynamicSet(_, ref e) => vec![e],
+        &SExpr::Let(ref bindings, ref body) | &SExpr::Parameterize(ref bindings, ref body, _) => {
+            let mut es : Vec<&SExpr> = bindings.iter().map(|&(_, ref v)| v).collect();
+            es.push(body);
+            es
+        },
+        &SExpr::If(ref cnd, ref thn, ref els) => vec![cnd, thn, els],
+        &SExpr::While(ref left, ref right) | &SExpr::Cmp(_, ref left, ref right) =>
+            vec![left, right],
+        &SExpr::App(ref f, ref args) => {
+            let mut es = vec![&**f];
+            es.extend(args.iter());
+            es
+        },
+        &SExpr::Prog(ref defs, ref main) => {
+            let mut es : Vec<&SExpr> = defs.iter().collect();
+            es.push(main);
+            es
+        },
+        _ => vec![],
+    }
+}
+
+// The number of nodes in `expr`, which is what the inlining threshold
+// is measured in.
+fn size(expr: &SExpr) -> usize {
+    1 + children(expr).into_iter().map(size).sum::<usize>()
+}
+
+// The variables `expr` reads or assigns.
+fn add_names(expr: &SExpr, names: &mut HashSet<String>) {
+    match expr {
+        &SExpr::Symbol(ref name, _) | &SExpr::Set(ref name, _, _) => {
+            names.insert(name.clone());
+        },
+        _ => (),
+    }
+    for e in children(expr) {
+        add_names(e, names);
+    }
+}
+
+fn names(expr: &SExpr) -> HashSet<String> {
+    let mut names = HashSet::new();
+    add_names(expr, &mut names);
+    return names;
+}
+
+// How many times each function is called by name.
+fn count_calls(expr: &SExpr, counts: &mut HashMap<String, usize>) {
+    if let &SExpr::App(box SExpr::Symbol(ref f, _), _) = expr {
+        *counts.entry(f.clone()).or_insert(0) += 1;
+    }
+    for e in children(expr) {
+        count_calls(e, counts);
+    }
+}
+
+// Whether `f` can call itself, directly or through other functions.
+// `calls` maps each function to the ones it names.
+fn is_recursive(f: &str, calls: &HashMap<String, HashSet<String>>) -> bool {
+    let mut seen = HashSet::new();
+    let mut todo : Vec<&String> = calls[f].iter().collect();
+    while let Some(g) = todo.pop() {
+        if g == f {
+            return true;
+        }
+        if seen.insert(g) {
+            todo.extend(calls[g].iter());
+        }
+    }
+    return false;
+}
+
+// A copy of `args` and `body` with new names for every variable they
+// bind, so that each inlined copy of a function is still unique.
+fn freshen(args: &[String], body: &SExpr) -> (Vec<String>, SExpr) {
+    let lambda = SExpr::Lambda(args.to_vec(), box body.clone());
+    let mut mapping : HashMap<String, String> =
+        names(&lambda).into_iter().map(|name| (name.clone(), name)).collect();
+    match uniquify(&mut mapping, lambda) {
+        Ok(SExpr::Lambda(args, body)) => (args, *body),
+        r => panic!("couldn't rename an inlined body: {:?}", r),
+    }
+}
+
+// `expr` with the calls to `fns` replaced by their bodies, whose
+// arguments are bound by a `let`.
+fn inline_calls(expr: SExpr, fns: &HashMap<String, (Vec<String>, SExpr)>) -> SExpr {
+    let inline = |e: SExpr| inline_calls(e, fns);
+    let inline_all = |es: Vec<SExpr>| -> Vec<SExpr> {
+        es.into_iter().map(|e| inline_calls(e, fns)).collect()
+    };
+    match expr {
+        SExpr::App(box SExpr::Symbol(f, span), args) => {
+            let args = inline_all(args);
+            match fns.get(&f) {
+                Some(&(ref params, ref body)) if params.len() == args.len() => {
+                    let (params, body) = freshen(params, body);
+                    // the body has no cycles through `fns`, so this ends
+                    let body = inline_calls(body, fns);
+                    if params.is_empty() {
+                        return body;
+                    }
+                    SExpr::Let(params.into_iter().zip(args).collect(), box body)
+                },
+                _ => SExpr::App(box SExpr::Symbol(f, span), args),
+            }
+        },
+        SExpr::App(f, args) => SExpr::App(box inline(*f), inline_all(args)),
+        SExpr::List(es) => SExpr::List(inline_all(es)),
+        SExpr::Begin(es) => SExpr::Begin(inline_all(es)),
+        SExpr::Tuple(es) => SExpr::Tuple(inline_all(es)),
+        SExpr::Define(name, args, body) => SExpr::Define(name, args, box inline(*body)),
+        SExpr::Lambda(args, body) => SExpr::Lambda(args, box inline(*body)),
+        SExpr::Set(name, span, val) => SExpr::Set(name, span, box inline(*val)),
+        SExpr::DynamicSet(label, val) => SExpr::DynamicSet(label, box inline(*val)),
+        SExpr::Let(bindings, body) =>
+            SExpr::Let(bindings.into_iter().map(|(k, v)| (k, inline(v))).collect(),
+                       box inline(*body)),
+        SExpr::If(cnd, thn, els) =>
+            SExpr::If(box inline(*cnd), box inline(*thn), box inline(*els)),
+        SExpr::While(test, body) => SExpr::While(box inline(*test), box inline(*body)),
+        SExpr::Cmp(cc, left, right) => SExpr::Cmp(cc, box inline(*left), box inline(*right)),
+        _ => expr,
+    }
+}
+
+// Inline the functions of at most `max_size` nodes that are called
+// from at most INLINE_MAX_CALLS places and can't call themselves.
+// Their bodies are renamed with uniquify at every call, and a function
+// that is no longer named anywhere afterwards is dropped. Runs on the
+// uniquified program, so that an inlined body can't capture the
+// caller's variables, and before box_mutated, which boxes the `let`
+// variables of arguments the body assigns.
+pub fn inline_functions(prog: SExpr, max_size: usize) -> SExpr {
+    let (defs, main) = match prog {
+        SExpr::Prog(defs, main) => (defs, main),
+        _ => return prog,
+    };
+
+    let mut calls = HashMap::new();
+    count_calls(&SExpr::Prog(defs.clone(), main.clone()), &mut calls);
+    let defined : HashSet<String> = defs.iter().filter_map(|def| match def {
+        &SExpr::Define(ref name, _, _) => Some(name.clone()),
+        _ => None,
+    }).collect();
+    let mut callees = HashMap::new();
+    for def in &defs {
+        if let &SExpr::Define(ref name, _, ref body) = def {
+            callees.insert(name.clone(), names(body).intersection(&defined).cloned().collect());
+        }
+    }
+
+    let mut fns = HashMap::new();
+    for def in &defs {
+        if let &SExpr::Define(ref name, ref args, ref body) = def {
+            // a rest parameter is collected using the argument count
+            // the caller passes
+            if size(body) <= max_size &&
+                calls.get(name).cloned().unwrap_or(0) <= INLINE_MAX_CALLS &&
+                !names(body).contains("%rest-args") &&
+                !is_recursive(name, &callees) {
+                    fns.insert(name.clone(), (args.clone(), (**body).clone()));
+                }
+        }
+    }
+    if fns.is_empty() {
+        return SExpr::Prog(defs, main);
+    }
+
+    let mut defs : Vec<SExpr> = defs.into_iter().map(|def| inline_calls(def, &fns)).collect();
+    let main = inline_calls(*main, &fns);
+    loop {
+        let mut used = names(&main);
+        for def in &defs {
+            used.extend(names(def));
+        }
+        let before = defs.len();
+        defs.retain(|def| match def {
+            &SExpr::Define(ref name, _, _) => !fns.contains_key(name) || used.contains(name),
+            _ => true,
+        });
+        if defs.len() == before {
+            break;
+        }
+    }
+    return SExpr::Prog(defs, box main);
+}
@@ -2,11 +2,35 @@
 pub enum Token {
     LParen,
     RParen,
+    Quote,
+    // `#;`, which comments out the expression after it
+    DatumComment,
     Symbol(String),
+    // #t or #f, which can also be written `true` and `false`
+    Bool(bool),
     Number(i64),
+    Float(f64),
+    Char(char),
+    Str(String),
     EOF,
 }
 
+// What the lexer couldn't make a token of, and the line and column
+// it got to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexError {
+    // an integer literal that doesn't fit in 64 bits
+    NumberTooLarge(usize, usize, String),
+    // a character literal with an unknown name, eg. #\foo
+    UnknownChar(usize, usize, String),
+    // a hex character literal that isn't a Unicode scalar value, eg.
+    // #\xd800
+    InvalidCodePoint(usize, usize, String),
+    UnterminatedString(usize, usize),
+    UnknownEscape(usize, usize, char),
+    UnexpectedChar(usize, usize, char),
+}
+
 pub struct LexerState {
     pub s: String,
     pub pos: usize,
@@ -15,10 +39,46 @@ pub struct LexerState {
     pub tok_buf: Option<Token>,
 }
 
+// A lexer at the start of `s`.
+pub fn lexer_for(s: &str) -> LexerState {
+    LexerState {
+        s: s.to_string(),
+        pos: 0,                 // absolute position
+        col: 1,                 // column in line
+        line_num: 1,
+        tok_buf: None,
+    }
+}
+
+// The length of the fraction and exponent following the digits of a
+// float, eg. ".14" in "3.14" or "e10" in "1e10", or 0 if there are
+// none.
+fn float_suffix_len(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    let digits = |from: usize| {
+        bytes.iter().skip(from).take_while(|b| (**b as char).is_digit(10)).count()
+    };
+
+    let mut len = 0;
+    if bytes.get(0) == Some(&b'.') && digits(1) > 0 {
+        len = 1 + digits(1);
+    }
+    if bytes.get(len) == Some(&b'e') || bytes.get(len) == Some(&b'E') {
+        let sign = match bytes.get(len + 1) {
+            Some(&b'+') | Some(&b'-') => 1,
+            _ => 0,
+        };
+        if digits(len + 1 + sign) > 0 {
+            len += 1 + sign + digits(len + 1 + sign);
+        }
+    }
+    return len;
+}
+
 fn is_valid_symbol_start(c: char) -> bool {
     // TODO: avoid allocatiing this in each call
     let symbol_start_chars = vec!['+', '-', '*', '/', '#', '<', '>',
-                                  '='];
+                                  '=', '!', '?', '_'];
 
     let mut ret = false;
     if c.is_alphabetic() { ret = true; }
@@ -32,10 +92,10 @@ fn is_valid_symbol_start(c: char) -> bool {
     return ret;
 }
 
-pub fn get_token(ls: &mut LexerState) -> Token {
+pub fn get_token(ls: &mut LexerState) -> Result<Token, LexError> {
     if let Some(tok) = ls.tok_buf.clone() {
         ls.tok_buf = None;
-        return tok;
+        return Ok(tok);
     }
     else {
         let mut iter = ls.s[ls.pos..].chars().peekable();
@@ -53,7 +113,55 @@ pub fn get_token(ls: &mut LexerState) -> Token {
                         None => break,
                     };
                 }
-                return Token::Number(acc.parse().unwrap());
+                let float_len = float_suffix_len(&ls.s[ls.pos..]);
+                if float_len > 0 {
+                    acc.push_str(&ls.s[ls.pos..ls.pos + float_len]);
+                    ls.pos += float_len;
+                    ls.col += float_len;
+                    return Ok(Token::Float(acc.parse().unwrap()));
+                }
+                return match acc.parse() {
+                    Ok(n) => Ok(Token::Number(n)),
+                    Err(_) => Err(LexError::NumberTooLarge(ls.line_num, ls.col, acc)),
+                };
+            }
+            else if ls.s[ls.pos..].starts_with("#;") {
+                ls.pos += 2;
+                ls.col += 2;
+                return Ok(Token::DatumComment);
+            }
+            // a character, eg. #\a, one named by a word, eg. #\space, or
+            // one given by its code in hex, eg. #\x41
+            else if ls.s[ls.pos..].starts_with("#\\") {
+                iter.next();
+                iter.next();
+                ls.pos += 2;
+                ls.col += 2;
+                let mut acc = String::new();
+                while let Some(&x) = iter.peek() {
+                    if !acc.is_empty() && !x.is_alphanumeric() {
+                        break;
+                    }
+                    acc.push(x);
+                    iter.next();
+                    ls.pos += x.len_utf8();
+                    ls.col += 1;
+                }
+                if acc.chars().count() == 1 {
+                    return Ok(Token::Char(acc.chars().next().unwrap()));
+                }
+                if acc.starts_with('x') && acc[1..].chars().all(|x| x.is_digit(16)) {
+                    return match u32::from_str_radix(&acc[1..], 16).ok().and_then(char::from_u32) {
+                        Some(x) => Ok(Token::Char(x)),
+                        None => Err(LexError::InvalidCodePoint(ls.line_num, ls.col, acc)),
+                    };
+                }
+                return match &acc[..] {
+                    "space" => Ok(Token::Char(' ')),
+                    "newline" => Ok(Token::Char('\n')),
+                    "tab" => Ok(Token::Char('\t')),
+                    _ => Err(LexError::UnknownChar(ls.line_num, ls.col, acc)),
+                };
             }
             else if is_valid_symbol_start(c) {
                 let mut acc = String::new();
@@ -68,14 +176,18 @@ pub fn get_token(ls: &mut LexerState) -> Token {
                         None => break,
                     };
                 }
-                return Token::Symbol(acc);
+                return Ok(match &acc[..] {
+                    "#t" | "true" => Token::Bool(true),
+                    "#f" | "false" => Token::Bool(false),
+                    _ => Token::Symbol(acc),
+                });
             }
             else {
                 match c {
                     '\n' => {
                         iter.next();
                         ls.pos += 1;
-                        ls.col = 0;
+                        ls.col = 1;
                         ls.line_num += 1;
                         continue;
                     },
@@ -90,7 +202,7 @@ pub fn get_token(ls: &mut LexerState) -> Token {
                             }
                         }
                         ls.line_num += 1;
-                        ls.col = 0;
+                        ls.col = 1;
                     },
                     ' ' => {
                         iter.next();
@@ -102,19 +214,132 @@ pub fn get_token(ls: &mut LexerState) -> Token {
                         iter.next();
                         ls.pos += 1;
                         ls.col += 1;
-                        return Token::LParen
+                        return Ok(Token::LParen)
                     },
                     ')' => {
                         iter.next();
                         ls.pos += 1;
                         ls.col += 1;
-                        return Token::RParen
+                        return Ok(Token::RParen)
+                    },
+                    '\'' => {
+                        iter.next();
+                        ls.pos += 1;
+                        ls.col += 1;
+                        return Ok(Token::Quote)
+                    },
+                    // the dot before a rest parameter
+                    '.' => {
+                        iter.next();
+                        ls.pos += 1;
+                        ls.col += 1;
+                        return Ok(Token::Symbol(".".to_string()))
+                    },
+                    '"' => {
+                        iter.next();
+                        ls.pos += 1;
+                        ls.col += 1;
+                        let mut acc = String::new();
+                        loop {
+                            let s = match iter.next() {
+                                Some(s) => s,
+                                None => return Err(LexError::UnterminatedString(ls.line_num,
+                                                                                ls.col)),
+                            };
+                            ls.pos += s.len_utf8();
+                            ls.col += 1;
+                            match s {
+                                '"' => return Ok(Token::Str(acc)),
+                                '\\' => {
+                                    let e = match iter.next() {
+                                        Some(e) => e,
+                                        None => return Err(LexError::UnterminatedString(
+                                            ls.line_num, ls.col)),
+                                    };
+                                    ls.pos += e.len_utf8();
+                                    ls.col += 1;
+                                    acc.push(match e {
+                                        'n' => '\n',
+                                        't' => '\t',
+                                        '\\' => '\\',
+                                        '"' => '"',
+                                        _ => return Err(LexError::UnknownEscape(ls.line_num,
+                                                                                ls.col, e)),
+                                    });
+                                },
+                                '\n' => {
+                                    acc.push(s);
+                                    ls.line_num += 1;
+                                    ls.col = 1;
+                                },
+                                _ => acc.push(s),
+                            }
+                        }
                     },
 
-                    _ => panic!("line {}:{} unexpected char: {}", ls.line_num, ls.col, c),
+                    _ => return Err(LexError::UnexpectedChar(ls.line_num, ls.col, c)),
                 }
             }
         }
-        return Token::EOF;
+        return Ok(Token::EOF);
     }
 }
+
+#[test]
+fn test_string_escapes() {
+    let cases = vec![(r#""a\nb""#, "a\nb"),
+                     (r#""a\tb""#, "a\tb"),
+                     (r#""a\\b""#, "a\\b"),
+                     (r#""a\"b""#, "a\"b")];
+    for (src, expected) in cases {
+        let mut lexer = lexer_for(src);
+        assert_eq!(Ok(Token::Str(expected.to_string())), get_token(&mut lexer));
+        // the escape takes two columns
+        assert_eq!(src.len() + 1, lexer.col);
+        assert_eq!(Ok(Token::EOF), get_token(&mut lexer));
+    }
+}
+
+#[test]
+fn test_chars() {
+    let mut lexer = lexer_for("(#\\a #\\space #\\))");
+    assert_eq!(Ok(Token::LParen), get_token(&mut lexer));
+    assert_eq!(Ok(Token::Char('a')), get_token(&mut lexer));
+    assert_eq!(Ok(Token::Char(' ')), get_token(&mut lexer));
+    assert_eq!(Ok(Token::Char(')')), get_token(&mut lexer));
+    assert_eq!(Ok(Token::RParen), get_token(&mut lexer));
+}
+
+#[test]
+fn test_bools() {
+    let mut lexer = lexer_for("#t true #f false truest #true");
+    assert_eq!(Ok(Token::Bool(true)), get_token(&mut lexer));
+    assert_eq!(Ok(Token::Bool(true)), get_token(&mut lexer));
+    assert_eq!(Ok(Token::Bool(false)), get_token(&mut lexer));
+    assert_eq!(Ok(Token::Bool(false)), get_token(&mut lexer));
+    assert_eq!(Ok(Token::Symbol("truest".to_string())), get_token(&mut lexer));
+    assert_eq!(Ok(Token::Symbol("#true".to_string())), get_token(&mut lexer));
+}
+
+#[test]
+fn test_hex_chars() {
+    let mut lexer = lexer_for("#\\x41 #\\newline #\\x3bb #\\x #\\x110000");
+    assert_eq!(Ok(Token::Char('A')), get_token(&mut lexer));
+    assert_eq!(Ok(Token::Char('\n')), get_token(&mut lexer));
+    assert_eq!(Ok(Token::Char('λ')), get_token(&mut lexer));
+    assert_eq!(Ok(Token::Char('x')), get_token(&mut lexer));
+    assert_eq!(Err(LexError::InvalidCodePoint(1, 37, "x110000".to_string())),
+               get_token(&mut lexer));
+}
+
+#[test]
+fn test_floats() {
+    let mut lexer = lexer_for("3.14 1e10 2.5E-3 7 1.x");
+    assert_eq!(Ok(Token::Float(3.14)), get_token(&mut lexer));
+    assert_eq!(Ok(Token::Float(1e10)), get_token(&mut lexer));
+    assert_eq!(Ok(Token::Float(2.5e-3)), get_token(&mut lexer));
+    assert_eq!(Ok(Token::Number(7)), get_token(&mut lexer));
+    assert_eq!(19, lexer.col);
+    // a dot without digits after it isn't a fraction
+    assert_eq!(Ok(Token::Number(1)), get_token(&mut lexer));
+}
@@ -0,0 +1,6793 @@
+#![feature(advanced_slice_patterns, slice_patterns)]
+#![feature(box_syntax, box_patterns)]
+
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod util;
+mod lexer;
+mod parser;
+mod anf;
+mod opt;
+mod check;
+mod error;
+mod typecheck;
+mod stats;
+mod inline;
+mod debug_map;
+
+pub use error::CompileError;
+
+use util::{get_unique_label, get_unique_varname, intern_symbol, interned_symbols,
+           reset_symbols, reset_var_counter};
+
+use lexer::Token;
+use lexer::{get_token, lexer_for};
+
+use parser::{SExpr, CC, Span, NO_SPAN, cc_name};
+use parser::{get_expr, get_toplevel};
+#[cfg(test)]
+use parser::{read, MAX_FIXNUM};
+
+use anf::{Flat,FlatResult};
+use anf::{check_arity, flatten};
+
+use opt::eliminate_common_subexprs;
+use opt::const_fold;
+use opt::{eliminate_dead_code, eliminate_dead_code_checked};
+use opt::mark_tail_calls;
+use opt::reuse_temps;
+use opt::assigned_vars;
+
+use check::{check_uniquified, check_flattened, check_homes_assigned};
+
+use typecheck::typecheck;
+
+pub use stats::{PassStats, stats_table};
+
+use inline::inline_functions;
+
+use debug_map::{FunctionMap, debug_map_json};
+
+
+
+#[derive(Debug, Clone, PartialEq, Hash, Eq)]
+enum Reg {
+    AL,
+
+    RAX, RBX, RBP, RSP, RCX, RDX, RDI, RSI,
+    R8, R9, R10, R11, R12, R13, R14, R15,
+}
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+enum X86Arg {
+    Reg(Reg),
+    Imm(u64),
+    RegOffset(Reg, i64),
+    RegIndex(Reg, Reg, i64, i64), // base + index * scale + displacement
+    GlobalVal(String),
+    FuncName(String),
+    XmmReg(u8),      // xmm0 and xmm1 are scratch for float arithmetic
+    Var(String),     // pseudo-x86
+}
+
+
+// TODO: It might be a good idea to pull the constructors pertaining
+// to pseudo-x86 into a separate datatype.
+#[derive(Debug, Clone)]
+enum X86 {
+    Mov(X86Arg, X86Arg),
+    Add(X86Arg, X86Arg),
+    Sub(X86Arg, X86Arg),
+    And(X86Arg, X86Arg),
+    Or(X86Arg, X86Arg),
+    Xor(X86Arg, X86Arg),
+    Sar(X86Arg, X86Arg),
+    Neg(X86Arg),
+    Cqo,                          // sign-extends rax into rdx
+    // divides rdx:rax, leaving the quotient in rax and the remainder
+    // in rdx
+    IDiv(X86Arg),
+    // SSE, only used with xmm registers and r11 or rax
+    MovSd(X86Arg, X86Arg),
+    AddSd(X86Arg, X86Arg),
+    SubSd(X86Arg, X86Arg),
+    XorPd(X86Arg, X86Arg),
+    CvtSi2Sd(X86Arg, X86Arg),
+    Cmp(X86Arg, X86Arg),
+    Push(Reg),
+    Pop(Reg),
+    Set(X86Arg, CC),
+    MovZx(X86Arg, X86Arg),
+    EqP(X86Arg, X86Arg),          // pseudo-X86
+    CmpP(CC, X86Arg, X86Arg),     // pseudo-X86
+    If(Box<X86>, Vec<X86>, Vec<X86>), // pseudo-X86
+    // pseudo-X86: runs the test instructions, leaves the loop if the
+    // cond holds, else runs the body and starts over
+    While(Vec<X86>, Box<X86>, Vec<X86>),
+
+    // pseudo-X86
+    IfWithLives(Box<X86>,                      // cond
+                Vec<X86>,                      // then
+                Vec<HashSet<String>>,          // then-live-sets
+                Vec<X86>,                      // else
+                Vec<HashSet<String>>           // else-live-sets
+    ),
+    // pseudo-X86
+    WhileWithLives(Vec<X86>,                   // test
+                   Vec<HashSet<String>>,       // test-live-sets
+                   Box<X86>,                   // cond
+                   Vec<X86>,                   // body
+                   Vec<HashSet<String>>        // body-live-sets
+    ),
+    Define(String, Vec<String>, Vec<X86>),
+    DefineWithLives(String,               //  name
+                    Vec<String>,          // vars
+                    Vec<HashSet<String>>, // live_sets
+                    Vec<X86>,             // instrs
+    ),
+    DefineWithStackSize(String, // name
+                        i64,    // stack size
+                        Vec<Reg>, // callee-save regs to save
+                        Vec<X86>, // instrs
+                        ),
+
+    Prog(Vec<X86>,              // defines
+         Vec<X86>,              // main-instructions
+         Vec<String>            // main-vars
+    ),
+
+    ProgWithLives(Vec<X86>,     // defines
+                  Vec<X86>,     // main-instructions
+                  Vec<String>,  // main-vars
+                  Vec<HashSet<String>> // live-sets
+    ),
+
+    ProgWithStackSize(Vec<X86>,     // defines
+                      Vec<X86>,     // main-instructions
+                      i64,          // stack size
+                      Vec<Reg>,     // callee-save regs to save
+    ),
+    Call(X86Arg),
+    TailJmp(X86Arg),              // pseudo-X86, leaves the frame and jumps
+    // pseudo-X86: make room for this many bytes on the heap, calling
+    // the collector if there isn't. The registers are roots besides
+    // the live variables, see collect_instrs.
+    Collect(u64, Vec<X86Arg>),
+    CMov(CC, X86Arg, X86Arg),
+    JmpIf(CC, String),
+    Jmp(String),
+    Label(String),
+    Comment(String),            // what the next instructions are for
+}
+
+// CPU family to select instructions for. `Baseline` sticks to
+// instructions available on every x86-64 CPU and prefers branches for
+// conditional values, `Modern` may use `cmov` and friends.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TargetCpu {
+    Baseline,
+    Modern,
+}
+
+// The stage after which `compile_with` stops and dumps its output.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Emit {
+    Tokens,                     // the lexer's token stream
+    Ast,                        // SExprs, straight out of the parser
+    Anf,                        // FlatResult handed to instruction selection
+    Px86,                       // pseudo-x86 with live sets, see pretty_x86
+    Asm,                        // NASM assembly
+    Object,                     // ELF object from compile_to_object;
+                                // compile_with gives its assembly
+    Executable,                 // linked program from compile_to_executable;
+                                // compile_with gives its assembly
+}
+
+// How variables are assigned registers. `LinearScan` walks the live
+// ranges in order, `GraphColoring` colors the interference graph
+// built from the live sets. Both can reuse a register across the two
+// branches of an `if`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Allocator {
+    LinearScan,
+    GraphColoring,
+}
+
+// The assembly dialect to print. `Intel` is for NASM, `Att` for GAS,
+// so the output can be assembled with `as` or `gcc`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Syntax {
+    Intel,
+    Att,
+}
+
+// The OS the program is for. macOS wants an underscore in front of
+// every symbol, and Mach-O objects rather than ELF ones.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Linux,
+    MacOs,
+}
+
+// Which optional passes run, see flat_passes and peephole_passes.
+// `O0` runs none of them, for output that follows the source closely.
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum OptLevel {
+    O0,
+    O1,
+    O2,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub target_cpu: TargetCpu,
+    pub emit: Emit,
+    pub allocator: Allocator,
+    // check the invariants passes rely on after running them, see
+    // check.rs
+    pub check_invariants: bool,
+    // trap when `+` or `-` overflows, rather than wrapping around
+    pub checked_arith: bool,
+    pub syntax: Syntax,
+    pub target: Target,
+    // comment the assembly with the Flat instruction each block of it
+    // was selected for
+    pub annotate: bool,
+    pub opt_level: OptLevel,
+    // bytes of heap for tuples, closures and other boxed objects. The
+    // collector takes as much again to copy them to.
+    pub heap_size: u64,
+    // reject programs with a type error that is certain to happen,
+    // see typecheck.rs
+    pub typecheck: bool,
+    // warn about each `let` binding that is never used, in the Report
+    pub warn_unused: bool,
+    // call the runtime through the PLT, so the program can be linked
+    // as a position-independent executable
+    pub pie: bool,
+    // show the Report's stats, the size of the program after each
+    // pass, with stats_table
+    pub stats: bool,
+    // at -O2, functions of at most this many nodes are inlined, see
+    // inline.rs
+    pub inline_size: usize,
+    // at -O2, the graph-coloring allocator gives the two variables of
+    // a move the same home where it can, see coalesce_moves
+    pub coalesce_moves: bool,
+    // write the labels of the functions and the homes of their
+    // variables to this file, see debug_map.rs
+    pub emit_map: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            target_cpu: TargetCpu::Baseline,
+            emit: Emit::Asm,
+            allocator: Allocator::GraphColoring,
+            check_invariants: cfg!(debug_assertions),
+            checked_arith: false,
+            syntax: Syntax::Intel,
+            target: Target::Linux,
+            annotate: false,
+            opt_level: OptLevel::O2,
+            heap_size: DEFAULT_HEAP_SIZE,
+            typecheck: false,
+            warn_unused: false,
+            pie: false,
+            stats: false,
+            inline_size: DEFAULT_INLINE_SIZE,
+            coalesce_moves: true,
+            emit_map: None,
+        }
+    }
+}
+
+const PRIMITIVES : [&'static str; 37] = [
+    "+", "-", "tuple-ref", "tuple-length", "tuple", "min", "max", "abs",
+    "quotient", "remainder", "modulo", "symbol->string",
+    "make-hash", "hash-set!", "hash-ref", "cons", "car", "cdr", "pair?",
+    "string-length", "eq?", "equal?", "assert", "print", "exit",
+    "print_hex", "print_binary", "make-vector", "vector-ref", "vector-set!", "vector-length",
+    // for variables boxed by box_mutated, rest parameters and
+    // `match` and `let-values`; these can't be lexed, so programs
+    // can't use them
+    "%unbox", "%set-box!", "%rest-args", "%tuple?", "%match-error",
+    "%values-error",
+];
+
+// Holds the number of arguments a function with a rest parameter was
+// called with, which callers pass in r11. See %rest-args.
+const ARGC_VAR : &'static str = "%argc";
+
+const CONST_TRUE : u64  = 0xffffffffffffffff;
+const CONST_FALSE : u64 = 0x7fffffffffffffff;
+const CONST_NIL : u64   = 0x3fffffffffffffff;
+// what `set!` and `while` give, see VOID in runtime.c
+const CONST_VOID : u64  = 0x1fffffffffffffff;
+
+// A character is its code shifted left by 8, with this in the low
+// byte.
+const CHAR_TAG : u64 = 0x0f;
+// And a symbol is its index in the symbol table, see print_symbols.
+const SYMBOL_TAG : u64 = 0x1f;
+
+// Boxed objects, eg. strings, are tagged with BOXED_TAG, and their
+// first word is their kind. These agree with runtime.c.
+const BOXED_TAG : u64 = 0x3;
+const STRING_KIND : u64 = 2;
+const FLOAT_KIND : u64 = 3;
+
+// emitted after main, calls overflow_error in runtime.c
+const OVERFLOW_TRAP : &'static str = "rusl_overflow_trap";
+
+// the number of dynamic variables, followed by them, see
+// print_dynamics
+const DYNAMICS : &'static str = "rusl_dynamics";
+
+// the number of symbols, followed by their names as strings, see
+// print_symbols
+const SYMBOLS : &'static str = "rusl_symbols";
+
+// The number of bytes main asks initialize to allocate for each half
+// of the heap, unless --heap-size says otherwise.
+pub const DEFAULT_HEAP_SIZE : u64 = 1 << 20;
+
+// The most nodes a function's body can have for it to be inlined,
+// unless --inline says otherwise. Enough for a helper like
+// `(define (inc x) (+ x 1))` several times over.
+pub const DEFAULT_INLINE_SIZE : usize = 20;
+
+// messages of rusl_error in runtime.c
+const ERROR_CAR_NOT_PAIR : u64 = 0;
+const ERROR_CDR_NOT_PAIR : u64 = 1;
+const ERROR_INDEX_OUT_OF_RANGE : u64 = 2;
+const ERROR_VECTOR_INDEX_OUT_OF_RANGE : u64 = 3;
+const ERROR_DIVISION_BY_ZERO : u64 = 4;
+const ERROR_NOT_SYMBOL : u64 = 5;
+const ERROR_NOT_NUMBER : u64 = 6;
+const ERROR_NO_MATCH : u64 = 7;
+const ERROR_VALUES_MISMATCH : u64 = 8;
+
+// The runtime function that allocates a vector. Unlike the others it
+// allocates on the heap, so the live variables are pushed as roots
+// around calls to it.
+const MAKE_VECTOR : &'static str = "make_vector";
+
+// R15 is used to point to rootstack
+// R11 is used to point to heap
+const CALLEE_SAVE_REGS : [Reg;4] =
+    [Reg::RBX, Reg::R12, Reg::R13, Reg::R14, // Reg::R15
+    ];
+const CALLER_SAVE_REGS : [Reg;7] =
+    [Reg::RDX, Reg::RCX, Reg::RSI, Reg::RDI,
+     Reg::R8, Reg::R9, Reg::R10, // Reg::R11
+    ];
+// patch_single_instr's scratch register. It's only given to a
+// variable in a function that has nothing to patch, see spare_regs,
+// and the result of a call is moved out of it right away, so nothing
+// patched can clobber a value still needed there.
+const SCRATCH_REG : Reg = Reg::RAX;
+// order of registers in which to place first 6 arguments
+const ARG_REG_ORDER : [Reg; 6] = [Reg::RDI,
+                                  Reg::RSI,
+                                  Reg::RDX,
+                                  Reg::RCX,
+                                  Reg::R8,
+                                  Reg::R9];
+
+// NOTE: registers to use during register allocation. Currently,
+// registers that are used in passing arguments are excluded because
+// the register allocation pass does not account for interferences
+// between registers and variables.
+const REGS : [Reg;5] = [
+    // callee-save
+    Reg::RBX, Reg::R12, Reg::R13, Reg::R14, // Reg::R15,
+
+    // caller-save
+    Reg::R10,
+    // Reg::RDX, Reg::RCX, Reg::RSI, Reg::RDI,
+    // Reg::R8, Reg::R9, Reg::R11
+];
+// caller-save registers a function can give to its variables as well
+// as REGS, if it doesn't use them otherwise. See spare_regs.
+const SPARE_REGS : [Reg;2] = [Reg::RAX, Reg::RDX];
+
+// uniquify variable names. This function simply adds a monotonically
+// increasing counter(VAR_COUNTER) to each and every variable.
+fn uniquify(mapping: &mut HashMap<String, String>, expr: SExpr)
+            -> Result<SExpr, CompileError> {
+    match expr {
+        SExpr::Symbol(name, span) => {
+            match mapping.get(&name) {
+                Some(uniq_name) => Ok(SExpr::Symbol(uniq_name.to_string(), span)),
+                None => Err(CompileError::UnboundVariable(name, span)),
+            }
+        },
+        SExpr::FuncName(_) => panic!("FuncName should not be used before closure-conversion"),
+        SExpr::Number(_) => Ok(expr),
+        SExpr::Bool(_) | SExpr::Void => Ok(expr),
+        SExpr::Float(_) | SExpr::Char(_) | SExpr::Str(_) | SExpr::QuotedSymbol(_) => Ok(expr),
+        SExpr::Tuple(elts) => {
+            let elts = try!(elts.into_iter()
+                            .map(|e| uniquify(mapping, e))
+                            .collect());
+            Ok(SExpr::Tuple(elts))
+        },
+        // Bindings are only in scope in the body, so that a local
+        // shadowing a function, or an outer variable, doesn't hide it
+        // after the body. The initializers see the outer variables,
+        // even one of the same name, as `(let ((x (+ x 1))) x)` does.
+        SExpr::Let(bindings, body) => {
+            let mut body_mapping = mapping.clone();
+            let mut new_bindings = vec![];
+            for (k,v) in bindings {
+                let v = try!(uniquify(mapping, v));
+                let uniq_k = get_unique_varname(&k);
+                body_mapping.insert(k, uniq_k.clone());
+                new_bindings.push((uniq_k, v));
+            }
+            return Ok(SExpr::Let(new_bindings,
+                                 Box::new(try!(uniquify(&mut body_mapping, *body)))));
+        },
+        SExpr::List(elts) => {
+            let elts = try!(elts.into_iter().map(|e| uniquify(mapping, e)).collect());
+
+            return Ok(SExpr::List(elts));
+        }
+        SExpr::Cmp(cc, left, right) =>
+            return Ok(SExpr::Cmp(cc,
+                                 box try!(uniquify(mapping, *left)),
+                                 box try!(uniquify(mapping, *right)))),
+        SExpr::Lambda(args, body) => {
+            let mut body_mapping = mapping.clone();
+            let mut new_args = vec![];
+            for arg in args {
+                let new_arg = get_unique_varname(&arg);
+                new_args.push(new_arg.clone());
+                body_mapping.insert(arg, new_arg);
+            }
+
+            return Ok(SExpr::Lambda(new_args,
+                                    Box::new(try!(uniquify(&mut body_mapping, *body)))));
+        },
+        SExpr::Define(name, args, val) => {
+            let uniq_fname = get_unique_varname(&name);
+            uniquify_define(mapping, uniq_fname, name, args, *val)
+        },
+        SExpr::If(cond, thn, els) => {
+            return Ok(SExpr::If(Box::new(try!(uniquify(mapping, *cond))),
+                                Box::new(try!(uniquify(mapping, *thn))),
+                                Box::new(try!(uniquify(mapping, *els)))));
+        },
+        SExpr::While(test, body) => {
+            return Ok(SExpr::While(box try!(uniquify(mapping, *test)),
+                                   box try!(uniquify(mapping, *body))));
+        },
+        SExpr::DefineVal(name, _) =>
+            panic!("toplevel_prog should have made the define of {} a let", name),
+        SExpr::Set(name, span, val) => {
+            let uniq_name = match mapping.get(&name) {
+                Some(uniq_name) => uniq_name.to_string(),
+                None => return Err(CompileError::UnboundVariable(name, span)),
+            };
+            Ok(SExpr::Set(uniq_name, span, box try!(uniquify(mapping, *val))))
+        },
+        SExpr::DynamicRef(_) => Ok(expr),
+        SExpr::DynamicSet(label, val) =>
+            Ok(SExpr::DynamicSet(label, box try!(uniquify(mapping, *val)))),
+        SExpr::DefineDynamic(_, _) | SExpr::Parameterize(_, _, _) =>
+            panic!("resolve_dynamics should have removed {:?}", expr),
+        SExpr::Begin(body) => {
+            return Ok(SExpr::Begin(try!(body.into_iter()
+                                        .map(|e| uniquify(mapping, e))
+                                        .collect())));
+        },
+        SExpr::App(f, args) => {
+            let args = try!(args.into_iter().map(|a| uniquify(mapping, a)).collect());
+            return Ok(SExpr::App(box try!(uniquify(mapping, *f)),
+                                 args));
+        },
+        // All the functions are in scope in every body, so that they
+        // can call ones defined after them.
+        SExpr::Prog(defs, e) => {
+            let mut uniq_fnames = vec![];
+            for def in &defs {
+                if let &SExpr::Define(ref name, _, _) = def {
+                    let uniq_fname = get_unique_varname(name);
+                    if !mapping.contains_key(name) {
+                        mapping.insert(name.clone(), uniq_fname.clone());
+                    }
+                    uniq_fnames.push(uniq_fname);
+                }
+            }
+            let mut uniq_fnames = uniq_fnames.into_iter();
+            let mut new_defs = vec![];
+            for def in defs {
+                new_defs.push(match def {
+                    SExpr::Define(name, args, val) =>
+                        try!(uniquify_define(mapping, uniq_fnames.next().unwrap(),
+                                             name, args, *val)),
+                    _ => try!(uniquify(mapping, def)),
+                });
+            }
+            let defs = new_defs;
+            return Ok(SExpr::Prog(defs, Box::new(try!(uniquify(mapping, *e)))))
+        },
+        SExpr::EOF => return Err(CompileError::EmptyProgram),
+    }
+}
+
+// The function's name stays in scope for the definitions and
+// expressions after it, and refers to the latest definition by that
+// name; its arguments are only in scope in the body.
+fn uniquify_define(mapping: &mut HashMap<String, String>, uniq_fname: String,
+                   name: String, args: Vec<String>, val: SExpr)
+                   -> Result<SExpr, CompileError> {
+    mapping.insert(name, uniq_fname.clone());
+
+    let mut body_mapping = mapping.clone();
+    let mut new_args = vec![];
+    for arg in args {
+        let new_arg = get_unique_varname(&arg);
+        new_args.push(new_arg.clone());
+        body_mapping.insert(arg, new_arg);
+    }
+
+    return Ok(SExpr::Define(uniq_fname,
+                            new_args,
+                            Box::new(try!(uniquify(&mut body_mapping, val)))));
+}
+
+// Collect the targets of `set!` in `expr`, and the variables used by a
+// lambda in `expr` that the lambda doesn't bind. Names are unique, so
+// a variable is bound in a lambda iff it's bound anywhere inside it.
+fn mutated_and_captured(expr: &SExpr,
+                        used: &mut HashSet<String>,
+                        bound: &mut HashSet<String>,
+                        mutated: &mut HashSet<String>,
+                        captured: &mut HashSet<String>) {
+    match expr {
+        &SExpr::Symbol(ref name, _) => {
+            used.insert(name.clone());
+        },
+        &SExpr::Set(ref name, _, ref val) => {
+            used.insert(name.clone());
+            mutated.insert(name.clone());
+            mutated_and_captured(val, used, bound, mutated, captured);
+        },
+        &SExpr::Lambda(ref args, ref body) => {
+            let mut lambda_used = HashSet::new();
+            let mut lambda_bound : HashSet<String> = args.iter().cloned().collect();
+            mutated_and_captured(body, &mut lambda_used, &mut lambda_bound, mutated, captured);
+            captured.extend(lambda_used.difference(&lambda_bound).cloned());
+            used.extend(lambda_used);
+            bound.extend(lambda_bound);
+        },
+        &SExpr::Define(_, ref args, ref body) => {
+            bound.extend(args.iter().cloned());
+            mutated_and_captured(body, used, bound, mutated, captured);
+        },
+        &SExpr::Let(ref bindings, ref body) => {
+            for &(ref k, ref v) in bindings {
+                bound.insert(k.clone());
+                mutated_and_captured(v, used, bound, mutated, captured);
+            }
+            mutated_and_captured(body, used, bound, mutated, captured);
+        },
+        &SExpr::DynamicSet(_, ref e) => mutated_and_captured(e, used, bound, mutated, captured),
+        &SExpr::If(ref cnd, ref thn, ref els) => {
+            mutated_and_captured(cnd, used, bound, mutated, captured);
+            mutated_and_captured(thn, used, bound, mutated, captured);
+            mutated_and_captured(els, used, bound, mutated, captured);
+        },
+        &SExpr::Cmp(_, ref left, ref right) | &SExpr::While(ref left, ref right) => {
+            mutated_and_captured(left, used, bound, mutated, captured);
+            mutated_and_captured(right, used, bound, mutated, captured);
+        },
+        &SExpr::App(ref f, ref es) => {
+            mutated_and_captured(f, used, bound, mutated, captured);
+            for e in es {
+                mutated_and_captured(e, used, bound, mutated, captured);
+            }
+        },
+        &SExpr::Tuple(ref es) | &SExpr::Begin(ref es) => {
+            for e in es {
+                mutated_and_captured(e, used, bound, mutated, captured);
+            }
+        },
+        &SExpr::Prog(ref defs, ref main) => {
+            for def in defs {
+                mutated_and_captured(def, used, bound, mutated, captured);
+            }
+            mutated_and_captured(main, used, bound, mutated, captured);
+        },
+        _ => (),
+    }
+}
+
+// Make `set!` visible to everything that reads the variable it
+// assigns. A mutated variable that a lambda captures is put in a box
+// (a one element tuple), since the closure gets a copy of the
+// variable. Other mutated variables stay in place, but are copied when
+// read, so that a `set!` later in the same expression doesn't change a
+// value that has already been read. Runs after uniquify.
+fn box_mutated(prog: SExpr) -> SExpr {
+    let mut mutated = HashSet::new();
+    let mut captured = HashSet::new();
+    mutated_and_captured(&prog, &mut HashSet::new(), &mut HashSet::new(),
+                         &mut mutated, &mut captured);
+    if mutated.is_empty() {
+        return prog;
+    }
+    let boxed = mutated.intersection(&captured).cloned().collect();
+    return box_vars(prog, &mutated, &boxed);
+}
+
+fn box_args(args: Vec<String>, body: SExpr, boxed: &HashSet<String>)
+            -> (Vec<String>, SExpr) {
+    let mut new_args = vec![];
+    let mut boxes = vec![];
+    for arg in args {
+        if boxed.contains(&arg) {
+            let unboxed = get_unique_varname(&arg);
+            boxes.push((arg, SExpr::Tuple(vec![SExpr::Symbol(unboxed.clone(), NO_SPAN)])));
+            new_args.push(unboxed);
+        } else {
+            new_args.push(arg);
+        }
+    }
+    if boxes.is_empty() {
+        return (new_args, body);
+    }
+    return (new_args, SExpr::Let(boxes, box body));
+}
+
+fn box_vars(expr: SExpr, mutated: &HashSet<String>, boxed: &HashSet<String>) -> SExpr {
+    let rewrite = |e: SExpr| box_vars(e, mutated, boxed);
+    let prim = |name: &str, args: Vec<SExpr>|
+        SExpr::App(box SExpr::Symbol(name.to_string(), NO_SPAN), args);
+
+    match expr {
+        SExpr::Symbol(name, span) => {
+            if boxed.contains(&name) {
+                prim("%unbox", vec![SExpr::Symbol(name, span)])
+            } else if mutated.contains(&name) {
+                let copy = get_unique_varname(&name);
+                SExpr::Let(vec![(copy.clone(), SExpr::Symbol(name, span))],
+                           box SExpr::Symbol(copy, NO_SPAN))
+            } else {
+                SExpr::Symbol(name, span)
+            }
+        },
+        SExpr::Set(name, span, val) => {
+            if boxed.contains(&name) {
+                prim("%set-box!", vec![SExpr::Symbol(name, span), rewrite(*val)])
+            } else {
+                SExpr::Set(name, span, box rewrite(*val))
+            }
+        },
+        SExpr::Let(bindings, body) => {
+            let bindings = bindings.into_iter().map(|(k, v)| {
+                let v = rewrite(v);
+                if boxed.contains(&k) {
+                    (k, SExpr::Tuple(vec![v]))
+                } else {
+                    (k, v)
+                }
+            }).collect();
+            SExpr::Let(bindings, box rewrite(*body))
+        },
+        SExpr::Lambda(args, body) => {
+            let (args, body) = box_args(args, rewrite(*body), boxed);
+            SExpr::Lambda(args, box body)
+        },
+        SExpr::Define(name, args, body) => {
+            let (args, body) = box_args(args, rewrite(*body), boxed);
+            SExpr::Define(name, args, box body)
+        },
+        SExpr::DynamicSet(label, val) => SExpr::DynamicSet(label, box rewrite(*val)),
+        SExpr::If(cnd, thn, els) =>
+            SExpr::If(box rewrite(*cnd), box rewrite(*thn), box rewrite(*els)),
+        SExpr::Cmp(cc, left, right) =>
+            SExpr::Cmp(cc, box rewrite(*left), box rewrite(*right)),
+        SExpr::While(test, body) => SExpr::While(box rewrite(*test), box rewrite(*body)),
+        // closure conversion only calls through a variable, so a boxed
+        // function is unboxed into one first
+        SExpr::App(box SExpr::Symbol(f, span), args) => {
+            let args = args.into_iter().map(|a| rewrite(a)).collect();
+            if boxed.contains(&f) {
+                let f_temp = get_unique_varname("tmp");
+                SExpr::Let(vec![(f_temp.clone(), rewrite(SExpr::Symbol(f, span)))],
+                           box SExpr::App(box SExpr::Symbol(f_temp, NO_SPAN), args))
+            } else {
+                SExpr::App(box SExpr::Symbol(f, span), args)
+            }
+        },
+        SExpr::App(f, args) =>
+            SExpr::App(box rewrite(*f), args.into_iter().map(|a| rewrite(a)).collect()),
+        SExpr::Tuple(elts) => SExpr::Tuple(elts.into_iter().map(|e| rewrite(e)).collect()),
+        SExpr::Begin(elts) => SExpr::Begin(elts.into_iter().map(|e| rewrite(e)).collect()),
+        SExpr::Prog(defs, main) =>
+            SExpr::Prog(defs.into_iter().map(|d| rewrite(d)).collect(), box rewrite(*main)),
+        e => e,
+    }
+}
+
+// Whether `expr` is a literal that is its own value in a register, so
+// that copying it to every use of a variable costs nothing. Strings and
+// floats are allocated, and each copy would be a different object.
+fn is_immediate(expr: &SExpr) -> bool {
+    match expr {
+        &SExpr::Number(_) | &SExpr::Bool(_) | &SExpr::Char(_) | &SExpr::QuotedSymbol(_) => true,
+        &SExpr::List(ref elts) => elts.is_empty(),
+        _ => false,
+    }
+}
+
+fn propagate(expr: SExpr, consts: &mut HashMap<String, SExpr>, mutated: &HashSet<String>)
+             -> SExpr {
+    let mut rewrite = |e: SExpr| propagate(e, consts, mutated);
+
+    match expr {
+        SExpr::Symbol(name, span) => match consts.get(&name) {
+            Some(c) => c.clone(),
+            None => SExpr::Symbol(name, span),
+        },
+        SExpr::Let(bindings, body) => {
+            let mut kept = vec![];
+            for (k, v) in bindings {
+                let v = propagate(v, consts, mutated);
+                if is_immediate(&v) && !mutated.contains(&k) {
+                    consts.insert(k, v);
+                } else {
+                    kept.push((k, v));
+                }
+            }
+            let mut body = propagate(*body, consts, mutated);
+            // `(let (... (x e)) x)` is `(let (...) e)`: the other
+            // bindings can't refer to x, and are still computed first
+            if kept.last().map_or(false, |&(ref k, _)| body == SExpr::Symbol(k.clone(), NO_SPAN)) {
+                body = kept.pop().unwrap().1;
+            }
+            if kept.is_empty() {
+                body
+            } else {
+                SExpr::Let(kept, box body)
+            }
+        },
+        SExpr::Set(name, span, val) => SExpr::Set(name, span, box rewrite(*val)),
+        SExpr::Lambda(args, body) => SExpr::Lambda(args, box rewrite(*body)),
+        SExpr::Define(name, args, body) => SExpr::Define(name, args, box rewrite(*body)),
+        SExpr::DynamicSet(label, val) => SExpr::DynamicSet(label, box rewrite(*val)),
+        SExpr::If(cnd, thn, els) =>
+            SExpr::If(box rewrite(*cnd), box rewrite(*thn), box rewrite(*els)),
+        SExpr::Cmp(cc, left, right) =>
+            SExpr::Cmp(cc, box rewrite(*left), box rewrite(*right)),
+        SExpr::While(test, body) => SExpr::While(box rewrite(*test), box rewrite(*body)),
+        // closure conversion only calls through a variable, so a
+        // constant that is called is bound to one again
+        SExpr::App(box SExpr::Symbol(f, span), args) => {
+            let args = args.into_iter().map(|a| rewrite(a)).collect();
+            match rewrite(SExpr::Symbol(f, span)) {
+                SExpr::Symbol(f, span) => SExpr::App(box SExpr::Symbol(f, span), args),
+                c => {
+                    let f_temp = get_unique_varname("tmp");
+                    SExpr::Let(vec![(f_temp.clone(), c)],
+                               box SExpr::App(box SExpr::Symbol(f_temp, NO_SPAN), args))
+                },
+            }
+        },
+        SExpr::App(f, args) =>
+            SExpr::App(box rewrite(*f), args.into_iter().map(|a| rewrite(a)).collect()),
+        SExpr::Tuple(elts) => SExpr::Tuple(elts.into_iter().map(|e| rewrite(e)).collect()),
+        SExpr::Begin(elts) => SExpr::Begin(elts.into_iter().map(|e| rewrite(e)).collect()),
+        SExpr::Prog(defs, main) =>
+            SExpr::Prog(defs.into_iter().map(|d| rewrite(d)).collect(), box rewrite(*main)),
+        e => e,
+    }
+}
+
+// Replace the variables a `let` binds to a number, boolean, character
+// or `()`, and that are never assigned, by the literal, and drop their
+// bindings, as well as the last binding of a `let` whose body is just
+// that variable. Unlike const_fold, this reaches into lambdas, which then
+// don't capture the variable, and it leaves const_fold literals to
+// fold, eg. in the arguments of an inlined function. Runs after
+// uniquify, so a name means the same variable everywhere.
+fn propagate_constants(prog: SExpr) -> SExpr {
+    let mut mutated = HashSet::new();
+    mutated_and_captured(&prog, &mut HashSet::new(), &mut HashSet::new(),
+                         &mut mutated, &mut HashSet::new());
+    return propagate(prog, &mut HashMap::new(), &mutated);
+}
+
+// Free variables of `expr`, in order of first use. `env` holds the
+// variables bound inside the expression being examined, and
+// `parent_env` those bound by enclosing scopes, which are the ones
+// that can be captured. Variables bound by a nested lambda stay bound
+// for its body, so an inner lambda's use of an outer lambda's
+// argument isn't reported, but its use of a variable from further out
+// is.
+fn get_free_variables(env: &HashSet<String>,
+                      parent_env: &HashSet<String>,
+                      expr: SExpr) -> Vec<String> {
+    match expr {
+        SExpr::Number(_) |
+        SExpr::Float(_) |
+        SExpr::Bool(_) |
+        SExpr::Void |
+        SExpr::Char(_) |
+        SExpr::Str(_) |
+        SExpr::QuotedSymbol(_) |
+        // a quoted list only holds constants
+        SExpr::List(_) |
+        SExpr::DynamicRef(_) |
+        SExpr::FuncName(_) => vec![],
+        SExpr::DynamicSet(_, val) |
+        SExpr::DefineDynamic(_, val) => get_free_variables(env, parent_env, *val),
+        // the names are dynamic variables, which aren't captured
+        SExpr::Parameterize(bindings, body, _) => {
+            let mut freevars = vec![];
+            for (_, v) in bindings {
+                freevars.extend_from_slice(&get_free_variables(env, parent_env, v));
+            }
+            freevars.extend_from_slice(&get_free_variables(env, parent_env, *body));
+            return freevars;
+        },
+        SExpr::Set(name, span, val) => {
+            let mut freevars = get_free_variables(env, parent_env, SExpr::Symbol(name, span));
+            freevars.extend_from_slice(&get_free_variables(env, parent_env, *val));
+            return freevars;
+        },
+        SExpr::Symbol(name, _) => {
+            match env.get(&name) {
+                Some(_) => vec![],
+                None => {
+                    match parent_env.get(&name) {
+                        Some(_) => vec![name],
+                        // a top-level function, which gets a closure
+                        // where it's used instead of being captured
+                        None => vec![],
+                    }
+                }
+            }
+        },
+        SExpr::If(cnd, thn, els) => {
+            let mut cnd_freevars =
+                get_free_variables(env, parent_env, *cnd);
+            let thn_freevars =
+                get_free_variables(env, parent_env, *thn);
+            let els_freevars = get_free_variables(env, parent_env, *els);
+
+            cnd_freevars.extend_from_slice(&thn_freevars);
+            cnd_freevars.extend_from_slice(&els_freevars);
+
+            return cnd_freevars;
+        },
+        SExpr::While(test, body) => {
+            let mut test_freevars = get_free_variables(env, parent_env, *test);
+            test_freevars.extend_from_slice(&get_free_variables(env, parent_env, *body));
+
+            return test_freevars;
+        },
+        SExpr::Cmp(_, left, right) => {
+            let mut left_freevars = get_free_variables(env, parent_env, *left);
+            let right_freevars = get_free_variables(env, parent_env, *right);
+            left_freevars.extend_from_slice(&right_freevars);
+
+            return left_freevars;
+        },
+        SExpr::Define(_, args, body) |
+        SExpr::Lambda(args, body) => {
+            let mut new_env = env.clone();
+            for arg in args {
+                new_env.insert(arg);
+            }
+
+            return get_free_variables(&new_env, parent_env, *body);
+        },
+        SExpr::Let(bindings, body) => {
+            let mut new_env = HashSet::new();
+            let mut bindings_free_vars = vec![];
+            for (k, v) in bindings {
+                let v_freevars =
+                    get_free_variables(env, parent_env, v);
+                new_env.insert(k);
+                bindings_free_vars.extend_from_slice(&v_freevars);
+            }
+
+            new_env = new_env.union(&env).cloned().collect();
+            let body_freevars = get_free_variables(&new_env,
+                                                   parent_env,
+                                                   *body);
+            bindings_free_vars.extend_from_slice(&body_freevars);
+
+            return bindings_free_vars;
+        },
+        SExpr::Begin(elts) |
+        SExpr::Tuple(elts) => {
+            let mut elts_freevars = vec![];
+            for elt in elts {
+                let elt_freevars = get_free_variables(env, parent_env, elt);
+                elts_freevars.extend_from_slice(&elt_freevars);
+            }
+
+            return elts_freevars;
+        },
+        SExpr::App(f, args) => {
+            let mut args_freevars = match *f {
+                // primitives and top-level functions aren't bound
+                // anywhere, and don't need capturing
+                SExpr::Symbol(ref name, _)
+                    if !env.contains(name) && !parent_env.contains(name) => vec![],
+                f => get_free_variables(env, parent_env, f),
+            };
+            for arg in args {
+                let arg_freevars =
+                    get_free_variables(env,
+                                       parent_env,
+                                       arg);
+                args_freevars.extend_from_slice(&arg_freevars);
+            }
+
+            return args_freevars;
+        },
+        _ => panic!("NYI: {:?}", expr),
+    }
+}
+
+// A name bound in the program as read, and whether anything in its
+// scope refers to it.
+struct Binding {
+    name: String,
+    span: Span,
+    is_let: bool,               // only `let` bindings are warned about
+    used: bool,
+}
+
+// Bind `names` in `scopes` while walking `body`, as mark_uses does.
+fn mark_uses_in_scope(names: Vec<(String, Span)>, is_let: bool, body: &[SExpr],
+                      scopes: &mut HashMap<String, Vec<usize>>, bindings: &mut Vec<Binding>) {
+    for &(ref name, span) in &names {
+        scopes.entry(name.clone()).or_insert(vec![]).push(bindings.len());
+        bindings.push(Binding { name: name.clone(), span: span, is_let: is_let, used: false });
+    }
+    for e in body {
+        mark_uses(e, scopes, bindings);
+    }
+    for &(ref name, _) in &names {
+        scopes.get_mut(name).map(|s| s.pop());
+    }
+}
+
+// The symbols among `es`, such as a lambda's parameters, with where
+// they were read.
+fn symbol_names(es: &[SExpr]) -> Vec<(String, Span)> {
+    es.iter().filter_map(|e| match e {
+        &SExpr::Symbol(ref name, span) => Some((name.clone(), span)),
+        _ => None,
+    }).collect()
+}
+
+// The names a `let` binds, after marking the uses in the values bound
+// to them, which are outside their scope.
+fn let_names(binds: &[SExpr], scopes: &mut HashMap<String, Vec<usize>>,
+             bindings: &mut Vec<Binding>) -> Vec<(String, Span)> {
+    let mut names = vec![];
+    for bind in binds {
+        match bind {
+            &SExpr::List(ref kv) if kv.len() == 2 => {
+                mark_uses(&kv[1], scopes, bindings);
+                names.extend(symbol_names(&kv[..1]));
+            },
+            _ => mark_uses(bind, scopes, bindings),
+        }
+    }
+    return names;
+}
+
+// Mark the bindings that the symbols in `expr` refer to as used.
+// `scopes` maps each name to the bindings of it that are in scope,
+// innermost last, as indices into `bindings`. This works on the forms
+// as read rather than the AST, since only they say where a name was
+// bound. A form other than `let`, `lambda` and `define` is walked like
+// a call, so a name it binds counts as a use of whatever it shadows.
+fn mark_uses(expr: &SExpr, scopes: &mut HashMap<String, Vec<usize>>,
+             bindings: &mut Vec<Binding>) {
+    match expr {
+        &SExpr::Symbol(ref name, _) => {
+            if let Some(&i) = scopes.get(name).and_then(|s| s.last()) {
+                bindings[i].used = true;
+            }
+        },
+        &SExpr::List(ref elts) => match &elts[..] {
+            &[SExpr::Symbol(ref k, _), _..] if k == "quote" => (),
+            &[SExpr::Symbol(ref k, _), SExpr::List(ref binds), ref body..] if k == "let" => {
+                let bound = let_names(binds, scopes, bindings);
+                mark_uses_in_scope(bound, true, body, scopes, bindings);
+            },
+            // a named let, whose bindings are the loop's parameters
+            &[SExpr::Symbol(ref k, _), SExpr::Symbol(ref name, span), SExpr::List(ref binds),
+              ref body..] if k == "let" => {
+                let mut bound = vec![(name.clone(), span)];
+                bound.extend(let_names(binds, scopes, bindings));
+                mark_uses_in_scope(bound, false, body, scopes, bindings);
+            },
+            &[SExpr::Symbol(ref k, _), SExpr::List(ref params), ref body..] if k == "lambda" =>
+                mark_uses_in_scope(symbol_names(params), false, body, scopes, bindings),
+            &[SExpr::Symbol(ref k, _), SExpr::List(ref sig), ref body..]
+                if k == "define" && !sig.is_empty() =>
+                mark_uses_in_scope(symbol_names(&sig[1..]), false, body, scopes, bindings),
+            _ => for e in elts {
+                mark_uses(e, scopes, bindings);
+            },
+        },
+        _ => (),
+    }
+}
+
+// A warning for each `let` binding in the top-level forms, as read,
+// that nothing in its body uses, in the order they appear.
+fn unused_binding_warnings(forms: &[SExpr]) -> Vec<String> {
+    let mut scopes = HashMap::new();
+    let mut bindings = vec![];
+    for form in forms {
+        mark_uses(form, &mut scopes, &mut bindings);
+    }
+    let mut unused : Vec<&Binding> = bindings.iter().filter(|b| b.is_let && !b.used).collect();
+    unused.sort_by_key(|b| (b.span.line, b.span.col));
+    return unused.iter()
+        .map(|b| format!("unused binding '{}' at line {}", b.name, b.span.line))
+        .collect();
+}
+
+fn symbol_is_primitive(sym: &str) -> bool {
+    PRIMITIVES.contains(&sym)
+}
+
+fn get_define_name(def: &SExpr) -> String {
+    match def {
+        &SExpr::Define(ref name, _, _) => name.to_string(),
+        &_ => panic!("not a Define"),
+    }
+}
+
+fn convert_to_closures(env: &HashSet<String>, expr: SExpr, toplevel_funs: &HashSet<String>)
+                       -> (SExpr, Vec<SExpr>) {
+    match expr.clone() {
+        // a top-level function used as a value, eg. passed to another
+        // function, gets a closure with nothing captured
+        SExpr::Symbol(ref f, _) if toplevel_funs.contains(f) =>
+            (SExpr::Tuple(vec![SExpr::FuncName(f.to_string())]), vec![]),
+        SExpr::Cmp(_, _, _) |
+        SExpr::Float(_) |
+        SExpr::Bool(_) |
+        SExpr::Void |
+        SExpr::Char(_) |
+        SExpr::Str(_) |
+        SExpr::QuotedSymbol(_) |
+        SExpr::List(_) |
+        SExpr::DynamicRef(_) |
+        SExpr::Symbol(_, _) |
+        SExpr::FuncName(_) |
+        SExpr::Number(_) => (expr, vec![]),
+        SExpr::If(cnd, thn, els) => {
+            let (converted_cnd, mut cnd_defines) =
+                convert_to_closures(env, *cnd, toplevel_funs);
+            let (converted_thn, thn_defines) =
+                convert_to_closures(env, *thn, toplevel_funs);
+            let (converted_els, els_defines) =
+                convert_to_closures(env, *els, toplevel_funs);
+
+            let converted = SExpr::If(box converted_cnd,
+                                      box converted_thn,
+                                      box converted_els);
+
+            cnd_defines.extend_from_slice(&thn_defines);
+            cnd_defines.extend_from_slice(&els_defines);
+
+            return (converted, cnd_defines);
+        },
+        SExpr::While(test, body) => {
+            let (converted_test, mut test_defines) =
+                convert_to_closures(env, *test, toplevel_funs);
+            let (converted_body, body_defines) =
+                convert_to_closures(env, *body, toplevel_funs);
+            test_defines.extend_from_slice(&body_defines);
+
+            return (SExpr::While(box converted_test, box converted_body), test_defines);
+        },
+        SExpr::Define(name, mut args, body) => {
+            let mut new_env = env.clone();
+            for arg in args.clone() {
+                new_env.insert(arg);
+            }
+            let (converted_body, body_defines) =
+                convert_to_closures(&new_env, *body, toplevel_funs);
+
+            args.insert(0, "clos".to_string());
+
+            let converted = SExpr::Define(name,
+                                          args,
+                                          box converted_body);
+
+            return (converted, body_defines);
+
+        },
+        SExpr::Lambda(mut args, body) => {
+            let mut new_env = env.clone();
+            for arg in args.clone() {
+                new_env.insert(arg);
+            }
+
+            let (converted_body, mut new_defines) =
+                convert_to_closures(&new_env, *body, toplevel_funs);
+
+            let lambda_name = get_unique_varname("lam");
+            let mut free_vars = vec![];
+            for fvar in get_free_variables(&HashSet::new(), env, expr) {
+                if !free_vars.contains(&fvar) {
+                    free_vars.push(fvar);
+                }
+            }
+            let mut load_free_vars = converted_body;
+            for (i, fvar) in free_vars.iter().enumerate() {
+                let bindings = vec![(fvar.to_string(),
+                                     SExpr::App(box SExpr::Symbol("tuple-ref".to_string(), NO_SPAN),
+                                                vec![SExpr::Symbol("clos".to_string(), NO_SPAN),
+                                                     SExpr::Number((i+1) as i64)]))];
+                load_free_vars =
+                    SExpr::Let(bindings,
+                               box load_free_vars);
+            }
+
+            args.insert(0, "clos".to_string());
+
+            let mut closure_elts =
+                vec![SExpr::FuncName(lambda_name.clone())];
+
+            let free_vars : Vec<SExpr> =
+                free_vars.iter().map(|fv| SExpr::Symbol(fv.to_string(), NO_SPAN)).collect();
+            closure_elts.extend_from_slice(&free_vars[..]);
+            let closure = SExpr::Tuple(closure_elts);
+
+            new_defines.extend_from_slice(&[
+                SExpr::Define(lambda_name,
+                              args, box load_free_vars)
+            ]);
+
+            return (closure, new_defines);
+        },
+        SExpr::Tuple(elts) => {
+            let mut converted_elts = vec![];
+            let mut elts_defines = vec![];
+
+            for elt in elts.clone() {
+                let (conv_elt, elt_defines) =
+                    convert_to_closures(env, elt, toplevel_funs);
+                converted_elts.push(conv_elt);
+                elts_defines.extend_from_slice(&elt_defines);
+            }
+
+            let converted = SExpr::Tuple(converted_elts);
+            return (converted, elts_defines);
+
+        },
+        SExpr::DynamicSet(label, val) => {
+            let (converted_val, val_defines) =
+                convert_to_closures(env, *val, toplevel_funs);
+            return (SExpr::DynamicSet(label, box converted_val), val_defines);
+        },
+        SExpr::Set(name, span, val) => {
+            let (converted_val, val_defines) =
+                convert_to_closures(env, *val, toplevel_funs);
+            return (SExpr::Set(name, span, box converted_val), val_defines);
+        },
+        SExpr::Begin(body) => {
+            let mut converted_body = vec![];
+            let mut body_defines = vec![];
+
+            for e in body {
+                let (conv_e, e_defines) =
+                    convert_to_closures(env, e, toplevel_funs);
+                converted_body.push(conv_e);
+                body_defines.extend_from_slice(&e_defines);
+            }
+
+            return (SExpr::Begin(converted_body), body_defines);
+        },
+        SExpr::App(box SExpr::Symbol(ref f, _), ref args)
+            if symbol_is_primitive(f) => {
+                let mut converted_args = vec![];
+                let mut args_defines = vec![];
+
+                for arg in args.clone() {
+                    let (conv_arg, arg_defines) =
+                        convert_to_closures(env, arg, toplevel_funs);
+                    converted_args.push(conv_arg);
+                    args_defines.extend_from_slice(&arg_defines);
+                }
+
+                let converted = SExpr::App(box SExpr::Symbol(f.to_string(), NO_SPAN),
+                                           converted_args);
+                return (converted, args_defines);
+            },
+        // Top-level functions have no free variables and never read
+        // their closure, so they are called directly and no closure is
+        // built for them.
+        SExpr::App(box SExpr::Symbol(ref f, _), ref args)
+            if toplevel_funs.contains(f) => {
+                let mut converted_args = vec![SExpr::Number(0)];
+                let mut args_defines = vec![];
+
+                for arg in args.clone() {
+                    let (conv_arg, arg_defines) =
+                        convert_to_closures(env, arg, toplevel_funs);
+                    converted_args.push(conv_arg);
+                    args_defines.extend_from_slice(&arg_defines);
+                }
+
+                let converted = SExpr::App(box SExpr::FuncName(f.to_string()),
+                                           converted_args);
+                return (converted, args_defines);
+            },
+        SExpr::App(box SExpr::Symbol(ref f, _), ref args)
+            if !symbol_is_primitive(f) => {
+                let (fclos, fdefines) =
+                    convert_to_closures(env, SExpr::Symbol(f.to_string(), NO_SPAN),
+                                        toplevel_funs);
+                let f_temp = get_unique_varname("tmp");
+
+                let mut converted_args =
+                    vec![SExpr::Symbol(f_temp.clone(), NO_SPAN)];
+                let mut args_defines = vec![];
+
+                for arg in args.clone() {
+                    let (conv_arg, arg_defines) =
+                        convert_to_closures(env, arg, toplevel_funs);
+                    converted_args.push(conv_arg);
+                    args_defines.extend_from_slice(&arg_defines);
+                }
+
+                args_defines.extend_from_slice(&fdefines);
+
+                let converted = SExpr::Let(vec![(f_temp.clone(), fclos)],
+                                           box SExpr::App(
+                                               box SExpr::App(box SExpr::Symbol("tuple-ref".to_string(), NO_SPAN),
+                                                              vec![SExpr::Symbol(f_temp, NO_SPAN),
+                                                                   SExpr::Number(0)]),
+                                               converted_args));
+                return (converted, args_defines);
+            },
+        // a call through a closure that is computed, eg. taken out of
+        // a tuple, is a call through a variable bound to it
+        SExpr::App(f, args) => {
+            let f_temp = get_unique_varname("tmp");
+            let call = SExpr::Let(vec![(f_temp.clone(), *f)],
+                                  box SExpr::App(box SExpr::Symbol(f_temp, NO_SPAN), args));
+            return convert_to_closures(env, call, toplevel_funs);
+        },
+        SExpr::Let(bindings, body) => {
+            let mut new_bindings = vec![];
+            let mut bindings_defines = vec![];
+            let mut new_env = env.clone();
+
+            for (k, v) in bindings {
+                let (converted_v, v_defines) =
+                    convert_to_closures(env, v, toplevel_funs);
+
+                new_env.insert(k.clone());
+                new_bindings.push((k, converted_v));
+                bindings_defines.extend_from_slice(&v_defines);
+            };
+
+            let (converted_body, body_defines) =
+                convert_to_closures(&new_env, *body, toplevel_funs);
+
+            bindings_defines.extend_from_slice(&body_defines);
+
+            let converted = SExpr::Let(new_bindings,
+                                       box converted_body);
+            return (converted, bindings_defines);
+        },
+        SExpr::Prog(defines, main) => {
+            let mut converted_defines = vec![];
+            let mut defines_new_defines = vec![];
+            let mut toplevel_funs = toplevel_funs.clone();
+            for def in &defines {
+                toplevel_funs.insert(get_define_name(def));
+            }
+
+            for def in defines.clone() {
+                let (converted_define, new_defines) =
+                    convert_to_closures(env, def.clone(), &toplevel_funs);
+
+                converted_defines.push(converted_define);
+                defines_new_defines.extend_from_slice(&new_defines);
+            }
+
+            let (converted_main, main_defines) =
+                convert_to_closures(env, *main, &toplevel_funs);
+
+            converted_defines.extend_from_slice(&defines_new_defines);
+            converted_defines.extend_from_slice(&main_defines);
+
+            let converted = SExpr::Prog(converted_defines,
+                                        box converted_main);
+
+            return (converted, vec![]);
+        },
+        _ => panic!("NYI: {:?}", expr),
+    }
+}
+
+
+fn flat_arg_type(v: &Flat) -> X86Arg {
+    match v {
+        &Flat::Symbol(ref name) => X86Arg::Var(name.clone()),
+        &Flat::FuncName(ref name) => X86Arg::FuncName(name.clone()),
+        &Flat::Number(n) => X86Arg::Imm((n << 1) as u64),
+        &Flat::Bool(b) => {
+            match b {
+                true => X86Arg::Imm(CONST_TRUE),
+                false => X86Arg::Imm(CONST_FALSE),
+            }
+        },
+        &Flat::Nil => X86Arg::Imm(CONST_NIL),
+        &Flat::Void => X86Arg::Imm(CONST_VOID),
+        &Flat::Char(c) => X86Arg::Imm(((c as u64) << 8) | CHAR_TAG),
+        &Flat::QuotedSymbol(ref name) => X86Arg::Imm(((intern_symbol(name) as u64) << 8) |
+                                                     SYMBOL_TAG),
+        &_ => panic!("flat_arg_type: compound expression {:?}", v),
+    }
+}
+
+// Call `f` with `args`, and store the result in `dest`.
+// Caller-save registers aren't saved around the call, instead
+// variables live across it are kept out of them during register
+// allocation. See live_across_calls.
+fn call_instrs(f: X86Arg, args: &Vec<Flat>, dest: String) -> Vec<X86> {
+    let mut instrs = vec![];
+
+    // TODO: if more than 6 args, spill args to stack
+    // push args
+    for (i, arg) in args.iter().map(|a| flat_arg_type(a)).enumerate() {
+        instrs.push(
+            X86::Mov(X86Arg::Reg(ARG_REG_ORDER[i].clone()),
+                     arg)
+        );
+    }
+
+    // the number of arguments, for functions with a rest parameter
+    if let X86Arg::Var(_) = f {
+        instrs.push(X86::Mov(X86Arg::Reg(Reg::R11), X86Arg::Imm(args.len() as u64)));
+    }
+    instrs.extend_from_slice(&[
+        X86::Call(f),
+    ]);
+
+    instrs.extend_from_slice(&[
+        X86::Mov(X86Arg::Var(dest), X86Arg::Reg(Reg::RAX))
+    ]);
+
+    return instrs;
+}
+
+// Leave the address of `obj`, whose tag is `tag`, in r11, and
+// `index` untagged in rax, after exiting with `error` unless it's
+// below the count in the word at `count_offset`.
+fn checked_index(obj: &Flat, tag: u64, index: &Flat, count_offset: i64, error: u64)
+                 -> Vec<X86> {
+    let rax = || X86Arg::Reg(Reg::RAX);
+    vec![
+        X86::Mov(X86Arg::Reg(Reg::R11), flat_arg_type(obj)),
+        X86::Sub(X86Arg::Reg(Reg::R11), X86Arg::Imm(tag)),
+        X86::Mov(rax(), flat_arg_type(index)),
+        X86::Sar(rax(), X86Arg::Imm(1)),
+        X86::If(box X86::CmpP(CC::L, rax(), X86Arg::Imm(0)),
+                runtime_error(error),
+                vec![]),
+        X86::If(box X86::CmpP(CC::GE, rax(), X86Arg::RegOffset(Reg::R11, count_offset)),
+                runtime_error(error),
+                vec![]),
+    ]
+}
+
+// Run `is_pair` if `v` is a pair, ie. a tuple of two elements, and
+// `not_pair` if it isn't.
+fn if_pair(v: X86Arg, is_pair: Vec<X86>, not_pair: Vec<X86>) -> Vec<X86> {
+    let r11 = || X86Arg::Reg(Reg::R11);
+    vec![
+        X86::Mov(r11(), v.clone()),
+        X86::And(r11(), X86Arg::Imm(3)),
+        X86::If(box X86::EqP(r11(), X86Arg::Imm(1)),
+                vec![X86::Mov(r11(), v),
+                     X86::Sub(r11(), X86Arg::Imm(1)),
+                     X86::If(box X86::EqP(X86Arg::RegOffset(Reg::R11, 0), X86Arg::Imm(2)),
+                             is_pair,
+                             not_pair.clone())],
+                not_pair),
+    ]
+}
+
+// Allocate a boxed object of `kind`, followed by `len` words, for
+// `dest`. Its address is left untagged in `dest` and in r11 for the
+// caller to fill in the words and then add the tag.
+fn alloc_boxed(dest: &String, kind: u64, len: usize) -> Vec<X86> {
+    let len = len + 1;
+    let total_len = 8*(len + (len % 2));
+    let mut instrs = heap_alloc(total_len as u64, vec![]);
+    instrs.extend_from_slice(&[
+        X86::Mov(X86Arg::Var(dest.clone()), X86Arg::Reg(Reg::R11)),
+        X86::Mov(X86Arg::RegOffset(Reg::R11, 0), X86Arg::Imm(kind))]);
+    return instrs;
+}
+
+// Take `len` bytes from the heap, leaving their address in r11. The
+// collector may run first, and move the objects the live variables
+// and `roots` point to.
+fn heap_alloc(len: u64, roots: Vec<X86Arg>) -> Vec<X86> {
+    let free_ptr = || X86Arg::GlobalVal("free_ptr".to_string());
+    vec![X86::Collect(len, roots),
+         X86::Mov(X86Arg::Reg(Reg::R11), free_ptr()),
+         X86::Add(free_ptr(), X86Arg::Imm(len))]
+}
+
+// Push `roots` onto the root stack that r15 points to, where the
+// collector finds and updates them.
+fn push_roots(roots: &Vec<X86Arg>) -> Vec<X86> {
+    if roots.is_empty() {
+        return vec![];
+    }
+    let mut instrs = vec![];
+    for (i, root) in roots.iter().enumerate() {
+        instrs.push(X86::Mov(X86Arg::RegOffset(Reg::R15, 8*i as i64), root.clone()));
+    }
+    instrs.push(X86::Add(X86Arg::Reg(Reg::R15), X86Arg::Imm(8*roots.len() as u64)));
+    return instrs;
+}
+
+// Pop `roots` back off the root stack. They go through r11, which is
+// free after a call, since rax may hold its result.
+fn pop_roots(roots: &Vec<X86Arg>) -> Vec<X86> {
+    if roots.is_empty() {
+        return vec![];
+    }
+    let mut instrs = vec![X86::Sub(X86Arg::Reg(Reg::R15), X86Arg::Imm(8*roots.len() as u64))];
+    for (i, root) in roots.iter().enumerate() {
+        instrs.push(X86::Mov(X86Arg::Reg(Reg::R11), X86Arg::RegOffset(Reg::R15, 8*i as i64)));
+        instrs.push(X86::Mov(root.clone(), X86Arg::Reg(Reg::R11)));
+    }
+    return instrs;
+}
+
+// Call collect in runtime.c if `len` more bytes don't fit before
+// heap_end. Every root is pushed for the collector to update, and
+// read back afterwards; that also saves the ones in caller-save
+// registers.
+fn collect_instrs(len: u64, roots: Vec<X86Arg>) -> Vec<X86> {
+    let r11 = || X86Arg::Reg(Reg::R11);
+    let mut gc = push_roots(&roots);
+    gc.extend_from_slice(&[
+        X86::Mov(X86Arg::Reg(Reg::RDI), X86Arg::Reg(Reg::R15)),
+        X86::Mov(X86Arg::Reg(Reg::RSI), X86Arg::Imm(len)),
+        X86::Call(X86Arg::FuncName("collect".to_string())),
+    ]);
+    gc.extend(pop_roots(&roots));
+    vec![X86::Mov(r11(), X86Arg::GlobalVal("free_ptr".to_string())),
+         X86::Add(r11(), X86Arg::Imm(len)),
+         X86::If(box X86::CmpP(CC::G, r11(), X86Arg::GlobalVal("heap_end".to_string())),
+                 gc,
+                 vec![])]
+}
+
+// Load the number `v` into `xmm` as a double. An integer literal is
+// converted at compile time, a variable may hold either an integer or
+// a float, and anything else is an error.
+fn load_float(v: &Flat, xmm: X86Arg) -> Vec<X86> {
+    let r11 = || X86Arg::Reg(Reg::R11);
+    let int_to_float = vec![X86::Mov(X86Arg::Reg(Reg::RAX), flat_arg_type(v)),
+                            X86::Sar(X86Arg::Reg(Reg::RAX), X86Arg::Imm(1)),
+                            X86::CvtSi2Sd(xmm.clone(), X86Arg::Reg(Reg::RAX))];
+    // the double is in the word after the kind
+    let load = vec![X86::MovSd(xmm, X86Arg::RegOffset(Reg::R11, 8))];
+    let is_float = vec![X86::Mov(r11(), flat_arg_type(v)),
+                        X86::Sub(r11(), X86Arg::Imm(BOXED_TAG)),
+                        X86::If(box X86::EqP(X86Arg::RegOffset(Reg::R11, 0),
+                                             X86Arg::Imm(FLOAT_KIND)),
+                                load,
+                                runtime_error(ERROR_NOT_NUMBER))];
+    match v {
+        &Flat::Number(_) => int_to_float,
+        _ => vec![
+            X86::Mov(r11(), flat_arg_type(v)),
+            X86::And(r11(), X86Arg::Imm(1)),
+            X86::If(box X86::EqP(r11(), X86Arg::Imm(0)),
+                    int_to_float,
+                    vec![X86::Mov(r11(), flat_arg_type(v)),
+                         X86::And(r11(), X86Arg::Imm(7)),
+                         X86::If(box X86::EqP(r11(), X86Arg::Imm(BOXED_TAG)),
+                                 is_float,
+                                 runtime_error(ERROR_NOT_NUMBER))]),
+        ],
+    }
+}
+
+// The operands of a comparison, after the instructions that find
+// them. The `%compare` of two values, see select_compare, is -1, 0 or
+// 1. Two fixnums are compared as they are instead, since comparing
+// that with 0 is the same. If either isn't one, eg. it is a float,
+// rusl_compare in runtime.c gives it.
+fn compare_operands(left: &Flat, right: &Flat) -> (Vec<X86>, X86Arg, X86Arg) {
+    let (left, right) = match left {
+        &Flat::Prim(ref f, ref args) if f == "%compare" => (&args[0], &args[1]),
+        _ => return (vec![], flat_arg_type(left), flat_arg_type(right)),
+    };
+    let (l, r) = (flat_arg_type(left), flat_arg_type(right));
+    let vars : Vec<&Flat> = vec![left, right].into_iter().filter(|a| match a {
+        &&Flat::Symbol(_) => true,
+        _ => false,
+    }).collect();
+    let rax = || X86Arg::Reg(Reg::RAX);
+    let r11 = || X86Arg::Reg(Reg::R11);
+    let mut instrs = vec![X86::Mov(r11(), flat_arg_type(vars[0]))];
+    for v in &vars[1..] {
+        instrs.push(X86::Or(r11(), flat_arg_type(v)));
+    }
+    instrs.extend_from_slice(&[
+        X86::And(r11(), X86Arg::Imm(1)),
+        X86::If(box X86::EqP(r11(), X86Arg::Imm(0)),
+                vec![X86::Mov(rax(), l.clone()),
+                     X86::Mov(r11(), r.clone())],
+                vec![X86::Mov(X86Arg::Reg(Reg::RDI), l),
+                     X86::Mov(X86Arg::Reg(Reg::RSI), r),
+                     X86::Call(X86Arg::FuncName("rusl_compare".to_string())),
+                     X86::Mov(r11(), X86Arg::Imm(0))]),
+    ]);
+    return (instrs, rax(), r11());
+}
+
+// Set `dest` to #t if the flags say `cc`, and to #f otherwise. setcc
+// gives 0 or 1, which is then turned into #f or #t.
+fn set_bool(cc: CC, dest: String) -> Vec<X86> {
+    vec![X86::Set(X86Arg::Reg(Reg::AL), cc),
+         X86::MovZx(X86Arg::Var(dest.clone()), X86Arg::Reg(Reg::AL)),
+         X86::If(box X86::EqP(X86Arg::Var(dest.clone()), X86Arg::Imm(1)),
+                 vec![X86::Mov(X86Arg::Var(dest.clone()),
+                               X86Arg::Imm(CONST_TRUE))],
+                 vec![X86::Mov(X86Arg::Var(dest),
+                               X86Arg::Imm(CONST_FALSE))])]
+}
+
+// Jump to the overflow trap if the arithmetic instruction before
+// overflowed. Integers are shifted left by one, so the 64-bit overflow
+// flag is also the one for the tagged value.
+fn overflow_check(config: &Config) -> Vec<X86> {
+    if config.checked_arith {
+        vec![X86::JmpIf(CC::O, OVERFLOW_TRAP.to_string())]
+    } else {
+        vec![]
+    }
+}
+
+// Report a type error found at run time and exit. `error` picks the
+// message, see rusl_error in runtime.c.
+fn runtime_error(error: u64) -> Vec<X86> {
+    vec![X86::Mov(X86Arg::Reg(Reg::RDI), X86Arg::Imm(error)),
+         X86::Call(X86Arg::FuncName("rusl_error".to_string()))]
+}
+
+// Whether `cmp left, right` would set the flags so that `cc` holds,
+// if both are immediates. Like cmp, this compares the tagged values,
+// as signed numbers.
+fn compare_constants(cc: &CC, left: &X86Arg, right: &X86Arg) -> Option<bool> {
+    let (l, r) = match (left, right) {
+        (&X86Arg::Imm(l), &X86Arg::Imm(r)) => (l as i64, r as i64),
+        _ => return None,
+    };
+    match cc {
+        &CC::E => Some(l == r),
+        &CC::NE => Some(l != r),
+        &CC::L => Some(l < r),
+        &CC::LE => Some(l <= r),
+        &CC::G => Some(l > r),
+        &CC::GE => Some(l >= r),
+        &CC::O => None,
+    }
+}
+
+// A one-line description of `instr`, for annotating the assembly
+// selected for it.
+fn describe_flat(instr: &Flat) -> String {
+    let describe_all = |f: &str, args: &Vec<Flat>| -> String {
+        let mut s = f.to_string();
+        for arg in args {
+            s.push_str(&format!(" {}", describe_flat(arg)));
+        }
+        s
+    };
+
+    match instr {
+        &Flat::Symbol(ref name) | &Flat::FuncName(ref name) => name.clone(),
+        &Flat::Number(n) => format!("{}", n),
+        &Flat::Float(f) => format!("{:?}", f),
+        &Flat::Bool(true) => "#t".to_string(),
+        &Flat::Bool(false) => "#f".to_string(),
+        &Flat::Char(c) => format!("{:?}", c),
+        &Flat::QuotedSymbol(ref name) => format!("'{}", name),
+        &Flat::Nil => "()".to_string(),
+        &Flat::Void => "#<void>".to_string(),
+        &Flat::Str(ref s) => format!("{:?}", s),
+        &Flat::Tuple(ref args) => describe_all("tuple", args),
+        &Flat::App(ref f, ref args) | &Flat::Prim(ref f, ref args) => describe_all(f, args),
+        &Flat::Cmp(ref cc, ref left, ref right) =>
+            format!("{} {} {}", cc_name(cc), describe_flat(left), describe_flat(right)),
+        &Flat::Assign(ref dest, ref e) => format!("{} -> {}", describe_flat(e), dest),
+        &Flat::Return(ref v) => format!("return {}", describe_flat(v)),
+        &Flat::If(ref cnd, _, _) => format!("if {}", describe_flat(cnd)),
+        &Flat::While(_, ref cnd, _) => format!("while {}", describe_flat(cnd)),
+    }
+}
+
+// The instructions for `instr`, after a comment saying what they are
+// for when `config.annotate` is set.
+fn select_flat(instr: Flat, config: &Config) -> Vec<X86> {
+    let mut instrs = vec![];
+    if config.annotate {
+        instrs.push(X86::Comment(describe_flat(&instr)));
+    }
+    instrs.extend(flat_to_px86(instr, config));
+    return instrs;
+}
+
+// convert one Flat instruction to pseudo-x86
+fn flat_to_px86(instr: Flat, config: &Config) -> Vec<X86> {
+    match instr {
+        Flat::Assign(dest, e) => {
+            match *e {
+                Flat::FuncName(name) => vec![X86::Mov(X86Arg::Var(dest),
+                                                      X86Arg::FuncName(name))],
+                Flat::Symbol(name) => vec![X86::Mov(X86Arg::Var(dest),
+                                                    X86Arg::Var(name))],
+                Flat::Number(n) => vec![X86::Mov(X86Arg::Var(dest),
+                                                 X86Arg::Imm((n << 1) as u64))],
+                Flat::Bool(b) => {
+                    let bval = match b {
+                        true => CONST_TRUE,
+                        false => CONST_FALSE,
+                    };
+                    return vec![X86::Mov(X86Arg::Var(dest),
+                                         X86Arg::Imm(bval as u64))];
+                },
+                Flat::Nil => vec![X86::Mov(X86Arg::Var(dest), X86Arg::Imm(CONST_NIL))],
+                Flat::Void => vec![X86::Mov(X86Arg::Var(dest), X86Arg::Imm(CONST_VOID))],
+                Flat::Char(c) => vec![X86::Mov(X86Arg::Var(dest),
+                                               flat_arg_type(&Flat::Char(c)))],
+                Flat::QuotedSymbol(name) => vec![X86::Mov(X86Arg::Var(dest),
+                                                          flat_arg_type(&Flat::QuotedSymbol(name)))],
+                // https://github.com/rust-lang/rust/issues/16223
+                x => match x {
+                    Flat::Prim(f, args) => {
+                        match &f[..] {
+                            "+" => {
+                                let (arg1, arg2) = match &args[..] {
+                                    &[ref arg1, ref arg2] => (arg1, arg2),
+                                    _ => panic!("check_arity should have rejected {:?}", args),
+                                };
+                                let mut instrs = vec![
+                                    X86::Mov(X86Arg::Var(dest.clone()),
+                                             flat_arg_type(arg1)),
+                                    X86::Add(X86Arg::Var(dest),
+                                             flat_arg_type(arg2))
+                                ];
+                                instrs.extend(overflow_check(config));
+                                return instrs;
+                            },
+                            "-" => {
+                                let arg = match &args[..] {
+                                    &[ref arg] => arg,
+                                    _ => panic!("check_arity should have rejected {:?}", args),
+                                };
+                                let mut instrs = vec![
+                                    X86::Mov(X86Arg::Var(dest.clone()),
+                                             flat_arg_type(arg)),
+                                    X86::Neg(X86Arg::Var(dest.clone()))
+                                ];
+                                instrs.extend(overflow_check(config));
+                                return instrs;
+                            },
+                            // The arguments after the first `n`, and the
+                            // closure, as a list. Only used at the start
+                            // of a function, before the argument
+                            // registers are clobbered.
+                            "%rest-args" => {
+                                let n = match &args[..] {
+                                    &[Flat::Number(n)] => n as usize + 1,
+                                    _ => panic!("%rest-args of {:?}", args),
+                                };
+                                let mut instrs = vec![X86::Mov(X86Arg::Var(dest.clone()),
+                                                               X86Arg::Imm(CONST_NIL))];
+                                for i in (n..ARG_REG_ORDER.len()).rev() {
+                                    let r11 = || X86Arg::Reg(Reg::R11);
+                                    // the arguments still to be consed
+                                    let arg_regs = ARG_REG_ORDER[n..i+1].iter()
+                                        .map(|r| X86Arg::Reg(r.clone()))
+                                        .collect();
+                                    let mut cons = heap_alloc(32, arg_regs);
+                                    cons.extend_from_slice(&[
+                                        X86::Mov(X86Arg::RegOffset(Reg::R11, 0), X86Arg::Imm(2)),
+                                        X86::Mov(X86Arg::RegOffset(Reg::R11, 8),
+                                                 X86Arg::Reg(ARG_REG_ORDER[i].clone())),
+                                        X86::Mov(X86Arg::RegOffset(Reg::R11, 16),
+                                                 X86Arg::Var(dest.clone())),
+                                        X86::Mov(X86Arg::Var(dest.clone()), r11()),
+                                        X86::Add(X86Arg::Var(dest.clone()), X86Arg::Imm(1)),
+                                    ]);
+                                    instrs.push(X86::If(box X86::CmpP(CC::G,
+                                                                      X86Arg::Var(ARGC_VAR.to_string()),
+                                                                      X86Arg::Imm(i as u64)),
+                                                        cons,
+                                                        vec![]));
+                                }
+                                return instrs;
+                            },
+                            // see select_float_arith. The integer
+                            // instructions if the variables are all
+                            // fixnums, otherwise the float ones.
+                            "%number+" | "%number-" => {
+                                let r11 = || X86Arg::Reg(Reg::R11);
+                                let op = f["%number".len()..].to_string();
+                                let float_op = format!("%float{}", op);
+                                let vars : Vec<&Flat> = args.iter().filter(|a| match a {
+                                    &&Flat::Symbol(_) => true,
+                                    _ => false,
+                                }).collect();
+                                // a literal that isn't a number is an error
+                                if args.iter().any(|a| match a {
+                                    &Flat::Symbol(_) | &Flat::Number(_) => false,
+                                    _ => true,
+                                }) {
+                                    return flat_to_px86(Flat::Assign(dest, box Flat::Prim(float_op, args)),
+                                                        config);
+                                }
+                                let mut instrs = vec![X86::Mov(r11(), flat_arg_type(vars[0]))];
+                                for v in &vars[1..] {
+                                    instrs.push(X86::Or(r11(), flat_arg_type(v)));
+                                }
+                                instrs.extend_from_slice(&[
+                                    X86::And(r11(), X86Arg::Imm(1)),
+                                    X86::If(box X86::EqP(r11(), X86Arg::Imm(0)),
+                                            flat_to_px86(Flat::Assign(dest.clone(),
+                                                                      box Flat::Prim(op, args.clone())),
+                                                         config),
+                                            flat_to_px86(Flat::Assign(dest, box Flat::Prim(float_op, args)),
+                                                         config)),
+                                ]);
+                                return instrs;
+                            },
+                            "%float+" | "%float-" => {
+                                let xmm0 = X86Arg::XmmReg(0);
+                                let xmm1 = X86Arg::XmmReg(1);
+                                // allocated first, since the collector
+                                // doesn't keep the xmm registers
+                                let mut instrs = alloc_boxed(&dest, FLOAT_KIND, 1);
+                                if f == "%float+" {
+                                    instrs.extend(load_float(&args[0], xmm0.clone()));
+                                    instrs.extend(load_float(&args[1], xmm1.clone()));
+                                    instrs.push(X86::AddSd(xmm0.clone(), xmm1));
+                                } else {
+                                    instrs.extend(load_float(&args[0], xmm1.clone()));
+                                    instrs.push(X86::XorPd(xmm0.clone(), xmm0.clone()));
+                                    instrs.push(X86::SubSd(xmm0.clone(), xmm1));
+                                }
+                                instrs.extend_from_slice(&[
+                                    X86::Mov(X86Arg::Reg(Reg::R11), X86Arg::Var(dest.clone())),
+                                    X86::MovSd(X86Arg::RegOffset(Reg::R11, 8), xmm0),
+                                    X86::Add(X86Arg::Var(dest), X86Arg::Imm(BOXED_TAG)),
+                                ]);
+                                return instrs;
+                            },
+                            "dynamic-ref" => {
+                                match &args[..] {
+                                    &[Flat::FuncName(ref label)] =>
+                                        return vec![X86::Mov(X86Arg::Var(dest),
+                                                             X86Arg::GlobalVal(label.clone()))],
+                                    _ => panic!("dynamic-ref of {:?}", args),
+                                }
+                            },
+                            "dynamic-set!" => {
+                                match &args[..] {
+                                    &[Flat::FuncName(ref label), ref val] =>
+                                        return vec![
+                                            X86::Mov(X86Arg::GlobalVal(label.clone()),
+                                                     flat_arg_type(val)),
+                                            X86::Mov(X86Arg::Var(dest),
+                                                     flat_arg_type(val)),
+                                        ],
+                                    _ => panic!("dynamic-set! of {:?}", args),
+                                }
+                            },
+                            "tuple-ref" => {
+                                let (tuple, index) = match &args[..] {
+                                    &[ref tuple, ref index] => (tuple, index),
+                                    _ => panic!("check_arity should have rejected {:?}", args),
+                                };
+
+                                let index = match index {
+                                    &Flat::Number(n) => n,
+                                    _ => {
+                                        let mut instrs = checked_index(tuple, 1, index, 0,
+                                                                       ERROR_INDEX_OUT_OF_RANGE);
+                                        instrs.push(X86::Mov(X86Arg::Var(dest),
+                                                             X86Arg::RegIndex(Reg::R11, Reg::RAX,
+                                                                              8, 8)));
+                                        return instrs;
+                                    },
+                                };
+
+                                // a negative index is out of range
+                                // whatever the count is
+                                let out_of_range = if index < 0 {
+                                    runtime_error(ERROR_INDEX_OUT_OF_RANGE)
+                                } else {
+                                    vec![X86::If(box X86::CmpP(CC::LE,
+                                                               X86Arg::RegOffset(Reg::R11, 0),
+                                                               X86Arg::Imm(index as u64)),
+                                                 runtime_error(ERROR_INDEX_OUT_OF_RANGE),
+                                                 vec![])]
+                                };
+                                let mut instrs = vec![
+                                    X86::Mov(X86Arg::Reg(Reg::R11), flat_arg_type(tuple)),
+                                    // subtract 1 from tuple tag
+                                    X86::Sub(X86Arg::Reg(Reg::R11), X86Arg::Imm(1)),
+                                ];
+                                // NOTE: first word contains count
+                                instrs.extend(out_of_range);
+                                instrs.push(X86::Mov(X86Arg::Var(dest),
+                                                     X86Arg::RegOffset(Reg::R11, 8*(index+1))));
+                                return instrs;
+                            },
+                            "vector-ref" => {
+                                let (vector, index) = match &args[..] {
+                                    &[ref vector, ref index] => (vector, index),
+                                    _ => panic!("vector-ref of {:?}", args),
+                                };
+                                let mut instrs = checked_index(vector, BOXED_TAG, index, 8,
+                                                               ERROR_VECTOR_INDEX_OUT_OF_RANGE);
+                                // the elements follow the kind and length
+                                instrs.push(X86::Mov(X86Arg::Var(dest),
+                                                     X86Arg::RegIndex(Reg::R11, Reg::RAX, 8, 16)));
+                                return instrs;
+                            },
+                            // rax is needed for the value, so the
+                            // element's address is worked out in r11
+                            "vector-set!" => {
+                                let (vector, index, val) = match &args[..] {
+                                    &[ref vector, ref index, ref val] => (vector, index, val),
+                                    _ => panic!("vector-set! of {:?}", args),
+                                };
+                                let rax = || X86Arg::Reg(Reg::RAX);
+                                let mut instrs = checked_index(vector, BOXED_TAG, index, 8,
+                                                               ERROR_VECTOR_INDEX_OUT_OF_RANGE);
+                                instrs.extend_from_slice(&[
+                                    X86::Add(rax(), rax()),
+                                    X86::Add(rax(), rax()),
+                                    X86::Add(rax(), rax()),
+                                    X86::Add(X86Arg::Reg(Reg::R11), rax()),
+                                    X86::Mov(X86Arg::RegOffset(Reg::R11, 16), flat_arg_type(val)),
+                                    X86::Mov(X86Arg::Var(dest), X86Arg::Imm(CONST_VOID)),
+                                ]);
+                                return instrs;
+                            },
+                            "vector-length" => {
+                                let vector = match &args[..] {
+                                    &[ref vector] => vector,
+                                    _ => panic!("vector-length of {:?}", args),
+                                };
+
+                                return vec![
+                                    X86::Mov(X86Arg::Reg(Reg::R11), flat_arg_type(vector)),
+                                    X86::Sub(X86Arg::Reg(Reg::R11), X86Arg::Imm(BOXED_TAG)),
+                                    // the length in the second word is untagged
+                                    X86::Mov(X86Arg::Var(dest.clone()),
+                                             X86Arg::RegOffset(Reg::R11, 8)),
+                                    X86::Add(X86Arg::Var(dest.clone()), X86Arg::Var(dest))
+                                ];
+                            },
+                            "string-length" => {
+                                let string = match &args[..] {
+                                    &[ref string] => string,
+                                    _ => panic!("string-length of {:?}", args),
+                                };
+
+                                return vec![
+                                    X86::Mov(X86Arg::Reg(Reg::R11), flat_arg_type(string)),
+                                    X86::Sub(X86Arg::Reg(Reg::R11), X86Arg::Imm(BOXED_TAG)),
+                                    // the length in the second word is untagged
+                                    X86::Mov(X86Arg::Var(dest.clone()),
+                                             X86Arg::RegOffset(Reg::R11, 8)),
+                                    X86::Add(X86Arg::Var(dest.clone()), X86Arg::Var(dest))
+                                ];
+                            },
+                            "tuple-length" => {
+                                let tuple = match &args[..] {
+                                    &[ref tuple] => tuple,
+                                    _ => panic!("check_arity should have rejected {:?}", args),
+                                };
+
+                                return vec![
+                                    X86::Mov(X86Arg::Reg(Reg::R11), flat_arg_type(tuple)),
+                                    X86::Sub(X86Arg::Reg(Reg::R11), X86Arg::Imm(1)),
+                                    // the count in the first word is untagged
+                                    X86::Mov(X86Arg::Var(dest.clone()),
+                                             X86Arg::RegOffset(Reg::R11, 0)),
+                                    X86::Add(X86Arg::Var(dest.clone()), X86Arg::Var(dest))
+                                ];
+                            },
+                            // a box is a tuple of one element
+                            "%unbox" => {
+                                let boxed = match &args[..] {
+                                    &[ref boxed] => boxed,
+                                    _ => panic!("%unbox of {:?}", args),
+                                };
+
+                                return vec![
+                                    X86::Mov(X86Arg::Reg(Reg::R11), flat_arg_type(boxed)),
+                                    X86::Sub(X86Arg::Reg(Reg::R11), X86Arg::Imm(1)),
+                                    X86::Mov(X86Arg::Var(dest), X86Arg::RegOffset(Reg::R11, 8)),
+                                ];
+                            },
+                            "%set-box!" => {
+                                let (boxed, val) = match &args[..] {
+                                    &[ref boxed, ref val] => (boxed, val),
+                                    _ => panic!("%set-box! of {:?}", args),
+                                };
+
+                                return vec![
+                                    X86::Mov(X86Arg::Reg(Reg::R11), flat_arg_type(boxed)),
+                                    X86::Sub(X86Arg::Reg(Reg::R11), X86Arg::Imm(1)),
+                                    X86::Mov(X86Arg::RegOffset(Reg::R11, 8), flat_arg_type(val)),
+                                    X86::Mov(X86Arg::Var(dest), X86Arg::Imm(CONST_VOID)),
+                                ];
+                            },
+                            "min" | "max" => {
+                                let (arg1, arg2) = match &args[..] {
+                                    &[ref arg1, ref arg2] => (flat_arg_type(arg1),
+                                                              flat_arg_type(arg2)),
+                                    _ => panic!("check_arity should have rejected {:?}", args),
+                                };
+
+                                match config.target_cpu {
+                                    TargetCpu::Baseline => {
+                                        // dest holds the comparison result
+                                        // until one of the branches
+                                        // overwrites it.
+                                        let cc = if f == "min" { CC::L } else { CC::G };
+                                        return vec![
+                                            X86::Cmp(arg1.clone(), arg2.clone()),
+                                            X86::Set(X86Arg::Reg(Reg::AL), cc),
+                                            X86::MovZx(X86Arg::Var(dest.clone()),
+                                                       X86Arg::Reg(Reg::AL)),
+                                            X86::If(box X86::EqP(X86Arg::Var(dest.clone()),
+                                                                 X86Arg::Imm(1)),
+                                                    vec![X86::Mov(X86Arg::Var(dest.clone()),
+                                                                  arg1)],
+                                                    vec![X86::Mov(X86Arg::Var(dest),
+                                                                  arg2)]),
+                                        ];
+                                    },
+                                    TargetCpu::Modern => {
+                                        let cc = if f == "min" { CC::G } else { CC::L };
+                                        return vec![
+                                            X86::Mov(X86Arg::Var(dest.clone()), arg1),
+                                            X86::Cmp(X86Arg::Var(dest.clone()), arg2.clone()),
+                                            X86::CMov(cc, X86Arg::Var(dest), arg2),
+                                        ];
+                                    },
+                                }
+                            },
+                            "abs" => {
+                                let arg = match &args[..] {
+                                    &[ref arg] => flat_arg_type(arg),
+                                    _ => panic!("check_arity should have rejected {:?}", args),
+                                };
+
+                                // only negating the smallest integer
+                                // overflows
+                                let mut negate = vec![X86::Neg(X86Arg::Var(dest.clone()))];
+                                negate.extend(overflow_check(config));
+                                let mut instrs = vec![X86::Mov(X86Arg::Var(dest.clone()),
+                                                               arg.clone())];
+                                match config.target_cpu {
+                                    TargetCpu::Baseline => {
+                                        instrs.push(X86::If(box X86::CmpP(CC::L,
+                                                                          X86Arg::Var(dest),
+                                                                          X86Arg::Imm(0)),
+                                                            negate,
+                                                            vec![]));
+                                    },
+                                    // the argument is put back if its
+                                    // negation is negative
+                                    TargetCpu::Modern => {
+                                        instrs.extend(negate);
+                                        instrs.push(X86::CMov(CC::L, X86Arg::Var(dest), arg));
+                                    },
+                                }
+                                return instrs;
+                            },
+                            // idiv truncates toward zero, like quotient
+                            // and remainder. The tags cancel out in the
+                            // quotient, which is tagged again, and carry
+                            // over to the remainder.
+                            "quotient" | "remainder" | "modulo" => {
+                                let (dividend, divisor) = match &args[..] {
+                                    &[ref a, ref b] => (flat_arg_type(a), flat_arg_type(b)),
+                                    _ => panic!("check_arity should have rejected {:?}", args),
+                                };
+
+                                let mut instrs = match divisor {
+                                    X86Arg::Imm(0) => runtime_error(ERROR_DIVISION_BY_ZERO),
+                                    X86Arg::Imm(_) => vec![],
+                                    _ => vec![X86::If(box X86::EqP(divisor.clone(),
+                                                                   X86Arg::Imm(0)),
+                                                      runtime_error(ERROR_DIVISION_BY_ZERO),
+                                                      vec![])],
+                                };
+                                instrs.extend(vec![
+                                    X86::Mov(X86Arg::Reg(Reg::RAX), dividend),
+                                    X86::Cqo,
+                                    X86::IDiv(divisor.clone()),
+                                ]);
+                                let dest = X86Arg::Var(dest);
+                                if f == "quotient" {
+                                    instrs.push(X86::Mov(dest.clone(), X86Arg::Reg(Reg::RAX)));
+                                    instrs.push(X86::Add(dest.clone(), dest));
+                                    instrs.extend(overflow_check(config));
+                                    return instrs;
+                                }
+                                instrs.push(X86::Mov(dest.clone(), X86Arg::Reg(Reg::RDX)));
+                                // modulo floors instead, so a remainder
+                                // whose sign isn't the divisor's is
+                                // moved past zero
+                                if f == "modulo" {
+                                    let signs_differ = vec![
+                                        X86::Mov(X86Arg::Reg(Reg::RAX), dest.clone()),
+                                        X86::Xor(X86Arg::Reg(Reg::RAX), divisor.clone()),
+                                        X86::If(box X86::CmpP(CC::L, X86Arg::Reg(Reg::RAX),
+                                                              X86Arg::Imm(0)),
+                                                vec![X86::Add(dest.clone(), divisor)],
+                                                vec![]),
+                                    ];
+                                    instrs.push(X86::If(box X86::CmpP(CC::NE, dest,
+                                                                      X86Arg::Imm(0)),
+                                                        signs_differ,
+                                                        vec![]));
+                                }
+                                return instrs;
+                            },
+                            // implemented by the runtime
+                            "make-hash" => {
+                                return call_instrs(X86Arg::FuncName("make_hash".to_string()),
+                                                   &args, dest);
+                            },
+                            "hash-set!" => {
+                                return call_instrs(X86Arg::FuncName("hash_set".to_string()),
+                                                   &args, dest);
+                            },
+                            "hash-ref" => {
+                                return call_instrs(X86Arg::FuncName("hash_ref".to_string()),
+                                                   &args, dest);
+                            },
+                            // the runtime's print, as for the program's
+                            // value, which gives its argument back
+                            "print" => {
+                                return call_instrs(X86Arg::FuncName("print".to_string()),
+                                                   &args, dest);
+                            },
+                            "print_hex" | "print_binary" => {
+                                return call_instrs(X86Arg::FuncName(f.clone()), &args, dest);
+                            },
+                            // allocates, see the Call arms of
+                            // assign_homes_to_instrs
+                            "make-vector" => {
+                                return call_instrs(X86Arg::FuncName(MAKE_VECTOR.to_string()),
+                                                   &args, dest);
+                            },
+                            "cons" => {
+                                return flat_to_px86(Flat::Assign(dest, box Flat::Tuple(args)),
+                                                    config);
+                            },
+                            // the same object, ie. the same tagged value
+                            "eq?" => {
+                                let (left, right) = match &args[..] {
+                                    &[ref left, ref right] => (left.clone(), right.clone()),
+                                    _ => panic!("eq? of {:?}", args),
+                                };
+                                let mut instrs = vec![X86::Cmp(flat_arg_type(&left),
+                                                               flat_arg_type(&right))];
+                                instrs.extend(set_bool(CC::E, dest));
+                                return instrs;
+                            },
+                            // tuples and strings with equal contents
+                            "equal?" => {
+                                return call_instrs(X86Arg::FuncName("deep_equal".to_string()),
+                                                   &args, dest);
+                            },
+                            "car" | "cdr" => {
+                                let (index, error) = if f == "car" {
+                                    (0, ERROR_CAR_NOT_PAIR)
+                                } else {
+                                    (1, ERROR_CDR_NOT_PAIR)
+                                };
+                                let pair = args[0].clone();
+                                let mut instrs = if_pair(flat_arg_type(&pair),
+                                                         vec![],
+                                                         runtime_error(error));
+                                instrs.extend(flat_to_px86(
+                                    Flat::Assign(dest, box Flat::Prim("tuple-ref".to_string(),
+                                                                      vec![pair,
+                                                                           Flat::Number(index)])),
+                                    config));
+                                return instrs;
+                            },
+                            // exit through assert_failed in runtime.c
+                            // if the value is #f, otherwise give it back
+                            "assert" => {
+                                return vec![
+                                    X86::Mov(X86Arg::Var(dest.clone()), flat_arg_type(&args[0])),
+                                    X86::If(box X86::EqP(X86Arg::Var(dest),
+                                                         X86Arg::Imm(CONST_FALSE)),
+                                            vec![X86::Call(X86Arg::FuncName("assert_failed".to_string()))],
+                                            vec![])];
+                            },
+                            // the C library's exit, with the integer
+                            // untagged, which flushes what print has
+                            // written. It doesn't return, so `dest` is
+                            // never set.
+                            "exit" => {
+                                let rdi = X86Arg::Reg(Reg::RDI);
+                                return vec![
+                                    X86::Mov(rdi.clone(), flat_arg_type(&args[0])),
+                                    X86::Sar(rdi, X86Arg::Imm(1)),
+                                    X86::Call(X86Arg::FuncName("exit".to_string()))];
+                            },
+                            // the name is a string in the symbol
+                            // table, see print_symbols
+                            "symbol->string" => {
+                                let rax = || X86Arg::Reg(Reg::RAX);
+                                let sym = flat_arg_type(&args[0]);
+                                let mut instrs = vec![
+                                    X86::Mov(rax(), sym.clone()),
+                                    X86::And(rax(), X86Arg::Imm(0xff)),
+                                    X86::If(box X86::EqP(rax(), X86Arg::Imm(SYMBOL_TAG)),
+                                            vec![],
+                                            runtime_error(ERROR_NOT_SYMBOL)),
+                                ];
+                                instrs.extend_from_slice(&[
+                                    X86::Mov(rax(), sym),
+                                    X86::Sar(rax(), X86Arg::Imm(8)),
+                                    X86::Mov(X86Arg::Reg(Reg::R11),
+                                             X86Arg::FuncName(SYMBOLS.to_string())),
+                                    X86::Mov(X86Arg::Var(dest),
+                                             X86Arg::RegIndex(Reg::R11, Reg::RAX, 8, 8)),
+                                ]);
+                                return instrs;
+                            },
+                            // a tuple's low bits are 01, see rec_print
+                            // in runtime.c
+                            "%tuple?" => {
+                                let r11 = || X86Arg::Reg(Reg::R11);
+                                return vec![
+                                    X86::Mov(r11(), flat_arg_type(&args[0])),
+                                    X86::And(r11(), X86Arg::Imm(3)),
+                                    X86::If(box X86::EqP(r11(), X86Arg::Imm(1)),
+                                            vec![X86::Mov(X86Arg::Var(dest.clone()),
+                                                          X86Arg::Imm(CONST_TRUE))],
+                                            vec![X86::Mov(X86Arg::Var(dest),
+                                                          X86Arg::Imm(CONST_FALSE))])];
+                            },
+                            // doesn't return, so `dest` is never set
+                            "%match-error" => return runtime_error(ERROR_NO_MATCH),
+                            "%values-error" => return runtime_error(ERROR_VALUES_MISMATCH),
+                            "pair?" => {
+                                return if_pair(flat_arg_type(&args[0]),
+                                               vec![X86::Mov(X86Arg::Var(dest.clone()),
+                                                             X86Arg::Imm(CONST_TRUE))],
+                                               vec![X86::Mov(X86Arg::Var(dest),
+                                                             X86Arg::Imm(CONST_FALSE))]);
+                            },
+                            _ => panic!("primitive not defined"),
+                        }
+                    },
+                    // `f` holds the address of the closure's code
+                    Flat::App(f, args) => {
+                        return call_instrs(X86Arg::Var(f), &args, dest);
+                    },
+                    Flat::Cmp(cc, left, right) => {
+                        let (mut instrs, left, right) = compare_operands(&*left, &*right);
+                        instrs.push(X86::Cmp(left, right));
+                        instrs.extend(set_bool(cc, dest));
+                        return instrs;
+                    },
+                    Flat::Tuple(elts) => {
+                        // with count in first word
+                        let len = elts.len() + 1;
+                        let total_len = 8*(len + (len % 2));
+                        let mut instrs = heap_alloc(total_len as u64, vec![]);
+                        instrs.push(X86::Mov(X86Arg::Var(dest.clone()),
+                                             X86Arg::Reg(Reg::R11)));
+                        // store count in first word
+                        instrs.extend_from_slice(&[
+                            X86::Mov(X86Arg::RegOffset(Reg::R11, 0),
+                                     X86Arg::Imm(elts.len() as u64))
+                        ]);
+
+                        for (i, elt) in elts.iter().enumerate() {
+                            instrs.push(
+                                X86::Mov(X86Arg::RegOffset(Reg::R11,
+                                                           8*(i+1) as i64),
+                                         flat_arg_type(elt))
+                            );
+                        }
+
+                        // add 1 to indicate it is a tuple
+                        instrs.extend_from_slice(&[
+                            X86::Add(X86Arg::Var(dest), X86Arg::Imm(1))
+                        ]);
+
+                        return instrs;
+                    },
+                    Flat::Float(f) => {
+                        let mut instrs = alloc_boxed(&dest, FLOAT_KIND, 1);
+                        instrs.extend_from_slice(&[
+                            X86::Mov(X86Arg::RegOffset(Reg::R11, 8), X86Arg::Imm(f.to_bits())),
+                            X86::Add(X86Arg::Var(dest), X86Arg::Imm(BOXED_TAG)),
+                        ]);
+                        return instrs;
+                    },
+                    // kind and length, followed by the bytes packed
+                    // into words
+                    Flat::Str(s) => {
+                        let words : Vec<u64> = s.as_bytes().chunks(8).map(|chunk| {
+                            chunk.iter().rev().fold(0, |word, &b| (word << 8) | b as u64)
+                        }).collect();
+                        let mut instrs = alloc_boxed(&dest, STRING_KIND, words.len() + 1);
+                        instrs.push(X86::Mov(X86Arg::RegOffset(Reg::R11, 8),
+                                             X86Arg::Imm(s.len() as u64)));
+                        for (i, word) in words.into_iter().enumerate() {
+                            instrs.push(X86::Mov(X86Arg::RegOffset(Reg::R11, 8*(i+2) as i64),
+                                                 X86Arg::Imm(word)));
+                        }
+                        instrs.push(X86::Add(X86Arg::Var(dest), X86Arg::Imm(BOXED_TAG)));
+                        return instrs;
+                    },
+                    _ => {
+                        println!("{:?}", x);
+                        panic!("NYI")
+                    },
+                },
+            }
+        },
+        // a tail call, see mark_tail_calls
+        Flat::Return(box Flat::App(f, args)) => {
+            let mut instrs = vec![];
+            // The argument registers aren't homes of any variable, so
+            // none of the moves clobbers another's source.
+            for (i, arg) in args.iter().map(|a| flat_arg_type(a)).enumerate() {
+                instrs.push(X86::Mov(X86Arg::Reg(ARG_REG_ORDER[i].clone()), arg));
+            }
+            // rax isn't restored when leaving the frame, unlike the
+            // callee-save register `f` may be in
+            instrs.push(X86::Mov(X86Arg::Reg(Reg::RAX), X86Arg::Var(f)));
+            instrs.push(X86::Mov(X86Arg::Reg(Reg::R11), X86Arg::Imm(args.len() as u64)));
+            instrs.push(X86::TailJmp(X86Arg::Reg(Reg::RAX)));
+            return instrs;
+        },
+        Flat::Return(v) => {
+            let val = flat_arg_type(&*v);
+            return vec![X86::Mov(X86Arg::Reg(Reg::RAX),
+                                 val)]
+        },
+        Flat::If(cnd, thn, els) => {
+            let mut thn_instrs = vec![];
+            for i in thn {
+                let mut i_instrs = select_flat(i, config);
+                thn_instrs.append(&mut i_instrs);
+            }
+            let mut els_instrs = vec![];
+            for i in els {
+                let mut i_instrs = select_flat(i, config);
+                els_instrs.append(&mut i_instrs);
+            }
+            // everything but #f counts as true
+            match *cnd {
+                Flat::Bool(false) => return els_instrs,
+                Flat::Bool(true) | Flat::Number(_) | Flat::Char(_) | Flat::QuotedSymbol(_) |
+                Flat::Nil | Flat::Void =>
+                    return thn_instrs,
+                Flat::Symbol(v) =>
+                    return vec![X86::If(Box::new(X86::EqP(X86Arg::Var(v),
+                                                          X86Arg::Imm(CONST_FALSE))),
+                                        els_instrs,
+                                        thn_instrs)],
+                // branch on the flags, without making a boolean,
+                // unless both sides are constants and the branch
+                // taken is already known
+                Flat::Cmp(cc, left, right) => {
+                    let (mut instrs, left, right) = compare_operands(&*left, &*right);
+                    return match compare_constants(&cc, &left, &right) {
+                        Some(true) => thn_instrs,
+                        Some(false) => els_instrs,
+                        None => {
+                            instrs.push(X86::If(box X86::CmpP(cc, left, right),
+                                                thn_instrs,
+                                                els_instrs));
+                            instrs
+                        },
+                    };
+                },
+                cnd => panic!("if cond needs to be a value: {:?}", cnd),
+            }
+        },
+        Flat::While(test, cnd, body) => {
+            let mut test_instrs = vec![];
+            for i in test {
+                test_instrs.append(&mut select_flat(i, config));
+            }
+            let mut body_instrs = vec![];
+            for i in body {
+                body_instrs.append(&mut select_flat(i, config));
+            }
+            match *cnd {
+                Flat::Symbol(v) =>
+                    return vec![X86::While(test_instrs,
+                                           box X86::EqP(X86Arg::Var(v),
+                                                        X86Arg::Imm(CONST_FALSE)),
+                                           body_instrs)],
+                cnd => panic!("while test needs to be a variable: {:?}", cnd),
+            }
+        },
+        _ => panic!("NYI"),
+    }
+}
+
+// Whether `instr` has a float literal.
+fn makes_float(instr: &Flat) -> bool {
+    match instr {
+        &Flat::Float(_) => true,
+        &Flat::Assign(_, ref e) | &Flat::Return(ref e) => makes_float(e),
+        &Flat::If(ref cnd, ref thn, ref els) =>
+            makes_float(cnd) || thn.iter().chain(els.iter()).any(makes_float),
+        &Flat::While(ref test, ref cnd, ref body) =>
+            makes_float(cnd) || test.iter().chain(body.iter()).any(makes_float),
+        &Flat::Cmp(_, ref left, ref right) => makes_float(left) || makes_float(right),
+        &Flat::Tuple(ref args) | &Flat::App(_, ref args) | &Flat::Prim(_, ref args) =>
+            args.iter().any(makes_float),
+        _ => false,
+    }
+}
+
+// Whether a number is known to be an integer or a float, as far as
+// can be seen from where it is assigned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NumKind {
+    Int,
+    Float,
+}
+
+// What `v` is known to be: a literal, integer or float arithmetic, or
+// a copy of one of them.
+fn num_kind(v: &Flat, kinds: &HashMap<String, NumKind>) -> Option<NumKind> {
+    match v {
+        &Flat::Number(_) => Some(NumKind::Int),
+        &Flat::Float(_) => Some(NumKind::Float),
+        &Flat::Symbol(ref name) => kinds.get(name).cloned(),
+        &Flat::Prim(ref f, _) if f == "+" || f == "-" => Some(NumKind::Int),
+        &Flat::Prim(ref f, _) if f == "%float+" || f == "%float-" => Some(NumKind::Float),
+        _ => None,
+    }
+}
+
+// `left` compared with `right`, or if either may be a float, the
+// `%compare` of them, see compare_operands, compared with 0. A literal
+// that isn't a number can't be equal to one, so then their bits are
+// compared as they are.
+fn select_compare(cc: CC, left: Flat, right: Flat, kinds: &HashMap<String, NumKind>) -> Flat {
+    let not_number = |a: &Flat| match a {
+        &Flat::Symbol(_) | &Flat::Number(_) => false,
+        _ => true,
+    };
+    let int = |a: &Flat| num_kind(a, kinds) == Some(NumKind::Int);
+    if not_number(&left) || not_number(&right) || (int(&left) && int(&right)) {
+        Flat::Cmp(cc, box left, box right)
+    } else {
+        Flat::Cmp(cc, box Flat::Prim("%compare".to_string(), vec![left, right]),
+                  box Flat::Number(0))
+    }
+}
+
+// Pick the arithmetic for `+` and `-`: `%float+` and `%float-`, which
+// use SSE, if an argument is known to be a float, the integer
+// instructions if they all are integers, and otherwise `%number+` and
+// `%number-`, which look at the arguments' tags at run time, since a
+// float can come from anywhere, eg. as a function's argument.
+// Comparisons are picked by select_compare.
+fn select_float_arith(instrs: Vec<Flat>, kinds: &mut HashMap<String, NumKind>) -> Vec<Flat> {
+    let mut new_instrs = vec![];
+    for instr in instrs {
+        match instr {
+            Flat::Assign(dest, e) => {
+                let e = match *e {
+                    Flat::Cmp(cc, left, right) => select_compare(cc, *left, *right, kinds),
+                    Flat::Prim(f, args) => {
+                        if f == "+" || f == "-" {
+                            let arg_kinds : Vec<Option<NumKind>> =
+                                args.iter().map(|a| num_kind(a, kinds)).collect();
+                            if arg_kinds.contains(&Some(NumKind::Float)) {
+                                Flat::Prim(format!("%float{}", f), args)
+                            } else if arg_kinds.iter().all(|k| k == &Some(NumKind::Int)) {
+                                Flat::Prim(f, args)
+                            } else {
+                                Flat::Prim(format!("%number{}", f), args)
+                            }
+                        } else {
+                            Flat::Prim(f, args)
+                        }
+                    },
+                    e => e,
+                };
+                // reuse_temps can give a float and an integer the
+                // same variable
+                match num_kind(&e, kinds) {
+                    Some(kind) => kinds.insert(dest.clone(), kind),
+                    None => kinds.remove(&dest),
+                };
+                new_instrs.push(Flat::Assign(dest, box e));
+            },
+            // what a variable is known to be after the `if` is what
+            // it is after both branches
+            Flat::If(cnd, thn, els) => {
+                let mut thn_kinds = kinds.clone();
+                let mut els_kinds = kinds.clone();
+                let cnd = match *cnd {
+                    Flat::Cmp(cc, left, right) => select_compare(cc, *left, *right, kinds),
+                    cnd => cnd,
+                };
+                let thn = select_float_arith(thn, &mut thn_kinds);
+                let els = select_float_arith(els, &mut els_kinds);
+                *kinds = thn_kinds.into_iter()
+                    .filter(|&(ref v, k)| els_kinds.get(v) == Some(&k))
+                    .collect();
+                new_instrs.push(Flat::If(box cnd, thn, els));
+            },
+            // the loop can change what a variable assigned in it is
+            // before the test runs again
+            Flat::While(test, cnd, body) => {
+                let mut assigned = assigned_vars(&test);
+                assigned.extend(assigned_vars(&body));
+                kinds.retain(|v, _| !assigned.contains(v));
+                let test = select_float_arith(test, kinds);
+                let body = select_float_arith(body, &mut kinds.clone());
+                new_instrs.push(Flat::While(test, cnd, body));
+            },
+            instr => new_instrs.push(instr),
+        }
+    }
+    return new_instrs;
+}
+
+// convert a Flat expression into pseudo-x86 instructions. pseudo-x86
+// is like x86 but with if's and temporaries. It is also
+// "unpatched" (see `patch_instructions`)
+fn select_instructions(flat_prog: FlatResult, config: &Config) -> X86 {
+
+    match flat_prog {
+        FlatResult::Define(name, args, assigns, mut vars) =>
+        {
+            // TODO: if more than 6 args, spill args to stack
+            let mut move_args = vec![];
+            for (i, arg) in args.iter().enumerate() {
+                move_args.push(
+                    X86::Mov(X86Arg::Var(arg.clone()),
+                             X86Arg::Reg(ARG_REG_ORDER[i].clone()))
+                );
+            }
+
+            // saved before anything can clobber r11
+            let rest_args = assigns.iter().any(|i| match i {
+                &Flat::Assign(_, box Flat::Prim(ref f, _)) => f == "%rest-args",
+                _ => false,
+            });
+            if rest_args {
+                move_args.insert(0, X86::Mov(X86Arg::Var(ARGC_VAR.to_string()),
+                                             X86Arg::Reg(Reg::R11)));
+                vars.push(ARGC_VAR.to_string());
+            }
+
+            let mut x86_instrs = move_args;
+            for i in assigns {
+                let mut i_instrs = select_flat(i, config);
+                x86_instrs.append(&mut i_instrs);
+            }
+
+            vars.extend_from_slice(&args);
+            return X86::Define(name,
+                               vars,
+                               x86_instrs);
+        },
+
+        FlatResult::Prog(defs, main_assigns, main_vars) => {
+            // only a float literal makes a float, so without one
+            // every number is an integer
+            let floats = main_assigns.iter().any(makes_float) ||
+                defs.iter().any(|def| match def {
+                    &FlatResult::Define(_, _, ref assigns, _) => assigns.iter().any(makes_float),
+                    _ => false,
+                });
+            let select_arith = |assigns: Vec<Flat>| if floats {
+                select_float_arith(assigns, &mut HashMap::new())
+            } else {
+                assigns
+            };
+
+            let mut x86_defines = vec![];
+            for def in defs {
+                let def = match def {
+                    FlatResult::Define(name, args, assigns, vars) =>
+                        FlatResult::Define(name, args, select_arith(assigns), vars),
+                    def => def,
+                };
+                x86_defines.push(select_instructions(def, config));
+            }
+
+            let mut x86_instrs = vec![];
+            for i in select_arith(main_assigns) {
+                let mut i_instrs = select_flat(i, config);
+                x86_instrs.append(&mut i_instrs);
+            }
+            return X86::Prog(x86_defines, x86_instrs, main_vars);
+        },
+        _ => panic!("flat_prog is not a top-level Prog"),
+    }
+}
+
+// For an instruction, returns a 3-tuple:
+// (variables used in instruction, variables read, variables written to)
+fn instruction_rw(instr: X86) -> (Vec<String>, Vec<String>, Vec<String>) {
+    match instr {
+        X86::Mov(X86Arg::Var(dest), X86Arg::Var(src)) => {
+            return (vec![dest.clone(), src.clone()],
+                    vec![src],
+                    vec![dest]);
+        },
+        X86::Mov(X86Arg::Var(dest), _) => {
+            return (vec![dest.clone()],
+                    vec![],
+                    vec![dest]);
+        },
+        X86::Mov(X86Arg::Reg(_), X86Arg::Var(src)) => {
+            return (vec![src.clone()],
+                    vec![src],
+                    vec![]);
+        },
+        X86::Mov(X86Arg::GlobalVal(_), X86Arg::Var(src)) |
+        X86::Mov(X86Arg::RegOffset(_, _), X86Arg::Var(src)) => {
+            return (vec![src.clone()],
+                    vec![src],
+                    vec![]);
+        },
+        X86::Mov(_, _) => {
+            return (vec![], vec![], vec![]);
+        },
+        X86::MovZx(X86Arg::Var(dest), _) => {
+            return (vec![dest.clone()],
+                    vec![],
+                    vec![dest]);
+        },
+        X86::MovZx(_, _) => {
+            return (vec![], vec![], vec![]);
+        },
+        // the destination is read too, since it's only overwritten
+        // when the condition holds.
+        X86::CMov(_, X86Arg::Var(dest), X86Arg::Var(src)) => {
+            return (vec![dest.clone(), src.clone()],
+                    vec![dest.clone(), src],
+                    vec![dest]);
+        },
+        X86::CMov(_, X86Arg::Var(dest), _) => {
+            return (vec![dest.clone()],
+                    vec![dest.clone()],
+                    vec![dest]);
+        },
+        X86::Set(_, _) =>  {
+            return (vec![], vec![], vec![]);
+        },
+
+        X86::Cmp(left, right) => {
+            match (left, right) {
+                (X86Arg::Var(l), X86Arg::Var(r)) => (vec![l.clone(),
+                                                          r.clone()],
+                                                     vec![l, r],
+                                                     vec![]),
+                (X86Arg::Var(l), _) => (vec![l.clone()],
+                                        vec![l],
+                                        vec![]),
+                (_, X86Arg::Var(r)) => (vec![r.clone()],
+                                        vec![r],
+                                        vec![]),
+                (_, _) => (vec![], vec![], vec![]),
+            }
+        },
+        X86::Sub(X86Arg::Var(dest), X86Arg::Var(src)) |
+        X86::Add(X86Arg::Var(dest), X86Arg::Var(src)) => {
+            return (vec![dest.clone(), src.clone()],
+                    vec![dest.clone(), src],
+                    vec![dest]);
+        },
+        X86::Sub(X86Arg::Var(dest), _) |
+        X86::Add(X86Arg::Var(dest), _) => {
+            return (vec![dest.clone()],
+                    vec![dest.clone()],
+                    vec![dest]);
+        },
+        // a register destination, eg. rax for the return value,
+        // isn't a variable, but the source may be
+        X86::Sub(X86Arg::Reg(_), X86Arg::Var(src)) |
+        X86::Add(X86Arg::Reg(_), X86Arg::Var(src)) => {
+            return (vec![src.clone()],
+                    vec![src],
+                    vec![]);
+        },
+        X86::Call(X86Arg::Var(f)) => {
+            return (vec![f.clone()],
+                    vec![f],
+                    vec![]);
+        },
+        X86::Sub(X86Arg::Reg(_), _) |
+        X86::And(X86Arg::Reg(_), X86Arg::Imm(_)) |
+        X86::Add(X86Arg::Reg(_), _) |
+        X86::Add(X86Arg::GlobalVal(_), X86Arg::Imm(_)) |
+        X86::Push(_) | X86::Pop(_) | X86::Call(_) | X86::TailJmp(_) |
+        X86::JmpIf(_, _) | X86::Comment(_) | X86::Collect(_, _) |
+        X86::Sar(X86Arg::Reg(_), X86Arg::Imm(_)) |
+        X86::MovSd(_, _) | X86::AddSd(_, _) | X86::SubSd(_, _) | X86::XorPd(_, _) |
+        X86::CvtSi2Sd(_, _) =>
+            return (vec![], vec![], vec![]),
+        X86::Neg(X86Arg::Var(n)) => {
+            return (vec![n.clone()],
+                    vec![n.clone()],
+                    vec![n.clone()]);
+        },
+        X86::Xor(X86Arg::Reg(_), X86Arg::Var(src)) |
+        X86::Or(X86Arg::Reg(_), X86Arg::Var(src)) |
+        X86::IDiv(X86Arg::Var(src)) => {
+            return (vec![src.clone()],
+                    vec![src],
+                    vec![]);
+        },
+        X86::Xor(X86Arg::Reg(_), _) | X86::Or(X86Arg::Reg(_), _) | X86::IDiv(_) | X86::Cqo =>
+            return (vec![], vec![], vec![]),
+        _ => panic!("NYI: {:?}", instr),
+    }
+}
+
+
+// Variables read by the condition of an `if` or a loop.
+fn cond_vars(cnd: &X86) -> HashSet<String> {
+    match cnd {
+        &X86::EqP(ref left, ref right) | &X86::CmpP(_, ref left, ref right) => {
+            let mut vars = HashSet::new();
+            for arg in vec![left, right] {
+                if let &X86Arg::Var(ref v) = arg {
+                    vars.insert(v.clone());
+                }
+            }
+            vars
+        },
+        _ => panic!("cond needs to be EqP or CmpP"),
+    }
+}
+
+// Find live variables during each instruction. For `if`s, the live
+// sets are embedded in the new list of instructions
+fn get_live_after_sets(mut instrs: Vec<X86>, lives: HashSet<String>)
+                   -> (HashSet<String>, Vec<HashSet<String>>, Vec<X86>) {
+    let mut live_of_next = lives.clone();
+    let mut live_after_sets = vec![];
+    let mut new_instrs = vec![];
+
+    instrs.reverse();
+    for instr in instrs {
+        match instr {
+            X86::If(cnd, thns, elss) => {
+                let (thn_lives, thn_live_sets, new_thns) =
+                    get_live_after_sets(thns.clone(), live_of_next.clone());
+                let (els_lives, els_live_sets, new_elss) =
+                    get_live_after_sets(elss.clone(), live_of_next.clone());
+                let cond_vars = cond_vars(&cnd);
+
+                let mut live = cond_vars;
+                live = live.union(&thn_lives).cloned().collect();
+                live = live.union(&els_lives).cloned().collect();
+
+                live_of_next = live.clone();
+                live_after_sets.push(live);
+
+                new_instrs.push(X86::IfWithLives(
+                    cnd,
+                    new_thns, thn_live_sets,
+                    new_elss, els_live_sets));
+            },
+
+            // What's live at the top of the loop is live at the end of
+            // the body too, so grow it until it stops changing.
+            X86::While(test, cnd, body) => {
+                let cond_vars = cond_vars(&cnd);
+                let mut live_top = HashSet::new();
+                loop {
+                    let (body_lives, body_live_sets, new_body) =
+                        get_live_after_sets(body.clone(), live_top.clone());
+                    let mut live_after_test = live_of_next.clone();
+                    live_after_test = live_after_test.union(&cond_vars).cloned().collect();
+                    live_after_test = live_after_test.union(&body_lives).cloned().collect();
+                    let (test_lives, test_live_sets, new_test) =
+                        get_live_after_sets(test.clone(), live_after_test);
+
+                    if test_lives == live_top {
+                        live_of_next = live_top.clone();
+                        live_after_sets.push(live_top);
+                        new_instrs.push(X86::WhileWithLives(
+                            new_test, test_live_sets,
+                            cnd,
+                            new_body, body_live_sets));
+                        break;
+                    }
+                    live_top = live_top.union(&test_lives).cloned().collect();
+                }
+            },
+
+            _ => {
+                let (_, read_vars, written_vars) =
+                    instruction_rw(instr.clone());
+                let mut live = live_of_next.clone();
+                let written_vars_set : HashSet<_> =
+                    written_vars.iter().cloned().collect();
+                live = live.difference(&written_vars_set).cloned().collect();
+                let read_vars_set : HashSet<_> = read_vars.iter().cloned().collect();
+                live = live.union(&read_vars_set).cloned().collect();
+
+                live_of_next = live.clone();
+                live_after_sets.push(live);
+                new_instrs.push(instr);
+            },
+        }
+    };
+
+    live_after_sets.reverse();
+    new_instrs.reverse();
+    return (live_of_next, live_after_sets, new_instrs);
+}
+
+fn uncover_live(prog: X86) -> X86 {
+    match prog {
+        X86::Define(name, vars, instrs) => {
+            let (_, live_sets, new_instrs) = get_live_after_sets(instrs, HashSet::new());
+            return X86::DefineWithLives(name, vars, live_sets, new_instrs);
+        },
+
+        X86::Prog(mut defs, instrs, vars) => {
+            let (_, live_sets, new_instrs) = get_live_after_sets(instrs, HashSet::new());
+
+            defs = defs.iter().map(|def| uncover_live(def.clone())).collect();
+            return X86::ProgWithLives(defs,
+                                      new_instrs,
+                                      vars,
+                                      live_sets);
+        },
+        _ => panic!("prog is not a top-level Prog"),
+    }
+}
+
+// Add the points from `start` to `end` to a variable's live ranges,
+// merging them with the ranges they overlap or are next to.
+fn add_live_range(ranges: &mut Vec<(i32, i32)>, start: i32, end: i32) {
+    let (mut start, mut end) = (start, end);
+    ranges.retain(|&(s, e)| {
+        if s <= end + 1 && start <= e + 1 {
+            start = cmp::min(start, s);
+            end = cmp::max(end, e);
+            return false;
+        }
+        true
+    });
+    ranges.push((start, end));
+    ranges.sort();
+}
+
+// The instruction on each line reads its operands at one point and
+// writes its result at the next, so that, as in build_interference, a
+// variable read for the last time doesn't overlap the one written.
+fn read_point(line_num: i32) -> i32 {
+    2 * line_num
+}
+
+fn write_point(line_num: i32) -> i32 {
+    2 * line_num + 1
+}
+
+// For each variable, figure out the ranges of points when it is live,
+// see read_point. A variable can be dead in between, eg. in the `then`
+// branch of an `if` when it's only read, or only assigned, in the
+// `else` branch, so the ranges have holes. Results are inserted into
+// live_ranges. Returns the line number following the last
+// instruction.
+fn compute_live_ranges(instrs: Vec<X86>, live_sets: Vec<HashSet<String>>,
+                       live_ranges: &mut HashMap<String, Vec<(i32, i32)>>,
+                       init_line_num: i32) -> i32 {
+    let mut line_num = init_line_num;
+    for (i, instr) in instrs.iter().enumerate() {
+        let live_set = &live_sets[i];
+        // what is live on entry to the function, or to a branch, came
+        // from before it rather than from the line before, which can
+        // be the end of the other branch
+        let start = if i == 0 { read_point(line_num) } else { write_point(line_num - 1) };
+        for v in live_set.iter() {
+            add_live_range(live_ranges.entry(v.clone()).or_insert(vec![]),
+                           start, read_point(line_num));
+        }
+        // A variable written here but not read afterwards still
+        // needs a register no other variable is using.
+        match instr {
+            &X86::IfWithLives(..) | &X86::WhileWithLives(..) => (),
+            _ => {
+                let (_, _, written) = instruction_rw(instr.clone());
+                for v in written {
+                    if live_sets.get(i + 1).map_or(true, |live| !live.contains(&v)) {
+                        add_live_range(live_ranges.entry(v).or_insert(vec![]),
+                                       write_point(line_num), write_point(line_num));
+                    }
+                }
+            },
+        }
+        line_num = line_num + 1;
+
+        // Number the branches one after the other, as they are laid
+        // out. A variable live in only one of them has a hole over
+        // the other.
+        if let &X86::IfWithLives(_, ref thns, ref thn_lives, ref elss, ref els_lives) = instr {
+            let thn_end = compute_live_ranges(thns.clone(), thn_lives.clone(),
+                                              live_ranges, line_num);
+            line_num = compute_live_ranges(elss.clone(), els_lives.clone(),
+                                           live_ranges, thn_end);
+        }
+        // A variable live at the top of a loop is live until the jump
+        // back to it.
+        if let &X86::WhileWithLives(ref test, ref test_lives, _, ref body, ref body_lives) = instr {
+            let loop_start = line_num;
+            let test_end = compute_live_ranges(test.clone(), test_lives.clone(),
+                                               live_ranges, line_num);
+            line_num = compute_live_ranges(body.clone(), body_lives.clone(),
+                                           live_ranges, test_end);
+            for v in live_set.iter() {
+                add_live_range(live_ranges.get_mut(v).unwrap(),
+                               write_point(loop_start - 1), read_point(line_num));
+            }
+        }
+    }
+
+    return line_num;
+}
+
+// The variables live after a loop's test: those read by the cond,
+// those live after the loop, and those live at the start of the body.
+// `live_top` is the loop's live set, which is the body's live-out.
+fn loop_test_live_out(live_top: &HashSet<String>, live_after: &HashSet<String>,
+                      cnd: &X86, body_lives: &Vec<HashSet<String>>) -> HashSet<String> {
+    let body_live_in = body_lives.first().unwrap_or(live_top);
+    let mut live = live_after.clone();
+    live = live.union(&cond_vars(cnd)).cloned().collect();
+    live = live.union(body_live_in).cloned().collect();
+    return live;
+}
+
+// A call clobbers all caller-save registers, so a variable live after
+// a call has to be kept in a callee-save register or on the stack.
+// `live_sets` and `live_out` are as in build_interference.
+fn live_across_calls(instrs: &Vec<X86>, live_sets: &Vec<HashSet<String>>,
+                     live_out: &HashSet<String>, across: &mut HashSet<String>) {
+    for (i, instr) in instrs.iter().enumerate() {
+        let live_after = if i + 1 < live_sets.len() {
+            &live_sets[i + 1]
+        } else {
+            live_out
+        };
+
+        match instr {
+            &X86::IfWithLives(_, ref thns, ref thn_lives, ref elss, ref els_lives) => {
+                live_across_calls(thns, thn_lives, live_after, across);
+                live_across_calls(elss, els_lives, live_after, across);
+            },
+            &X86::WhileWithLives(ref test, ref test_lives, ref cnd, ref body, ref body_lives) => {
+                let live_top = &live_sets[i];
+                let test_live_out = loop_test_live_out(live_top, live_after, cnd, body_lives);
+                live_across_calls(test, test_lives, &test_live_out, across);
+                live_across_calls(body, body_lives, live_top, across);
+            },
+            &X86::Call(_) => {
+                for v in live_after {
+                    across.insert(v.clone());
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+fn is_caller_save(regs: &[Reg], reg: i32) -> bool {
+    CALLER_SAVE_REGS.contains(&regs[reg as usize])
+}
+
+fn ranges_overlap(a: &Vec<(i32, i32)>, b: &Vec<(i32, i32)>) -> bool {
+    a.iter().any(|&(s1, e1)| b.iter().any(|&(s2, e2)| s1 <= e2 && s2 <= e1))
+}
+
+// Allocate registers for variables, in the order their first live
+// ranges start. A register can go to a variable if none of the
+// variables it was given to is live at the same time, which is how a
+// variable live in one branch of an `if` can share one with a variable
+// live in the other. If it can't find a free register, the variable
+// won't be present as a key in the returned hash-map. Registers are
+// numbered by their index in `regs`.
+fn allocate_registers(live_ranges: HashMap<String, Vec<(i32, i32)>>,
+                      across_calls: &HashSet<String>, regs: &[Reg])
+                      -> HashMap<String, i32> {
+    let mut live_ranges_vec : Vec<_> = live_ranges.into_iter().collect();
+    live_ranges_vec.sort_by_key(|&(ref v, ref ranges)| (ranges[0].0, v.clone()));
+
+    let mut mapping : HashMap<String, i32> = HashMap::new();
+    let mut holders : Vec<Vec<(i32, i32)>> = vec![vec![]; regs.len()];
+    for (v, ranges) in live_ranges_vec {
+        // the last registers are caller-save, and don't have to be
+        // saved in the prologue, so they're tried first unless `v` is
+        // live across a call
+        let pick = (0..regs.len() as i32).rev().find(|r| {
+            (!across_calls.contains(&v) || !is_caller_save(regs, *r)) &&
+                !ranges_overlap(&holders[*r as usize], &ranges)
+        });
+        if let Some(reg) = pick {
+            holders[reg as usize].extend(ranges);
+            mapping.insert(v, reg);
+        }
+    }
+    return mapping;
+}
+
+fn add_interference(graph: &mut HashMap<String, HashSet<String>>,
+                    a: &String, b: &String) {
+    graph.entry(a.clone()).or_insert(HashSet::new()).insert(b.clone());
+    graph.entry(b.clone()).or_insert(HashSet::new()).insert(a.clone());
+}
+
+// Add an edge between every variable written by an instruction and
+// every other variable live after it. `live_sets[i]` is the set of
+// variables live before instruction i, so the set live after it is
+// the next one's, or `live_out` for the last instruction.
+fn build_interference(instrs: &Vec<X86>, live_sets: &Vec<HashSet<String>>,
+                      live_out: &HashSet<String>,
+                      graph: &mut HashMap<String, HashSet<String>>) {
+    for (i, instr) in instrs.iter().enumerate() {
+        let live_after = if i + 1 < live_sets.len() {
+            &live_sets[i + 1]
+        } else {
+            live_out
+        };
+
+        match instr {
+            &X86::IfWithLives(_, ref thns, ref thn_lives, ref elss, ref els_lives) => {
+                build_interference(thns, thn_lives, live_after, graph);
+                build_interference(elss, els_lives, live_after, graph);
+            },
+            &X86::WhileWithLives(ref test, ref test_lives, ref cnd, ref body, ref body_lives) => {
+                let live_top = &live_sets[i];
+                let test_live_out = loop_test_live_out(live_top, live_after, cnd, body_lives);
+                build_interference(test, test_lives, &test_live_out, graph);
+                build_interference(body, body_lives, live_top, graph);
+            },
+            // a move doesn't make its source and dest interfere, they
+            // hold the same value
+            &X86::Mov(X86Arg::Var(ref d), X86Arg::Var(ref s)) => {
+                graph.entry(d.clone()).or_insert(HashSet::new());
+                for v in live_after {
+                    if v != d && v != s {
+                        add_interference(graph, d, v);
+                    }
+                }
+            },
+            _ => {
+                let (_, _, written) = instruction_rw(instr.clone());
+                for d in written.iter() {
+                    graph.entry(d.clone()).or_insert(HashSet::new());
+                    for v in live_after {
+                        if v != d {
+                            add_interference(graph, d, v);
+                        }
+                    }
+                }
+            },
+        }
+    }
+}
+
+// Color the interference graph with regs.len() colors, most
+// constrained variable first. Like allocate_registers, variables
+// that can't get a register are left out of the returned hash-map.
+fn color_graph(graph: &HashMap<String, HashSet<String>>,
+               across_calls: &HashSet<String>, regs: &[Reg]) -> HashMap<String, i32> {
+    let mut mapping : HashMap<String, i32> = HashMap::new();
+    let mut uncolored : Vec<String> = graph.keys().cloned().collect();
+    uncolored.sort();
+
+    while uncolored.len() > 0 {
+        // pick the variable whose neighbours already use the most
+        // registers, breaking ties by degree, then by name
+        let mut best = 0;
+        let mut best_key = (0, 0);
+        for (i, v) in uncolored.iter().enumerate() {
+            let neighbours = graph.get(v).unwrap();
+            let used : HashSet<_> = neighbours.iter()
+                .filter_map(|n| mapping.get(n))
+                .collect();
+            let key = (used.len(), neighbours.len());
+            if i == 0 || key > best_key {
+                best = i;
+                best_key = key;
+            }
+        }
+        let v = uncolored.remove(best);
+
+        let used : HashSet<i32> = graph.get(&v).unwrap().iter()
+            .filter_map(|n| mapping.get(n))
+            .cloned()
+            .collect();
+        // caller-save registers don't have to be saved in the
+        // prologue, so try them first unless `v` is live across a call
+        let crosses_call = across_calls.contains(&v);
+        let mut candidates : Vec<i32> = vec![];
+        if !crosses_call {
+            candidates.extend((0..regs.len() as i32).filter(|r| is_caller_save(regs, *r)));
+        }
+        candidates.extend((0..regs.len() as i32).filter(|r| !is_caller_save(regs, *r)));
+        match candidates.into_iter().find(|r| !used.contains(r)) {
+            Some(reg) => {
+                mapping.insert(v, reg);
+            },
+            None => (),
+        }
+    }
+
+    return mapping;
+}
+
+// The variable pairs moved from one to the other in `instrs`.
+fn move_pairs(instrs: &Vec<X86>, pairs: &mut Vec<(String, String)>) {
+    for instr in instrs {
+        match instr {
+            &X86::Mov(X86Arg::Var(ref d), X86Arg::Var(ref s)) => {
+                pairs.push((d.clone(), s.clone()));
+            },
+            &X86::IfWithLives(_, ref thns, _, ref elss, _) => {
+                move_pairs(thns, pairs);
+                move_pairs(elss, pairs);
+            },
+            &X86::WhileWithLives(ref test, _, _, ref body, _) => {
+                move_pairs(test, pairs);
+                move_pairs(body, pairs);
+            },
+            _ => (),
+        }
+    }
+}
+
+// Merge the two variables of a move into one node of the interference
+// graph when they don't interfere, so that they get the same register
+// and the move becomes `mov r, r`, which remove_self_moves drops. A
+// merge is only made when the merged node has fewer than regs.len()
+// neighbours that have regs.len() or more neighbours themselves, so
+// that it doesn't make the graph any harder to color (Briggs). Returns
+// what each merged-away variable was merged into.
+fn coalesce_moves(graph: &mut HashMap<String, HashSet<String>>,
+                  moves: &Vec<(String, String)>,
+                  across_calls: &mut HashSet<String>, regs: &[Reg])
+                  -> HashMap<String, String> {
+    let mut merged : HashMap<String, String> = HashMap::new();
+    let find = |merged: &HashMap<String, String>, v: &String| -> String {
+        let mut v = v.clone();
+        while let Some(into) = merged.get(&v) {
+            v = into.clone();
+        }
+        v
+    };
+
+    for &(ref d, ref s) in moves {
+        let (a, b) = (find(&merged, d), find(&merged, s));
+        if a == b || !graph.contains_key(&a) || !graph.contains_key(&b) ||
+            graph[&a].contains(&b) {
+            continue;
+        }
+
+        let neighbours : HashSet<String> = graph[&a].union(&graph[&b]).cloned().collect();
+        let significant = neighbours.iter()
+            .filter(|n| graph[*n].len() >= regs.len())
+            .count();
+        if significant >= regs.len() {
+            continue;
+        }
+
+        for n in graph.remove(&b).unwrap() {
+            let n_neighbours = graph.get_mut(&n).unwrap();
+            n_neighbours.remove(&b);
+            n_neighbours.insert(a.clone());
+        }
+        graph.insert(a.clone(), neighbours);
+        if across_calls.contains(&b) {
+            across_calls.insert(a.clone());
+        }
+        merged.insert(b, a);
+    }
+
+    let mut merged_into = HashMap::new();
+    for v in merged.keys() {
+        merged_into.insert(v.clone(), find(&merged, v));
+    }
+    return merged_into;
+}
+
+fn assign_homes_to_op2(locs: &HashMap<String, X86Arg>,
+                       dest: X86Arg, src: X86Arg) -> (X86Arg, X86Arg) {
+    match (dest.clone(), src.clone()) {
+        (X86Arg::Var(d), X86Arg::Var(s)) =>
+            (locs.get(&d).unwrap().clone(),
+             locs.get(&s).unwrap().clone()),
+        (X86Arg::Var(d), _) => {
+            (locs.get(&d).unwrap().clone(),
+             src)
+        },
+        (_, X86Arg::Var(s)) =>
+            (dest, locs.get(&s).unwrap().clone()),
+        (_, X86Arg::FuncName(_)) => {
+            (dest, src)
+        },
+        (X86Arg::RegOffset(_, _), X86Arg::Imm(_)) |
+        (X86Arg::RegOffset(_, _), X86Arg::Reg(_)) |
+        (X86Arg::GlobalVal(_), X86Arg::Imm(_)) |
+        (X86Arg::GlobalVal(_), X86Arg::Reg(_)) |
+        (X86Arg::Imm(_), X86Arg::Imm(_)) |
+        (X86Arg::Reg(_), _) =>
+            (dest, src),
+        _ => panic!("unreachable: {:?}", (dest, src)),
+    }
+}
+
+fn assign_homes_to_cond(locs: &HashMap<String, X86Arg>, cnd: X86) -> X86 {
+    match cnd {
+        X86::EqP(left, right) => {
+            let (new_left, new_right) = assign_homes_to_op2(locs, left, right);
+            X86::EqP(new_left, new_right)
+        },
+        X86::CmpP(cc, left, right) => {
+            let (new_left, new_right) = assign_homes_to_op2(locs, left, right);
+            X86::CmpP(cc, new_left, new_right)
+        },
+        _ => panic!("cond should be an EqP or CmpP"),
+    }
+}
+
+// Given a list of instructions and mapping from vars to
+// "homes"(register/stack location), return a new list of instructions
+// with vars replaced with their assigned homes.
+// The homes of the variables in `live`, in a fixed order, to be
+// pushed onto the root stack.
+fn root_homes(live: &HashSet<String>, locs: &HashMap<String, X86Arg>) -> Vec<X86Arg> {
+    let mut vars : Vec<_> = live.iter().collect();
+    vars.sort();
+    vars.iter().map(|v| locs.get(*v).unwrap().clone()).collect()
+}
+
+// `live_sets` and `live_out` are as in build_interference. The
+// variables live at an allocation, or after a call that may allocate,
+// are the roots the collector needs.
+fn assign_homes_to_instrs(instrs: Vec<X86>, live_sets: Vec<HashSet<String>>,
+                          live_out: &HashSet<String>,
+                          locs: HashMap<String, X86Arg>) -> Vec<X86> {
+    let mut new_instrs = vec![];
+    for (idx, i) in instrs.into_iter().enumerate() {
+        let live_after = if idx + 1 < live_sets.len() {
+            live_sets[idx + 1].clone()
+        } else {
+            live_out.clone()
+        };
+
+        match i {
+            X86::IfWithLives(cnd, thn, thn_lives, els, els_lives) => {
+                let new_cnd = assign_homes_to_cond(&locs, *cnd);
+                let new_thn = assign_homes_to_instrs(thn, thn_lives, &live_after, locs.clone());
+                let new_els = assign_homes_to_instrs(els, els_lives, &live_after, locs.clone());
+                new_instrs.push(
+                    X86::If(Box::new(new_cnd), new_thn, new_els)
+                );
+            },
+            X86::WhileWithLives(test, test_lives, cnd, body, body_lives) => {
+                let live_top = live_sets[idx].clone();
+                let test_live_out = loop_test_live_out(&live_top, &live_after, &cnd, &body_lives);
+                let new_cnd = assign_homes_to_cond(&locs, *cnd);
+                let new_test = assign_homes_to_instrs(test, test_lives, &test_live_out,
+                                                      locs.clone());
+                let new_body = assign_homes_to_instrs(body, body_lives, &live_top, locs.clone());
+                new_instrs.push(X86::While(new_test, box new_cnd, new_body));
+            },
+            // nothing is read or written, so what's live before is
+            // live after
+            X86::Collect(len, mut roots) => {
+                roots.extend(root_homes(&live_sets[idx], &locs));
+                new_instrs.extend(collect_instrs(len, roots));
+            },
+            X86::Mov(dest, src) => {
+                let (new_dest, new_src) = assign_homes_to_op2(&locs, dest, src);
+                new_instrs.push(X86::Mov(new_dest, new_src))
+            },
+            X86::MovZx(dest, src) => {
+                let (new_dest, new_src) = assign_homes_to_op2(&locs, dest, src);
+                new_instrs.push(X86::MovZx(new_dest, new_src))
+            },
+            X86::Add(dest, src) => {
+                let (new_dest, new_src) = assign_homes_to_op2(&locs, dest, src);
+                new_instrs.push(X86::Add(new_dest, new_src))
+            },
+            X86::Cmp(left, right) => {
+                let (new_left, new_right) =
+                    assign_homes_to_op2(&locs, left, right);
+                new_instrs.push(X86::Cmp(new_left, new_right))
+            },
+            X86::CMov(cc, dest, src) => {
+                let (new_dest, new_src) = assign_homes_to_op2(&locs, dest, src);
+                new_instrs.push(X86::CMov(cc, new_dest, new_src))
+            },
+            X86::Neg(n) => {
+                let new_n = match n {
+                    X86Arg::Var(v) => locs.get(&v).unwrap().clone(),
+                    _ => n,
+                };
+
+                new_instrs.push(X86::Neg(new_n))
+            }
+            X86::Xor(dest, src) => {
+                let (new_dest, new_src) = assign_homes_to_op2(&locs, dest, src);
+                new_instrs.push(X86::Xor(new_dest, new_src))
+            },
+            X86::Or(dest, src) => {
+                let (new_dest, new_src) = assign_homes_to_op2(&locs, dest, src);
+                new_instrs.push(X86::Or(new_dest, new_src))
+            },
+            X86::IDiv(X86Arg::Var(ref v)) => {
+                new_instrs.push(X86::IDiv(locs.get(v).unwrap().clone()))
+            },
+            // the function called may allocate
+            X86::Call(X86Arg::Var(ref f)) => {
+                let roots = root_homes(&live_after, &locs);
+                new_instrs.extend(push_roots(&roots));
+                new_instrs.push(X86::Call(locs.get(f).unwrap().clone()));
+                new_instrs.extend(pop_roots(&roots));
+            },
+            // make_vector is told where the roots end, in rdx
+            X86::Call(X86Arg::FuncName(ref f)) if f == MAKE_VECTOR => {
+                let roots = root_homes(&live_after, &locs);
+                new_instrs.extend(push_roots(&roots));
+                new_instrs.push(X86::Mov(X86Arg::Reg(Reg::RDX), X86Arg::Reg(Reg::R15)));
+                new_instrs.push(i.clone());
+                new_instrs.extend(pop_roots(&roots));
+            },
+            X86::Call(X86Arg::FuncName(_)) => {
+                new_instrs.push(i.clone())
+            },
+            X86::Set(X86Arg::Reg(_), _) |
+            X86::TailJmp(X86Arg::Reg(_)) |
+            X86::Push(_) | X86::Pop(_) |
+            X86::Sub(X86Arg::Reg(_), X86Arg::Imm(_)) |
+            X86::And(X86Arg::Reg(_), X86Arg::Imm(_)) |
+            X86::JmpIf(_, _) | X86::Comment(_) |
+            X86::Sar(X86Arg::Reg(_), X86Arg::Imm(_)) |
+            X86::MovSd(_, _) | X86::AddSd(_, _) | X86::SubSd(_, _) | X86::XorPd(_, _) |
+            X86::CvtSi2Sd(_, _) | X86::IDiv(X86Arg::Imm(_)) | X86::Cqo
+                => {
+                new_instrs.push(i);
+            },
+            _ => panic!("NYI: {:?}", i),
+        }
+    };
+
+    return new_instrs;
+}
+
+// Allocate registers from `regs` to the variables of `instrs`.
+fn allocate_homes(vars: &Vec<String>, instrs: &Vec<X86>,
+                  live_sets: Vec<HashSet<String>>, config: &Config,
+                  regs: &[Reg]) -> HashMap<String, i32> {
+    let mut across_calls = HashSet::new();
+    live_across_calls(instrs, &live_sets, &HashSet::new(), &mut across_calls);
+
+    match config.allocator {
+        Allocator::LinearScan => {
+            let mut live_ranges = HashMap::new();
+            compute_live_ranges(instrs.clone(),
+                                live_sets,
+                                &mut live_ranges, 1);
+            allocate_registers(live_ranges, &across_calls, regs)
+        },
+        Allocator::GraphColoring => {
+            let mut graph = HashMap::new();
+            for var in vars {
+                graph.insert(var.clone(), HashSet::new());
+            }
+            build_interference(instrs, &live_sets, &HashSet::new(), &mut graph);
+            if config.opt_level < OptLevel::O2 || !config.coalesce_moves {
+                color_graph(&graph, &across_calls, regs)
+            } else {
+                let mut moves = vec![];
+                move_pairs(instrs, &mut moves);
+                let merged = coalesce_moves(&mut graph, &moves, &mut across_calls, regs);
+                let mut mapping = color_graph(&graph, &across_calls, regs);
+                for (v, into) in merged {
+                    if let Some(reg) = mapping.get(&into).cloned() {
+                        mapping.insert(v, reg);
+                    }
+                }
+                mapping
+            }
+        },
+    }
+}
+
+// The registers besides REGS, if any, that the variables of a
+// function can have: rax and rdx, when nothing else in it uses them.
+// That rules out calls and allocation, which clobber them, and
+// anything patch_instructions would rewrite with SCRATCH_REG. rax can
+// still end the function, as the return value.
+fn spare_regs(instrs: &Vec<X86>) -> Vec<Reg> {
+    let mut used = HashSet::new();
+    match instrs.split_last() {
+        // nothing is live after the return value is moved to rax
+        Some((&X86::Mov(X86Arg::Reg(Reg::RAX), ref ret), init)) => {
+            for instr in init {
+                regs_used(instr, &mut used);
+            }
+            arg_regs_used(ret, &mut used);
+        },
+        _ => for instr in instrs {
+            regs_used(instr, &mut used);
+        },
+    }
+    SPARE_REGS.iter().filter(|r| !used.contains(*r)).cloned().collect()
+}
+
+fn arg_regs_used(arg: &X86Arg, used: &mut HashSet<Reg>) {
+    match arg {
+        &X86Arg::Reg(Reg::AL) => {
+            used.insert(Reg::RAX);
+        },
+        &X86Arg::Reg(ref r) | &X86Arg::RegOffset(ref r, _) => {
+            used.insert(r.clone());
+        },
+        &X86Arg::RegIndex(ref base, ref index, _, _) => {
+            used.insert(base.clone());
+            used.insert(index.clone());
+        },
+        _ => (),
+    }
+}
+
+// Add the registers `instr` uses, besides the homes of its variables,
+// to `used`.
+fn regs_used(instr: &X86, used: &mut HashSet<Reg>) {
+    match instr {
+        &X86::IfWithLives(ref cnd, ref thn, _, ref els, _) => {
+            regs_used(cnd, used);
+            for i in thn.iter().chain(els.iter()) {
+                regs_used(i, used);
+            }
+        },
+        &X86::WhileWithLives(ref test, _, ref cnd, ref body, _) => {
+            regs_used(cnd, used);
+            for i in test.iter().chain(body.iter()) {
+                regs_used(i, used);
+            }
+        },
+        &X86::EqP(ref left, ref right) | &X86::CmpP(_, ref left, ref right) =>
+            regs_used(&X86::Cmp(left.clone(), right.clone()), used),
+        &X86::Call(_) | &X86::TailJmp(_) | &X86::Collect(_, _) |
+        &X86::MovSd(_, _) | &X86::AddSd(_, _) | &X86::SubSd(_, _) |
+        &X86::XorPd(_, _) | &X86::CvtSi2Sd(_, _) | &X86::Cqo | &X86::IDiv(_) => {
+            used.extend(SPARE_REGS.iter().cloned());
+        },
+        &X86::Mov(ref a, ref b) | &X86::Add(ref a, ref b) | &X86::Sub(ref a, ref b) |
+        &X86::And(ref a, ref b) | &X86::Or(ref a, ref b) | &X86::Xor(ref a, ref b) |
+        &X86::Sar(ref a, ref b) | &X86::Cmp(ref a, ref b) | &X86::MovZx(ref a, ref b) |
+        &X86::CMov(_, ref a, ref b) => {
+            arg_regs_used(a, used);
+            arg_regs_used(b, used);
+        },
+        &X86::Set(ref a, _) | &X86::Neg(ref a) => arg_regs_used(a, used),
+        &X86::Push(ref r) | &X86::Pop(ref r) => {
+            used.insert(r.clone());
+        },
+        _ => (),
+    }
+    // variables get registers, so only what needs patching whatever
+    // their homes are uses the scratch register
+    if patch_single_instr(instr.clone()).len() > 1 {
+        used.insert(SCRATCH_REG);
+    }
+}
+
+// Returns each variable's home, the number of stack slots, and the
+// callee-save registers that were handed out, which the prologue has
+// to save. Stack slots go below the saved registers.
+fn decide_locs(vars: &Vec<String>, instrs: &Vec<X86>,
+               live_sets: Vec<HashSet<String>>, config: &Config)
+               -> (HashMap<String, X86Arg>, i64, Vec<Reg>) {
+    let mut regs = REGS.to_vec();
+    regs.extend(spare_regs(instrs));
+    let mut reg_alloc = allocate_homes(vars, instrs, live_sets.clone(), config, &regs);
+    // a spilled variable may need the scratch register to be patched
+    if regs.len() > REGS.len() && vars.iter().any(|v| !reg_alloc.contains_key(v)) {
+        regs = REGS.to_vec();
+        reg_alloc = allocate_homes(vars, instrs, live_sets, config, &regs);
+    }
+    let used_callee_save : Vec<Reg> = CALLEE_SAVE_REGS.iter()
+        .filter(|r| reg_alloc.values().any(|i| &&regs[*i as usize] == r))
+        .cloned()
+        .collect();
+    let saved_size = used_callee_save.len() as i64;
+
+    let mut locs = HashMap::new();
+    let mut stack_size = 0;
+    for var in vars.clone() {
+        // flatten can list a variable more than once
+        if locs.contains_key(&var) {
+            continue;
+        }
+        locs.insert(
+            var.clone(),
+            match reg_alloc.get(&var) {
+                Some(reg) => X86Arg::Reg(regs[reg.clone() as usize].clone()),
+                None => {
+                    stack_size += 1;
+                    X86Arg::RegOffset(Reg::RBP, (saved_size + stack_size) * -8)
+                },
+            }
+        );
+    };
+
+    return (locs, stack_size, used_callee_save);
+}
+
+// `homes` gets the home of every variable of each function, and of
+// main, which comes last.
+fn assign_homes(prog: X86, config: &Config, homes: &mut Vec<(String, HashMap<String, X86Arg>)>)
+                -> X86 {
+    match prog {
+        X86::DefineWithLives(name, vars, live_sets, instrs) => {
+            let (locs, stack_size, saved) =
+                decide_locs(&vars, &instrs, live_sets.clone(), config);
+            homes.push((name.clone(), locs.clone()));
+            return X86::DefineWithStackSize(name, stack_size, saved,
+                                            assign_homes_to_instrs(instrs, live_sets,
+                                                                   &HashSet::new(), locs));
+        },
+
+        X86::ProgWithLives(defs, instrs, vars, live_sets) => {
+            let (locs, stack_size, saved) =
+                decide_locs(&vars, &instrs, live_sets.clone(), config);
+            let mut new_defs = vec![];
+            for def in defs {
+                new_defs.push(assign_homes(def, config, homes));
+            }
+            homes.push(("main".to_string(), locs.clone()));
+
+            return X86::ProgWithStackSize(new_defs,
+                                          assign_homes_to_instrs(instrs, live_sets,
+                                                                 &HashSet::new(), locs),
+                                          stack_size, saved);
+        },
+        _ => panic!("assign_homes: not top level prog"),
+    }
+}
+
+// The condition code that holds exactly when `cc` doesn't.
+fn negate_cc(cc: CC) -> CC {
+    match cc {
+        CC::E => CC::NE,
+        CC::NE => CC::E,
+        CC::L => CC::GE,
+        CC::GE => CC::L,
+        CC::G => CC::LE,
+        CC::LE => CC::G,
+        CC::O => panic!("no condition code for not overflowing"),
+    }
+}
+
+// A register, a stack slot or an immediate, which cmov can read
+// whichever branch is taken.
+fn is_cmov_source(arg: &X86Arg) -> bool {
+    match arg {
+        &X86Arg::Reg(_) | &X86Arg::Imm(_) | &X86Arg::RegOffset(Reg::RBP, _) => true,
+        _ => false,
+    }
+}
+
+// An `if` whose branches each move a simple value into the same place
+// as a cmov, eg. `(if (< x 0) 0 x)`:
+//   cmp x, 0
+//   mov dest, x
+//   cmovl dest, 0
+// The compare comes first, as mov leaves the flags alone, so that dest
+// may be one of its operands. None for any other shape.
+fn lower_if_to_cmov(cc: &CC, left: &X86Arg, right: &X86Arg, thn: &[X86], els: &[X86])
+                    -> Option<Vec<X86>> {
+    let (dest, thn_src, els_src) = match (thn, els) {
+        (&[X86::Mov(ref dest, ref thn_src)], &[X86::Mov(ref els_dest, ref els_src)])
+            if dest == els_dest => (dest, thn_src, els_src),
+        _ => return None,
+    };
+    match dest {
+        &X86Arg::Reg(_) | &X86Arg::RegOffset(Reg::RBP, _) => (),
+        _ => return None,
+    }
+    if !is_cmov_source(thn_src) || !is_cmov_source(els_src) {
+        return None;
+    }
+
+    let mut instrs = vec![X86::Cmp(left.clone(), right.clone())];
+    if dest == els_src {
+        instrs.push(X86::CMov(cc.clone(), dest.clone(), thn_src.clone()));
+    } else if dest == thn_src {
+        instrs.push(X86::CMov(negate_cc(cc.clone()), dest.clone(), els_src.clone()));
+    } else {
+        instrs.push(X86::Mov(dest.clone(), els_src.clone()));
+        instrs.push(X86::CMov(cc.clone(), dest.clone(), thn_src.clone()));
+    }
+    return Some(instrs);
+}
+
+fn lower_if (instr: X86, config: &Config) -> Vec<X86> {
+    match instr {
+        X86::If(cnd, thn, els) => {
+            let (cc, cmp_left, cmp_right) = match *cnd {
+                X86::EqP(left, right) => (CC::E, left, right),
+                X86::CmpP(cc, left, right) => (cc, left, right),
+                _ => panic!("if cond is always EqP or CmpP"),
+            };
+            let thn_label = get_unique_label("then");
+            let end_label = get_unique_label("endif");
+
+            let mut new_elss = vec![];
+            for i in els {
+                new_elss.extend_from_slice(&lower_if(i, config));
+            }
+            let mut new_thns = vec![];
+            for i in thn {
+                new_thns.extend_from_slice(&lower_if(i, config));
+            }
+
+            if config.target_cpu == TargetCpu::Modern {
+                if let Some(instrs) = lower_if_to_cmov(&cc, &cmp_left, &cmp_right,
+                                                       &new_thns, &new_elss) {
+                    return instrs;
+                }
+            }
+
+            // With an empty branch, jump over the other one on the
+            // condition that skips it, without a jmp between them.
+            if new_elss.is_empty() || new_thns.is_empty() {
+                let (skip_cc, mut body) = if new_elss.is_empty() {
+                    (negate_cc(cc), new_thns)
+                } else {
+                    (cc, new_elss)
+                };
+                let mut if_instrs = vec![
+                    X86::Cmp(cmp_left, cmp_right),
+                    X86::JmpIf(skip_cc, end_label.clone()),
+                ];
+                if_instrs.append(&mut body);
+                if_instrs.push(X86::Label(end_label));
+                return if_instrs;
+            }
+
+            let mut if_instrs = vec![
+                X86::Cmp(cmp_left, cmp_right),
+                X86::JmpIf(cc, thn_label.clone()),
+            ];
+            if_instrs.append(&mut new_elss);
+            if_instrs.extend_from_slice(&[
+                X86::Jmp(end_label.clone()),
+                X86::Label(thn_label),
+            ]);
+            if_instrs.append(&mut new_thns);
+            if_instrs.extend_from_slice(&[
+                X86::Label(end_label),
+            ]);
+
+            return if_instrs;
+        },
+        X86::While(test, cnd, body) => {
+            let (eqp_left, eqp_right) = match *cnd {
+                X86::EqP(left, right) => (left, right),
+                _ => panic!("while cond is always EqP"),
+            };
+            let top_label = get_unique_label("loop");
+            let end_label = get_unique_label("endloop");
+
+            let mut loop_instrs = vec![X86::Label(top_label.clone())];
+            for i in test {
+                loop_instrs.extend_from_slice(&lower_if(i, config));
+            }
+            loop_instrs.extend_from_slice(&[
+                X86::Cmp(eqp_left, eqp_right),
+                X86::JmpIf(CC::E, end_label.clone()),
+            ]);
+            for i in body {
+                loop_instrs.extend_from_slice(&lower_if(i, config));
+            }
+            loop_instrs.extend_from_slice(&[
+                X86::Jmp(top_label),
+                X86::Label(end_label),
+            ]);
+
+            return loop_instrs;
+        },
+        _ => vec![instr],
+    }
+}
+
+fn lower_conditionals(prog: X86, config: &Config) -> X86 {
+    match prog {
+        X86::DefineWithStackSize(name, stack_size, saved, mut instrs) => {
+            instrs = instrs.iter().flat_map(|i| lower_if(i.clone(), config)).collect();
+
+            return X86::DefineWithStackSize(name, stack_size, saved, instrs);
+        },
+        X86::ProgWithStackSize(mut defs, mut instrs, stack_size, saved) => {
+            instrs = instrs.iter().flat_map(|i| lower_if(i.clone(), config)).collect();
+            defs = defs.iter().map(|d| lower_conditionals(d.clone(), config)).collect();
+
+            return X86::ProgWithStackSize(defs, instrs, stack_size, saved);
+        }
+        _ => panic!("lower_conditionals: not top-level Prog"),
+    }
+}
+
+// Collapse runs of consecutive labels into the first of them, and drop
+// `jmp L` when `L:` is the very next instruction. Repeats until
+// nothing changes, since dropping a jump can make two labels adjacent.
+// Runs after remove_self_moves, which can leave labels adjacent.
+fn remove_jumps_to_next(mut instrs: Vec<X86>) -> Vec<X86> {
+    loop {
+        let mut renames : HashMap<String, String> = HashMap::new();
+        let mut collapsed : Vec<X86> = vec![];
+        for instr in instrs.iter() {
+            match (instr, collapsed.last()) {
+                (&X86::Label(ref l), Some(&X86::Label(ref first))) => {
+                    renames.insert(l.clone(), first.clone());
+                    continue;
+                },
+                _ => (),
+            }
+            collapsed.push(instr.clone());
+        }
+
+        let rename = |l: String| renames.get(&l).cloned().unwrap_or(l);
+        let collapsed : Vec<X86> = collapsed.into_iter().map(|i| match i {
+            X86::Jmp(l) => X86::Jmp(rename(l)),
+            X86::JmpIf(cc, l) => X86::JmpIf(cc, rename(l)),
+            _ => i,
+        }).collect();
+
+        let mut new_instrs = vec![];
+        for (i, instr) in collapsed.iter().enumerate() {
+            match (instr, collapsed.get(i + 1)) {
+                (&X86::Jmp(ref l), Some(&X86::Label(ref next))) if l == next => (),
+                _ => new_instrs.push(instr.clone()),
+            }
+        }
+
+        if new_instrs.len() == instrs.len() {
+            return new_instrs;
+        }
+        instrs = new_instrs;
+    }
+}
+
+fn is_memory(arg: &X86Arg) -> bool {
+    match arg {
+        &X86Arg::RegOffset(_, _) | &X86Arg::RegIndex(_, _, _, _) |
+        &X86Arg::GlobalVal(_) => true,
+        _ => false,
+    }
+}
+
+fn patch_single_instr(instr: X86) -> Vec<X86> {
+    match instr {
+        // both source and dest are indirect addresses
+        X86::Mov(X86Arg::RegOffset(dest_reg, dest),
+                 X86Arg::RegOffset(src_reg, src)) => {
+            vec![X86::Mov(X86Arg::Reg(SCRATCH_REG),
+                          X86Arg::RegOffset(src_reg, src)),
+                 X86::Mov(X86Arg::RegOffset(dest_reg, dest),
+                          X86Arg::Reg(SCRATCH_REG))]
+        },
+        // the scratch register may be the index, which is read
+        // before it's overwritten
+        X86::Mov(dest @ X86Arg::RegOffset(_, _), src @ X86Arg::RegIndex(_, _, _, _)) => {
+            vec![X86::Mov(X86Arg::Reg(SCRATCH_REG), src),
+                 X86::Mov(dest, X86Arg::Reg(SCRATCH_REG))]
+        },
+        // only a register can take a 64-bit immediate
+        X86::Mov(dest @ X86Arg::RegOffset(_, _), X86Arg::Imm(i))
+            if (i as i64) != (i as i32 as i64) => {
+            vec![X86::Mov(X86Arg::Reg(SCRATCH_REG), X86Arg::Imm(i)),
+                 X86::Mov(dest, X86Arg::Reg(SCRATCH_REG))]
+        },
+        // nor the address of a function
+        X86::Mov(dest @ X86Arg::RegOffset(_, _), X86Arg::FuncName(f)) => {
+            vec![X86::Mov(X86Arg::Reg(SCRATCH_REG), X86Arg::FuncName(f)),
+                 X86::Mov(dest, X86Arg::Reg(SCRATCH_REG))]
+        },
+        // a global can only be moved to or from a register
+        X86::Mov(X86Arg::GlobalVal(g), src @ X86Arg::RegOffset(_, _)) |
+        X86::Mov(X86Arg::GlobalVal(g), src @ X86Arg::Imm(_)) => {
+            vec![X86::Mov(X86Arg::Reg(SCRATCH_REG), src),
+                 X86::Mov(X86Arg::GlobalVal(g), X86Arg::Reg(SCRATCH_REG))]
+        },
+        X86::Mov(dest @ X86Arg::RegOffset(_, _), X86Arg::GlobalVal(g)) => {
+            vec![X86::Mov(X86Arg::Reg(SCRATCH_REG), X86Arg::GlobalVal(g)),
+                 X86::Mov(dest, X86Arg::Reg(SCRATCH_REG))]
+        },
+        X86::MovZx(X86Arg::RegOffset(dest_reg, offset),
+                   src) => {
+            vec![X86::MovZx(X86Arg::Reg(SCRATCH_REG), src),
+                 X86::Mov(X86Arg::RegOffset(dest_reg, offset),
+                          X86Arg::Reg(SCRATCH_REG))]
+        },
+        // both source and dest are indirect addresses
+        X86::Add(X86Arg::RegOffset(dest_reg, dest),
+                 X86Arg::RegOffset(src_reg, src)) => {
+            vec![X86::Mov(X86Arg::Reg(SCRATCH_REG),
+                          X86Arg::RegOffset(dest_reg.clone(), dest)),
+                 X86::Add(X86Arg::Reg(SCRATCH_REG),
+                          X86Arg::RegOffset(src_reg, src)),
+                 X86::Mov(X86Arg::RegOffset(dest_reg, dest),
+                          X86Arg::Reg(SCRATCH_REG))
+            ]
+        },
+        // add can't take imm64 either. If the destination is the
+        // scratch register, r11 holds the immediate instead.
+        X86::Add(X86Arg::Reg(SCRATCH_REG), X86Arg::Imm(i)) if (i as i64) != (i as i32 as i64) => {
+            vec![X86::Mov(X86Arg::Reg(Reg::R11), X86Arg::Imm(i)),
+                 X86::Add(X86Arg::Reg(SCRATCH_REG), X86Arg::Reg(Reg::R11))]
+        },
+        X86::Add(dest, X86Arg::Imm(i)) if (i as i64) != (i as i32 as i64) => {
+            vec![X86::Mov(X86Arg::Reg(SCRATCH_REG), X86Arg::Imm(i)),
+                 X86::Add(dest, X86Arg::Reg(SCRATCH_REG))]
+        },
+        // rax is the destination of xor, so r11 holds the immediate
+        X86::Xor(dest, X86Arg::Imm(i)) if (i as i64) != (i as i32 as i64) => {
+            vec![X86::Mov(X86Arg::Reg(Reg::R11), X86Arg::Imm(i)),
+                 X86::Xor(dest, X86Arg::Reg(Reg::R11))]
+        },
+        // idiv can't take an immediate, and rax is the dividend
+        X86::IDiv(X86Arg::Imm(i)) => {
+            vec![X86::Mov(X86Arg::Reg(Reg::R11), X86Arg::Imm(i)),
+                 X86::IDiv(X86Arg::Reg(Reg::R11))]
+        },
+        X86::Neg(X86Arg::RegOffset(reg, offset)) => {
+            vec![X86::Mov(X86Arg::Reg(SCRATCH_REG),
+                          X86Arg::RegOffset(reg.clone(), offset)),
+                 X86::Neg(X86Arg::Reg(SCRATCH_REG)),
+                 X86::Mov(X86Arg::RegOffset(reg, offset),
+                          X86Arg::Reg(SCRATCH_REG))]
+        },
+        // cmp can't take an immediate as its first operand. The
+        // second one may still need patching, eg. if it's an imm64.
+        X86::Cmp(X86Arg::Imm(i), right) => {
+            let mut instrs = vec![X86::Mov(X86Arg::Reg(SCRATCH_REG), X86Arg::Imm(i))];
+            instrs.extend(patch_single_instr(X86::Cmp(X86Arg::Reg(SCRATCH_REG), right)));
+            instrs
+        },
+        // at most one operand can be in memory
+        X86::Cmp(ref left, ref right) if is_memory(left) && is_memory(right) => {
+            vec![X86::Mov(X86Arg::Reg(SCRATCH_REG), left.clone()),
+                 X86::Cmp(X86Arg::Reg(SCRATCH_REG), right.clone())]
+        },
+        // cmp can't take imm64. The first operand may be rax, so
+        // r11 holds the immediate.
+        X86::Cmp(left, X86Arg::Imm(i)) if (i as i64) != (i as i32 as i64) => {
+            vec![X86::Mov(X86Arg::Reg(Reg::R11), X86Arg::Imm(i)),
+                 X86::Cmp(left, X86Arg::Reg(Reg::R11))]
+        },
+        // cmov can't take an immediate source, and its destination
+        // must be a register
+        X86::CMov(cc, X86Arg::Reg(dest), X86Arg::Imm(i)) => {
+            vec![X86::Mov(X86Arg::Reg(Reg::R11), X86Arg::Imm(i)),
+                 X86::CMov(cc, X86Arg::Reg(dest), X86Arg::Reg(Reg::R11))]
+        },
+        X86::CMov(cc, X86Arg::RegOffset(dest_reg, offset), src) => {
+            let mut instrs = vec![
+                X86::Mov(X86Arg::Reg(SCRATCH_REG),
+                         X86Arg::RegOffset(dest_reg.clone(), offset))
+            ];
+            instrs.extend(patch_single_instr(X86::CMov(cc, X86Arg::Reg(SCRATCH_REG), src)));
+            instrs.push(X86::Mov(X86Arg::RegOffset(dest_reg, offset),
+                                 X86Arg::Reg(SCRATCH_REG)));
+            instrs
+        },
+        _ => vec![instr],
+    }
+}
+
+fn patch_instructions(prog: X86) -> X86 {
+    match prog {
+        X86::DefineWithStackSize(name, stack_size, saved, instrs) => {
+            let patched_instrs =
+                instrs.iter().flat_map(|i| patch_single_instr(i.clone())).collect();
+
+            return X86::DefineWithStackSize(name,
+                                            stack_size,
+                                            saved,
+                                            patched_instrs);
+        },
+        X86::ProgWithStackSize(mut defs, instrs, stack_size, saved) => {
+            let patched_instrs =
+                instrs.iter().flat_map(|i| patch_single_instr(i.clone())).collect();
+
+            defs = defs.iter().map(|d| patch_instructions(d.clone())).collect();
+
+            return X86::ProgWithStackSize(defs, patched_instrs, stack_size, saved);
+        },
+        _ => panic!("patch_instructions: not top-level Prog"),
+    }
+}
+
+
+// Apply `f` to the instructions of main and of every define.
+fn map_prog_instrs<F>(prog: X86, f: &F) -> X86
+    where F: Fn(Vec<X86>) -> Vec<X86> {
+    match prog {
+        X86::DefineWithStackSize(name, stack_size, saved, instrs) => {
+            return X86::DefineWithStackSize(name, stack_size, saved, f(instrs));
+        },
+        X86::ProgWithStackSize(defs, instrs, stack_size, saved) => {
+            let defs = defs.into_iter().map(|d| map_prog_instrs(d, f)).collect();
+            return X86::ProgWithStackSize(defs, f(instrs), stack_size, saved);
+        },
+        _ => panic!("map_prog_instrs: not top-level Prog"),
+    }
+}
+
+// Peephole pass: drop `mov a, a`, which assign_homes leaves behind
+// when the source and destination of a move get the same home.
+fn remove_self_moves(prog: X86) -> X86 {
+    map_prog_instrs(prog, &|instrs: Vec<X86>| {
+        instrs.into_iter().filter(|i| match i {
+            &X86::Mov(ref dest, ref src) => dest != src,
+            _ => true,
+        }).collect()
+    })
+}
+
+fn display_reg(reg: &Reg) -> String {
+    match reg {
+        &Reg::AL => "al",
+        &Reg::RAX => "rax",
+        &Reg::RBX => "rbx",
+        &Reg::RBP => "rbp",
+        &Reg::RSP => "rsp",
+        &Reg::RDX => "rdx",
+        &Reg::RCX => "rcx",
+        &Reg::RDI => "rdi",
+        &Reg::RSI => "rsi",
+        &Reg::R8 => "r8",
+        &Reg::R9 => "r9",
+        &Reg::R10 => "r10",
+        &Reg::R11 => "r11",
+        &Reg::R12 => "r12",
+        &Reg::R13 => "r13",
+        &Reg::R14 => "r14",
+        &Reg::R15 => "r15",
+    }.to_string()
+}
+
+fn print_x86_arg(arg: X86Arg, config: &Config) -> String {
+    if config.syntax == Syntax::Att {
+        return match arg {
+            X86Arg::Reg(r) => format!("%{}", display_reg(&r)),
+            X86Arg::Imm(n) => format!("${}", n as i64),
+            X86Arg::RegOffset(r, offset) => format!("{}(%{})", offset, display_reg(&r)),
+            X86Arg::RegIndex(base, index, scale, offset) =>
+                format!("{}(%{},%{},{})", offset, display_reg(&base), display_reg(&index), scale),
+            X86Arg::FuncName(f) => format!("${}", print_symbol(&f, config)),
+            X86Arg::GlobalVal(g) => format!("{}(%rip)", print_symbol(&g, config)),
+            X86Arg::XmmReg(n) => format!("%xmm{}", n),
+            _ => panic!("invalid arg type: {:?}", arg),
+        };
+    }
+
+    match arg {
+        X86Arg::Reg(r) => format!("{}", display_reg(&r)),
+        X86Arg::Imm(n) => format!("{}", n),
+        X86Arg::RegOffset(r, offset) => {
+            if offset < 0 {
+                format!("QWORD [{}{}]",
+                        display_reg(&r),
+                        offset)
+            }
+            else {
+                format!("QWORD [{}+{}]",
+                        display_reg(&r),
+                        offset)
+            }
+        },
+        X86Arg::RegIndex(base, index, scale, offset) =>
+            format!("QWORD [{}+{}*{}+{}]", display_reg(&base), display_reg(&index), scale, offset),
+        X86Arg::FuncName(f) => print_symbol(&f, config),
+        X86Arg::GlobalVal(g) => format!("QWORD [rel {}]", print_symbol(&g, config)),
+        X86Arg::XmmReg(n) => format!("xmm{}", n),
+        _ => panic!("invalid arg type: {:?}", arg),
+    }
+}
+
+fn print_cc(cc: CC) -> String {
+    match cc {
+        CC::E => "e",
+        CC::NE => "ne",
+        CC::L => "l",
+        CC::LE => "le",
+        CC::G => "g",
+        CC::GE => "ge",
+        CC::O => "o",
+    }.to_string()
+}
+
+// GAS only takes letters, digits, `_`, `.` and `$` in a bare symbol,
+// other names, like `even?`, have to be quoted.
+fn print_label(label: &str, config: &Config) -> String {
+    let bare = label.chars().all(|c| c.is_digit(36) || "_.$".contains(c));
+    if config.syntax == Syntax::Att && !bare {
+        return format!("\"{}\"", label);
+    }
+    return label.to_string();
+}
+
+// The name of a function or of global data as the linker knows it.
+fn print_symbol(name: &str, config: &Config) -> String {
+    match config.target {
+        Target::Linux => print_label(name, config),
+        Target::MacOs => print_label(&format!("_{}", name), config),
+    }
+}
+
+// An instruction with a destination and a source operand. `att` is
+// the AT&T mnemonic, which says the operand size where the operands
+// may not.
+fn print_op2(intel: &str, att: &str, dest: X86Arg, src: X86Arg, config: &Config) -> String {
+    match config.syntax {
+        Syntax::Intel => format!("{} {}, {}", intel,
+                                 print_x86_arg(dest, config),
+                                 print_x86_arg(src, config)),
+        Syntax::Att => format!("{} {}, {}", att,
+                               print_x86_arg(src, config),
+                               print_x86_arg(dest, config)),
+    }
+}
+
+// The operand of a `call` or `jmp`, which is a label or, in AT&T
+// syntax, `*` and where to find the address.
+fn print_target(target: X86Arg, config: &Config) -> String {
+    match (target, &config.syntax) {
+        (X86Arg::FuncName(ref f), &Syntax::Intel)
+            if config.pie && config.target == Target::Linux && EXTERNS.contains(&&f[..]) =>
+            format!("{} wrt ..plt", print_symbol(f, config)),
+        (X86Arg::FuncName(ref f), &Syntax::Att)
+            if config.pie && config.target == Target::Linux && EXTERNS.contains(&&f[..]) =>
+            format!("{}@PLT", print_symbol(f, config)),
+        (X86Arg::FuncName(f), _) => print_symbol(&f, config),
+        (target, &Syntax::Intel) => print_x86_arg(target, config),
+        (target, &Syntax::Att) => format!("*{}", print_x86_arg(target, config)),
+    }
+}
+
+fn print_instr(instr: X86, config: &Config) -> String {
+    let instr_string = match instr.clone() {
+        // a position-independent executable finds the address of a
+        // function relative to rip
+        X86::Mov(dest, X86Arg::FuncName(f)) if config.pie => match config.syntax {
+            Syntax::Intel => format!("lea {}, [rel {}]", print_x86_arg(dest, config),
+                                     print_symbol(&f, config)),
+            Syntax::Att => format!("leaq {}(%rip), {}", print_symbol(&f, config),
+                                   print_x86_arg(dest, config)),
+        },
+        // otherwise it's a 64-bit immediate
+        X86::Mov(dest, X86Arg::FuncName(f)) =>
+            print_op2("mov", "movabsq", dest, X86Arg::FuncName(f), config),
+        X86::Mov(dest, src) => print_op2("mov", "movq", dest, src, config),
+        X86::Add(dest, src) => print_op2("add", "addq", dest, src, config),
+        X86::Sub(dest, src) => print_op2("sub", "subq", dest, src, config),
+        X86::And(dest, src) => print_op2("and", "andq", dest, src, config),
+        X86::Or(dest, src) => print_op2("or", "orq", dest, src, config),
+        X86::Xor(dest, src) => print_op2("xor", "xorq", dest, src, config),
+        X86::Sar(dest, src) => print_op2("sar", "sarq", dest, src, config),
+        X86::MovSd(dest, src) => print_op2("movsd", "movsd", dest, src, config),
+        X86::AddSd(dest, src) => print_op2("addsd", "addsd", dest, src, config),
+        X86::SubSd(dest, src) => print_op2("subsd", "subsd", dest, src, config),
+        X86::XorPd(dest, src) => print_op2("xorpd", "xorpd", dest, src, config),
+        X86::CvtSi2Sd(dest, src) => print_op2("cvtsi2sd", "cvtsi2sdq", dest, src, config),
+        X86::Cmp(left, right) => print_op2("cmp", "cmpq", left, right, config),
+        X86::JmpIf(cc, label) => format!("j{} {}",
+                                         print_cc(cc),
+                                         print_label(&label, config)),
+        X86::Jmp(label) => format!("jmp {}", print_label(&label, config)),
+        X86::CMov(cc, dest, src) => {
+            let cmov = format!("cmov{}", print_cc(cc));
+            print_op2(&cmov, &cmov, dest, src, config)
+        },
+        X86::Label(label) => format!("{}:", print_label(&label, config)),
+        X86::Comment(text) => match config.syntax {
+            Syntax::Intel => format!("; {}", text),
+            Syntax::Att => format!("# {}", text),
+        },
+        X86::Call(label) => format!("call {}", print_target(label, config)),
+        X86::TailJmp(f) => format!("jmp {}", print_target(f, config)),
+        X86::Set(X86Arg::Reg(r), cc) =>
+            format!("set{} {}", print_cc(cc), print_x86_arg(X86Arg::Reg(r), config)),
+        X86::MovZx(dest, src) => print_op2("movzx", "movzbq", dest, src, config),
+        X86::Neg(n) => match config.syntax {
+            Syntax::Intel => format!("neg {}", print_x86_arg(n, config)),
+            Syntax::Att => format!("negq {}", print_x86_arg(n, config)),
+        },
+        X86::Cqo => match config.syntax {
+            Syntax::Intel => "cqo".to_string(),
+            Syntax::Att => "cqto".to_string(),
+        },
+        X86::IDiv(n) => match config.syntax {
+            Syntax::Intel => format!("idiv {}", print_x86_arg(n, config)),
+            Syntax::Att => format!("idivq {}", print_x86_arg(n, config)),
+        },
+        X86::Push(r) => match config.syntax {
+            Syntax::Intel => format!("push {}", display_reg(&r)),
+            Syntax::Att => format!("pushq %{}", display_reg(&r)),
+        },
+        X86::Pop(r) => match config.syntax {
+            Syntax::Intel => format!("pop {}", display_reg(&r)),
+            Syntax::Att => format!("popq %{}", display_reg(&r)),
+        },
+        _ => panic!("invalid op: {:?}", instr),
+    };
+
+    match instr {
+        X86::Label(_) => return format!("{}\n", instr_string),
+        _ => return format!("    {}\n", instr_string),
+    }
+}
+
+// The instructions that set up a function's frame, and that tear it
+// down before it returns. The callee-save registers in `saved` are
+// pushed after rbp, and the frame is padded so that rsp stays 16-byte
+// aligned at calls.
+fn print_frame(stack_size: i64, saved: &Vec<Reg>, config: &Config) -> (String, String) {
+    let padding = (saved.len() as i64 + stack_size) % 2;
+    let stack_size = X86Arg::Imm((8 * (stack_size + padding)) as u64);
+    let rsp = X86Arg::Reg(Reg::RSP);
+    let rbp = X86Arg::Reg(Reg::RBP);
+
+    let mut enter = print_instr(X86::Push(Reg::RBP), config);
+    enter.push_str(&print_instr(X86::Mov(rbp.clone(), rsp.clone()), config));
+    for r in saved.iter() {
+        enter.push_str(&print_instr(X86::Push(r.clone()), config));
+    }
+    enter.push_str("\n");
+    enter.push_str(&print_instr(X86::Sub(rsp.clone(), stack_size.clone()), config));
+
+    let mut leave = print_instr(X86::Add(rsp.clone(), stack_size), config);
+    for r in saved.iter().rev() {
+        leave.push_str(&print_instr(X86::Pop(r.clone()), config));
+    }
+    leave.push_str("\n");
+    leave.push_str(&print_instr(X86::Mov(rsp, rbp), config));
+    leave.push_str(&print_instr(X86::Pop(Reg::RBP), config));
+
+    return (enter, leave);
+}
+
+// The runtime functions the generated code calls, or whose data it
+// uses. GAS takes undefined symbols to be external, NASM needs to be
+// told.
+const EXTERNS : [&'static str; 17] = [
+    "print", "initialize", "heap", "heap_end", "rootstack", "free_ptr",
+    "make_hash", "hash_set", "hash_ref", "rusl_error", "deep_equal",
+    "overflow_error", "collect", "assert_failed", MAKE_VECTOR, "exit",
+    "rusl_compare",
+];
+
+// Runtime functions that are only declared for a program that calls
+// them, so that it doesn't refer to ones it doesn't use.
+const OPTIONAL_EXTERNS : [&'static str; 2] = ["print_hex", "print_binary"];
+
+// Whether `instrs` call the function `f`.
+fn calls_function(instrs: &[X86], f: &str) -> bool {
+    instrs.iter().any(|i| match i {
+        &X86::Call(X86Arg::FuncName(ref name)) => name == f,
+        _ => false,
+    })
+}
+
+fn print_x86(prog: X86, config: &Config) -> String {
+    let rax = X86Arg::Reg(Reg::RAX);
+    let rdi = X86Arg::Reg(Reg::RDI);
+    let instrs_str = match prog {
+        X86::DefineWithStackSize(name, stack_size, saved, instrs) => {
+            let (enter, leave) = print_frame(stack_size, &saved, config);
+            let mut instrs_str = format!("{}:\n{}", print_symbol(&name, config), enter);
+            for i in instrs {
+                match i {
+                    X86::TailJmp(_) => {
+                        instrs_str.push_str(&leave);
+                        instrs_str.push_str(&print_instr(i, config));
+                    },
+                    i => instrs_str.push_str(&print_instr(i, config)),
+                }
+            }
+
+            instrs_str.push_str(&print_instr(X86::Mov(rdi, rax), config));
+            instrs_str.push_str(&leave);
+            instrs_str.push_str("    ret\n");
+            instrs_str
+        },
+        X86::ProgWithStackSize(defs, instrs, stack_size, saved) => {
+            let (enter, leave) = print_frame(stack_size, &saved, config);
+            let optional : Vec<&str> = OPTIONAL_EXTERNS.iter().cloned().filter(|f| {
+                calls_function(&instrs, f) || defs.iter().any(|def| match def {
+                    &X86::DefineWithStackSize(_, _, _, ref body) => calls_function(body, f),
+                    _ => false,
+                })
+            }).collect();
+            let mut defs_str = String::new();
+            for def in defs {
+                defs_str.push_str(&print_x86(def, config)[..]);
+            }
+            let main = print_symbol("main", config);
+            // Linux makes the stack executable for objects without
+            // the note
+            let mut instrs_str = match (&config.syntax, &config.target) {
+                (&Syntax::Intel, &Target::Linux) =>
+                    String::from("section .note.GNU-stack noalloc noexec nowrite progbits\n"),
+                (&Syntax::Att, &Target::Linux) =>
+                    String::from(".section .note.GNU-stack,\"\",@progbits\n"),
+                (_, &Target::MacOs) => String::new(),
+            };
+            instrs_str.push_str(&match config.syntax {
+                Syntax::Intel => {
+                    let mut header = String::from("section .text\n");
+                    for f in EXTERNS.iter().chain(optional.iter()) {
+                        header.push_str(&format!("extern {}\n", print_symbol(f, config)));
+                    }
+                    header.push_str(&format!("global {}\n", main));
+                    header
+                },
+                Syntax::Att => format!(".text\n.globl {}\n", main),
+            });
+            instrs_str.push_str(&format!("{}:\n", main));
+            instrs_str.push_str(&enter);
+            let init = vec![
+                X86::Mov(X86Arg::Reg(Reg::RDI), X86Arg::Imm(config.heap_size)),
+                X86::Mov(X86Arg::Reg(Reg::RSI), X86Arg::FuncName(DYNAMICS.to_string())),
+                X86::Mov(X86Arg::Reg(Reg::RDX), X86Arg::FuncName(SYMBOLS.to_string())),
+                X86::Call(X86Arg::FuncName("initialize".to_string())),
+                X86::Mov(X86Arg::Reg(Reg::R15), X86Arg::GlobalVal("rootstack".to_string())),
+            ];
+            for i in init {
+                instrs_str.push_str(&print_instr(i, config));
+            }
+            for i in instrs {
+                instrs_str.push_str(&print_instr(i, config));
+            }
+            instrs_str.push_str(&print_instr(X86::Mov(rdi, rax), config));
+            instrs_str.push_str(&print_instr(X86::Call(X86Arg::FuncName("print".to_string())),
+                                             config));
+            // the exit status; errors exit with 1 before getting here
+            instrs_str.push_str(&print_instr(X86::Mov(X86Arg::Reg(Reg::RAX), X86Arg::Imm(0)),
+                                             config));
+            instrs_str.push_str(&leave);
+            instrs_str.push_str("    ret\n");
+            // called rather than jumped to, so the stack is aligned
+            // as the C function expects
+            instrs_str.push_str(&print_instr(X86::Label(OVERFLOW_TRAP.to_string()), config));
+            instrs_str.push_str(&print_instr(X86::Call(X86Arg::FuncName("overflow_error".to_string())),
+                                             config));
+            instrs_str.push_str(&defs_str[..]);
+            instrs_str
+        },
+        _ => panic!("print_x86: not top-level Prog"),
+    };
+
+    return instrs_str;
+}
+
+// The optional passes over the flattened program, in the order they
+// run:
+//   -O0  none
+//   -O1  const_fold, eliminate_dead_code (or eliminate_dead_code_checked
+//        with checked_arith)
+//   -O2  eliminate_common_subexprs before those, and reuse_temps and
+//        mark_tail_calls after
+fn flat_passes(config: &Config) -> Vec<fn(FlatResult) -> FlatResult> {
+    let level = &config.opt_level;
+    let mut passes : Vec<fn(FlatResult) -> FlatResult> = vec![];
+    if *level >= OptLevel::O2 {
+        passes.push(eliminate_common_subexprs);
+    }
+    if *level >= OptLevel::O1 {
+        passes.push(const_fold);
+        if config.checked_arith {
+            passes.push(eliminate_dead_code_checked);
+        } else {
+            passes.push(eliminate_dead_code);
+        }
+    }
+    if *level >= OptLevel::O2 {
+        passes.push(reuse_temps);
+        passes.push(mark_tail_calls);
+    }
+    return passes;
+}
+
+fn jump_threading(prog: X86) -> X86 {
+    map_prog_instrs(prog, &remove_jumps_to_next)
+}
+
+// The optional peephole passes over the patched instructions:
+//   -O0  none
+//   -O1  remove_self_moves, remove_jumps_to_next
+//   -O2  the same, and the graph-coloring allocator coalesces moves
+//        (see coalesce_moves) so that there are more self-moves
+fn peephole_passes(level: &OptLevel) -> Vec<fn(X86) -> X86> {
+    let mut passes : Vec<fn(X86) -> X86> = vec![];
+    if *level >= OptLevel::O1 {
+        passes.push(remove_self_moves);
+        passes.push(jump_threading);
+    }
+    return passes;
+}
+
+// Append `form` to `forms`, with top-level `begin`s spliced in.
+fn splice_begins(form: SExpr, forms: &mut Vec<SExpr>) {
+    match form {
+        SExpr::Begin(body) => {
+            for e in body {
+                splice_begins(e, forms);
+            }
+        },
+        _ => forms.push(form),
+    }
+}
+
+// Assemble the top-level forms into a Prog. Function definitions
+// become the Prog's defines, and the remaining forms, in order, its
+// result: `(define a e)` binds `a` for the forms after it, and the
+// value of the last form is the value of the program. Returns None if
+// there is nothing to evaluate.
+fn toplevel_prog(toplevel: Vec<SExpr>) -> Option<SExpr> {
+    let mut forms = vec![];
+    for form in toplevel {
+        splice_begins(form, &mut forms);
+    }
+
+    let mut defs = vec![];
+    let mut body = vec![];
+    for form in forms {
+        match form {
+            SExpr::Define(_, _, _) => defs.push(form),
+            _ => body.push(form),
+        }
+    }
+
+    let mut main : Option<SExpr> = None;
+    for form in body.into_iter().rev() {
+        main = Some(match (form, main) {
+            (SExpr::DefineVal(name, val), Some(rest)) =>
+                SExpr::Let(vec![(name, *val)], box rest),
+            (SExpr::DefineVal(name, val), None) =>
+                SExpr::Let(vec![(name.clone(), *val)], box SExpr::Symbol(name, NO_SPAN)),
+            (e, Some(SExpr::Begin(mut rest))) => {
+                rest.insert(0, e);
+                SExpr::Begin(rest)
+            },
+            (e, Some(rest)) => SExpr::Begin(vec![e, rest]),
+            (e, None) => e,
+        });
+    }
+
+    // a file of only definitions is a program that does nothing, and
+    // prints nothing, like one whose value is void
+    if main.is_none() && !defs.is_empty() {
+        main = Some(SExpr::Void);
+    }
+    return main.map(|main| SExpr::Prog(defs, box main));
+}
+
+// Give every dynamic variable defined at the top level of `prog` a
+// label for its storage.
+fn dynamic_labels(expr: &SExpr, labels: &mut HashMap<String, String>) {
+    match expr {
+        &SExpr::Prog(_, ref main) => dynamic_labels(main, labels),
+        &SExpr::Let(_, ref body) => dynamic_labels(body, labels),
+        &SExpr::Begin(ref body) => {
+            for e in body {
+                dynamic_labels(e, labels);
+            }
+        },
+        &SExpr::DefineDynamic(ref name, _) => {
+            labels.insert(name.clone(), get_unique_varname("dynamic"));
+        },
+        _ => (),
+    }
+}
+
+// Replace uses of dynamic variables that aren't shadowed by a `let` or
+// an argument with reads of their storage (DynamicRef), and
+// `define-dynamic` with a store (DynamicSet). `parameterize` becomes
+//
+//   (let ((new v-expr) ...)
+//     (let ((saved v) ...)
+//       (begin <store new in v> ...
+//              (let ((result body))
+//                (begin <store saved in v> ... result)))))
+//
+// Nothing can leave `body` early, so restoring after it returns is
+// enough to undo the rebinding. It's an error to parameterize a
+// variable that isn't dynamic.
+fn resolve_dynamics(expr: SExpr, labels: &HashMap<String, String>,
+                    bound: &HashSet<String>) -> Result<SExpr, CompileError> {
+    let resolve = |e: SExpr| resolve_dynamics(e, labels, bound);
+    let resolve_all = |es: Vec<SExpr>| -> Result<Vec<SExpr>, CompileError> {
+        es.into_iter().map(|e| resolve_dynamics(e, labels, bound)).collect()
+    };
+    let label_of = |name: &String| -> Option<String> {
+        if bound.contains(name) {
+            None
+        } else {
+            labels.get(name).cloned()
+        }
+    };
+
+    let resolved = match expr {
+        SExpr::Symbol(ref name, _) if label_of(name).is_some() =>
+            SExpr::DynamicRef(label_of(name).unwrap()),
+        SExpr::Set(name, span, val) => match label_of(&name) {
+            Some(label) => SExpr::DynamicSet(label, box try!(resolve(*val))),
+            None => SExpr::Set(name, span, box try!(resolve(*val))),
+        },
+        // get_toplevel only allows `define-dynamic` where
+        // dynamic_labels finds it
+        SExpr::DefineDynamic(name, init) =>
+            SExpr::DynamicSet(labels.get(&name).unwrap().clone(), box try!(resolve(*init))),
+        SExpr::Parameterize(bindings, body, span) => {
+            let mut new_bindings = vec![];
+            let mut saved_bindings = vec![];
+            let mut set_new = vec![];
+            let mut set_saved = vec![];
+            for (name, val) in bindings {
+                let label = match label_of(&name) {
+                    Some(label) => label,
+                    None => return Err(CompileError::NotDynamic(name, span)),
+                };
+                let new = get_unique_varname("new");
+                let saved = get_unique_varname("saved");
+                new_bindings.push((new.clone(), try!(resolve(val))));
+                saved_bindings.push((saved.clone(), SExpr::DynamicRef(label.clone())));
+                set_new.push(SExpr::DynamicSet(label.clone(), box SExpr::Symbol(new, NO_SPAN)));
+                set_saved.push(SExpr::DynamicSet(label, box SExpr::Symbol(saved, NO_SPAN)));
+            }
+
+            let result = get_unique_varname("result");
+            set_saved.push(SExpr::Symbol(result.clone(), NO_SPAN));
+            set_new.push(SExpr::Let(vec![(result, try!(resolve(*body)))],
+                                    box SExpr::Begin(set_saved)));
+            SExpr::Let(new_bindings,
+                       box SExpr::Let(saved_bindings,
+                                      box SExpr::Begin(set_new)))
+        },
+        SExpr::Let(bindings, body) => {
+            let mut body_bound = bound.clone();
+            let mut new_bindings = vec![];
+            for (k, v) in bindings {
+                body_bound.insert(k.clone());
+                new_bindings.push((k, try!(resolve(v))));
+            }
+            SExpr::Let(new_bindings, box try!(resolve_dynamics(*body, labels, &body_bound)))
+        },
+        SExpr::Lambda(args, body) => {
+            let mut body_bound = bound.clone();
+            body_bound.extend(args.iter().cloned());
+            SExpr::Lambda(args, box try!(resolve_dynamics(*body, labels, &body_bound)))
+        },
+        SExpr::Define(name, args, body) => {
+            let mut body_bound = bound.clone();
+            body_bound.extend(args.iter().cloned());
+            SExpr::Define(name, args, box try!(resolve_dynamics(*body, labels, &body_bound)))
+        },
+        SExpr::DefineVal(name, val) => SExpr::DefineVal(name, box try!(resolve(*val))),
+        SExpr::If(cnd, thn, els) =>
+            SExpr::If(box try!(resolve(*cnd)), box try!(resolve(*thn)), box try!(resolve(*els))),
+        SExpr::While(test, body) =>
+            SExpr::While(box try!(resolve(*test)), box try!(resolve(*body))),
+        SExpr::Cmp(cc, left, right) =>
+            SExpr::Cmp(cc, box try!(resolve(*left)), box try!(resolve(*right))),
+        SExpr::Tuple(elts) => SExpr::Tuple(try!(resolve_all(elts))),
+        SExpr::Begin(body) => SExpr::Begin(try!(resolve_all(body))),
+        SExpr::App(f, args) => SExpr::App(box try!(resolve(*f)), try!(resolve_all(args))),
+        SExpr::DynamicSet(label, val) => SExpr::DynamicSet(label, box try!(resolve(*val))),
+        SExpr::Prog(defs, main) => SExpr::Prog(try!(resolve_all(defs)), box try!(resolve(*main))),
+        _ => expr,
+    };
+    return Ok(resolved);
+}
+
+// Storage for the dynamic variables, after their number at DYNAMICS
+// so that the collector can find them. Emitted even if there are
+// none, since main refers to it.
+fn print_dynamics(labels: &HashMap<String, String>, config: &Config) -> String {
+    let mut labels : Vec<_> = labels.values().collect();
+    labels.sort();
+    let (section, quad) = match config.syntax {
+        Syntax::Intel => ("section .data", "dq"),
+        Syntax::Att => (".data", ".quad"),
+    };
+    let mut data = format!("{}\n{}:\n    {} {}\n",
+                           section, print_symbol(DYNAMICS, config), quad, labels.len());
+    for label in labels {
+        data.push_str(&format!("{}:\n    {} 0\n", print_symbol(label, config), quad));
+    }
+    return data;
+}
+
+// The symbol table, after the dynamic variables in the data section:
+// at SYMBOLS, the number of symbols followed by their names, each a
+// string laid out as the compiled code allocates them, with the
+// string's tag. Symbol i is at index i, as given by intern_symbol.
+// The strings aren't on the heap, so the collector leaves them alone.
+fn print_symbols(symbols: &[String], config: &Config) -> String {
+    let (quad, byte, align) = match config.syntax {
+        Syntax::Intel => ("dq", "db", "align 8"),
+        Syntax::Att => (".quad", ".byte", ".balign 8"),
+    };
+    let label = |i: usize| print_symbol(&format!("{}{}", SYMBOLS, i), config);
+    let mut data = format!("{}:\n    {} {}\n", print_symbol(SYMBOLS, config), quad, symbols.len());
+    for i in 0..symbols.len() {
+        data.push_str(&format!("    {} {}+{}\n", quad, label(i), BOXED_TAG));
+    }
+    for (i, name) in symbols.iter().enumerate() {
+        let bytes : Vec<String> = name.bytes().map(|b| b.to_string()).collect();
+        data.push_str(&format!("{}\n{}:\n    {} {}\n    {} {}\n    {} {}\n",
+                               align, label(i), quad, STRING_KIND, quad, name.len(),
+                               byte, bytes.join(", ")));
+    }
+    return data;
+}
+
+fn pretty_live_set(live: &HashSet<String>) -> String {
+    let mut vars : Vec<&String> = live.iter().collect();
+    vars.sort();
+    let vars : Vec<String> = vars.iter().map(|v| v.to_string()).collect();
+    return format!("{{{}}}", vars.join(", "));
+}
+
+// An operand as pretty_x86 shows it. Before homes are assigned, it may
+// still be a variable, which print_x86_arg can't print.
+fn pretty_arg(arg: &X86Arg) -> String {
+    match arg {
+        &X86Arg::Var(ref v) => v.clone(),
+        _ => print_x86_arg(arg.clone(), &Config::default()),
+    }
+}
+
+// An instruction as pretty_x86 shows it, in Intel syntax. The ones
+// without operands that may be variables are printed as they're
+// assembled.
+fn pretty_instr(instr: &X86) -> String {
+    let op1 = |op: &str, arg: &X86Arg| format!("{} {}", op, pretty_arg(arg));
+    let op2 = |op: &str, dest: &X86Arg, src: &X86Arg| {
+        format!("{} {}, {}", op, pretty_arg(dest), pretty_arg(src))
+    };
+    match instr {
+        &X86::Mov(ref dest, ref src) => op2("mov", dest, src),
+        &X86::Add(ref dest, ref src) => op2("add", dest, src),
+        &X86::Sub(ref dest, ref src) => op2("sub", dest, src),
+        &X86::And(ref dest, ref src) => op2("and", dest, src),
+        &X86::Or(ref dest, ref src) => op2("or", dest, src),
+        &X86::Xor(ref dest, ref src) => op2("xor", dest, src),
+        &X86::Sar(ref dest, ref src) => op2("sar", dest, src),
+        &X86::MovSd(ref dest, ref src) => op2("movsd", dest, src),
+        &X86::AddSd(ref dest, ref src) => op2("addsd", dest, src),
+        &X86::SubSd(ref dest, ref src) => op2("subsd", dest, src),
+        &X86::XorPd(ref dest, ref src) => op2("xorpd", dest, src),
+        &X86::CvtSi2Sd(ref dest, ref src) => op2("cvtsi2sd", dest, src),
+        &X86::MovZx(ref dest, ref src) => op2("movzx", dest, src),
+        &X86::Cmp(ref left, ref right) => op2("cmp", left, right),
+        &X86::CMov(ref cc, ref dest, ref src) =>
+            op2(&format!("cmov{}", print_cc(cc.clone())), dest, src),
+        &X86::Set(ref dest, ref cc) => op1(&format!("set{}", print_cc(cc.clone())), dest),
+        &X86::Neg(ref n) => op1("neg", n),
+        &X86::IDiv(ref n) => op1("idiv", n),
+        &X86::Call(ref f) => op1("call", f),
+        &X86::TailJmp(ref f) => op1("jmp", f),
+        _ => print_instr(instr.clone(), &Config::default()).trim().to_string(),
+    }
+}
+
+fn pretty_cond(cnd: &X86) -> String {
+    match cnd {
+        &X86::EqP(ref left, ref right) =>
+            format!("{} {} {}", pretty_arg(left), cc_name(&CC::E), pretty_arg(right)),
+        &X86::CmpP(ref cc, ref left, ref right) =>
+            format!("{} {} {}", pretty_arg(left), cc_name(cc), pretty_arg(right)),
+        _ => panic!("pretty_cond: not a condition: {:?}", cnd),
+    }
+}
+
+// Append `instrs` to `out`, indented by `depth` levels. With `lives`,
+// each instruction is followed by the variables live before it.
+fn pretty_instrs(instrs: &[X86], lives: Option<&[HashSet<String>]>, depth: usize,
+                 out: &mut String) {
+    let indent = "    ".repeat(depth);
+    let line = |text: String, i: usize, out: &mut String| {
+        match lives {
+            Some(lives) => out.push_str(&format!("{}{:<32} live: {}\n", indent, text,
+                                                 pretty_live_set(&lives[i]))),
+            None => out.push_str(&format!("{}{}\n", indent, text)),
+        }
+    };
+    for (i, instr) in instrs.iter().enumerate() {
+        match instr {
+            &X86::If(ref cnd, ref thn, ref els) => {
+                line(format!("if {}", pretty_cond(cnd)), i, out);
+                out.push_str(&format!("{}then:\n", indent));
+                pretty_instrs(thn, None, depth + 1, out);
+                out.push_str(&format!("{}else:\n", indent));
+                pretty_instrs(els, None, depth + 1, out);
+            },
+            &X86::IfWithLives(ref cnd, ref thn, ref thn_lives, ref els, ref els_lives) => {
+                line(format!("if {}", pretty_cond(cnd)), i, out);
+                out.push_str(&format!("{}then:\n", indent));
+                pretty_instrs(thn, Some(thn_lives), depth + 1, out);
+                out.push_str(&format!("{}else:\n", indent));
+                pretty_instrs(els, Some(els_lives), depth + 1, out);
+            },
+            &X86::While(ref test, ref cnd, ref body) => {
+                line("while:".to_string(), i, out);
+                pretty_instrs(test, None, depth + 1, out);
+                out.push_str(&format!("{}until {}, do:\n", indent, pretty_cond(cnd)));
+                pretty_instrs(body, None, depth + 1, out);
+            },
+            &X86::WhileWithLives(ref test, ref test_lives, ref cnd, ref body, ref body_lives) => {
+                line("while:".to_string(), i, out);
+                pretty_instrs(test, Some(test_lives), depth + 1, out);
+                out.push_str(&format!("{}until {}, do:\n", indent, pretty_cond(cnd)));
+                pretty_instrs(body, Some(body_lives), depth + 1, out);
+            },
+            &X86::Collect(len, ref roots) => {
+                let roots : Vec<String> = roots.iter().map(pretty_arg).collect();
+                line(format!("collect {} bytes, roots: {}", len, roots.join(", ")), i, out);
+            },
+            _ => line(pretty_instr(instr), i, out),
+        }
+    }
+}
+
+// The pseudo-x86 of `prog`, one instruction per line with its
+// branches and loop bodies indented, and the live sets if uncover_live
+// has run. It's for reading, `--emit=px86`, and nasm can't assemble it.
+fn pretty_x86(prog: &X86) -> String {
+    let mut out = String::new();
+    let header = |name: &str, vars: &[String], out: &mut String| {
+        out.push_str(&format!("{}: (vars: {})\n", name, vars.join(", ")));
+    };
+    match prog {
+        &X86::Define(ref name, ref vars, ref instrs) => {
+            header(name, vars, &mut out);
+            pretty_instrs(instrs, None, 1, &mut out);
+        },
+        &X86::DefineWithLives(ref name, ref vars, ref lives, ref instrs) => {
+            header(name, vars, &mut out);
+            pretty_instrs(instrs, Some(lives), 1, &mut out);
+        },
+        &X86::Prog(ref defs, ref instrs, ref vars) => {
+            for def in defs {
+                out.push_str(&pretty_x86(def));
+            }
+            header("main", vars, &mut out);
+            pretty_instrs(instrs, None, 1, &mut out);
+        },
+        &X86::ProgWithLives(ref defs, ref instrs, ref vars, ref lives) => {
+            for def in defs {
+                out.push_str(&pretty_x86(def));
+            }
+            header("main", vars, &mut out);
+            pretty_instrs(instrs, Some(lives), 1, &mut out);
+        },
+        _ => panic!("pretty_x86: not a top-level Prog or Define"),
+    }
+    return out;
+}
+
+// A directory of its own for the assembler's input and output.
+fn scratch_dir() -> io::Result<PathBuf> {
+    static SCRATCH_COUNTER : AtomicUsize = ATOMIC_USIZE_INIT;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    let dir = env::temp_dir().join(format!("rusl-{}-{}-{}",
+                                           now.as_secs(), now.subsec_nanos(),
+                                           SCRATCH_COUNTER.fetch_add(1, Ordering::SeqCst)));
+    try!(fs::create_dir_all(&dir));
+    return Ok(dir);
+}
+
+// Compile `source` and assemble it with nasm, or with as for AT&T
+// syntax, into a relocatable object for the target, ELF64 or Mach-O,
+// to be linked with the runtime.
+pub fn compile_to_object(source: &str, config: &Config) -> Result<Vec<u8>, CompileError> {
+    let mut asm_config = config.clone();
+    asm_config.emit = Emit::Asm;
+    let asm = try!(compile_with(source, &asm_config));
+    return assemble(&asm, config);
+}
+
+// The assembling half of compile_to_object, for `asm` that has already
+// been compiled with `config`.
+pub fn assemble(asm: &str, config: &Config) -> Result<Vec<u8>, CompileError> {
+    let dir = try!(scratch_dir().map_err(|e| CompileError::Assemble(e.to_string())));
+    let asm_path = dir.join("prog.s");
+    let obj_path = dir.join("prog.o");
+    let assembler = match config.syntax {
+        Syntax::Intel => "nasm",
+        Syntax::Att => "as",
+    };
+    let obj = File::create(&asm_path)
+        .and_then(|mut f| f.write_all(asm.as_bytes()))
+        .and_then(|_| match (&config.syntax, &config.target) {
+            (&Syntax::Intel, &Target::Linux) => Command::new("nasm").arg("-f").arg("elf64")
+                .arg(&asm_path).arg("-o").arg(&obj_path).output(),
+            (&Syntax::Intel, &Target::MacOs) => Command::new("nasm").arg("-f").arg("macho64")
+                .arg(&asm_path).arg("-o").arg(&obj_path).output(),
+            (&Syntax::Att, &Target::Linux) => Command::new("as").arg("--64")
+                .arg(&asm_path).arg("-o").arg(&obj_path).output(),
+            (&Syntax::Att, &Target::MacOs) => Command::new("as").arg("-arch").arg("x86_64")
+                .arg(&asm_path).arg("-o").arg(&obj_path).output(),
+        })
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => CompileError::MissingTool(assembler.to_string()),
+            _ => CompileError::Assemble(format!("{}: {}", assembler, e)),
+        })
+        .and_then(|output| {
+            if !output.status.success() {
+                return Err(CompileError::Assemble(
+                    String::from_utf8_lossy(&output.stderr).into_owned()));
+            }
+            let mut obj = vec![];
+            try!(File::open(&obj_path)
+                 .and_then(|mut f| f.read_to_end(&mut obj))
+                 .map_err(|e| CompileError::Assemble(e.to_string())));
+            Ok(obj)
+        });
+
+    let _ = fs::remove_dir_all(&dir);
+    return obj;
+}
+
+// The runtime compiled programs are linked with, kept in the compiler
+// so that it can build executables from anywhere.
+const RUNTIME_C : &'static str = include_str!("../runtime.c");
+
+// Compile `source` and link it with the runtime into an executable at
+// `path`, using gcc. Unless `config.pie` is set, the code refers to its
+// functions by absolute address, so it isn't linked as a PIE.
+pub fn compile_to_executable(source: &str, config: &Config, path: &Path)
+                             -> Result<(), CompileError> {
+    let obj = try!(compile_to_object(source, config));
+    return link(&obj, config, path);
+}
+
+// The linking half of compile_to_executable, for an object from
+// compile_to_object.
+pub fn link(obj: &[u8], config: &Config, path: &Path) -> Result<(), CompileError> {
+    let dir = try!(scratch_dir().map_err(|e| CompileError::Link(e.to_string())));
+    let obj_path = dir.join("prog.o");
+    let runtime_path = dir.join("runtime.c");
+    let mut gcc = Command::new("gcc");
+    gcc.args(&["-std=c99", if config.pie { "-pie" } else { "-no-pie" }])
+        .arg(&runtime_path).arg(&obj_path).arg("-o").arg(path);
+
+    let linked = File::create(&obj_path)
+        .and_then(|mut f| f.write_all(&obj))
+        .and_then(|_| File::create(&runtime_path))
+        .and_then(|mut f| f.write_all(RUNTIME_C.as_bytes()))
+        .map_err(|e| CompileError::Link(e.to_string()))
+        .and_then(|_| gcc.output().map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => CompileError::MissingTool("gcc".to_string()),
+            _ => CompileError::Link(format!("gcc: {}", e)),
+        }))
+        .and_then(|output| {
+            if !output.status.success() {
+                return Err(CompileError::Link(
+                    String::from_utf8_lossy(&output.stderr).into_owned()));
+            }
+            Ok(())
+        });
+
+    let _ = fs::remove_dir_all(&dir);
+    return linked;
+}
+
+// A broken invariant is a bug in the compiler rather than in the
+// program being compiled, so there's nothing better to do than stop.
+fn check_pass(config: &Config, pass: &str, check: &Fn() -> Result<(), String>) {
+    if !config.check_invariants {
+        return;
+    }
+    if let Err(e) = check() {
+        panic!("invariant broken after {}: {}", pass, e);
+    }
+}
+
+pub fn compile(source: &str) -> Result<String, CompileError> {
+    compile_with(source, &Config::default())
+}
+
+// The passes recurse on the program's structure, so a deeply nested
+// one, eg. an `if` hundreds deep in generated code, needs far more
+// stack than the main thread has.
+const COMPILER_STACK_SIZE : usize = 256 << 20;
+
+// Run the whole pipeline on `source` and return the generated NASM
+// assembly.
+pub fn compile_with(source: &str, config: &Config) -> Result<String, CompileError> {
+    compile_reporting(source, config, &mut Report::default())
+}
+
+// What the compiler has to say about a program besides its assembly,
+// for the caller to show as it likes.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub warnings: Vec<String>,  // with `config.warn_unused`
+    pub stats: Vec<PassStats>,  // for `config.stats`, see stats_table
+}
+
+// compile_with, also filling in `report`, as far as compiling got.
+pub fn compile_reporting(source: &str, config: &Config, report: &mut Report)
+                         -> Result<String, CompileError> {
+    let source = source.to_string();
+    let config = config.clone();
+    let compiler = thread::Builder::new()
+        .stack_size(COMPILER_STACK_SIZE)
+        .spawn(move || {
+            let mut report = Report::default();
+            let asm = compile_counting(&source, &config, &mut report);
+            (asm, report)
+        })
+        .expect("couldn't start the compiler thread");
+    match compiler.join() {
+        Ok((asm, compiled_report)) => {
+            *report = compiled_report;
+            return asm;
+        },
+        // a broken invariant, see check_pass
+        Err(e) => panic::resume_unwind(e),
+    }
+}
+
+// compile_reporting on the compiler's thread, recording the size of
+// the program after each pass in `report.stats`.
+fn compile_counting(source: &str, config: &Config, report: &mut Report)
+                    -> Result<String, CompileError> {
+    // the same program always gets the same names, and so the same
+    // assembly
+    reset_var_counter();
+    reset_symbols();
+    let mut lexer = lexer_for(source);
+
+    if config.emit == Emit::Tokens {
+        let mut tokens = String::new();
+        loop {
+            let tok = try!(get_token(&mut lexer));
+            tokens.push_str(&format!("{:?}\n", tok));
+            if tok == Token::EOF {
+                return Ok(tokens);
+            }
+        }
+    }
+
+    let mut uniquify_mapping : HashMap<String, String> = HashMap::new();
+    for prim in PRIMITIVES.iter() {
+        uniquify_mapping.insert(prim.to_string(), prim.to_string());
+    }
+
+    // the forms as read are kept for unused_binding_warnings
+    let mut forms = vec![];
+    let mut toplevel = vec![];
+    let mut form = try!(get_expr(&mut lexer));
+    while form != SExpr::EOF {
+        toplevel.push(try!(get_toplevel(&form)));
+        forms.push(form);
+        form = try!(get_expr(&mut lexer));
+    }
+    if config.warn_unused {
+        report.warnings = unused_binding_warnings(&forms);
+    }
+
+    if config.emit == Emit::Ast {
+        let mut ast = String::new();
+        for sexpr in toplevel {
+            ast.push_str(&format!("{:#?}\n", sexpr));
+        }
+        return Ok(ast);
+    }
+
+    let prog = match toplevel_prog(toplevel) {
+        Some(prog) => prog,
+        None => return Err(CompileError::EmptyProgram),
+    };
+    if config.typecheck {
+        try!(typecheck(&prog, &PRIMITIVES));
+    }
+
+    let mut dynamics = HashMap::new();
+    dynamic_labels(&prog, &mut dynamics);
+    let prog = try!(resolve_dynamics(prog, &dynamics, &HashSet::new()));
+
+    let uniquified = try!(uniquify(&mut uniquify_mapping, prog));
+    check_pass(config, "uniquify", &|| check_uniquified(&uniquified));
+    try!(check_arity(&uniquified));
+    let uniquified = if config.opt_level >= OptLevel::O2 {
+        inline_functions(uniquified, config.inline_size)
+    } else {
+        uniquified
+    };
+    check_pass(config, "inline", &|| check_uniquified(&uniquified));
+    let uniquified = if config.opt_level >= OptLevel::O1 {
+        propagate_constants(uniquified)
+    } else {
+        uniquified
+    };
+    let uniquified = box_mutated(uniquified);
+
+    let (closures_converted, _) =
+        convert_to_closures(&HashSet::new(), uniquified, &HashSet::new());
+
+    let mut flattened = flatten(closures_converted);
+    check_pass(config, "flatten", &|| check_flattened(&flattened));
+    report.stats.push(PassStats::of_flat("flatten", &flattened));
+    for pass in flat_passes(config) {
+        flattened = pass(flattened);
+    }
+    check_pass(config, "optimizing", &|| check_flattened(&flattened));
+    report.stats.push(PassStats::of_flat("optimize", &flattened));
+
+    if config.emit == Emit::Anf {
+        return Ok(format!("{:#?}\n", flattened));
+    }
+
+    let instrs = select_instructions(flattened, config);
+    report.stats.push(PassStats::of_x86("select_instructions", &instrs));
+    let instrs = uncover_live(instrs);
+    if config.emit == Emit::Px86 {
+        return Ok(pretty_x86(&instrs));
+    }
+    let mut homes = vec![];
+    let homes_assigned = assign_homes(instrs, config, &mut homes);
+    check_pass(config, "assign_homes", &|| check_homes_assigned(&homes_assigned));
+    report.stats.push(PassStats::of_x86("assign_homes", &homes_assigned));
+
+    let ifs_lowered = lower_conditionals(homes_assigned, config);
+    let mut patched = patch_instructions(ifs_lowered);
+    report.stats.push(PassStats::of_x86("patch_instructions", &patched));
+    for pass in peephole_passes(&config.opt_level) {
+        patched = pass(patched);
+    }
+    report.stats.push(PassStats::of_x86("peephole", &patched));
+
+    let mut asm = print_x86(patched, config);
+    asm.push_str(&print_dynamics(&dynamics, config));
+    asm.push_str(&print_symbols(&interned_symbols(), config));
+    if let Some(ref path) = config.emit_map {
+        let map = debug_map_json(&function_maps(&uniquify_mapping, homes, config));
+        try!(File::create(path)
+             .and_then(|mut f| f.write_all(map.as_bytes()))
+             .map_err(|e| CompileError::WriteMap(format!("{}: {}", path, e))));
+    }
+    return Ok(asm);
+}
+
+// The entries of the debug map. `mapping` is what uniquify renamed the
+// top-level functions to, and `homes` is from assign_homes.
+fn function_maps(mapping: &HashMap<String, String>,
+                 homes: Vec<(String, HashMap<String, X86Arg>)>,
+                 config: &Config) -> Vec<FunctionMap> {
+    let names : HashMap<&String, &String> = mapping.iter()
+        .filter(|&(name, _)| !PRIMITIVES.contains(&&name[..]))
+        .map(|(name, label)| (label, name))
+        .collect();
+    let compiled : HashSet<String> = homes.iter().map(|&(ref label, _)| label.clone()).collect();
+    let mut maps = vec![];
+    for (label, locs) in homes {
+        let mut vars : Vec<(String, String)> = locs.into_iter()
+            .map(|(var, home)| (var, print_x86_arg(home, config)))
+            .collect();
+        vars.sort();
+        maps.push(FunctionMap {
+            name: names.get(&label).map(|name| name.to_string()),
+            label: Some(print_symbol(&label, config)),
+            homes: vars,
+        });
+    }
+    // functions inlined at every call aren't compiled at all
+    let mut inlined : Vec<&String> = names.iter()
+        .filter(|&(label, _)| !compiled.contains(*label))
+        .map(|(_, name)| *name)
+        .collect();
+    inlined.sort();
+    for name in inlined {
+        maps.push(FunctionMap { name: Some(name.clone()), label: None, homes: vec![] });
+    }
+    return maps;
+}
+
+// Whether nasm and gcc are around to assemble and link test programs.
+#[cfg(test)]
+fn toolchain_available() -> bool {
+    Command::new("nasm").arg("-v").output().is_ok() &&
+        Command::new("gcc").arg("--version").output().is_ok()
+}
+
+// Compile `source`, assemble and link it with runtime.c, run it and
+// return what it printed, without the trailing newline.
+#[cfg(test)]
+fn compile_and_run(source: &str) -> Result<String, String> {
+    compile_and_run_with(source, &Config::default())
+}
+
+// What the program printed, or if it failed, its exit status and what
+// it printed to stderr.
+#[cfg(test)]
+fn compile_and_run_with(source: &str, config: &Config) -> Result<String, String> {
+    fn run(cmd: &mut Command) -> Result<String, String> {
+        let output = try!(cmd.output().map_err(|e| format!("{:?}: {}", cmd, e)));
+        match output.status.code() {
+            Some(0) => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+            Some(code) => Err(format!("exit status {}: {}", code,
+                                      String::from_utf8_lossy(&output.stderr))),
+            None => Err(format!("{:?} was killed by a signal", cmd)),
+        }
+    }
+
+    let dir = try!(scratch_dir().map_err(|e| e.to_string()));
+    try!(compile_to_executable(source, config, &dir.join("a.out"))
+         .map_err(|e| format!("{:?}", e)));
+    let stdout = try!(run(&mut Command::new(dir.join("a.out"))));
+
+    let _ = fs::remove_dir_all(&dir);
+    return Ok(stdout.trim_right().to_string());
+}
+
+#[test]
+fn test_compile_and_run() {
+    if !toolchain_available() {
+        return;
+    }
+
+    assert_eq!(Ok("42".to_string()), compile_and_run("(+ 40 2)"));
+    assert_eq!(Ok("(#t, 1, 4)".to_string()),
+               compile_and_run("(let ((b (< 1 2))) (tuple b (if b 1 2) (if #f 3 4)))"));
+}
+
+#[test]
+fn test_compile() {
+    // without optimization, nothing is folded away
+    let config = Config { opt_level: OptLevel::O0, ..Config::default() };
+    let asm = compile_with("(+ 1 2)", &config).unwrap();
+    assert!(asm.contains("global main"));
+    assert!(asm.contains("    add "));
+
+    for source in &["", "  ; only a comment\n"] {
+        match compile(source) {
+            Err(CompileError::EmptyProgram) => (),
+            r => panic!("expected EmptyProgram, got {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn test_only_definitions() {
+    let src = "(define (f x) x)\n(define (g) (f 1))";
+    assert!(compile(src).is_ok());
+    if toolchain_available() {
+        assert_eq!(Ok("".to_string()), compile_and_run(src));
+    }
+}
+
+#[test]
+fn test_compile_to_object() {
+    if !toolchain_available() {
+        return;
+    }
+
+    let obj = compile_to_object("(+ 40 2)", &Config::default()).unwrap();
+    assert_eq!(b"\x7fELF", &obj[..4]);
+    // compile_and_run links the object with the runtime
+    assert_eq!(Ok("(1, 2)".to_string()), compile_and_run("(tuple 1 2)"));
+}
+
+#[test]
+fn test_unbound_variable() {
+    match compile("(+ x 1)") {
+        Err(e @ CompileError::UnboundVariable(_, _)) =>
+            assert_eq!("error: line 1:4 unbound variable 'x'", format!("error: {}", e)),
+        r => panic!("expected UnboundVariable, got {:?}", r),
+    }
+    match compile("(define (f a)\n  (+ a\n     b))\n(f 1)") {
+        Err(CompileError::UnboundVariable(ref name, span))
+            if name == "b" && (span.line, span.col) == (3, 6) => (),
+        r => panic!("expected UnboundVariable at 3:6, got {:?}", r),
+    }
+    // the unbound use, not the parameter of the same name before it
+    match compile("(define (f x) x)\n(+ x 1)") {
+        Err(CompileError::UnboundVariable(ref name, span))
+            if name == "x" && (span.line, span.col) == (2, 4) => (),
+        r => panic!("expected UnboundVariable at 2:4, got {:?}", r),
+    }
+    // primitives and functions are bound
+    assert!(compile("(define (f a) (+ a 1))\n(f 1)").is_ok());
+}
+
+#[test]
+fn test_compile_unknown_form() {
+    use parser::ParseError;
+
+    match compile("(define-syntax foo (syntax-rules (x) x))\n(foo 1)") {
+        Err(CompileError::Parse(ParseError::UnknownForm(ref name)))
+            if name == "define-syntax" => (),
+        r => panic!("expected UnknownForm, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_malformed_programs() {
+    use lexer::LexError;
+    use parser::ParseError;
+
+    match compile("(+ 1\n  (+ 2 3)") {
+        Err(e @ CompileError::Parse(ParseError::UnclosedParen(1, 1))) =>
+            assert_eq!("line 1:1 '(' is never closed", e.to_string()),
+        r => panic!("expected UnclosedParen, got {:?}", r),
+    }
+    match compile("(+ 1 2))") {
+        Err(CompileError::Parse(ParseError::UnmatchedParen(1, 8))) => (),
+        r => panic!("expected UnmatchedParen, got {:?}", r),
+    }
+    match compile("(string-length \"abc)") {
+        Err(CompileError::Parse(ParseError::Lex(LexError::UnterminatedString(1, 21)))) => (),
+        r => panic!("expected UnterminatedString, got {:?}", r),
+    }
+    match compile("(define (f 1) 1)\n(f 2)") {
+        Err(CompileError::Parse(ParseError::BadParameter(ref p))) if p == "1" => (),
+        r => panic!("expected BadParameter, got {:?}", r),
+    }
+    match compile("(car 1 2)") {
+        Err(e @ CompileError::Arity(_, 1, 2, _)) =>
+            assert_eq!("line 1:2 `car` takes 1 argument, not 2", e.to_string()),
+        r => panic!("expected Arity, got {:?}", r),
+    }
+    // the call with the wrong number of arguments, not the first one
+    match compile("(tuple (car '(1))\n       (car '(2) 3))") {
+        Err(CompileError::Arity(_, 1, 2, span)) if (span.line, span.col) == (2, 9) => (),
+        r => panic!("expected Arity at 2:9, got {:?}", r),
+    }
+    for &(source, binding) in &[("(let ((x)) x)", "'(x)"), ("(let (x) x)", "x")] {
+        match compile(source) {
+            Err(CompileError::Parse(ParseError::BadBinding(ref b))) if b == binding => (),
+            r => panic!("expected BadBinding, got {:?}", r),
+        }
+    }
+    match compile("(+ 1 2)\n'") {
+        Err(e @ CompileError::Parse(ParseError::EmptyQuote(2, 1))) =>
+            assert_eq!("line 2:1 `'` isn't followed by an expression", e.to_string()),
+        r => panic!("expected EmptyQuote, got {:?}", r),
+    }
+    match compile("(+ 1 ())") {
+        Err(CompileError::Parse(ParseError::EmptyApplication)) => (),
+        r => panic!("expected EmptyApplication, got {:?}", r),
+    }
+    match compile("(define () 1)") {
+        Err(CompileError::Parse(ParseError::BadArity(ref form))) if form == "define" => (),
+        r => panic!("expected BadArity, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_match() {
+    use parser::ParseError;
+
+    match compile("(match 1 (0 1) ((a 2) 2))") {
+        Err(CompileError::Parse(ParseError::NonExhaustiveMatch)) => (),
+        r => panic!("expected NonExhaustiveMatch, got {:?}", r),
+    }
+    match compile("(match 1 (0 1) (_))") {
+        Err(CompileError::Parse(ParseError::BadMatchClause(_))) => (),
+        r => panic!("expected BadMatchClause, got {:?}", r),
+    }
+
+    if !toolchain_available() {
+        return;
+    }
+    assert_eq!(Ok("3".to_string()), compile_and_run("(match (tuple 1 2) ((a b) (+ a b)))"));
+    // a tuple pattern doesn't read the length of something else
+    assert_eq!(Ok("(0, 7, 0)".to_string()),
+               compile_and_run("(define (f x) (match x ((1 b) b) (_ 0)))
+                                (tuple (f 5) (f (tuple 1 7)) (f #t))"));
+    assert_eq!(Ok("(10, 20, 5, 6)".to_string()),
+               compile_and_run("(define (f x) (match x (0 10) (#t 20) (y y)))
+                                (tuple (f 0) (f #t) (f 5)
+                                       (match (tuple 1 (tuple 2 3))
+                                         ((a (1 c)) 0)
+                                         ((_ (b c)) (+ 1 (+ b c)))))"));
+}
+
+#[test]
+fn test_rest_params() {
+    if !toolchain_available() {
+        return;
+    }
+    let sum = "(define (sumlist l) (if (null? l) 0 (+ (car l) (sumlist (cdr l)))))
+               (define (sum . xs) (sumlist xs))\n";
+    assert_eq!(Ok("6".to_string()), compile_and_run(&format!("{}(sum 1 2 3)", sum)));
+    assert_eq!(Ok("0".to_string()), compile_and_run(&format!("{}(sum)", sum)));
+    assert_eq!(Ok("(1, (2, (3, ())))".to_string()),
+               compile_and_run("(let ((f (lambda (a . rest) (cons a rest)))) (f 1 2 3))"));
+}
+
+#[test]
+fn test_function_values() {
+    // a top-level function passed to another, and called through a
+    // variable, gets a closure holding its address
+    let src = "(define (add1 x) (+ x 1))
+               (define (twice f x) (f (f x)))
+               (let ((g add1)) (tuple (twice g 5) (g 1)))";
+    let att = Config { syntax: Syntax::Att, ..Config::default() };
+    let asm = compile_with(src, &att).unwrap();
+    assert!(!asm.lines().any(|l| l.contains("movabsq") && l.contains("(%")));
+
+    if toolchain_available() {
+        for config in vec![Config::default(), att] {
+            assert_eq!(Ok("(7, 2)".to_string()), compile_and_run_with(src, &config));
+        }
+    }
+}
+
+#[test]
+fn test_indirect_calls() {
+    // the closure is taken out of the tuple and called through the
+    // address it holds
+    let src = "(define (add1 x) (+ x 1))
+               (let ((t (tuple add1 41))) ((tuple-ref t 0) (tuple-ref t 1)))";
+    let asm = compile(src).unwrap();
+    assert!(asm.lines().any(|l| {
+        REGS.iter().any(|r| l.trim() == format!("call {}", display_reg(r)))
+    }));
+
+    if toolchain_available() {
+        assert_eq!(Ok("42".to_string()), compile_and_run(src));
+        assert_eq!(Ok("5".to_string()), compile_and_run("((lambda (x) (+ x 1)) 4)"));
+    }
+}
+
+#[test]
+fn test_mutual_recursion() {
+    if !toolchain_available() {
+        return;
+    }
+    let even = "(define (even? n) (if (= n 0) #t (odd? (+ n (- 1)))))\n";
+    let odd = "(define (odd? n) (if (= n 0) #f (even? (+ n (- 1)))))\n";
+    assert_eq!(Ok("#t".to_string()), compile_and_run(&format!("{}{}(even? 10)", even, odd)));
+    assert_eq!(Ok("#t".to_string()), compile_and_run(&format!("{}{}(even? 10)", odd, even)));
+}
+
+#[test]
+fn test_named_let() {
+    if !toolchain_available() {
+        return;
+    }
+    assert_eq!(Ok("55".to_string()),
+               compile_and_run("(let loop ((i 10) (acc 0))
+                                  (if (= i 0) acc (loop (+ i (- 1)) (+ acc i))))"));
+    // the inner loop doesn't hide the outer one outside its body
+    assert_eq!(Ok("6".to_string()),
+               compile_and_run("(let loop ((i 3))
+                                  (if (= i 0)
+                                      0
+                                      (+ (let loop ((j i)) (if (= j 0) 0 (+ 1 (loop (+ j (- 1))))))
+                                         (loop (+ i (- 1))))))"));
+}
+
+#[test]
+fn test_letrec() {
+    use parser::ParseError;
+
+    match compile("(letrec ((a b) (b 1)) a)") {
+        Err(CompileError::Parse(ParseError::LetrecUninitialized(ref name))) if name == "b" => (),
+        r => panic!("expected LetrecUninitialized, got {:?}", r),
+    }
+    // only called once b is initialized
+    assert!(compile("(letrec ((f (lambda () b)) (b 1)) (f))").is_ok());
+
+    if !toolchain_available() {
+        return;
+    }
+    assert_eq!(Ok("120".to_string()),
+               compile_and_run("(letrec ((mul (lambda (a b)
+                                                (if (= b 0) 0 (+ a (mul a (+ b (- 1)))))))
+                                         (fact (lambda (n)
+                                                 (if (= n 0) 1 (mul n (fact (+ n (- 1))))))))
+                                  (fact 5))"));
+}
+
+#[test]
+fn test_opt_level() {
+    let o0 = compile_with("(+ 1 2)", &Config { opt_level: OptLevel::O0, ..Config::default() });
+    let o2 = compile_with("(+ 1 2)", &Config { opt_level: OptLevel::O2, ..Config::default() });
+    let adds = |asm: &String| asm.lines().filter(|l| l.starts_with("    add ") &&
+                                                 !l.starts_with("    add rsp")).count();
+    assert_eq!(1, adds(&o0.unwrap()));
+    let o2 = o2.unwrap();
+    assert_eq!(0, adds(&o2));
+    assert!(o2.contains("mov rax, 6\n"));
+}
+
+#[test]
+fn test_target_cpu() {
+    let baseline = Config { target_cpu: TargetCpu::Baseline, ..Config::default() };
+    let modern = Config { target_cpu: TargetCpu::Modern, ..Config::default() };
+
+    for src in ["(min 3 7)", "(max 3 7)"].iter() {
+        let asm = compile_with(src, &baseline).unwrap();
+        assert!(!asm.contains("cmov"));
+        assert!(asm.contains("    je "));
+    }
+
+    let asm = compile_with("(min 3 7)", &modern).unwrap();
+    assert!(asm.contains("    cmovg "));
+    assert!(!asm.contains("    je "));
+
+    let asm = compile_with("(max 3 7)", &modern).unwrap();
+    assert!(asm.contains("    cmovl "));
+    assert!(!asm.contains("    je "));
+
+    let src = "(define (f x) (abs x)) (tuple (f (- 5)) (f 0) (f 7))";
+    let no_inline = |config: &Config| Config { inline_size: 0, ..config.clone() };
+    let asm = compile_with(src, &no_inline(&baseline)).unwrap();
+    assert!(!asm.contains("cmov"));
+    assert!(asm.contains("    neg "));
+    let asm = compile_with(src, &no_inline(&modern)).unwrap();
+    assert!(asm.contains("    neg "));
+    assert!(asm.contains("    cmovl "));
+    assert!(!asm.contains("    jge "));
+
+    // an `if` choosing between two simple values
+    let src = "(define (f x) (if (< x 0) 0 x)) (f (- 5))";
+    let asm = compile_with(src, &no_inline(&baseline)).unwrap();
+    assert!(!asm.contains("cmov"));
+    assert!(asm.contains(".L"));
+    let asm = compile_with(src, &no_inline(&modern)).unwrap();
+    assert!(asm.contains("    cmovl "));
+    assert!(!asm.contains(".L"));
+    let asm = compile_with("(define (f x) (if (< x 0) (print x) x)) (f 1)",
+                           &no_inline(&modern)).unwrap();
+    assert!(!asm.contains("cmov"));
+    assert!(asm.contains(".L"));
+
+    if !toolchain_available() {
+        return;
+    }
+    for config in &[baseline.clone(), modern.clone()] {
+        assert_eq!(Ok("0".to_string()), compile_and_run_with(src, &no_inline(config)));
+    }
+    for config in &[baseline, modern] {
+        assert_eq!(Ok("(5, 0, 7, 3, 7)".to_string()),
+                   compile_and_run_with("(define (g x) (abs x))
+                                         (tuple (g (- 5)) (g 0) (g 7) (min 3 7) (max 3 7))",
+                                        config));
+    }
+}
+
+#[test]
+fn test_emit() {
+    let src = "(let ((x 1)) (+ x 2))";
+    let mut config = Config { opt_level: OptLevel::O0, ..Config::default() };
+
+    config.emit = Emit::Tokens;
+    let tokens = compile_with(src, &config).unwrap();
+    assert!(tokens.starts_with("LParen\nSymbol(\"let\")\n"));
+    assert!(tokens.ends_with("EOF\n"));
+
+    config.emit = Emit::Ast;
+    let ast = compile_with(src, &config).unwrap();
+    assert!(ast.starts_with("Let("));
+    assert!(ast.contains("\"+\""));
+
+    config.emit = Emit::Anf;
+    let anf = compile_with(src, &config).unwrap();
+    assert!(anf.starts_with("Prog("));
+    assert!(anf.contains("Assign("));
+    assert!(anf.contains("Return("));
+
+    config.emit = Emit::Asm;
+    let asm = compile_with(src, &config).unwrap();
+    assert!(asm.contains("global main"));
+}
+
+#[test]
+fn test_pretty_x86() {
+    let src = "(define (f x) (if (< x 1) x 2)) (f 0)";
+    let config = Config { emit: Emit::Px86, opt_level: OptLevel::O0, ..Config::default() };
+    let px86 = compile_with(src, &config).unwrap();
+    let lines : Vec<&str> = px86.lines().collect();
+
+    let def = lines.iter().position(|l| l.starts_with("f") && l.contains(": (vars: ")).unwrap();
+    let body = &lines[def + 1..];
+    let if_line = body.iter().find(|l| l.trim_left().starts_with("if ")).unwrap();
+    assert!(if_line.starts_with("    if x"));
+    assert!(if_line.contains(" < 2 "));
+    // x is needed by the test and the then branch
+    let x = if_line.split_whitespace().nth(1).unwrap();
+    let only_x = format!("live: {{{}}}", x);
+    assert!(if_line.ends_with(&only_x));
+    let thn = body.iter().position(|l| l.trim() == "then:").unwrap();
+    assert!(body[thn + 1].starts_with("        mov if"));
+    assert!(body[thn + 1].ends_with(&only_x));
+    let els = body.iter().position(|l| l.trim() == "else:").unwrap();
+    assert!(body[els + 1].ends_with("live: {}"));
+    assert!(lines.iter().any(|l| l.starts_with("main: (vars: ")));
+}
+
+#[test]
+fn test_tail_calls() {
+    let src = "(define (sum n acc) (if (= n 0) acc (sum (+ n (- 1)) (+ acc n))))
+               (sum 1000000 0)";
+    let asm = compile(src).unwrap();
+    assert!(asm.contains("    jmp rax"));
+
+    if !toolchain_available() {
+        return;
+    }
+    // far deeper than the stack would allow if each call kept a frame
+    assert_eq!(Ok("500000500000".to_string()), compile_and_run(src));
+}
+
+#[test]
+fn test_set() {
+    match compile("(+ 1 2)\n  (set! undefined 1)") {
+        Err(e @ CompileError::UnboundVariable(_, _)) =>
+            assert_eq!("line 2:9 unbound variable 'undefined'", e.to_string()),
+        r => panic!("expected UnboundVariable, got {:?}", r),
+    }
+
+    if !toolchain_available() {
+        return;
+    }
+    assert_eq!(Ok("42".to_string()),
+               compile_and_run("(let ((x 1)) (begin (set! x (+ x 41)) x))"));
+    // `x` was read before it was set
+    assert_eq!(Ok("6".to_string()),
+               compile_and_run("(let ((x 1)) (+ x (begin (set! x 5) x)))"));
+    // the closure shares `n` with the `let`
+    assert_eq!(Ok("42".to_string()),
+               compile_and_run("(let ((n 0))
+                                  (let ((inc (lambda (d) (set! n (+ n d)))))
+                                    (begin (inc 1) (inc 41) n)))"));
+}
+
+#[test]
+fn test_void() {
+    if !toolchain_available() {
+        return;
+    }
+    // nothing is printed for a program whose value is void
+    assert_eq!(Ok("".to_string()), compile_and_run("(let ((x 0)) (set! x 1))"));
+    assert_eq!(Ok("".to_string()),
+               compile_and_run("(let ((i 0)) (while (< i 3) (set! i (+ i 1))))"));
+    // a boxed variable, since the closure sets it
+    assert_eq!(Ok("(#<void>, 5)".to_string()),
+               compile_and_run("(let ((n 0))
+                                  (let ((set (lambda (v) (set! n v))))
+                                    (tuple (set 5) n)))"));
+}
+
+#[test]
+fn test_if_comparisons() {
+    let cases = vec![("<", "l", "(1, 0, 0)"),
+                     (">", "g", "(0, 0, 1)"),
+                     ("<=", "le", "(1, 1, 0)"),
+                     (">=", "ge", "(0, 1, 1)"),
+                     ("=", "e", "(0, 1, 0)")];
+    for (op, cc, expected) in cases {
+        let src = format!("(define (f x) (if ({} x 3) 1 0))\n(tuple (f 2) (f 3) (f 4))", op);
+        // the comparison is branched on, without making a boolean;
+        // inlined, it would be folded away
+        let asm = compile_with(&src, &Config { inline_size: 0, ..Config::default() }).unwrap();
+        assert!(asm.contains(&format!("j{} ", cc)));
+        assert!(!asm.contains(&format!("set{} ", cc)));
+
+        if toolchain_available() {
+            assert_eq!(Ok(expected.to_string()), compile_and_run(&src));
+        }
+    }
+}
+
+#[test]
+fn test_deeply_nested_if() {
+    // (if (< x 0) 0 (if (< x 1) 1 ... 0))
+    let depth = 500;
+    let mut src = "(define (f x) ".to_string();
+    for i in 0..depth {
+        src.push_str(&format!("(if (< x {}) {} ", i, i));
+    }
+    src.push_str("0");
+    for _ in 0..depth {
+        src.push_str(")");
+    }
+    src.push_str(")\n(tuple (f 7) (f 499) (f 500))");
+
+    let asm = compile(&src).unwrap();
+    assert!(asm.contains("f1:"));
+    if toolchain_available() {
+        assert_eq!(Ok("(8, 0, 0)".to_string()), compile_and_run(&src));
+    }
+}
+
+#[test]
+fn test_constant_if() {
+    let mut config = Config::default();
+    config.opt_level = OptLevel::O0;
+    for &(src, expected) in &[("(if #t 1 2)", "1"), ("(if (= 1 1) 1 2)", "1"),
+                              ("(if (< 3 2) 1 2)", "2"), ("(if (= #t #f) 1 2)", "2")] {
+        // only the branch taken is selected, even without constant
+        // folding
+        let asm = compile_with(src, &config).unwrap();
+        let main = &asm[asm.find("main:").unwrap()..];
+        assert!(!main.contains("cmp") && !main.contains("jmp"), "{}", main);
+        assert!(!main.contains(" je ") && !main.contains(" jl "), "{}", main);
+
+        if toolchain_available() {
+            assert_eq!(Ok(expected.to_string()), compile_and_run_with(src, &config));
+        }
+    }
+}
+
+#[test]
+fn test_mismatched_comparison() {
+    match compile("(= #t 1)") {
+        Err(e) => assert_eq!("`=` compares a boolean with a number, use `eq?` instead",
+                             e.to_string()),
+        r => panic!("expected an error, got {:?}", r),
+    }
+
+    // a boolean is never equal to a number
+    if toolchain_available() {
+        assert_eq!(Ok("(#f, #f)".to_string()),
+                   compile_and_run("(let ((b #t)) (tuple (eq? b 1) (= b 1)))"));
+    }
+}
+
+#[test]
+fn test_typecheck() {
+    let typed = Config { typecheck: true, ..Config::default() };
+    let errors = vec![
+        ("(+ #t 1)", "`+` expects a number, not a boolean in `(+ #t 1)`"),
+        ("(let ((n (+ 1 2))) (car n))", "`car` expects a tuple, not a number in `(car n)`"),
+        ("(let ((n 5)) (if n 1 2))", "the test of `if` is a number, not a boolean in `(if n 1 2)`"),
+        ("(let ((t (tuple 1 2))) (t 3))", "`t` is a tuple, not a function in `(t 3)`"),
+        ("(+ 'a 1)", "`+` expects a number, not a symbol in `(+ 'a 1)`"),
+        ("(symbol->string 5)",
+         "`symbol->string` expects a symbol, not a number in `(symbol->string 5)`"),
+    ];
+    for (src, msg) in errors {
+        match compile_with(src, &typed) {
+            Err(e) => assert_eq!(format!("type error: {}", msg), e.to_string()),
+            r => panic!("expected a type error for {}, got {:?}", src, r),
+        }
+        // without the flag, the error is left for run time
+        assert!(compile(src).is_ok());
+    }
+
+    // a variable that is `set!` can change type, and arguments can be
+    // anything
+    let src = "(define (apply f x) (f x))
+               (let ((n 0) (t (tuple 1 2)))
+                 (begin (set! n #t)
+                        (if n (apply (lambda (y) (+ y 1)) (tuple-ref t 1)) 0)))";
+    assert!(compile_with(src, &typed).is_ok());
+    if toolchain_available() {
+        assert_eq!(Ok("3".to_string()), compile_and_run_with(src, &typed));
+    }
+}
+
+#[test]
+fn test_unused_bindings() {
+    let src = "(define (f y)
+                 (let ((x (+ y 12345)) (z 2))
+                   (+ y z)))
+               (let ((unused (f 1)))
+                 (match (f 2) (n n)))";
+    let config = Config { warn_unused: true, ..Config::default() };
+    let mut report = Report::default();
+    compile_reporting(src, &config, &mut report).unwrap();
+    assert_eq!(vec!["unused binding 'x' at line 2", "unused binding 'unused' at line 4"],
+               report.warnings);
+
+    // a binding is used by its body, not by its own value, and not
+    // through an inner one of the same name
+    let shadowed = "(let ((a 1))
+                      (let ((a 2) (b a))
+                        (+ a b)))
+                    (let ((c 3)) (let ((c c)) 0))";
+    compile_reporting(shadowed, &config, &mut report).unwrap();
+    assert_eq!(vec!["unused binding 'c' at line 4"], report.warnings);
+    compile_reporting(shadowed, &Config::default(), &mut report).unwrap();
+    assert!(report.warnings.is_empty());
+
+    // the initializer of `x` is pure, so it's only computed without
+    // optimization; 12345 is 24690 when tagged
+    let o0 = compile_with(src, &Config { opt_level: OptLevel::O0, ..Config::default() });
+    assert!(o0.unwrap().contains("24690"));
+    assert!(!compile(src).unwrap().contains("24690"));
+    if toolchain_available() {
+        assert_eq!(Ok("4".to_string()), compile_and_run(src));
+    }
+}
+
+#[test]
+fn test_quoted_list() {
+    if !toolchain_available() {
+        return;
+    }
+    let src = "(define (sum l) (if (null? l) 0 (+ (car l) (sum (cdr l)))))
+               (sum '(1 2 3))";
+    assert_eq!(Ok("6".to_string()), compile_and_run(src));
+    assert_eq!(Ok("(1, (#t, ()))".to_string()), compile_and_run("(cons 1 '(#t))"));
+}
+
+#[test]
+fn test_pairs() {
+    let asm = compile("(car 5)").unwrap();
+    assert!(asm.contains("call rusl_error"));
+
+    if !toolchain_available() {
+        return;
+    }
+    assert_eq!(Ok("1".to_string()), compile_and_run("(car (cons 1 2))"));
+    assert_eq!(Ok("2".to_string()), compile_and_run("(cdr (cons 1 2))"));
+    assert_eq!(Ok("(#t, #f, #f)".to_string()),
+               compile_and_run("(tuple (pair? (cons 1 2)) (pair? 5) (pair? (tuple 1 2 3)))"));
+    // the error goes to stderr, before anything is printed
+    assert_eq!(Err("exit status 1: error: cdr: argument is not a pair\n".to_string()),
+               compile_and_run("(cdr 5)"));
+}
+
+#[test]
+fn test_strings() {
+    if !toolchain_available() {
+        return;
+    }
+    assert_eq!(Ok("hello, \"world\"".to_string()),
+               compile_and_run("\"hello, \\\"world\\\"\""));
+    assert_eq!(Ok("(13, 0, #\\a)".to_string()),
+               compile_and_run("(tuple (string-length \"hello, world!\") (string-length \"\") #\\a)"));
+}
+
+#[test]
+fn test_equality() {
+    let asm = compile("(equal? 1 2)").unwrap();
+    assert!(asm.contains("call deep_equal"));
+
+    if !toolchain_available() {
+        return;
+    }
+    assert_eq!(Ok("(#t, #f, #f)".to_string()),
+               compile_and_run("(tuple (eq? 3 3) (eq? 3 4) (eq? (tuple 1 2) (tuple 1 2)))"));
+    assert_eq!(Ok("(#t, #f, #t)".to_string()),
+               compile_and_run("(let ((p (tuple 1 2)))
+                                  (tuple (equal? p (tuple 1 2))
+                                         (equal? p (tuple 1 3))
+                                         (equal? \"ab\" \"ab\")))"));
+}
+
+#[test]
+fn test_checked_arith() {
+    let src = "(+ 4611686018427387903 4611686018427387903)";
+    let checked = Config { checked_arith: true, ..Config::default() };
+    assert!(!compile(src).unwrap().contains("jo "));
+    assert!(compile_with(src, &checked).unwrap().contains("jo rusl_overflow_trap"));
+    // an unused sum is kept so that it still traps
+    let unused = "(let ((x (+ 4611686018427387903 1))) 0)";
+    assert!(compile_with(unused, &checked).unwrap().contains("jo rusl_overflow_trap"));
+
+    if !toolchain_available() {
+        return;
+    }
+    // the sum doesn't fit, so it isn't folded away
+    assert_eq!(Ok("-2".to_string()), compile_and_run(src));
+    assert_eq!(Err("exit status 1: error: integer overflow\n".to_string()),
+               compile_and_run_with(src, &checked));
+    assert_eq!(Ok("42".to_string()), compile_and_run_with("(+ 40 2)", &checked));
+    assert_eq!(Err("exit status 1: error: integer overflow\n".to_string()),
+               compile_and_run_with(unused, &checked));
+}
+
+#[test]
+fn test_not_equal() {
+    let src = "(define (f x y) (if (not= x y) 1 0))\n(tuple (f 1 2) (f 2 2) (if (/= 3 4) 5 6))";
+    let asm = compile_with(src, &Config { inline_size: 0, ..Config::default() }).unwrap();
+    assert!(asm.contains("jne "));
+
+    if toolchain_available() {
+        assert_eq!(Ok("(1, 0, 5)".to_string()), compile_and_run(src));
+    }
+
+    // an empty else branch is skipped on the negated condition
+    let one_armed = X86::If(box X86::CmpP(CC::L, X86Arg::Reg(Reg::RAX), X86Arg::Imm(0)),
+                            vec![X86::Neg(X86Arg::Reg(Reg::RAX))],
+                            vec![]);
+    match &lower_if(one_armed, &Config::default())[..] {
+        &[X86::Cmp(_, _), X86::JmpIf(CC::GE, ref skip), X86::Neg(_), X86::Label(ref end)] =>
+            assert_eq!(skip, end),
+        instrs => panic!("unexpected lowering {:?}", instrs),
+    }
+}
+
+#[test]
+fn test_floats() {
+    let asm = compile("(+ 1.5 2.25)").unwrap();
+    assert!(asm.contains("addsd xmm0, xmm1"));
+    // a program without a float literal only has integers
+    let src = "(define (f x) (if (< x 1) (+ x 1) x)) (f 0)";
+    let asm = compile_with(src, &Config { inline_size: 0, ..Config::default() }).unwrap();
+    assert!(!asm.contains("xmm") && !asm.contains("call rusl_compare"));
+
+    if !toolchain_available() {
+        return;
+    }
+    assert_eq!(Ok("3.75".to_string()), compile_and_run("(+ 1.5 2.25)"));
+    assert_eq!(Ok("(3.5, -2.5)".to_string()),
+               compile_and_run("(let ((x 1.5)) (tuple (+ x 2) (- (+ x 1))))"));
+    // floats the compiler can't see: an argument, a tuple's element
+    // and a closure's argument
+    assert_eq!(Ok("2.5".to_string()), compile_and_run("(define (f x) (+ x 1)) (f 1.5)"));
+    assert_eq!(Ok("(3.5, 0.75, -1.5)".to_string()),
+               compile_and_run("(let ((t (tuple 1.5 2)) (g (lambda (y) (+ y (- 0.25)))))
+                                  (tuple (+ (tuple-ref t 0) (tuple-ref t 1)) (g 1)
+                                         (- (tuple-ref t 0))))"));
+    assert_eq!(Ok("(#t, #f, #t, #t, #t)".to_string()),
+               compile_and_run("(define (lt a b) (< a b))
+                                (define (same a b) (= a b))
+                                (tuple (lt 1.5 2) (lt 2 1.5) (same 1.5 1.5) (same 2 2.0)
+                                       (lt #\\a #\\b))"));
+}
+
+#[test]
+fn test_label_names() {
+    // `then1` was given the name `then11`, as was the label of the
+    // third `if` in it
+    let src = "(define (then1 x)
+                 (begin (if (< x 0) 0 0) (if (< x 1) 1 0) (if (< x 2) 2 0)
+                        (if (< x 1) 1 2)))
+               (then1 0)";
+    for syntax in vec![Syntax::Intel, Syntax::Att] {
+        let config = Config { syntax: syntax, opt_level: OptLevel::O0, ..Config::default() };
+        let asm = compile_with(src, &config).unwrap();
+        let labels : Vec<&str> = asm.lines().filter(|l| l.ends_with(':')).collect();
+        let unique : HashSet<&&str> = labels.iter().collect();
+        assert_eq!(labels.len(), unique.len(), "{}", asm);
+        assert!(labels.contains(&".Lthen1:"));
+    }
+
+    if toolchain_available() {
+        let config = Config { opt_level: OptLevel::O0, ..Config::default() };
+        assert_eq!(Ok("1".to_string()), compile_and_run_with(src, &config));
+    }
+}
+
+#[test]
+fn test_while() {
+    let src = "(let ((i 1))
+                 (let ((acc 0))
+                   (begin (while (<= i 10)
+                            (begin (set! acc (+ acc i))
+                                   (set! i (+ i 1))))
+                          acc)))";
+    let asm = compile(src).unwrap();
+    assert!(asm.contains("loop"));
+
+    if !toolchain_available() {
+        return;
+    }
+    // the loop-carried variables stay live across the jump back
+    for allocator in vec![Allocator::LinearScan, Allocator::GraphColoring] {
+        let config = Config { allocator: allocator, ..Config::default() };
+        assert_eq!(Ok("55".to_string()), compile_and_run_with(src, &config));
+    }
+}
+
+#[test]
+fn test_heap_size() {
+    // 2000 pairs of 32 bytes each
+    let src = "(let ((i 0))
+                 (let ((l (quote ())))
+                   (begin (while (< i 2000)
+                            (begin (set! l (cons i l))
+                                   (set! i (+ i 1))))
+                          (car l))))";
+    let asm = compile(src).unwrap();
+    assert!(asm.contains(&format!("mov rdi, {}\n    mov rsi, rusl_dynamics\n    \
+                                   mov rdx, rusl_symbols\n    call initialize",
+                                  DEFAULT_HEAP_SIZE)));
+    assert!(asm.contains("call collect"));
+
+    if !toolchain_available() {
+        return;
+    }
+    assert_eq!(Ok("1999".to_string()), compile_and_run(src));
+    // the collector exits before anything is printed
+    let small = Config { heap_size: 4096, ..Config::default() };
+    assert_eq!(Err("exit status 1: error: out of heap space\n".to_string()),
+               compile_and_run_with(src, &small));
+}
+
+#[test]
+fn test_garbage_collection() {
+    // each call to build allocates 1600 bytes, and only the last list,
+    // `keep` and the hash table's entry stay live
+    let src = "(define (build n acc)
+                 (if (= n 0) acc (build (+ n (- 1)) (cons n acc))))
+               (define (sum l acc)
+                 (if (pair? l) (sum (cdr l) (+ acc (car l))) acc))
+               (let ((h (make-hash)))
+                 (let ((i 0) (total 0) (keep (tuple 1 2 3)))
+                   (begin
+                     (hash-set! h keep (cons 7 (quote ())))
+                     (while (< i 200)
+                       (begin (set! total (+ total (sum (build 50 (quote ())) 0)))
+                              (set! i (+ i 1))))
+                     (tuple total keep (hash-ref h keep 0) (+ 1.5 2.25)))))";
+    let asm = compile(src).unwrap();
+    assert!(asm.contains("extern collect"));
+    assert!(asm.contains("mov r15, QWORD [rel rootstack]"));
+
+    if !toolchain_available() {
+        return;
+    }
+    for allocator in vec![Allocator::LinearScan, Allocator::GraphColoring] {
+        let config = Config { allocator: allocator, heap_size: 2048, ..Config::default() };
+        assert_eq!(Ok("(255000, (1, 2, 3), (7, ()), 3.75)".to_string()),
+                   compile_and_run_with(src, &config));
+    }
+}
+
+#[test]
+fn test_vectors() {
+    if !toolchain_available() {
+        return;
+    }
+    let src = "(let ((v (make-vector 3 0)))
+                 (begin (vector-set! v 1 42)
+                        (tuple (vector-ref v 1) (vector-length v) v)))";
+    assert_eq!(Ok("(42, 3, #(0, 42, 0))".to_string()), compile_and_run(src));
+
+    // the tuples stored in the vector, and the variables live when
+    // it's made, survive the collections in between
+    let src = "(define (fill v i)
+                 (if (< i (vector-length v))
+                     (begin (vector-set! v i (cons i (tuple i i i)))
+                            (fill v (+ i 1)))
+                     v))
+               (define (sum v i acc)
+                 (if (< i (vector-length v))
+                     (sum v (+ i 1) (+ acc (car (vector-ref v i))))
+                     acc))
+               (let ((keep (tuple 1 2)) (total 0) (i 0))
+                 (begin
+                   (while (< i 20)
+                     (begin (set! total (+ total (sum (fill (make-vector 10 keep) 0) 0 0)))
+                            (set! i (+ i 1))))
+                   (tuple total keep (vector-ref (make-vector 2 keep) 1))))";
+    for allocator in vec![Allocator::LinearScan, Allocator::GraphColoring] {
+        let config = Config { allocator: allocator, heap_size: 1024, ..Config::default() };
+        assert_eq!(Ok("(900, (1, 2), (1, 2))".to_string()), compile_and_run_with(src, &config));
+    }
+}
+
+#[test]
+fn test_inline() {
+    // inlined, the call is (+ 41 1), which is folded
+    let src = "(define (inc x) (+ x 1))\n(inc 41)";
+    let asm = compile(src).unwrap();
+    assert!(asm.contains("mov rax, 84"));
+    assert!(!asm.contains("\ninc"));
+    let asm = compile_with(src, &Config { inline_size: 0, ..Config::default() }).unwrap();
+    assert!(asm.contains("\ninc"));
+
+    // a recursive function is called as usual
+    let asm = compile("(define (down n) (if (< n 1) 0 (down (+ n (- 1)))))\n(down 3)").unwrap();
+    assert!(asm.contains("\ndown"));
+
+    if !toolchain_available() {
+        return;
+    }
+    // each copy of the body gets its own variables
+    assert_eq!(Ok("(2, 8)".to_string()),
+               compile_and_run("(define (dbl x) (let ((y x)) (+ y y)))
+                                (define (quad x) (dbl (dbl x)))
+                                (tuple (dbl 1) (quad 2))"));
+}
+
+#[test]
+fn test_propagate_constants() {
+    let sym = |s: &str| SExpr::Symbol(s.to_string(), NO_SPAN);
+    let add = |a: SExpr, b: SExpr| SExpr::App(box sym("+"), vec![a, b]);
+    let prog = SExpr::Let(vec![("x".to_string(), SExpr::Number(5))],
+                          box add(sym("x"), sym("x")));
+    assert_eq!(add(SExpr::Number(5), SExpr::Number(5)), propagate_constants(prog));
+    // an assigned variable keeps its binding
+    let prog = SExpr::Let(vec![("x".to_string(), SExpr::Number(5))],
+                          box SExpr::Set("x".to_string(), NO_SPAN, box add(sym("x"), SExpr::Number(1))));
+    assert_eq!(prog.clone(), propagate_constants(prog));
+    // a let of just the variable it binds is the value
+    let prog = SExpr::Let(vec![("x".to_string(), add(SExpr::Number(1), SExpr::Number(2)))],
+                          box sym("x"));
+    assert_eq!(add(SExpr::Number(1), SExpr::Number(2)), propagate_constants(prog));
+    let print = |e: SExpr| SExpr::App(box sym("print"), vec![e]);
+    let prog = SExpr::Let(vec![("y".to_string(), print(SExpr::Number(1))),
+                               ("x".to_string(), print(SExpr::Number(2)))],
+                          box sym("x"));
+    assert_eq!(SExpr::Let(vec![("y".to_string(), print(SExpr::Number(1)))],
+                          box print(SExpr::Number(2))),
+               propagate_constants(prog));
+
+    // which const_fold then folds
+    assert!(compile("(let ((x 5)) (+ x x))").unwrap().contains("mov rax, 20"));
+    // and a lambda doesn't capture the constant
+    let asm = compile("(let ((n 2)) (let ((f (lambda (x) (+ x n)))) (f 40)))").unwrap();
+    assert!(asm.contains("mov QWORD [r11+0], 1\n"));
+
+    if !toolchain_available() {
+        return;
+    }
+    assert_eq!(Ok("(10, 42, 12)".to_string()),
+               compile_and_run("(let ((x 5) (n 2) (y 5))
+                                  (tuple (+ x x)
+                                         ((lambda (z) (+ z n)) 40)
+                                         (begin (set! y 6) (+ y y))))"));
+    assert_eq!(Ok("3".to_string()), compile_and_run("(let ((x (+ 1 2))) x)"));
+}
+
+#[test]
+fn test_tuple_length() {
+    // the size of the tuple is known, so its header isn't read
+    let asm = compile("(tuple-length (tuple 1 2 3))").unwrap();
+    assert!(asm.contains("mov rax, 6"));
+    assert!(!asm.contains("QWORD [r11+0]"));
+
+    let asm = compile_with("(define (len t) (tuple-length t))\n(len (tuple 1 2))",
+                           &Config { inline_size: 0, ..Config::default() }).unwrap();
+    assert!(asm.contains("QWORD [r11+0]"));
+
+    if !toolchain_available() {
+        return;
+    }
+    assert_eq!(Ok("(3, 2)".to_string()),
+               compile_and_run("(define (len t) (tuple-length t))
+                                (tuple (tuple-length (tuple 1 2 3)) (len (tuple 4 5)))"));
+}
+
+#[test]
+fn test_assert() {
+    let asm = compile("(let ((ignored (assert (< 1 2)))) 3)").unwrap();
+    assert!(asm.contains("extern assert_failed"));
+    assert_eq!(asm.matches("call assert_failed").count(), 1);
+}
+
+#[test]
+fn test_hash_table() {
+    let asm = compile("(let ((h (make-hash)))
+                         (let ((ignored (hash-set! h 1 42)))
+                           (+ (hash-ref h 1 0) (hash-ref h 2 (- 1)))))").unwrap();
+    assert!(asm.contains("extern make_hash"));
+    assert!(asm.contains("call make_hash"));
+    assert_eq!(asm.matches("call hash_set").count(), 1);
+    assert_eq!(asm.matches("call hash_ref").count(), 2);
+}
+
+#[test]
+fn test_graph_coloring() {
+    // Each branch of the `if` needs every register a leaf function
+    // has, with rax and rdx, on its own. Both allocators reuse the
+    // registers across the branches, and give a variable the register
+    // of one read for the last time by the instruction that writes
+    // it, so neither spills.
+    let src = "(define (f a)
+                 (if (< a 0)
+                     (+ (+ a 1) (+ (+ a 2) (+ (+ a 3) (+ (+ a 4)
+                        (+ (+ a 5) (+ (+ a 6) (+ a 7)))))))
+                     (+ (+ a 8) (+ (+ a 9) (+ (+ a 10) (+ (+ a 11)
+                        (+ (+ a 12) (+ (+ a 13) (+ a 14)))))))))
+               (f 3)";
+
+    let spills = |allocator: Allocator| -> i64 {
+        let config = Config { allocator: allocator, ..Config::default() };
+        let mut lexer = lexer_for(src);
+        let def = read(&mut lexer).unwrap();
+        let main = read(&mut lexer).unwrap();
+        let mut mapping = HashMap::new();
+        for prim in PRIMITIVES.iter() {
+            mapping.insert(prim.to_string(), prim.to_string());
+        }
+        let uniquified = uniquify(&mut mapping, SExpr::Prog(vec![def], box main)).unwrap();
+        let (converted, _) =
+            convert_to_closures(&HashSet::new(), uniquified, &HashSet::new());
+        let instrs = uncover_live(select_instructions(flatten(converted), &config));
+        match assign_homes(instrs, &config, &mut vec![]) {
+            X86::ProgWithStackSize(defs, _, main_size, _) => {
+                defs.iter().fold(main_size, |acc, def| match def {
+                    &X86::DefineWithStackSize(_, size, _, _) => acc + size,
+                    _ => panic!("not a DefineWithStackSize"),
+                })
+            },
+            _ => panic!("not a ProgWithStackSize"),
+        }
+    };
+
+    assert_eq!(spills(Allocator::LinearScan), 0);
+    assert_eq!(spills(Allocator::GraphColoring), 0);
+}
+
+#[test]
+fn test_emit_map() {
+    let dir = scratch_dir().unwrap();
+    let path = dir.join("map.json").to_string_lossy().into_owned();
+    let src = "(define (add1 x) (+ x 1))
+               (define (sum n) (if (< n 1) 0 (+ n (sum (+ n (- 1))))))
+               (sum (add1 3))";
+    compile_with(src, &Config { emit_map: Some(path.clone()), ..Config::default() }).unwrap();
+    let mut map = String::new();
+    File::open(&path).and_then(|mut f| f.read_to_string(&mut map)).unwrap();
+    let _ = fs::remove_dir_all(&dir);
+
+    // every top-level function is listed, add1 without a label since
+    // it's inlined
+    assert!(map.contains("{\"name\": \"add1\", \"label\": null, \"homes\": {}}"));
+    let sum = map.lines().find(|l| l.contains("\"name\": \"sum\"")).unwrap();
+    assert!(sum.contains("\"label\": \"sum"));
+    assert!(sum.contains("\"n"));
+    assert!(map.contains("{\"name\": null, \"label\": \"main\""));
+}
+
+#[test]
+fn test_stats() {
+    // more variables live at once than there are registers
+    let vars : Vec<String> = (0..20).map(|i| format!("v{}", i)).collect();
+    let bindings : Vec<String> = vars.iter().enumerate()
+        .map(|(i, v)| format!("({} (+ a {}))", v, i)).collect();
+    let src = format!("(define (f a) (let ({}) (tuple {})))\n(f 1)",
+                      bindings.join(" "), vars.join(" "));
+
+    let mut report = Report::default();
+    compile_reporting(&src, &Config { stats: true, ..Config::default() }, &mut report).unwrap();
+    let stats = report.stats;
+    let passes : Vec<&str> = stats.iter().map(|s| s.pass).collect();
+    assert_eq!(vec!["flatten", "optimize", "select_instructions", "assign_homes",
+                    "patch_instructions", "peephole"], passes);
+    assert!(stats.iter().all(|s| s.instrs > 0));
+    assert_eq!(None, stats[2].spilled);
+    assert!(stats[3].spilled.unwrap() > 0);
+
+    let table = stats_table(&stats);
+    assert!(table.starts_with("pass"));
+    assert!(table.lines().next().unwrap().ends_with("spilled"));
+    let homes = table.lines().find(|l| l.starts_with("assign_homes")).unwrap();
+    assert!(homes.ends_with(&format!(" {}", stats[3].spilled.unwrap())));
+}
+
+#[test]
+fn test_large_numbers() {
+    // too large for a 32-bit immediate, so moved through a register
+    let o0 = Config { opt_level: OptLevel::O0, ..Config::default() };
+    let asm = compile_with("(+ 5000000000 1)", &o0).unwrap();
+    assert!(asm.lines().any(|l| l.starts_with("    mov r") && l.ends_with(", 10000000000")));
+    let too_large = format!("(+ {} 1)", MAX_FIXNUM + 1);
+    assert_eq!(format!("{} is too large for an integer, which is at most {}",
+                       MAX_FIXNUM + 1, MAX_FIXNUM),
+               compile(&too_large).unwrap_err().to_string());
+
+    if !toolchain_available() {
+        return;
+    }
+    for config in &[o0, Config::default()] {
+        assert_eq!(Ok("5000000001".to_string()),
+                   compile_and_run_with("(+ 5000000000 1)", config));
+        assert_eq!(Ok("#t".to_string()),
+                   compile_and_run_with("(define (f x) (< x 5000000000)) (f 1)", config));
+        assert_eq!(Ok(MAX_FIXNUM.to_string()),
+                   compile_and_run_with(&format!("(+ {} 0)", MAX_FIXNUM), config));
+    }
+}
+
+#[test]
+fn test_computed_tuple_index() {
+    let src = "(define (get t i) (tuple-ref t i)) (get (tuple 1 2) (tuple-length (tuple 0)))";
+    assert!(compile(src).unwrap().contains(", QWORD [r11+rax*8+8]\n"));
+    let att = Config { syntax: Syntax::Att, ..Config::default() };
+    assert!(compile_with(src, &att).unwrap().contains(" 8(%r11,%rax,8), "));
+    if toolchain_available() {
+        assert_eq!(Ok("2".to_string()), compile_and_run(src));
+    }
+}
+
+#[test]
+fn test_scratch_register() {
+    assert!(!REGS.contains(&SCRATCH_REG));
+    let patched = patch_single_instr(X86::Mov(X86Arg::RegOffset(Reg::RBP, -8),
+                                              X86Arg::RegOffset(Reg::RBP, -16)));
+    assert_eq!(format!("{:?}", vec![
+        X86::Mov(X86Arg::Reg(SCRATCH_REG), X86Arg::RegOffset(Reg::RBP, -16)),
+        X86::Mov(X86Arg::RegOffset(Reg::RBP, -8), X86Arg::Reg(SCRATCH_REG)),
+    ]), format!("{:?}", patched));
+
+    // with linear scan, variables live across the call are spilled,
+    // and are moved between stack slots after it
+    let vars : Vec<String> = (0..12).map(|i| format!("v{}", i)).collect();
+    let bindings : Vec<String> = vars.iter().enumerate()
+        .map(|(i, v)| format!("({} (+ a {}))", v, i)).collect();
+    let src = format!("(define (g x) (+ x 100))
+                       (define (f a) (let ({}) (let ((r (g a))) (tuple r {}))))
+                       (f 0)", bindings.join(" "), vars.join(" "));
+    let linear = Config { allocator: Allocator::LinearScan, ..Config::default() };
+    let asm = compile_with(&src, &linear).unwrap();
+    let lines : Vec<&str> = asm.lines().map(|l| l.trim()).collect();
+    for (i, _) in lines.iter().enumerate().filter(|&(_, l)| l.starts_with("call")) {
+        // the first use of rax after a call reads its result, unless
+        // the result isn't needed, eg. main's exit status after print
+        let mut rest = lines[i + 1..].iter().take_while(|l| !l.ends_with(":") && **l != "ret");
+        if let Some(l) = rest.find(|l| l.contains("rax")) {
+            assert!(l.ends_with(", rax") || *l == "mov rax, 0", "{} after {}", l, lines[i]);
+        }
+    }
+    if toolchain_available() {
+        assert_eq!(Ok("(100, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11)".to_string()),
+                   compile_and_run_with(&src, &linear));
+    }
+}
+
+#[test]
+fn test_live_ranges_across_branches() {
+    let mut ranges = vec![];
+    add_live_range(&mut ranges, 1, 2);
+    add_live_range(&mut ranges, 2, 3);
+    add_live_range(&mut ranges, 7, 8);
+    assert_eq!(ranges, vec![(1, 3), (7, 8)]);
+
+    // `x` is live in the `then` branch and after the `if`, `y` only
+    // in the `else` branch, in between, so they can share a register.
+    let mut live_ranges = HashMap::new();
+    live_ranges.insert("x".to_string(), ranges);
+    live_ranges.insert("y".to_string(), vec![(4, 6)]);
+    live_ranges.insert("z".to_string(), vec![(2, 5)]);
+    let mapping = allocate_registers(live_ranges, &HashSet::new(), &REGS);
+    assert_eq!(mapping.get("x"), mapping.get("y"));
+    assert!(mapping.get("x") != mapping.get("z"));
+}
+
+#[test]
+fn test_live_ranges_at_entry() {
+    // the parameters are both live once they're moved in, so they get
+    // registers of their own, but the sum can have the register of
+    // `a`, which it reads for the last time, rather than a callee-save
+    // one
+    let src = "(define (f a b) (+ a b))\n(f 1 2)";
+    let config = Config { allocator: Allocator::LinearScan, inline_size: 0,
+                          ..Config::default() };
+    let asm = compile_with(src, &config).unwrap();
+    let start = asm.find("\nf").unwrap() + 1;
+    let body = &asm[start..start + asm[start..].find("    ret").unwrap()];
+    assert!(body.contains("    sub rsp, 0\n"));
+    assert_eq!(1, body.matches("push ").count());
+    let home = |arg: &str| {
+        let mov = body.lines().find(|l| l.ends_with(&format!(", {}", arg))).unwrap();
+        mov.trim_left_matches("    mov ").split(',').next().unwrap().to_string()
+    };
+    assert!(home("rsi") != home("rdx"));
+
+    if toolchain_available() {
+        assert_eq!(Ok("3".to_string()), compile_and_run_with(src, &config));
+    }
+}
+
+#[test]
+fn test_nested_lambda_captures() {
+    let src = "(let ((x 42))
+                 (let ((f (lambda (a) (lambda (b) (+ x (+ a b))))))
+                   (let ((g (f 1)))
+                     (g 2))))";
+    let mut lexer = lexer_for(src);
+    let mut mapping = HashMap::new();
+    for prim in PRIMITIVES.iter() {
+        mapping.insert(prim.to_string(), prim.to_string());
+    }
+    let uniquified = uniquify(&mut mapping,
+                              SExpr::Prog(vec![], box read(&mut lexer).unwrap())).unwrap();
+    // the unique names given to `x` and `a`
+    let (x, a) = match uniquified {
+        SExpr::Prog(_, box SExpr::Let(ref x_binds, box SExpr::Let(ref f_binds, _))) =>
+            match f_binds[0].1 {
+                SExpr::Lambda(ref args, _) => (x_binds[0].0.clone(), args[0].clone()),
+                _ => panic!("not a Lambda"),
+            },
+        _ => panic!("unexpected shape: {:?}", uniquified),
+    };
+    let (converted, _) =
+        convert_to_closures(&HashSet::new(), uniquified, &HashSet::new());
+
+    // The variables a lambda's body loads out of its closure, outermost
+    // binding first.
+    fn loads_from_closure(body: &SExpr) -> Vec<String> {
+        match body {
+            &SExpr::Let(ref bindings, ref body) => {
+                let mut loads = loads_from_closure(body);
+                match &bindings[..] {
+                    &[(ref k, SExpr::App(_, ref args))]
+                        if args[0] == SExpr::Symbol("clos".to_string(), NO_SPAN) =>
+                        loads.insert(0, k.clone()),
+                    _ => (),
+                }
+                loads
+            },
+            _ => vec![],
+        }
+    }
+
+    match converted {
+        SExpr::Prog(ref defs, _) => {
+            assert_eq!(defs.len(), 2);
+            let mut loads : Vec<_> = defs.iter().map(|def| match def {
+                &SExpr::Define(_, _, ref body) => loads_from_closure(body),
+                _ => panic!("not a Define"),
+            }).collect();
+            loads.sort_by_key(|l| l.len());
+
+            // the outer lambda captures `x` only, not its own argument
+            assert_eq!(vec![x.clone()], loads[0]);
+            // the inner lambda captures `x` through the outer one
+            let mut inner = loads[1].clone();
+            inner.sort();
+            let mut expected = vec![x, a];
+            expected.sort();
+            assert_eq!(expected, inner);
+        },
+        _ => panic!("not a Prog"),
+    }
+}
+
+#[test]
+fn test_coalesce_moves() {
+    // the result of the first call is copied out of the caller-save
+    // register it comes back in to a callee-save one, to live across
+    // the second call; coalesced, it goes there straight from rax
+    let src = "(define (g y) y)
+               (define (h x)
+                 (let ((a (g x)))
+                   (let ((r (g a)))
+                     (let ((b a))
+                       (let ((c b))
+                         (+ c r))))))
+               (h 1)";
+    let movs = |coalesce: bool| {
+        let config = Config { coalesce_moves: coalesce, inline_size: 0, ..Config::default() };
+        let asm = compile_with(src, &config).unwrap();
+        let start = asm.find("\nh").unwrap();
+        asm[start..].lines().filter(|l| l.starts_with("    mov ")).count()
+    };
+    assert!(movs(true) < movs(false));
+
+    if toolchain_available() {
+        assert_eq!(Ok("2".to_string()), compile_and_run(src));
+    }
+}
+
+#[test]
+fn test_live_across_call() {
+    // five values computed before a call and used after it. There are
+    // only four callee-save registers to keep them in, so one has to
+    // go on the stack rather than in R10.
+    let vars : Vec<String> = (0..5).map(|i| format!("v{}", i)).collect();
+    let mut instrs = vec![];
+    for (i, v) in vars.iter().enumerate() {
+        instrs.push(X86::Mov(X86Arg::Var(v.clone()), X86Arg::Imm(i as u64)));
+    }
+    instrs.push(X86::Call(X86Arg::FuncName("f".to_string())));
+    for v in vars[1..].iter() {
+        instrs.push(X86::Add(X86Arg::Var(vars[0].clone()), X86Arg::Var(v.clone())));
+    }
+    instrs.push(X86::Mov(X86Arg::Reg(Reg::RAX), X86Arg::Var(vars[0].clone())));
+
+    let (_, live_sets, instrs) = get_live_after_sets(instrs, HashSet::new());
+    for allocator in vec![Allocator::LinearScan, Allocator::GraphColoring] {
+        let config = Config { allocator: allocator, ..Config::default() };
+        let (locs, stack_size, _) = decide_locs(&vars, &instrs, live_sets.clone(), &config);
+        assert_eq!(1, stack_size);
+        for v in vars.iter() {
+            match locs.get(v) {
+                Some(&X86Arg::Reg(ref r)) => assert!(!CALLER_SAVE_REGS.contains(r)),
+                _ => (),
+            }
+        }
+    }
+
+    let asm = compile("(define (id x) x)
+                       (let ((a (+ 1 2)))
+                         (+ a (id 4)))").unwrap();
+    assert!(!asm.contains("push r10"));
+}
+
+#[test]
+fn test_duplicate_vars() {
+    // six values live across a call, so two of them go on the stack,
+    // each listed twice
+    let vars : Vec<String> = (0..6).map(|i| format!("v{}", i)).collect();
+    let mut instrs = vec![];
+    for (i, v) in vars.iter().enumerate() {
+        instrs.push(X86::Mov(X86Arg::Var(v.clone()), X86Arg::Imm(i as u64)));
+    }
+    instrs.push(X86::Call(X86Arg::FuncName("f".to_string())));
+    for v in vars[1..].iter() {
+        instrs.push(X86::Add(X86Arg::Var(vars[0].clone()), X86Arg::Var(v.clone())));
+    }
+    instrs.push(X86::Mov(X86Arg::Reg(Reg::RAX), X86Arg::Var(vars[0].clone())));
+
+    let mut listed = vars.clone();
+    listed.extend(vars.clone());
+    let (_, live_sets, instrs) = get_live_after_sets(instrs, HashSet::new());
+    for allocator in vec![Allocator::LinearScan, Allocator::GraphColoring] {
+        let config = Config { allocator: allocator, ..Config::default() };
+        let (locs, stack_size, _) = decide_locs(&listed, &instrs, live_sets.clone(), &config);
+        assert_eq!(2, stack_size);
+        let slots : HashSet<i64> = locs.values().filter_map(|l| match l {
+            &X86Arg::RegOffset(Reg::RBP, offset) => Some(offset),
+            _ => None,
+        }).collect();
+        assert_eq!(2, slots.len());
+    }
+}
+
+#[test]
+fn test_toplevel_begin() {
+    use parser::ParseError;
+
+    let mut lexer = lexer_for("(begin (define a 41) (define (add1 x) (+ x 1)))
+                               (begin (define b 1) (add1 a))");
+    let toplevel = vec![read(&mut lexer).unwrap(), read(&mut lexer).unwrap()];
+
+    let sym = |s: &str| SExpr::Symbol(s.to_string(), NO_SPAN);
+    let add1 = SExpr::Define("add1".to_string(), vec!["x".to_string()],
+                             box SExpr::App(box sym("+"), vec![sym("x"), SExpr::Number(1)]));
+    let main = SExpr::Let(vec![("a".to_string(), SExpr::Number(41))],
+                          box SExpr::Let(vec![("b".to_string(), SExpr::Number(1))],
+                                         box SExpr::App(box sym("add1"), vec![sym("a")])));
+    assert_eq!(Some(SExpr::Prog(vec![add1], box main)), toplevel_prog(toplevel));
+
+    assert!(compile("(begin (define a 1) a)").is_ok());
+    assert!(compile("(begin (define (f x) x))").is_ok());
+    assert!(compile("(begin)").is_err());
+
+    // a definition inside an expression, and a `begin` with nothing to
+    // evaluate, are errors rather than crashes
+    for src in &["(define (f) (define y 1) y)\n(f)", "(if #t (define y 1) 2)"] {
+        match compile(src) {
+            Err(e @ CompileError::Parse(ParseError::NotTopLevel(_))) =>
+                assert_eq!("`define` is only allowed at the top level", e.to_string()),
+            r => panic!("expected NotTopLevel, got {:?}", r),
+        }
+    }
+    match compile("(+ 1 (begin))") {
+        Err(CompileError::Parse(ParseError::BadArity(ref form))) if form == "begin" => (),
+        r => panic!("expected BadArity, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_shadowing_functions() {
+    let mut lexer = lexer_for("(define (foo x) (+ x 1))
+                               (define (foo x) (+ x 100))
+                               (let ((foo 5)) foo)");
+    let first = read(&mut lexer).unwrap();
+    let second = read(&mut lexer).unwrap();
+    let main = read(&mut lexer).unwrap();
+    let mut mapping = HashMap::new();
+    for prim in PRIMITIVES.iter() {
+        mapping.insert(prim.to_string(), prim.to_string());
+    }
+    match uniquify(&mut mapping, SExpr::Prog(vec![first, second], box main)).unwrap() {
+        SExpr::Prog(ref defs, _) => match (&defs[0], &defs[1]) {
+            (&SExpr::Define(ref f1, _, _), &SExpr::Define(ref f2, _, _)) => {
+                assert!(f1 != f2);
+                // the `let` doesn't leave `foo` naming the local
+                assert_eq!(Some(f2), mapping.get("foo"));
+            },
+            defs => panic!("not Defines: {:?}", defs),
+        },
+        prog => panic!("not a Prog: {:?}", prog),
+    }
+
+    if !toolchain_available() {
+        return;
+    }
+    // a call refers to the latest definition before it
+    assert_eq!(Ok("(2, 101)".to_string()),
+               compile_and_run("(define (foo x) (+ x 1))
+                                (define (bar y) (foo y))
+                                (define (foo x) (+ x 100))
+                                (tuple (bar 1) (foo 1))"));
+    assert_eq!(Ok("(11, 25, 3)".to_string()),
+               compile_and_run("(define (foo x) (+ x 1))
+                                (define (baz foo) (foo 5))
+                                (tuple (let ((foo (lambda (z) (+ z 10)))) (foo 1))
+                                       (baz (lambda (w) (+ w 20)))
+                                       (foo 2))"));
+}
+
+#[test]
+fn test_shadowing_lets() {
+    let sym = |s: &str| SExpr::Symbol(s.to_string(), NO_SPAN);
+    let add1 = |e: SExpr| SExpr::App(box sym("+"), vec![e, SExpr::Number(1)]);
+    let inner = SExpr::Let(vec![("x".to_string(), add1(sym("x")))], box sym("x"));
+    let prog = SExpr::Let(vec![("x".to_string(), SExpr::Number(1))], box inner);
+    let mut mapping = HashMap::new();
+    mapping.insert("+".to_string(), "+".to_string());
+    match uniquify(&mut mapping, prog).unwrap() {
+        SExpr::Let(ref outer, box SExpr::Let(ref inner, box SExpr::Symbol(ref body, _))) => {
+            let (ref x1, _) = outer[0];
+            let (ref x2, ref init) = inner[0];
+            assert!(x1 != x2);
+            // the inner initializer reads the outer x, the body the inner
+            assert_eq!(&add1(sym(x1)), init);
+            assert_eq!(x2, body);
+        },
+        prog => panic!("not two lets: {:?}", prog),
+    }
+    assert!(!mapping.contains_key("x"));
+
+    if !toolchain_available() {
+        return;
+    }
+    assert_eq!(Ok("2".to_string()), compile_and_run("(let ((x 1)) (let ((x (+ x 1))) x))"));
+}
+
+#[test]
+fn test_leaf_saves_no_callee_save_regs() {
+    // `x` isn't live across a call, so it gets a caller-save register
+    // and the prologue has nothing to save; `y` is, so g saves the
+    // callee-save register it gets
+    let src = "(define (add1 x) (+ x 1))
+               (define (g y) (+ y (add1 y)))
+               (g 20)";
+    let asm = compile_with(src, &Config { inline_size: 0, ..Config::default() }).unwrap();
+    let body = |name: &str| {
+        let start = asm.find(&format!("\n{}", name)).unwrap() + 1;
+        let end = start + asm[start..].find("    ret\n").unwrap();
+        asm[start..end].to_string()
+    };
+    let saves = |body: &str| -> Vec<Reg> {
+        CALLEE_SAVE_REGS.iter()
+            .filter(|r| body.contains(&format!("push {}\n", display_reg(r))))
+            .cloned()
+            .collect()
+    };
+    assert_eq!(Vec::<Reg>::new(), saves(&body("add1")));
+    assert_eq!(1, saves(&body("g2:")).len());
+
+    if toolchain_available() {
+        assert_eq!(Ok("41".to_string()), compile_and_run(src));
+    }
+}
+
+#[test]
+fn test_spare_regs() {
+    // six values live at once, besides the closure, are one more than
+    // REGS has room for, but a leaf function can use rax and rdx too
+    let src = "(define (f a)
+                 (let ((b (+ a 1)) (c (+ a 2)) (d (+ a 3)) (e (+ a 4)) (g (+ a 5)))
+                   (+ a (+ b (+ c (+ d (+ e g)))))))
+               (f 1)";
+    for allocator in vec![Allocator::GraphColoring, Allocator::LinearScan] {
+        let config = Config { allocator: allocator, ..Config::default() };
+        let asm = compile_with(src, &config).unwrap();
+        let start = asm.find("\nf1:").unwrap();
+        let body = &asm[start..start + asm[start..].find("ret").unwrap()];
+        assert!(!body.contains("rbp-"), "{}", body);
+
+        if toolchain_available() {
+            assert_eq!(Ok("21".to_string()), compile_and_run_with(src, &config));
+        }
+    }
+
+    // a call clobbers them
+    let var = |v: &str| X86Arg::Var(v.to_string());
+    assert_eq!(vec![Reg::RAX, Reg::RDX],
+               spare_regs(&vec![X86::Mov(var("x"), X86Arg::Imm(2)),
+                                X86::Mov(X86Arg::Reg(Reg::RAX), var("x"))]));
+    assert!(spare_regs(&vec![X86::Call(X86Arg::FuncName("f".to_string())),
+                             X86::Mov(X86Arg::Reg(Reg::RAX), var("x"))]).is_empty());
+    // and the scratch register is needed for a 64-bit immediate
+    assert_eq!(vec![Reg::RDX],
+               spare_regs(&vec![X86::Add(var("x"), X86Arg::Imm(CONST_FALSE))]));
+}
+
+#[test]
+fn test_annotate() {
+    let src = "(define (add1 x) (+ x 1))\n(add1 41)";
+    assert!(!compile(src).unwrap().contains(";"));
+
+    let config = Config { annotate: true, inline_size: 0, ..Config::default() };
+    let asm = compile_with(src, &config).unwrap();
+    let lines : Vec<&str> = asm.lines().collect();
+    let add = lines.iter()
+        .position(|l| l.starts_with("    add ") && !l.starts_with("    add rsp")).unwrap();
+    assert!(lines[add - 1].starts_with("    ; + x"));
+    assert!(asm.contains("    ; return "));
+}
+
+#[test]
+fn test_macos_target() {
+    let src = "(define (add1 x) (+ x 1))\n(add1 41)";
+    let linux = compile(src).unwrap();
+    let macos = compile_with(src, &Config { target: Target::MacOs, inline_size: 0,
+                                            ..Config::default() }).unwrap();
+
+    assert!(linux.contains("global main\nmain:\n"));
+    assert!(linux.contains("extern print\n"));
+    assert!(linux.contains("    call print\n"));
+    assert!(macos.contains("global _main\n_main:\n"));
+    assert!(macos.contains("extern _print\n"));
+    assert!(macos.contains("    call _print\n"));
+    assert!(macos.contains("    mov r15, QWORD [rel _rootstack]\n"));
+    assert!(!macos.contains(" print\n"));
+
+    // the function is called by the name it's defined with
+    let start = macos.find("\n_add1").unwrap() + 1;
+    let label = &macos[start..start + macos[start..].find(":\n").unwrap()];
+    assert!(macos.contains(&format!("    mov r10, {}\n", label)));
+
+    let att = Config { syntax: Syntax::Att, target: Target::MacOs, ..Config::default() };
+    assert!(compile_with(src, &att).unwrap().starts_with(".text\n.globl _main\n_main:\n"));
+}
+
+#[test]
+fn test_optional_externs() {
+    assert!(!compile("(print 255)").unwrap().contains("print_hex"));
+    let asm = compile("(define (show x) (print_hex x))\n(show 255)").unwrap();
+    assert!(asm.contains("extern print_hex\n"));
+    assert!(!asm.contains("print_binary"));
+}
+
+#[test]
+fn test_pie() {
+    let src = "(define (add1 x) (+ x 1))\n(add1 41)";
+    let asm = compile(src).unwrap();
+    assert!(asm.starts_with("section .note.GNU-stack noalloc noexec nowrite progbits\n"));
+    assert!(asm.contains("    call print\n"));
+
+    let pie = compile_with(src, &Config { pie: true, ..Config::default() }).unwrap();
+    assert!(pie.contains("    call print wrt ..plt\n"));
+    assert!(pie.contains("    call initialize wrt ..plt\n"));
+    // the program's own functions don't go through the PLT
+    assert!(!pie.lines().any(|l| l.contains("add1") && l.contains("plt")));
+
+    let att = Config { syntax: Syntax::Att, pie: true, ..Config::default() };
+    assert!(compile_with(src, &att).unwrap().contains("    call print@PLT\n"));
+
+    // nor are addresses absolute, whether of the runtime's tables or
+    // of a closure's code
+    let closure = "(define (adder n) (lambda (x) (+ x n)))\n((adder 40) 2)";
+    let pie = compile_with(closure, &Config { pie: true, ..Config::default() }).unwrap();
+    assert!(pie.contains("    lea rsi, [rel rusl_dynamics]\n"));
+    assert!(pie.contains(", [rel lam"));
+    assert!(!pie.lines().any(|l| l.trim_left().starts_with("mov") && l.contains("rusl_")));
+    let att = compile_with(closure, &Config { pie: true, ..att }).unwrap();
+    assert!(att.contains("    leaq rusl_dynamics(%rip), %rsi\n"));
+    assert!(!att.contains("movabsq"));
+    if toolchain_available() {
+        let pie = Config { pie: true, ..Config::default() };
+        assert_eq!(Ok("42".to_string()), compile_and_run_with(closure, &pie));
+    }
+
+    // Mach-O has neither
+    let macos = Config { target: Target::MacOs, pie: true, ..Config::default() };
+    let asm = compile_with(src, &macos).unwrap();
+    assert!(!asm.contains("GNU-stack"));
+    assert!(asm.contains("    call _print\n"));
+}
+
+#[test]
+fn test_att_syntax() {
+    let src = "(define (add1 x) (+ x 1))\n(add1 41)";
+    let att = Config { syntax: Syntax::Att, inline_size: 0, ..Config::default() };
+    let asm = compile_with(src, &att).unwrap();
+    assert!(asm.starts_with(".section .note.GNU-stack,\"\",@progbits\n.text\n.globl main\n"));
+    assert!(!asm.contains("extern"));
+
+    // the same function as in test_leaf_saves_no_callee_save_regs
+    let start = asm.find("\nadd1").unwrap() + 1;
+    let body = &asm[start + asm[start..].find(":\n").unwrap() + 2..];
+    assert_eq!("    pushq %rbp
+    movq %rsp, %rbp
+
+    subq $0, %rsp
+    movq %rdi, %r10
+    movq %rsi, %r10
+    addq $2, %r10
+    movq %r10, %rax
+    movq %rax, %rdi
+    addq $0, %rsp
+
+    movq %rbp, %rsp
+    popq %rbp
+    ret
+.data
+rusl_dynamics:
+    .quad 0
+rusl_symbols:
+    .quad 0
+", body);
+
+    assert_eq!("movq heap(%rip), %r15",
+               print_instr(X86::Mov(X86Arg::Reg(Reg::R15), X86Arg::GlobalVal("heap".to_string())),
+                           &att).trim());
+    assert_eq!("movq $-1, -16(%rbp)",
+               print_instr(X86::Mov(X86Arg::RegOffset(Reg::RBP, -16), X86Arg::Imm(CONST_TRUE)),
+                           &att).trim());
+    assert_eq!("call *%rax",
+               print_instr(X86::Call(X86Arg::Reg(Reg::RAX)), &att).trim());
+    assert_eq!("\"even?1\":",
+               print_instr(X86::Label("even?1".to_string()), &att).trim());
+
+    if toolchain_available() {
+        assert_eq!(Ok("42".to_string()), compile_and_run_with(src, &att));
+    }
+}
+
+#[test]
+fn test_patch_cmp() {
+    fn legal(instr: &X86) -> bool {
+        match instr {
+            &X86::Cmp(X86Arg::Imm(_), _) => false,
+            &X86::Cmp(ref left, ref right) if is_memory(left) && is_memory(right) => false,
+            &X86::Cmp(_, X86Arg::Imm(i)) => (i as i64) == (i as i32 as i64),
+            _ => true,
+        }
+    }
+
+    let mem = |offset| X86Arg::RegOffset(Reg::RBP, offset);
+    let imm64 = X86Arg::Imm(CONST_FALSE);
+    let illegal = vec![
+        X86::Cmp(X86Arg::Imm(2), X86Arg::Reg(Reg::RBX)),
+        X86::Cmp(X86Arg::Imm(2), imm64.clone()),
+        X86::Cmp(mem(-8), mem(-16)),
+        X86::Cmp(X86Arg::GlobalVal("free_ptr".to_string()), mem(-8)),
+        X86::Cmp(mem(-8), imm64.clone()),
+        X86::Cmp(X86Arg::Reg(Reg::RBX), imm64.clone()),
+    ];
+    for instr in illegal {
+        assert!(!legal(&instr));
+        let patched = patch_single_instr(instr.clone());
+        assert!(patched.iter().all(legal), "{:?} patched to {:?}", instr, patched);
+    }
+
+    // legal ones are left alone
+    for instr in vec![X86::Cmp(mem(-8), X86Arg::Imm(2)),
+                      X86::Cmp(X86Arg::Reg(Reg::RBX), mem(-8))] {
+        assert_eq!(format!("{:?}", vec![instr.clone()]),
+                   format!("{:?}", patch_single_instr(instr)));
+    }
+}
+
+#[test]
+fn test_add_to_register() {
+    let var = |v: &str| X86Arg::Var(v.to_string());
+    let rax = X86Arg::Reg(Reg::RAX);
+    let prog = X86::Prog(vec![],
+                         vec![X86::Mov(var("x"), X86Arg::Imm(20)),
+                              X86::Mov(var("y"), X86Arg::Imm(22)),
+                              X86::Mov(rax.clone(), var("x")),
+                              X86::Add(rax.clone(), var("y")),
+                              X86::Add(rax.clone(), X86Arg::Imm(CONST_FALSE))],
+                         vec!["x".to_string(), "y".to_string()]);
+    let live = uncover_live(prog);
+    if let X86::ProgWithLives(_, _, _, ref live_sets) = live {
+        // `y` is live until it's added to rax
+        assert!(live_sets[3].contains("y"));
+        assert!(!live_sets[4].contains("y"));
+    } else {
+        panic!("uncover_live returned {:?}", live);
+    }
+
+    let homes = assign_homes(live, &Config::default(), &mut vec![]);
+    let instrs = match homes {
+        X86::ProgWithStackSize(_, instrs, _, _) => instrs,
+        _ => panic!("assign_homes returned {:?}", homes),
+    };
+    assert!(instrs.iter().all(|i| !format!("{:?}", i).contains("Var")), "{:?}", instrs);
+
+    // rax can't hold the immediate it's being added to
+    let patched = patch_single_instr(X86::Add(rax.clone(), X86Arg::Imm(CONST_FALSE)));
+    assert_eq!(format!("{:?}", vec![X86::Mov(X86Arg::Reg(Reg::R11), X86Arg::Imm(CONST_FALSE)),
+                                    X86::Add(rax.clone(), X86Arg::Reg(Reg::R11))]),
+               format!("{:?}", patched));
+}
+
+#[test]
+fn test_remove_self_moves() {
+    let rbx = || X86Arg::Reg(Reg::RBX);
+    let instrs = vec![
+        X86::Mov(rbx(), X86Arg::Imm(1)),
+        X86::Mov(rbx(), rbx()),
+        X86::Label("l".to_string()),
+        X86::Mov(X86Arg::RegOffset(Reg::RBP, -8), X86Arg::RegOffset(Reg::RBP, -8)),
+        X86::Jmp("l".to_string()),
+        X86::Mov(X86Arg::Reg(Reg::RAX), rbx()),
+    ];
+    let def = X86::DefineWithStackSize("f".to_string(), 1, vec![], instrs.clone());
+    let prog = X86::ProgWithStackSize(vec![def], instrs, 1, vec![]);
+
+    let expected = "[Mov(Reg(RBX), Imm(1)), Label(\"l\"), Jmp(\"l\"), Mov(Reg(RAX), Reg(RBX))]";
+    let once = remove_self_moves(prog);
+    match once.clone() {
+        X86::ProgWithStackSize(defs, instrs, _, _) => {
+            assert_eq!(expected, format!("{:?}", instrs));
+            match defs[0] {
+                X86::DefineWithStackSize(_, _, _, ref instrs) =>
+                    assert_eq!(expected, format!("{:?}", instrs)),
+                _ => panic!("not a DefineWithStackSize"),
+            }
+        },
+        _ => panic!("not a ProgWithStackSize"),
+    }
+
+    // idempotent
+    assert_eq!(format!("{:?}", once.clone()),
+               format!("{:?}", remove_self_moves(once)));
+}
+
+#[test]
+fn test_remove_jumps_to_next() {
+    let label = |l: &str| X86::Label(l.to_string());
+    let jmp = |l: &str| X86::Jmp(l.to_string());
+    let instrs = vec![
+        X86::JmpIf(CC::E, "b".to_string()),
+        jmp("c"),
+        label("a"),
+        label("b"),
+        X86::Neg(X86Arg::Reg(Reg::RAX)),
+        jmp("a"),
+        label("c"),
+        label("d"),
+    ];
+    assert_eq!("[JmpIf(E, \"a\"), Jmp(\"c\"), Label(\"a\"), Neg(Reg(RAX)), Jmp(\"a\"), Label(\"c\")]",
+               format!("{:?}", remove_jumps_to_next(instrs)));
+
+    // the inner `if` ends the outer one's then-branch, so both end
+    // labels land next to each other
+    let src = "(define (f x)
+                 (if (< x 0)
+                     (if (< x (- 10)) 1 2)
+                     3))
+               (tuple (f (- 20)) (f (- 5)) (f 5))";
+    let asm = compile(src).unwrap();
+    let lines : Vec<&str> = asm.lines().collect();
+    for pair in lines.windows(2) {
+        assert!(!(pair[0].ends_with(':') && pair[1].ends_with(':')), "{:?}", pair);
+        assert!(pair[0].trim() != format!("jmp {}", pair[1].trim_right_matches(':')));
+    }
+
+    if toolchain_available() {
+        assert_eq!(Ok("(1, 2, 3)".to_string()), compile_and_run(src));
+    }
+}
+
+#[test]
+fn test_parameterize() {
+    use parser::ParseError;
+
+    let src = "(define-dynamic depth 1)
+               (define (getdepth) depth)
+               (tuple (getdepth)
+                      (parameterize ((depth (+ depth 1))) (getdepth))
+                      (getdepth))";
+    assert!(compile(src).is_ok());
+
+    // the new value is stored, the body evaluated, and the saved value
+    // stored back
+    let mut lexer = lexer_for("(define-dynamic d 1) (parameterize ((d 2)) (+ d 1))");
+    let toplevel = vec![read(&mut lexer).unwrap(), read(&mut lexer).unwrap()];
+    let prog = toplevel_prog(toplevel).unwrap();
+    let mut labels = HashMap::new();
+    dynamic_labels(&prog, &mut labels);
+    let d = SExpr::DynamicRef(labels["d"].clone());
+    let set_d = |val: &str| SExpr::DynamicSet(labels["d"].clone(),
+                                              box SExpr::Symbol(val.to_string(), NO_SPAN));
+    let body = match resolve_dynamics(prog, &labels, &HashSet::new()).unwrap() {
+        SExpr::Prog(_, box SExpr::Begin(body)) => body,
+        prog => panic!("unexpected program {:?}", prog),
+    };
+    assert_eq!(SExpr::DynamicSet(labels["d"].clone(), box SExpr::Number(1)), body[0]);
+    match &body[1] {
+        &SExpr::Let(ref new, box SExpr::Let(ref saved, box SExpr::Begin(ref stores))) => {
+            let (ref new, ref saved) = (new[0].0.clone(), saved[0].0.clone());
+            let result = match &stores[1] {
+                &SExpr::Let(ref result, _) => result[0].0.clone(),
+                e => panic!("expected the body's let, got {:?}", e),
+            };
+            let sum = SExpr::App(box SExpr::Symbol("+".to_string(), NO_SPAN),
+                                 vec![d.clone(), SExpr::Number(1)]);
+            let restore = SExpr::Begin(vec![set_d(saved), SExpr::Symbol(result.clone(), NO_SPAN)]);
+            let stores = SExpr::Begin(vec![set_d(new),
+                                           SExpr::Let(vec![(result, sum)], box restore)]);
+            let saved = SExpr::Let(vec![(saved.clone(), d.clone())], box stores);
+            assert_eq!(SExpr::Let(vec![(new.clone(), SExpr::Number(2))], box saved), body[1]);
+        },
+        e => panic!("expected the parameterize's lets, got {:?}", e),
+    }
+
+    // only a dynamic variable can be parameterized, and only defined at
+    // the top level
+    match compile("(let ((x 1))\n  (parameterize ((x 2)) x))") {
+        Err(e @ CompileError::NotDynamic(_, _)) =>
+            assert_eq!("line 2:4 `parameterize` of 'x', which isn't a dynamic variable",
+                       e.to_string()),
+        r => panic!("expected NotDynamic, got {:?}", r),
+    }
+    for src in &["(define (f) (define-dynamic d 1) d)\n(f)", "(if #t (define-dynamic d 1) 2)"] {
+        match compile(src) {
+            Err(CompileError::Parse(ParseError::NotTopLevel(ref form)))
+                if form == "define-dynamic" => (),
+            r => panic!("expected NotTopLevel, got {:?}", r),
+        }
+    }
+
+    if toolchain_available() {
+        assert_eq!(Ok("(1, 2, 1)".to_string()), compile_and_run(src));
+    }
+}
@@ -0,0 +1,862 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use parser::CC;
+use anf::{Flat, FlatResult};
+
+// Variables assigned anywhere in a list of Flat instructions,
+// including inside the branches of `if`s and the bodies of loops.
+pub fn assigned_vars(instrs: &Vec<Flat>) -> HashSet<String> {
+    let mut assigned = HashSet::new();
+    add_assigned_vars(instrs, &mut assigned);
+    return assigned;
+}
+
+// Add the variables assigned in `instrs` to `assigned`. Nested `if`s
+// add to the same set, so a deep nest doesn't copy it at every level.
+fn add_assigned_vars(instrs: &Vec<Flat>, assigned: &mut HashSet<String>) {
+    for instr in instrs {
+        match instr {
+            &Flat::Assign(ref dest, _) => {
+                assigned.insert(dest.clone());
+            },
+            &Flat::If(_, ref thn, ref els) => {
+                add_assigned_vars(thn, assigned);
+                add_assigned_vars(els, assigned);
+            },
+            &Flat::While(ref test, _, ref body) => {
+                add_assigned_vars(test, assigned);
+                add_assigned_vars(body, assigned);
+            },
+            _ => (),
+        }
+    }
+}
+
+// A primitive and its operands: the variables' names, and the
+// constants as they are printed with {:?}, which no name looks like.
+type PrimKey = (String, Vec<String>);
+
+// Forget every available result that reads from, or was stored into,
+// `var`.
+fn invalidate(avail: &mut HashMap<PrimKey, String>, var: &str) {
+    let stale : Vec<_> = avail.iter()
+        .filter(|&(k, v)| k.1.iter().any(|arg| arg == var) || v == var)
+        .map(|(k, _)| k.clone())
+        .collect();
+    for k in stale {
+        avail.remove(&k);
+    }
+}
+
+// The key of `e` if it is a primitive whose result depends only on
+// its operands. Tuples, pairs and strings are never mutated, but
+// vectors, boxes and hash tables are, and `cons` and `tuple` make a
+// new object, which `eq?` tells apart.
+fn prim_key(e: &Flat) -> Option<PrimKey> {
+    let (f, args) = match e {
+        &Flat::Prim(ref f, ref args) => (f, args),
+        _ => return None,
+    };
+    match &f[..] {
+        "+" | "-" | "min" | "max" | "abs" | "quotient" | "remainder" | "modulo" |
+        "tuple-ref" | "tuple-length" | "car" | "cdr" | "pair?" | "%tuple?" | "eq?" |
+        "string-length" | "symbol->string" => (),
+        _ => return None,
+    }
+    let mut operands = vec![];
+    for arg in args {
+        operands.push(match arg {
+            &Flat::Symbol(ref name) => name.clone(),
+            &Flat::Number(_) | &Flat::Bool(_) | &Flat::Char(_) | &Flat::Nil |
+            &Flat::QuotedSymbol(_) => format!("{:?}", arg),
+            _ => return None,
+        });
+    }
+    return Some((f.clone(), operands));
+}
+
+// `avail` maps a primitive and its operands to the variable holding
+// the result of an earlier computation of it. Variables only change
+// by being assigned, set! included, so calls don't invalidate it.
+fn cse_assigns(instrs: Vec<Flat>, avail: &mut HashMap<PrimKey, String>)
+               -> Vec<Flat> {
+    let mut new_instrs = vec![];
+    for instr in instrs {
+        match instr {
+            Flat::Assign(dest, e) => {
+                let key = prim_key(&e);
+
+                let reuse = match key {
+                    Some(ref k) => avail.get(k).cloned(),
+                    None => None,
+                };
+                invalidate(avail, &dest);
+
+                match (key, reuse) {
+                    (_, Some(prev)) => {
+                        new_instrs.push(Flat::Assign(dest, box Flat::Symbol(prev)));
+                    },
+                    (Some(k), None) => {
+                        if !k.1.contains(&dest) {
+                            avail.insert(k, dest.clone());
+                        }
+                        new_instrs.push(Flat::Assign(dest, e));
+                    },
+                    (None, None) => new_instrs.push(Flat::Assign(dest, e)),
+                }
+            },
+            Flat::If(cnd, thn, els) => {
+                // Results computed before the `if` are available in
+                // both branches, but ones computed inside a branch are
+                // not available after it.
+                let mut clobbered = assigned_vars(&thn);
+                clobbered = clobbered.union(&assigned_vars(&els)).cloned().collect();
+
+                let new_thn = cse_assigns(thn, &mut avail.clone());
+                let new_els = cse_assigns(els, &mut avail.clone());
+                for var in clobbered {
+                    invalidate(avail, &var);
+                }
+
+                new_instrs.push(Flat::If(cnd, new_thn, new_els));
+            },
+            Flat::While(test, cnd, body) => {
+                // Only results from variables the loop doesn't assign
+                // stay available on every iteration.
+                let mut clobbered = assigned_vars(&test);
+                clobbered = clobbered.union(&assigned_vars(&body)).cloned().collect();
+                for var in clobbered.iter() {
+                    invalidate(avail, var);
+                }
+
+                let mut loop_avail = avail.clone();
+                let new_test = cse_assigns(test, &mut loop_avail);
+                let new_body = cse_assigns(body, &mut loop_avail);
+
+                new_instrs.push(Flat::While(new_test, cnd, new_body));
+            },
+            _ => new_instrs.push(instr),
+        }
+    }
+
+    return new_instrs;
+}
+
+// Common-subexpression elimination: a primitive computed again from
+// the same operands, eg. the second `(+ x y)` in `(+ (+ x y) (+ x y))`
+// or `tuple-ref` of the same slot, is replaced by a copy of the first
+// result, as long as none of the operands has been assigned since.
+pub fn eliminate_common_subexprs(prog: FlatResult) -> FlatResult {
+    match prog {
+        FlatResult::Prog(defs, assigns, vars) => {
+            let defs = defs.into_iter().map(|d| eliminate_common_subexprs(d)).collect();
+            return FlatResult::Prog(defs,
+                                    cse_assigns(assigns, &mut HashMap::new()),
+                                    vars);
+        },
+        FlatResult::Define(name, args, assigns, vars) => {
+            return FlatResult::Define(name,
+                                      args,
+                                      cse_assigns(assigns, &mut HashMap::new()),
+                                      vars);
+        },
+        FlatResult::Flat(_, _, _) => prog,
+    }
+}
+
+// `v` with a variable known to hold a constant replaced by it.
+fn const_value(v: Flat, consts: &HashMap<String, Flat>) -> Flat {
+    match v {
+        Flat::Symbol(name) => match consts.get(&name) {
+            Some(c) => c.clone(),
+            None => Flat::Symbol(name),
+        },
+        _ => v,
+    }
+}
+
+// `n` if it fits in a tagged integer
+fn fixnum(n: i64) -> Option<Flat> {
+    if (n << 1) >> 1 == n { Some(Flat::Number(n)) } else { None }
+}
+
+// The value of `e` if it can be computed at compile time. Arithmetic
+// that overflows is left for run time, where it wraps, or traps with
+// `Config::checked_arith`.
+fn fold_expr(e: &Flat) -> Option<Flat> {
+    match e {
+        &Flat::Number(_) | &Flat::Bool(_) | &Flat::Char(_) | &Flat::Nil | &Flat::Void |
+        &Flat::QuotedSymbol(_) => Some(e.clone()),
+        &Flat::Prim(ref f, ref args) => match (&f[..], &args[..]) {
+            ("+", &[Flat::Number(a), Flat::Number(b)]) =>
+                a.checked_add(b).and_then(fixnum),
+            ("-", &[Flat::Number(n)]) => n.checked_neg().and_then(fixnum),
+            // dividing by zero is an error left for run time
+            ("quotient", &[Flat::Number(a), Flat::Number(b)]) =>
+                a.checked_div(b).and_then(fixnum),
+            ("remainder", &[Flat::Number(a), Flat::Number(b)]) =>
+                a.checked_rem(b).and_then(fixnum),
+            ("modulo", &[Flat::Number(a), Flat::Number(b)]) =>
+                a.checked_rem(b).map(|r| if r != 0 && (r < 0) != (b < 0) { r + b } else { r })
+                    .and_then(fixnum),
+            _ => None,
+        },
+        &Flat::Cmp(ref cc, box Flat::Number(a), box Flat::Number(b)) => {
+            let res = match cc {
+                &CC::E => a == b,
+                &CC::NE => a != b,
+                &CC::L => a < b,
+                &CC::LE => a <= b,
+                &CC::G => a > b,
+                &CC::GE => a >= b,
+                &CC::O => return None,
+            };
+            Some(Flat::Bool(res))
+        },
+        _ => None,
+    }
+}
+
+// `consts` maps variables to the literal they were last assigned, and
+// `lengths` maps variables holding a tuple built in this function to
+// its number of elements.
+fn fold_assigns(instrs: Vec<Flat>,
+                consts: &mut HashMap<String, Flat>,
+                lengths: &mut HashMap<String, i64>) -> Vec<Flat> {
+    let mut new_instrs = vec![];
+    for instr in instrs {
+        match instr {
+            Flat::Assign(dest, e) => {
+                let e = match *e {
+                    Flat::Symbol(_) => const_value(*e, consts),
+                    Flat::Prim(f, args) => {
+                        if f == "+" || f == "-" {
+                            let args = args.into_iter().map(|a| const_value(a, consts)).collect();
+                            Flat::Prim(f, args)
+                        } else {
+                            Flat::Prim(f, args)
+                        }
+                    },
+                    Flat::Cmp(cc, left, right) =>
+                        Flat::Cmp(cc,
+                                  box const_value(*left, consts),
+                                  box const_value(*right, consts)),
+                    Flat::Tuple(elts) =>
+                        Flat::Tuple(elts.into_iter().map(|e| const_value(e, consts)).collect()),
+                    Flat::App(f, args) =>
+                        Flat::App(f, args.into_iter().map(|a| const_value(a, consts)).collect()),
+                    e => e,
+                };
+
+                let known_len = match e {
+                    Flat::Prim(ref f, ref args) if f == "tuple-length" => match &args[..] {
+                        &[Flat::Symbol(ref tup)] => lengths.get(tup).cloned(),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                let e = match known_len {
+                    Some(n) => Flat::Number(n),
+                    None => e,
+                };
+
+                let len = match e {
+                    Flat::Tuple(ref elts) => Some(elts.len() as i64),
+                    Flat::Symbol(ref tup) => lengths.get(tup).cloned(),
+                    _ => None,
+                };
+                match len {
+                    Some(n) => {
+                        lengths.insert(dest.clone(), n);
+                    },
+                    None => {
+                        lengths.remove(&dest);
+                    },
+                }
+
+                match fold_expr(&e) {
+                    Some(c) => {
+                        consts.insert(dest.clone(), c.clone());
+                        new_instrs.push(Flat::Assign(dest, box c));
+                    },
+                    None => {
+                        consts.remove(&dest);
+                        new_instrs.push(Flat::Assign(dest, box e));
+                    },
+                }
+            },
+            Flat::Return(v) => new_instrs.push(Flat::Return(box const_value(*v, consts))),
+            Flat::If(cnd, thn, els) => {
+                let cnd = match *cnd {
+                    Flat::Cmp(cc, left, right) => {
+                        let cmp = Flat::Cmp(cc,
+                                            box const_value(*left, consts),
+                                            box const_value(*right, consts));
+                        fold_expr(&cmp).unwrap_or(cmp)
+                    },
+                    cnd => const_value(cnd, consts),
+                };
+                // everything but #f counts as true
+                match cnd {
+                    Flat::Bool(false) =>
+                        new_instrs.extend(fold_assigns(els, consts, lengths)),
+                    Flat::Bool(true) | Flat::Number(_) | Flat::Char(_) | Flat::QuotedSymbol(_) |
+                    Flat::Nil | Flat::Void =>
+                        new_instrs.extend(fold_assigns(thn, consts, lengths)),
+                    cnd => {
+                        let mut clobbered = assigned_vars(&thn);
+                        clobbered = clobbered.union(&assigned_vars(&els)).cloned().collect();
+
+                        let new_thn = fold_assigns(thn, &mut consts.clone(), &mut lengths.clone());
+                        let new_els = fold_assigns(els, &mut consts.clone(), &mut lengths.clone());
+                        for var in clobbered {
+                            consts.remove(&var);
+                            lengths.remove(&var);
+                        }
+
+                        new_instrs.push(Flat::If(box cnd, new_thn, new_els));
+                    },
+                }
+            },
+            Flat::While(test, cnd, body) => {
+                // what the loop assigns differs between iterations
+                let mut clobbered = assigned_vars(&test);
+                clobbered = clobbered.union(&assigned_vars(&body)).cloned().collect();
+                for var in clobbered.iter() {
+                    consts.remove(var);
+                    lengths.remove(var);
+                }
+
+                let mut loop_consts = consts.clone();
+                let mut loop_lengths = lengths.clone();
+                let new_test = fold_assigns(test, &mut loop_consts, &mut loop_lengths);
+                let new_body = fold_assigns(body, &mut loop_consts, &mut loop_lengths);
+
+                new_instrs.push(Flat::While(new_test, cnd, new_body));
+            },
+            _ => new_instrs.push(instr),
+        }
+    }
+
+    return new_instrs;
+}
+
+// Constant folding of primitives and comparisons whose arguments are
+// literals, of `if`s whose condition is a literal, and of
+// `tuple-length` of tuples whose size is known. Variables assigned a
+// literal are replaced by it where it is used, which leaves the
+// assignment itself to eliminate_dead_code.
+pub fn const_fold(prog: FlatResult) -> FlatResult {
+    match prog {
+        FlatResult::Prog(defs, assigns, vars) => {
+            let defs = defs.into_iter().map(|d| const_fold(d)).collect();
+            return FlatResult::Prog(defs,
+                                    fold_assigns(assigns, &mut HashMap::new(), &mut HashMap::new()),
+                                    vars);
+        },
+        FlatResult::Define(name, args, assigns, vars) => {
+            return FlatResult::Define(name,
+                                      args,
+                                      fold_assigns(assigns, &mut HashMap::new(), &mut HashMap::new()),
+                                      vars);
+        },
+        FlatResult::Flat(_, _, _) => prog,
+    }
+}
+
+// Whether computing `e` has no effect besides producing its value.
+// Calls may do anything, and the hash-table primitives write memory
+// that other values can see. With `checked_arith`, the arithmetic
+// that can overflow traps when it does.
+fn is_pure(e: &Flat, checked_arith: bool) -> bool {
+    match e {
+        &Flat::Symbol(_) | &Flat::FuncName(_) | &Flat::Number(_) | &Flat::Bool(_) |
+        &Flat::Char(_) | &Flat::Nil | &Flat::Void | &Flat::Float(_) | &Flat::Str(_) |
+        &Flat::QuotedSymbol(_) | &Flat::Cmp(_, _, _) | &Flat::Tuple(_) => true,
+        &Flat::Prim(ref f, _) => match &f[..] {
+            "+" | "-" | "abs" => !checked_arith,
+            "min" | "max" | "dynamic-ref" | "%unbox" |
+            "cons" | "pair?" | "%tuple?" | "string-length" | "eq?" => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn flat_reads(v: &Flat, read: &mut HashSet<String>) {
+    match v {
+        &Flat::Symbol(ref name) => {
+            read.insert(name.clone());
+        },
+        &Flat::Assign(_, ref e) | &Flat::Return(ref e) => flat_reads(e, read),
+        &Flat::If(ref cnd, ref thn, ref els) => {
+            flat_reads(cnd, read);
+            for i in thn.iter().chain(els.iter()) {
+                flat_reads(i, read);
+            }
+        },
+        &Flat::While(ref test, ref cnd, ref body) => {
+            flat_reads(cnd, read);
+            for i in test.iter().chain(body.iter()) {
+                flat_reads(i, read);
+            }
+        },
+        &Flat::Cmp(_, ref left, ref right) => {
+            flat_reads(left, read);
+            flat_reads(right, read);
+        },
+        &Flat::App(ref f, ref args) => {
+            read.insert(f.clone());
+            for a in args {
+                flat_reads(a, read);
+            }
+        },
+        &Flat::Tuple(ref args) | &Flat::Prim(_, ref args) => {
+            for a in args {
+                flat_reads(a, read);
+            }
+        },
+        &Flat::FuncName(_) | &Flat::Number(_) | &Flat::Bool(_) | &Flat::Char(_) |
+        &Flat::QuotedSymbol(_) | &Flat::Nil | &Flat::Void | &Flat::Float(_) |
+        &Flat::Str(_) => (),
+    }
+}
+
+fn remove_unread_assigns(instrs: Vec<Flat>, read: &HashSet<String>,
+                         checked_arith: bool) -> Vec<Flat> {
+    let mut new_instrs = vec![];
+    for instr in instrs {
+        match instr {
+            Flat::Assign(ref dest, ref e)
+                if !read.contains(dest) && is_pure(e, checked_arith) => (),
+            Flat::If(cnd, thn, els) =>
+                new_instrs.push(Flat::If(cnd,
+                                         remove_unread_assigns(thn, read, checked_arith),
+                                         remove_unread_assigns(els, read, checked_arith))),
+            Flat::While(test, cnd, body) =>
+                new_instrs.push(Flat::While(remove_unread_assigns(test, read, checked_arith),
+                                            cnd,
+                                            remove_unread_assigns(body, read, checked_arith))),
+            _ => new_instrs.push(instr),
+        }
+    }
+
+    return new_instrs;
+}
+
+fn dce_body(mut assigns: Vec<Flat>, vars: Vec<String>, args: &[String], checked_arith: bool)
+            -> (Vec<Flat>, Vec<String>) {
+    // removing an assignment can leave the ones feeding it unread
+    loop {
+        let mut read = HashSet::new();
+        for instr in &assigns {
+            flat_reads(instr, &mut read);
+        }
+        let new_assigns = remove_unread_assigns(assigns.clone(), &read, checked_arith);
+        if new_assigns == assigns {
+            break;
+        }
+        assigns = new_assigns;
+    }
+
+    let assigned = assigned_vars(&assigns);
+    let vars = vars.into_iter()
+        .filter(|v| assigned.contains(v) || args.contains(v))
+        .collect();
+    return (assigns, vars);
+}
+
+fn dce(prog: FlatResult, checked_arith: bool) -> FlatResult {
+    match prog {
+        FlatResult::Prog(defs, assigns, vars) => {
+            let defs = defs.into_iter().map(|d| dce(d, checked_arith)).collect();
+            let (assigns, vars) = dce_body(assigns, vars, &[], checked_arith);
+            return FlatResult::Prog(defs, assigns, vars);
+        },
+        FlatResult::Define(name, args, assigns, vars) => {
+            let (assigns, vars) = dce_body(assigns, vars, &args, checked_arith);
+            return FlatResult::Define(name, args, assigns, vars);
+        },
+        FlatResult::Flat(_, _, _) => prog,
+    }
+}
+
+// Dead-code elimination: drop assignments whose variable is never
+// read, as long as computing the value has no side effects.
+pub fn eliminate_dead_code(prog: FlatResult) -> FlatResult {
+    dce(prog, false)
+}
+
+// eliminate_dead_code for `Config::checked_arith`, which keeps unread
+// arithmetic so that it still traps if it overflows.
+pub fn eliminate_dead_code_checked(prog: FlatResult) -> FlatResult {
+    dce(prog, true)
+}
+
+// How many times each variable is assigned, counting the branches of
+// `if`s and the bodies of loops.
+fn count_assigns(instrs: &Vec<Flat>, counts: &mut HashMap<String, usize>) {
+    for instr in instrs {
+        match instr {
+            &Flat::Assign(ref dest, _) => {
+                *counts.entry(dest.clone()).or_insert(0) += 1;
+            },
+            &Flat::If(_, ref thn, ref els) => {
+                count_assigns(thn, counts);
+                count_assigns(els, counts);
+            },
+            &Flat::While(ref test, _, ref body) => {
+                count_assigns(test, counts);
+                count_assigns(body, counts);
+            },
+            _ => (),
+        }
+    }
+}
+
+// `v` with the variables in `renamed` given their new names.
+fn rename_vars(v: Flat, renamed: &HashMap<String, String>) -> Flat {
+    let rename = |name: String| renamed.get(&name).cloned().unwrap_or(name);
+    match v {
+        Flat::Symbol(name) => Flat::Symbol(rename(name)),
+        Flat::Assign(dest, e) => Flat::Assign(rename(dest), box rename_vars(*e, renamed)),
+        Flat::Return(e) => Flat::Return(box rename_vars(*e, renamed)),
+        Flat::If(cnd, thn, els) =>
+            Flat::If(box rename_vars(*cnd, renamed),
+                     thn.into_iter().map(|i| rename_vars(i, renamed)).collect(),
+                     els.into_iter().map(|i| rename_vars(i, renamed)).collect()),
+        Flat::While(test, cnd, body) =>
+            Flat::While(test.into_iter().map(|i| rename_vars(i, renamed)).collect(),
+                        box rename_vars(*cnd, renamed),
+                        body.into_iter().map(|i| rename_vars(i, renamed)).collect()),
+        Flat::Cmp(cc, left, right) =>
+            Flat::Cmp(cc, box rename_vars(*left, renamed), box rename_vars(*right, renamed)),
+        Flat::App(f, args) =>
+            Flat::App(rename(f), args.into_iter().map(|a| rename_vars(a, renamed)).collect()),
+        Flat::Tuple(args) =>
+            Flat::Tuple(args.into_iter().map(|a| rename_vars(a, renamed)).collect()),
+        Flat::Prim(f, args) =>
+            Flat::Prim(f, args.into_iter().map(|a| rename_vars(a, renamed)).collect()),
+        v => v,
+    }
+}
+
+// Give the temporaries assigned by `instrs` the names of earlier ones
+// whose values are dead. Only variables assigned once, by `instrs`
+// itself rather than a nested `if` or loop, are renamed, and a name is
+// only reused after the instruction that last reads it, so that no
+// instruction writes a variable it also reads. `read_after` holds the
+// variables read once `instrs` is done, eg. by a loop's condition.
+fn reuse_in(instrs: Vec<Flat>,
+            once: &HashSet<String>,
+            read_after: &HashSet<String>,
+            renamed: &mut HashMap<String, String>) -> Vec<Flat> {
+    let mut last_read = HashMap::new();
+    let mut candidates = vec![];
+    for (i, instr) in instrs.iter().enumerate() {
+        let mut read = HashSet::new();
+        flat_reads(instr, &mut read);
+        for v in read {
+            last_read.insert(v, i);
+        }
+        if let &Flat::Assign(ref dest, _) = instr {
+            if dest.starts_with("tmp") && once.contains(dest) &&
+                !last_read.contains_key(dest) &&
+                !read_after.contains(dest) {
+                candidates.push((dest.clone(), i));
+            }
+        }
+    }
+
+    // the candidates whose values are dead after each instruction
+    let mut dead_after : HashMap<usize, Vec<String>> = HashMap::new();
+    for (v, def) in candidates.iter().cloned() {
+        let last = last_read.get(&v).cloned().unwrap_or(def);
+        dead_after.entry(last).or_insert(vec![]).push(v);
+    }
+
+    let candidates : HashSet<String> = candidates.into_iter().map(|(v, _)| v).collect();
+    let mut free = vec![];
+    let mut new_instrs = vec![];
+    for (i, instr) in instrs.into_iter().enumerate() {
+        match instr {
+            Flat::Assign(dest, e) => {
+                if candidates.contains(&dest) {
+                    if let Some(name) = free.pop() {
+                        renamed.insert(dest.clone(), name);
+                    }
+                }
+                new_instrs.push(rename_vars(Flat::Assign(dest, e), renamed));
+            },
+            Flat::If(cnd, thn, els) => {
+                let thn = reuse_in(thn, once, &HashSet::new(), renamed);
+                let els = reuse_in(els, once, &HashSet::new(), renamed);
+                new_instrs.push(Flat::If(box rename_vars(*cnd, renamed), thn, els));
+            },
+            Flat::While(test, cnd, body) => {
+                let mut cnd_reads = HashSet::new();
+                flat_reads(&cnd, &mut cnd_reads);
+                let test = reuse_in(test, once, &cnd_reads, renamed);
+                let body = reuse_in(body, once, &HashSet::new(), renamed);
+                new_instrs.push(Flat::While(test, box rename_vars(*cnd, renamed), body));
+            },
+            instr => new_instrs.push(rename_vars(instr, renamed)),
+        }
+        if let Some(dead) = dead_after.get(&i) {
+            for v in dead {
+                free.push(renamed.get(v).cloned().unwrap_or(v.clone()));
+            }
+        }
+    }
+    return new_instrs;
+}
+
+// Variables copied to or from another variable, by an assignment or
+// by the instructions selected for a primitive, which the register
+// allocator can often give the same home so that the copy goes away.
+fn copied_vars(instrs: &Vec<Flat>, copied: &mut HashSet<String>) {
+    for instr in instrs {
+        match instr {
+            &Flat::Assign(ref dest, box Flat::Symbol(ref src)) => {
+                copied.insert(dest.clone());
+                copied.insert(src.clone());
+            },
+            &Flat::Assign(ref dest, box Flat::Prim(ref f, ref args))
+                if ["+", "-", "min", "max", "abs", "assert"].contains(&&f[..]) => {
+                    let mut read = HashSet::new();
+                    for a in args {
+                        flat_reads(a, &mut read);
+                    }
+                    if !read.is_empty() {
+                        copied.insert(dest.clone());
+                        copied.extend(read);
+                    }
+                },
+            &Flat::If(_, ref thn, ref els) => {
+                copied_vars(thn, copied);
+                copied_vars(els, copied);
+            },
+            &Flat::While(ref test, _, ref body) => {
+                copied_vars(test, copied);
+                copied_vars(body, copied);
+            },
+            _ => (),
+        }
+    }
+}
+
+fn reuse_body(assigns: Vec<Flat>, vars: Vec<String>) -> (Vec<Flat>, Vec<String>) {
+    let mut counts = HashMap::new();
+    count_assigns(&assigns, &mut counts);
+    // sharing a name with another variable would keep a copy from
+    // being coalesced
+    let mut copied = HashSet::new();
+    copied_vars(&assigns, &mut copied);
+    let once = counts.into_iter()
+        .filter(|&(ref v, n)| n == 1 && !copied.contains(v))
+        .map(|(v, _)| v)
+        .collect();
+
+    let mut renamed = HashMap::new();
+    let assigns = reuse_in(assigns, &once, &HashSet::new(), &mut renamed);
+    let vars = vars.into_iter().filter(|v| !renamed.contains_key(v)).collect();
+    return (assigns, vars);
+}
+
+// Reuse temporaries: a variable whose value is no longer needed is
+// assigned again in place of a new one, eg. the temporaries of a
+// nested expression like `(f (g (h x)))`, so that fewer
+// variables are handed to register allocation.
+pub fn reuse_temps(prog: FlatResult) -> FlatResult {
+    match prog {
+        FlatResult::Prog(defs, assigns, vars) => {
+            let defs = defs.into_iter().map(|d| reuse_temps(d)).collect();
+            let (assigns, vars) = reuse_body(assigns, vars);
+            return FlatResult::Prog(defs, assigns, vars);
+        },
+        FlatResult::Define(name, args, assigns, vars) => {
+            let (assigns, vars) = reuse_body(assigns, vars);
+            return FlatResult::Define(name, args, assigns, vars);
+        },
+        FlatResult::Flat(_, _, _) => prog,
+    }
+}
+
+// Move the `Return` at the end of `instrs` as far in as it goes: into
+// both branches of an `if` that ends the function, past copies and
+// constants, and onto the call whose result is returned.
+fn tail_return(mut instrs: Vec<Flat>) -> Vec<Flat> {
+    let ret = match instrs.last() {
+        Some(&Flat::Return(box Flat::Symbol(ref ret))) => ret.clone(),
+        _ => return instrs,
+    };
+    if instrs.len() < 2 {
+        return instrs;
+    }
+
+    let last = instrs.len() - 2;
+    let folded = match instrs[last] {
+        Flat::Assign(ref dest, ref e) if *dest == ret => match **e {
+            Flat::App(_, _) => Some(vec![Flat::Return(e.clone())]),
+            Flat::Symbol(_) | Flat::Number(_) | Flat::Bool(_) | Flat::Char(_) |
+            Flat::QuotedSymbol(_) | Flat::Nil | Flat::Void =>
+                Some(vec![Flat::Return(e.clone())]),
+            _ => None,
+        },
+        Flat::If(ref cnd, ref thn, ref els) => {
+            let mut thn = thn.clone();
+            let mut els = els.clone();
+            thn.push(Flat::Return(box Flat::Symbol(ret.clone())));
+            els.push(Flat::Return(box Flat::Symbol(ret.clone())));
+            Some(vec![Flat::If(cnd.clone(), tail_return(thn), tail_return(els))])
+        },
+        _ => None,
+    };
+
+    match folded {
+        Some(folded) => {
+            instrs.truncate(last);
+            instrs.extend(folded);
+            // a returned copy can expose a call before it
+            match instrs.last() {
+                Some(&Flat::Return(box Flat::Symbol(_))) => return tail_return(instrs),
+                _ => return instrs,
+            }
+        },
+        None => return instrs,
+    }
+}
+
+// A call whose result is returned straight away becomes a
+// `Return(App(..))`, which is compiled to a jump once the caller's
+// frame is gone, so tail-recursive functions run in constant stack
+// space. `main` returns to the runtime, which prints its result, so
+// only the functions are changed.
+pub fn mark_tail_calls(prog: FlatResult) -> FlatResult {
+    match prog {
+        FlatResult::Prog(defs, assigns, vars) => {
+            let defs = defs.into_iter().map(|d| mark_tail_calls(d)).collect();
+            return FlatResult::Prog(defs, assigns, vars);
+        },
+        FlatResult::Define(name, args, assigns, vars) => {
+            return FlatResult::Define(name, args, tail_return(assigns), vars);
+        },
+        FlatResult::Flat(_, _, _) => prog,
+    }
+}
+
+#[test]
+fn test_eliminate_common_subexprs() {
+    use lexer::lexer_for;
+    use parser::{read, SExpr};
+    use anf::flatten;
+
+    fn count_prims(instrs: &Vec<Flat>, prim: &str) -> usize {
+        let mut count = 0;
+        for instr in instrs {
+            match instr {
+                &Flat::Assign(_, box Flat::Prim(ref f, _)) if f == prim =>
+                    count += 1,
+                &Flat::If(_, ref thn, ref els) =>
+                    count += count_prims(thn, prim) + count_prims(els, prim),
+                _ => (),
+            }
+        }
+        return count;
+    }
+
+    // how many times `prim` is computed in `input`, before and after
+    let counts = |input: &str, prim: &str| {
+        let mut lexer = lexer_for(input);
+        let flattened = flatten(SExpr::Prog(vec![], Box::new(read(&mut lexer).unwrap())));
+        let before = match flattened {
+            FlatResult::Prog(_, ref assigns, _) => count_prims(assigns, prim),
+            _ => panic!("not a Prog"),
+        };
+        match eliminate_common_subexprs(flattened) {
+            FlatResult::Prog(_, ref assigns, _) => (before, count_prims(assigns, prim)),
+            _ => panic!("not a Prog"),
+        }
+    };
+
+    assert_eq!((2, 1), counts("(let ((t (tuple 1 2)))
+                                 (+ (tuple-ref t 0) (tuple-ref t 0)))", "tuple-ref"));
+    // the inner sums are computed once, and the outer one adds the
+    // result to itself
+    assert_eq!((3, 2), counts("(let ((x 1) (y 2)) (+ (+ x y) (+ x y)))", "+"));
+    // x changes in between
+    assert_eq!((3, 3), counts("(let ((x 1) (y 2))
+                                 (+ (+ x y) (begin (set! x 5) (+ x y))))", "+"));
+    // each cons is a new pair
+    assert_eq!((2, 2), counts("(let ((x 1)) (eq? (cons x x) (cons x x)))", "cons"));
+}
+
+#[test]
+fn test_const_fold() {
+    use lexer::lexer_for;
+    use parser::{read, SExpr};
+    use anf::flatten;
+
+    let mut lexer = lexer_for("(+ 1 2)");
+
+    let flattened = flatten(SExpr::Prog(vec![], Box::new(read(&mut lexer).unwrap())));
+    match eliminate_dead_code(const_fold(flattened)) {
+        FlatResult::Prog(_, assigns, vars) => {
+            assert_eq!(vec![Flat::Return(box Flat::Number(3))], assigns);
+            assert!(vars.is_empty());
+        },
+        _ => panic!("not a Prog"),
+    }
+
+    let divide = |f: &str, a, b| {
+        fold_expr(&Flat::Prim(f.to_string(), vec![Flat::Number(a), Flat::Number(b)]))
+    };
+    assert_eq!(Some(Flat::Number(-2)), divide("quotient", -7, 3));
+    assert_eq!(Some(Flat::Number(-1)), divide("remainder", -7, 3));
+    assert_eq!(Some(Flat::Number(2)), divide("modulo", -7, 3));
+    assert_eq!(Some(Flat::Number(-2)), divide("modulo", 7, -3));
+    assert_eq!(Some(Flat::Number(0)), divide("modulo", -6, 3));
+    assert_eq!(None, divide("quotient", 1, 0));
+}
+
+#[test]
+fn test_eliminate_dead_code() {
+    use lexer::lexer_for;
+    use parser::{read, SExpr};
+    use anf::flatten;
+
+    let mut lexer = lexer_for("(let ((x (+ 1 2))) 99)");
+
+    let flattened = flatten(SExpr::Prog(vec![], Box::new(read(&mut lexer).unwrap())));
+    match eliminate_dead_code(flattened) {
+        FlatResult::Prog(_, assigns, vars) => {
+            assert_eq!(vec![Flat::Return(box Flat::Number(99))], assigns);
+            assert!(vars.is_empty());
+        },
+        _ => panic!("not a Prog"),
+    }
+}
+
+#[test]
+fn test_reuse_temps() {
+    use parser::{SExpr, NO_SPAN};
+    use anf::flatten;
+
+    let app = |f: &str, arg: SExpr| SExpr::App(box SExpr::FuncName(f.to_string()), vec![arg]);
+    let body = app("f", app("g", app("h", SExpr::Symbol("x".to_string(), NO_SPAN))));
+    let flattened = flatten(SExpr::Define("k".to_string(), vec!["x".to_string()], box body));
+    let temps = |prog: &FlatResult| match prog {
+        &FlatResult::Define(_, _, ref assigns, ref vars) => {
+            let assigned = assigned_vars(assigns);
+            assert!(vars.iter().all(|v| assigned.contains(v)), "{:?}", prog);
+            assigned.len()
+        },
+        _ => panic!("not a Define"),
+    };
+    assert_eq!(6, temps(&flattened));
+    // the temporaries holding h and `(h x)` are dead once they've
+    // been passed on, and their names are reused for the results of g
+    // and f
+    assert_eq!(4, temps(&reuse_temps(flattened)));
+}
@@ -1,29 +1,89 @@
-use std::process;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 
-use lexer::Token;
+use lexer::{LexError, Token};
 use lexer::LexerState;
+#[cfg(test)]
+use lexer::lexer_for;
 use lexer::get_token;
-
-use log;
+use util::get_unique_varname;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum CC {
     // condition codes
-    E, L, LE, G, GE,
+    E, NE, L, LE, G, GE,
+    O,                          // overflow, only used by JmpIf
+}
+
+// The name of the comparison that tests `cc`, as a program writes it.
+pub fn cc_name(cc: &CC) -> &'static str {
+    match cc {
+        &CC::E => "=",
+        &CC::NE => "not=",
+        &CC::L => "<",
+        &CC::LE => "<=",
+        &CC::G => ">",
+        &CC::GE => ">=",
+        &CC::O => "overflow?",
+    }
+}
+
+// A line and column in the source, both counting from 1, or NO_SPAN
+// for code the compiler made up. Where a form was read doesn't change
+// what it means, so spans are equal to each other, and forms compare
+// equal wherever they were read.
+#[derive(Debug, Clone, Copy)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+pub const NO_SPAN : Span = Span { line: 0, col: 0 };
+
+impl Span {
+    pub fn is_known(&self) -> bool {
+        self.line > 0
+    }
+}
+
+impl PartialEq for Span {
+    fn eq(&self, _: &Span) -> bool {
+        true
+    }
+}
+
+impl Eq for Span {}
+
+impl Hash for Span {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum SExpr {
-    Symbol(String),
+    Symbol(String, Span),       // with where it was read, for errors
     Number(i64),
+    Float(u64),                 // the bits of an f64, so SExpr can be Eq
     Bool(bool),
+    Char(char),
+    Str(String),
+    QuotedSymbol(String),       // 'name, a symbol rather than a variable
     List(Vec<SExpr>),
     FuncName(String),           // for closure-conversion
 
     Define(String, Vec<String>, Box<SExpr>),
+    DefineVal(String, Box<SExpr>), // (define name value)
     Let(Vec<(String, SExpr)>, Box<SExpr>),
     Lambda(Vec<String>, Box<SExpr>),
     If(Box<SExpr>, Box<SExpr>, Box<SExpr>),
+    Begin(Vec<SExpr>),
+    Set(String, Span, Box<SExpr>), // (set! name value), with where name was read
+    While(Box<SExpr>, Box<SExpr>), // (while test body)
+    Void,                          // the else of an `if` without one
+
+    DefineDynamic(String, Box<SExpr>), // (define-dynamic name init)
+    Parameterize(Vec<(String, SExpr)>, Box<SExpr>, Span), // with where it was read
+    DynamicRef(String),                // label of the variable's storage
+    DynamicSet(String, Box<SExpr>),
     Tuple(Vec<SExpr>),
     Cmp(CC, Box<SExpr>, Box<SExpr>),
     App(Box<SExpr>, Vec<SExpr>),
@@ -31,6 +91,151 @@ pub enum SExpr {
     EOF,
 }
 
+// Joins the forms in `exprs` with spaces.
+fn show_all(exprs: &[SExpr]) -> String {
+    exprs.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(" ")
+}
+
+fn show_bindings(bindings: &[(String, SExpr)]) -> String {
+    bindings.iter().map(|&(ref k, ref v)| format!("({} {})", k, v)).collect::<Vec<_>>().join(" ")
+}
+
+// An expression written back out as rusl source, for error messages.
+impl fmt::Display for SExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &SExpr::Symbol(ref name, _) | &SExpr::FuncName(ref name) => write!(f, "{}", name),
+            &SExpr::Number(n) => write!(f, "{}", n),
+            &SExpr::Float(bits) => write!(f, "{:?}", f64::from_bits(bits)),
+            &SExpr::Bool(true) => write!(f, "#t"),
+            &SExpr::Bool(false) => write!(f, "#f"),
+            &SExpr::Char(c) => write!(f, "#\\{}", c),
+            &SExpr::Str(ref s) => write!(f, "{:?}", s),
+            &SExpr::QuotedSymbol(ref name) => write!(f, "'{}", name),
+            &SExpr::List(ref elts) => write!(f, "'({})", show_all(elts)),
+            &SExpr::Void => write!(f, "#<void>"),
+            &SExpr::Define(ref name, ref args, ref body) =>
+                write!(f, "(define ({}{}{}) {})", name,
+                       if args.is_empty() { "" } else { " " }, args.join(" "), body),
+            &SExpr::DefineVal(ref name, ref val) => write!(f, "(define {} {})", name, val),
+            &SExpr::Let(ref bindings, ref body) =>
+                write!(f, "(let ({}) {})", show_bindings(bindings), body),
+            &SExpr::Lambda(ref args, ref body) =>
+                write!(f, "(lambda ({}) {})", args.join(" "), body),
+            &SExpr::If(ref cnd, ref thn, box SExpr::Void) => write!(f, "(if {} {})", cnd, thn),
+            &SExpr::If(ref cnd, ref thn, ref els) => write!(f, "(if {} {} {})", cnd, thn, els),
+            &SExpr::Begin(ref body) => write!(f, "(begin {})", show_all(body)),
+            &SExpr::Set(ref name, _, ref val) |
+            &SExpr::DynamicSet(ref name, ref val) => write!(f, "(set! {} {})", name, val),
+            &SExpr::While(ref test, ref body) => write!(f, "(while {} {})", test, body),
+            &SExpr::DefineDynamic(ref name, ref init) =>
+                write!(f, "(define-dynamic {} {})", name, init),
+            &SExpr::Parameterize(ref bindings, ref body, _) =>
+                write!(f, "(parameterize ({}) {})", show_bindings(bindings), body),
+            &SExpr::DynamicRef(ref name) => write!(f, "{}", name),
+            &SExpr::Tuple(ref elts) => write!(f, "(tuple {})", show_all(elts)),
+            &SExpr::Cmp(ref cc, ref left, ref right) =>
+                write!(f, "({} {} {})", cc_name(cc), left, right),
+            &SExpr::App(ref func, ref args) if args.is_empty() => write!(f, "({})", func),
+            &SExpr::App(ref func, ref args) => write!(f, "({} {})", func, show_all(args)),
+            &SExpr::Prog(ref defs, ref main) => {
+                for def in defs {
+                    try!(writeln!(f, "{}", def));
+                }
+                write!(f, "{}", main)
+            },
+            &SExpr::EOF => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    // a Scheme special form that rusl does not support, eg.
+    // `define-syntax`
+    UnknownForm(String),
+    // a `match` whose last clause can fail to match
+    NonExhaustiveMatch,
+    // a `letrec` initializer, other than a lambda, that uses the
+    // variable bound by it or by a later binding
+    LetrecUninitialized(String),
+    // a special form with too few or too many parts, eg. `(if x)`
+    BadArity(String),
+    // a comparison of two literals of different types, eg.
+    // `(= #t 1)`: the comparison, and the types of its arguments
+    MismatchedTypes(String, String, String),
+    // an integer literal too large to be tagged, see MAX_FIXNUM
+    NumberTooLarge(i64),
+    Lex(LexError),
+    // a `)` without a `(`, at this line and column
+    UnmatchedParen(usize, usize),
+    // a `(` the program ends before the `)` of
+    UnclosedParen(usize, usize),
+    // a parameter of a function that isn't a name
+    BadParameter(String),
+    // something other than a name that a `let`, `define` or `set!`
+    // binds, eg. `(let ((true 1)) ...)`
+    BadName(String),
+    // a `#;` with no expression after it to comment out, at this line
+    // and column
+    EmptyDatumComment(usize, usize),
+    // a `let-values` binding of this many names to a `values` of this
+    // many values
+    ValuesMismatch(usize, usize),
+    // a `match` pattern that isn't a literal, a name or a tuple of
+    // patterns
+    BadPattern(String),
+    // a `match` clause that isn't a pattern and a body
+    BadMatchClause(String),
+    // a binding in `let` or `parameterize` that isn't a name and a
+    // value, eg. `(let ((x)) x)`
+    BadBinding(String),
+    // something that can't be quoted
+    BadQuote(String),
+    // a `'` with no expression after it to quote, at this line and
+    // column
+    EmptyQuote(usize, usize),
+    // `()`, which is neither a call nor, unquoted, the empty list
+    EmptyApplication,
+    // a definition inside an expression, such as a function body,
+    // rather than at the top level: the form, eg. `define-dynamic`
+    NotTopLevel(String),
+}
+
+impl From<LexError> for ParseError {
+    fn from(err: LexError) -> ParseError {
+        ParseError::Lex(err)
+    }
+}
+
+// The largest integer a literal can be: integers are shifted left by
+// one bit to tag them, so they have 63 bits. Arithmetic wraps around
+// at the same point.
+pub const MAX_FIXNUM : i64 = (1 << 62) - 1;
+
+// Special forms from Scheme that aren't implemented. A list headed by
+// one of these is reported as an error, rather than being compiled as
+// a call to a function by that name.
+const UNSUPPORTED_FORMS : [&'static str; 17] = [
+    "define-syntax", "let-syntax", "letrec-syntax", "syntax-rules",
+    "define-record-type", "let*", "letrec*",
+    "cond", "case", "and", "or", "when", "unless", "do",
+    "quasiquote", "delay", "case-lambda",
+];
+
+
+// The type of a literal, or None if `expr` isn't one.
+fn literal_type(expr: &SExpr) -> Option<&'static str> {
+    match expr {
+        &SExpr::Number(_) | &SExpr::Float(_) => Some("number"),
+        &SExpr::Bool(_) => Some("boolean"),
+        &SExpr::Char(_) => Some("character"),
+        &SExpr::Str(_) => Some("string"),
+        &SExpr::QuotedSymbol(_) => Some("symbol"),
+        &SExpr::List(_) => Some("list"),
+        _ => None,
+    }
+}
 
 fn unread(ls: &mut LexerState, tok: Token) {
     if let Some(_) = ls.tok_buf {
@@ -42,174 +247,760 @@ fn unread(ls: &mut LexerState, tok: Token) {
 }
 
 
-fn get_list(ls: &mut LexerState) -> Vec<SExpr> {
-    match get_expr(ls) {
-        exp => match get_token(ls) {
-            Token::RParen => return vec![exp],
+// The next token, after any `#;` and the expression it comments out.
+// They can nest, so `#; #; 1 2 3` is read as 3.
+fn next_token(ls: &mut LexerState) -> Result<Token, ParseError> {
+    loop {
+        match try!(get_token(ls)) {
+            Token::DatumComment => {
+                // the lexer is past the `#;`
+                let (line, col) = (ls.line_num, ls.col - 2);
+                match try!(get_token(ls)) {
+                    Token::RParen | Token::EOF =>
+                        return Err(ParseError::EmptyDatumComment(line, col)),
+                    tok => unread(ls, tok),
+                }
+                try!(get_expr(ls));
+            },
+            tok => return Ok(tok),
+        }
+    }
+}
+
+// The elements of a list up to its `)`, which the `(` at line `line`,
+// column `col` opened.
+fn get_list(ls: &mut LexerState, line: usize, col: usize) -> Result<Vec<SExpr>, ParseError> {
+    let mut seq = vec![];
+    loop {
+        match try!(next_token(ls)) {
+            Token::RParen => return Ok(seq),
+            Token::EOF => return Err(ParseError::UnclosedParen(line, col)),
             tok => {
                 unread(ls, tok);
-                let mut seq = get_list(ls);
-                seq.insert(0, exp);
-                return seq;
+                seq.push(try!(get_expr(ls)));
             },
-        },
+        }
     }
 }
 
-pub fn get_expr(ls: &mut LexerState) -> SExpr {
-    match get_token(ls) {
-        Token::Symbol(s) => return SExpr::Symbol(s),
-        Token::Number(n) => return SExpr::Number(n),
+pub fn get_expr(ls: &mut LexerState) -> Result<SExpr, ParseError> {
+    match try!(next_token(ls)) {
+        Token::Symbol(s) => {
+            // the lexer is past the symbol
+            let span = Span { line: ls.line_num, col: ls.col - s.chars().count() };
+            return Ok(SExpr::Symbol(s, span));
+        },
+        Token::Bool(b) => return Ok(SExpr::Bool(b)),
+        Token::Number(n) => return Ok(SExpr::Number(n)),
+        Token::Float(f) => return Ok(SExpr::Float(f.to_bits())),
+        Token::Char(c) => return Ok(SExpr::Char(c)),
+        Token::Str(s) => return Ok(SExpr::Str(s)),
         Token::LParen => {
-            return SExpr::List(get_list(ls));
+            // the lexer is past the `(`
+            let (line, col) = (ls.line_num, ls.col - 1);
+            return Ok(SExpr::List(try!(get_list(ls, line, col))));
+        },
+        // 'x is read as (quote x)
+        Token::Quote => {
+            // the lexer is past the `'`
+            let (line, col) = (ls.line_num, ls.col - 1);
+            match try!(get_expr(ls)) {
+                SExpr::EOF => return Err(ParseError::EmptyQuote(line, col)),
+                quoted => return Ok(SExpr::List(vec![SExpr::Symbol("quote".to_string(), NO_SPAN),
+                                                     quoted])),
+            }
         },
-        Token::RParen => panic!("line {}:{} unmatched ')'",
-                                ls.line_num, ls.col),
-        Token::EOF => return SExpr::EOF,
+        Token::RParen => return Err(ParseError::UnmatchedParen(ls.line_num, ls.col - 1)),
+        Token::EOF => return Ok(SExpr::EOF),
+        Token::DatumComment => unreachable!(),
     }
 }
 
-pub fn get_arg_names(args: &Vec<SExpr>) -> Vec<String> {
+pub fn get_arg_names(args: &Vec<SExpr>) -> Result<Vec<String>, ParseError> {
     let mut arg_names = vec![];
     for arg in args {
         match arg {
-            &SExpr::Symbol(ref name) => arg_names.push(name.clone()),
-            _ => {
-                error!("arg should be a Symbol");
-                process::exit(0);
+            &SExpr::Symbol(ref name, _) => arg_names.push(name.clone()),
+            _ => return Err(ParseError::BadParameter(arg.to_string())),
+        }
+    }
+
+    return Ok(arg_names);
+}
+
+// The value of a quoted expression. Only numbers, booleans,
+// characters, strings, symbols and lists of them can be quoted; a
+// quoted list stays a List.
+fn get_datum(expr: &SExpr) -> Result<SExpr, ParseError> {
+    match expr {
+        &SExpr::Number(_) | &SExpr::Float(_) | &SExpr::Bool(_) | &SExpr::Char(_) |
+        &SExpr::Str(_) => Ok(expr.clone()),
+        &SExpr::Symbol(ref name, _) => Ok(SExpr::QuotedSymbol(name.clone())),
+        &SExpr::List(ref elts) => {
+            let mut datums = vec![];
+            for e in elts {
+                datums.push(try!(get_datum(e)));
+            }
+            Ok(SExpr::List(datums))
+        },
+        _ => Err(ParseError::BadQuote(expr.to_string())),
+    }
+}
+
+// The parameters of a `define` or `lambda`. With a rest parameter, as
+// in (f a . rest), `body` is wrapped in a `let` binding it to the list
+// of the arguments after the others.
+fn get_params(params: &Vec<SExpr>, body: SExpr) -> Result<(Vec<String>, SExpr), ParseError> {
+    match params.iter().position(|p| p == &SExpr::Symbol(".".to_string(), NO_SPAN)) {
+        Some(dot) if dot + 2 == params.len() => {
+            let fixed = try!(get_arg_names(&params[..dot].to_vec()));
+            let rest = match params[dot + 1] {
+                SExpr::Symbol(ref rest, _) => rest.clone(),
+                ref p => return Err(ParseError::BadParameter(p.to_string())),
+            };
+            let rest_args = SExpr::App(box SExpr::Symbol("%rest-args".to_string(), NO_SPAN),
+                                       vec![SExpr::Number(fixed.len() as i64)]);
+            Ok((fixed, SExpr::Let(vec![(rest, rest_args)], box body)))
+        },
+        // `.` should be followed by exactly one parameter
+        Some(_) => Err(ParseError::BadParameter(".".to_string())),
+        None => Ok((try!(get_arg_names(params)), body)),
+    }
+}
+
+// Adds the tests `v` has to pass to match `pat` to `tests`, and the
+// variables `pat` binds to parts of `v` to `bindings`. Returns whether
+// `pat` can fail to match a value of its shape, ie. if it has a
+// literal in it.
+fn match_pattern(pat: &SExpr, v: SExpr, tests: &mut Vec<SExpr>,
+                 bindings: &mut Vec<(String, SExpr)>) -> Result<bool, ParseError> {
+    let eq = |f: &str, v: SExpr, lit: SExpr| SExpr::App(box SExpr::Symbol(f.to_string(), NO_SPAN),
+                                                        vec![v, lit]);
+    let test = match pat {
+        &SExpr::Symbol(ref sym, _) if sym == "_" => return Ok(false),
+        &SExpr::Bool(_) => eq("eq?", v, pat.clone()),
+        &SExpr::Symbol(ref name, _) => {
+            bindings.push((name.clone(), v));
+            return Ok(false);
+        },
+        &SExpr::Number(_) => SExpr::Cmp(CC::E, box v, box pat.clone()),
+        &SExpr::Char(_) => eq("eq?", v, pat.clone()),
+        &SExpr::Float(_) | &SExpr::Str(_) => eq("equal?", v, pat.clone()),
+        &SExpr::List(ref quoted) if quoted.len() == 2 &&
+            quoted[0] == SExpr::Symbol("quote".to_string(), NO_SPAN) => match quoted[1] {
+                SExpr::Symbol(ref name, _) =>
+                    eq("eq?", v, SExpr::QuotedSymbol(name.clone())),
+                _ => eq("equal?", v, try!(get_datum(&quoted[1]))),
+            },
+        &SExpr::List(ref pats) if pats.is_empty() =>
+            SExpr::Cmp(CC::E, box v, box SExpr::List(vec![])),
+        // a tuple, checked before its length is read
+        &SExpr::List(ref pats) => {
+            tests.push(SExpr::App(box SExpr::Symbol("%tuple?".to_string(), NO_SPAN),
+                                  vec![v.clone()]));
+            let len = SExpr::App(box SExpr::Symbol("tuple-length".to_string(), NO_SPAN), vec![v.clone()]);
+            tests.push(SExpr::Cmp(CC::E, box len, box SExpr::Number(pats.len() as i64)));
+            let mut refutable = false;
+            for (i, p) in pats.iter().enumerate() {
+                let elt = SExpr::App(box SExpr::Symbol("tuple-ref".to_string(), NO_SPAN),
+                                     vec![v.clone(), SExpr::Number(i as i64)]);
+                refutable |= try!(match_pattern(p, elt, tests, bindings));
             }
+            return Ok(refutable);
+        },
+        _ => return Err(ParseError::BadPattern(pat.to_string())),
+    };
+    tests.push(test);
+    return Ok(true);
+}
+
+// (match subject (pattern body) ...) becomes a chain of `if`s, one per
+// clause, with the subject bound to a variable programs can't name.
+fn get_match(subject: &SExpr, clauses: &[SExpr]) -> Result<SExpr, ParseError> {
+    let var = get_unique_varname("%match");
+    let mut arms = vec![];
+    for clause in clauses {
+        let (pat, body) = match clause {
+            &SExpr::List(ref elts) if elts.len() == 2 => (&elts[0], &elts[1]),
+            _ => return Err(ParseError::BadMatchClause(clause.to_string())),
+        };
+        let mut tests = vec![];
+        let mut bindings = vec![];
+        let refutable = try!(match_pattern(pat, SExpr::Symbol(var.clone(), NO_SPAN),
+                                           &mut tests, &mut bindings));
+        let mut body = try!(get_ast(body));
+        if !bindings.is_empty() {
+            body = SExpr::Let(bindings, box body);
+        }
+        arms.push((refutable, tests, body));
+    }
+
+    // the last clause has to match anything of the right shape. A
+    // value of another shape is an error at run time.
+    match arms.last() {
+        Some(&(false, _, _)) => (),
+        _ => return Err(ParseError::NonExhaustiveMatch),
+    }
+    let mut result = SExpr::App(box SExpr::Symbol("%match-error".to_string(), NO_SPAN), vec![]);
+    while let Some((_, tests, body)) = arms.pop() {
+        // each test only runs if the ones before it passed
+        let mut test = None;
+        for t in tests.into_iter().rev() {
+            test = Some(match test {
+                None => t,
+                Some(rest) => SExpr::If(box t, box rest, box SExpr::Bool(false)),
+            });
         }
+        result = match test {
+            Some(test) => SExpr::If(box test, box body, box result),
+            None => body,
+        };
+    }
+
+    return Ok(SExpr::Let(vec![(var, try!(get_ast(subject)))], box result));
+}
+
+// ((name value) ...), as in `let` and `parameterize`
+fn get_bindings(bindings: &Vec<SExpr>) -> Result<Vec<(String, SExpr)>, ParseError> {
+    let mut astified_bindings = vec![];
+    for bind_pair in bindings {
+        let (key, val) = match bind_pair {
+            &SExpr::List(ref kv) if kv.len() == 2 => (kv[0].clone(), kv[1].clone()),
+            _ => return Err(ParseError::BadBinding(bind_pair.to_string())),
+        };
+
+        let keyname = match key {
+            SExpr::Symbol(k, _) => k,
+            _ => return Err(ParseError::BadName(key.to_string())),
+        };
+        astified_bindings.push((keyname, try!(get_ast(&val))));
+    }
+
+    return Ok(astified_bindings);
+}
+
+// (let name ((var init) ...) body) calls a local function `name`,
+// which takes the vars and whose body can call `name`, with the inits.
+// It becomes
+//
+//   (let ((init-var init) ...)
+//     (let ((name #f))
+//       (begin (set! name (lambda (var ...) body))
+//              (name init-var ...))))
+//
+// `name` is assigned and captured, so box_mutated boxes it and the
+// lambda sees itself. The inits are evaluated before `name` is bound.
+fn get_named_let(name: &String, bindings: &Vec<SExpr>, body: &SExpr)
+                 -> Result<SExpr, ParseError> {
+    let mut params = vec![];
+    let mut init_bindings = vec![];
+    let mut call_args = vec![];
+    for (var, init) in try!(get_bindings(bindings)) {
+        let init_var = get_unique_varname("init");
+        params.push(var);
+        call_args.push(SExpr::Symbol(init_var.clone(), NO_SPAN));
+        init_bindings.push((init_var, init));
     }
 
-    return arg_names;
+    let lambda = SExpr::Lambda(params, box try!(get_ast(body)));
+    let call = SExpr::App(box SExpr::Symbol(name.clone(), NO_SPAN), call_args);
+    let loop_body = SExpr::Begin(vec![SExpr::Set(name.clone(), NO_SPAN, box lambda), call]);
+    return Ok(SExpr::Let(init_bindings,
+                         box SExpr::Let(vec![(name.clone(), SExpr::Bool(false))],
+                                        box loop_body)));
 }
 
-pub fn get_ast(expr: &SExpr) -> SExpr {
+// Whether evaluating `expr` reads `name`, other than in the body of a
+// lambda, which may run later.
+fn reads_eagerly(expr: &SExpr, name: &String) -> bool {
+    let any = |es: &Vec<SExpr>| es.iter().any(|e| reads_eagerly(e, name));
     match expr {
-        &SExpr::Symbol(ref sym) => {
-            match &sym[..] {
-                "#f" => SExpr::Bool(false),
-                "#t" => SExpr::Bool(true),
-                _ => SExpr::Symbol(sym.clone()),
+        &SExpr::Symbol(ref sym, _) => sym == name,
+        &SExpr::Let(ref bindings, ref body) | &SExpr::Parameterize(ref bindings, ref body, _) => {
+            bindings.iter().any(|&(_, ref v)| reads_eagerly(v, name)) ||
+                (!bindings.iter().any(|&(ref k, _)| k == name) && reads_eagerly(body, name))
+        },
+        &SExpr::If(ref cnd, ref thn, ref els) =>
+            reads_eagerly(cnd, name) || reads_eagerly(thn, name) || reads_eagerly(els, name),
+        &SExpr::Cmp(_, ref left, ref right) | &SExpr::While(ref left, ref right) =>
+            reads_eagerly(left, name) || reads_eagerly(right, name),
+        &SExpr::Set(_, _, ref val) => reads_eagerly(val, name),
+        &SExpr::Begin(ref es) | &SExpr::Tuple(ref es) => any(es),
+        &SExpr::App(ref f, ref args) => reads_eagerly(f, name) || any(args),
+        _ => false,
+    }
+}
+
+// (letrec ((name init) ...) body) binds the names before evaluating
+// any init, so that the inits, typically lambdas, can refer to each
+// other:
+//
+//   (let ((name #f) ...)
+//     (begin (set! name init) ... body))
+fn get_letrec(bindings: &Vec<SExpr>, body: &SExpr) -> Result<SExpr, ParseError> {
+    let bindings = try!(get_bindings(bindings));
+    for (i, &(_, ref init)) in bindings.iter().enumerate() {
+        if let &SExpr::Lambda(_, _) = init {
+            continue;
+        }
+        for &(ref later, _) in bindings[i..].iter() {
+            if reads_eagerly(init, later) {
+                return Err(ParseError::LetrecUninitialized(later.clone()));
             }
+        }
+    }
+
+    let mut names = vec![];
+    let mut body_exprs = vec![];
+    for (name, init) in bindings {
+        names.push((name.clone(), SExpr::Bool(false)));
+        body_exprs.push(SExpr::Set(name, NO_SPAN, box init));
+    }
+    body_exprs.push(try!(get_ast(body)));
+    return Ok(SExpr::Let(names, box SExpr::Begin(body_exprs)));
+}
+
+// Check that every `values` `expr` can give as its value has `count`
+// values, and say whether all of them are `values`. Values that come
+// from a call are checked at run time.
+fn check_values(expr: &SExpr, count: usize) -> Result<bool, ParseError> {
+    match expr {
+        &SExpr::Tuple(ref vals) if vals.len() != count =>
+            Err(ParseError::ValuesMismatch(count, vals.len())),
+        &SExpr::Tuple(_) => Ok(true),
+        &SExpr::Let(_, ref body) | &SExpr::Parameterize(_, ref body, _) => check_values(body, count),
+        &SExpr::Begin(ref es) if !es.is_empty() => check_values(&es[es.len() - 1], count),
+        &SExpr::If(_, ref thn, ref els) => {
+            let thn_checked = try!(check_values(thn, count));
+            let els_checked = try!(check_values(els, count));
+            Ok(thn_checked && els_checked)
         },
+        _ => Ok(false),
+    }
+}
+
+// (let-values (((name ...) init) ...) body) binds the names to the
+// elements of the tuple made by `values` in each init:
+//
+//   (let ((vals init) ...)
+//     (let ((name (tuple-ref vals 0)) ...)
+//       body))
+//
+// Like `let`, all the inits are evaluated before any name is bound.
+// When an init's values aren't all visible, the names are only bound
+// if `vals` is a tuple of as many values as there are names, and it's
+// an error at run time otherwise.
+fn get_let_values(bindings: &Vec<SExpr>, body: &[SExpr]) -> Result<SExpr, ParseError> {
+    let mut init_bindings = vec![];
+    let mut name_bindings = vec![];
+    let mut unchecked = vec![];
+    for binding in bindings {
+        let (names, init) = match binding {
+            &SExpr::List(ref elts) if elts.len() == 2 => match &elts[0] {
+                &SExpr::List(ref names) => (try!(get_arg_names(names)), try!(get_ast(&elts[1]))),
+                _ => return Err(ParseError::BadArity("let-values".to_string())),
+            },
+            _ => return Err(ParseError::BadArity("let-values".to_string())),
+        };
+        let vals = get_unique_varname("%values");
+        if !try!(check_values(&init, names.len())) {
+            unchecked.push((vals.clone(), names.len()));
+        }
+
+        for (i, name) in names.into_iter().enumerate() {
+            let elt = SExpr::App(box SExpr::Symbol("tuple-ref".to_string(), NO_SPAN),
+                                 vec![SExpr::Symbol(vals.clone(), NO_SPAN), SExpr::Number(i as i64)]);
+            name_bindings.push((name, elt));
+        }
+        init_bindings.push((vals, init));
+    }
+
+    let mut body = SExpr::Let(name_bindings, box try!(get_body(body)));
+    while let Some((vals, count)) = unchecked.pop() {
+        let vals = SExpr::Symbol(vals, NO_SPAN);
+        let is_tuple = SExpr::App(box SExpr::Symbol("%tuple?".to_string(), NO_SPAN),
+                                  vec![vals.clone()]);
+        let len = SExpr::App(box SExpr::Symbol("tuple-length".to_string(), NO_SPAN), vec![vals]);
+        let test = SExpr::If(box is_tuple,
+                             box SExpr::Cmp(CC::E, box len, box SExpr::Number(count as i64)),
+                             box SExpr::Bool(false));
+        body = SExpr::If(box test,
+                         box body,
+                         box SExpr::App(box SExpr::Symbol("%values-error".to_string(), NO_SPAN),
+                                        vec![]));
+    }
+    return Ok(SExpr::Let(init_bindings, box body));
+}
+
+// The body of a function or `let`, which is a `begin` if it has more
+// than one expression. None of them can be a definition.
+fn get_body(exprs: &[SExpr]) -> Result<SExpr, ParseError> {
+    if let &[ref body] = exprs {
+        return get_ast(body);
+    }
+    let mut body = vec![];
+    for e in exprs {
+        body.push(try!(get_ast(e)));
+    }
+    return Ok(SExpr::Begin(body));
+}
+
+// The condition a comparison like `<` tests, or None if `name` isn't
+// one.
+fn comparison_cc(name: &str) -> Option<CC> {
+    match name {
+        ">" => Some(CC::G),
+        "<" => Some(CC::L),
+        ">=" => Some(CC::GE),
+        "<=" => Some(CC::LE),
+        "=" => Some(CC::E),
+        "not=" | "/=" => Some(CC::NE),
+        _ => None,
+    }
+}
+
+// A form at the top level of the program, which unlike one inside an
+// expression can be a definition. So can the forms of a `begin` here,
+// which are spliced into the program, and so it can also be empty.
+pub fn get_toplevel(expr: &SExpr) -> Result<SExpr, ParseError> {
+    match expr {
         &SExpr::List(ref elts) =>
             match &elts[..] {
-                &[SExpr::Symbol(ref k), SExpr::List(ref defelts), ref body]
+                &[SExpr::Symbol(ref k, _), SExpr::List(ref defelts), _, _..]
                     if k == "define" => {
-                    let ref name = defelts[0];
-                    let args = defelts[1..].to_vec();
-
-                    match name {
-                        &SExpr::Symbol(ref name) => {
-                            return SExpr::Define(name.clone(), get_arg_names(&args.to_vec()),
-                                                 Box::new(get_ast(body)));
+                    match &defelts[..] {
+                        &[SExpr::Symbol(ref name, _), ref args..] => {
+                            let (params, body) = try!(get_params(&args.to_vec(), try!(get_body(&elts[2..]))));
+                            return Ok(SExpr::Define(name.clone(), params, box body));
                         },
-                        _ => panic!("invalid function prototype"),
+                        &[ref name, _..] => return Err(ParseError::BadName(name.to_string())),
+                        &[] => return Err(ParseError::BadArity(k.clone())),
                     }
                 },
-                &[SExpr::Symbol(ref k), ref cnd, ref thn, ref els]
+                &[SExpr::Symbol(ref k, _), SExpr::Symbol(ref name, _), ref val]
+                    if k == "define" => {
+                    return Ok(SExpr::DefineVal(name.clone(), box try!(get_ast(val))));
+                },
+                &[SExpr::Symbol(ref k, _), ref name, _]
+                    if k == "define" => {
+                    return Err(ParseError::BadName(name.to_string()));
+                },
+                &[SExpr::Symbol(ref k, _), _..]
+                    if k == "define" => {
+                    return Err(ParseError::BadArity(k.clone()));
+                },
+                &[SExpr::Symbol(ref k, _), SExpr::Symbol(ref name, _), ref init]
+                    if k == "define-dynamic" => {
+                        return Ok(SExpr::DefineDynamic(name.clone(), box try!(get_ast(init))));
+                    },
+                &[SExpr::Symbol(ref k, _), _..]
+                    if k == "begin" => {
+                        let mut body = vec![];
+                        for e in elts[1..].iter() {
+                            body.push(try!(get_toplevel(e)));
+                        }
+                        return Ok(SExpr::Begin(body));
+                    },
+                _ => get_ast(expr),
+            },
+        _ => get_ast(expr),
+    }
+}
+
+pub fn get_ast(expr: &SExpr) -> Result<SExpr, ParseError> {
+    match expr {
+        &SExpr::Number(n) if n > MAX_FIXNUM => Err(ParseError::NumberTooLarge(n)),
+        &SExpr::List(ref elts) =>
+            match &elts[..] {
+                &[SExpr::Symbol(ref k, _), _..]
+                    if k == "define" || k == "define-dynamic" => {
+                        return Err(ParseError::NotTopLevel(k.clone()));
+                    },
+                &[SExpr::Symbol(ref k, _)]
+                    if k == "begin" => {
+                        return Err(ParseError::BadArity(k.clone()));
+                    },
+                &[SExpr::Symbol(ref k, _), _..]
+                    if k == "begin" => {
+                        let mut body = vec![];
+                        for e in elts[1..].iter() {
+                            body.push(try!(get_ast(e)));
+                        }
+                        return Ok(SExpr::Begin(body));
+                    },
+                &[SExpr::Symbol(ref k, _), ref cnd, ref thn, ref els]
                     if k == "if" => {
-                    return SExpr::If(Box::new(get_ast(cnd)),
-                                     Box::new(get_ast(thn)),
-                                     Box::new(get_ast(els)));
+                    return Ok(SExpr::If(Box::new(try!(get_ast(cnd))),
+                                        Box::new(try!(get_ast(thn))),
+                                        Box::new(try!(get_ast(els)))));
                     },
-                &[SExpr::Symbol(ref k), SExpr::List(ref bindings), ref body]
+                &[SExpr::Symbol(ref k, _), ref cnd, ref thn]
+                    if k == "if" => {
+                    return Ok(SExpr::If(Box::new(try!(get_ast(cnd))),
+                                        Box::new(try!(get_ast(thn))),
+                                        Box::new(SExpr::Void)));
+                    },
+                &[SExpr::Symbol(ref k, _), _..]
+                    if k == "if" => {
+                        return Err(ParseError::BadArity(k.clone()));
+                    },
+                &[SExpr::Symbol(ref k, _), ref test, ref body]
+                    if k == "while" => {
+                        return Ok(SExpr::While(box try!(get_ast(test)),
+                                               box try!(get_ast(body))));
+                    },
+                &[SExpr::Symbol(ref k, _), SExpr::List(ref bindings), _, _..]
                     if k == "let" => {
-                        let mut astified_bindings = vec![];
-                        for bind_pair in bindings {
-                            let (key, val) = match bind_pair {
-                                // TODO: check length
-                                &SExpr::List(ref kv) => (kv[0].clone(), kv[1].clone()),
-                                _ => panic!("non-list in let-binding"),
-                            };
-
-                            let keyname = match key {
-                                SExpr::Symbol(k) => k,
-                                _ => panic!("let binding key is not symbol"),
-                            };
-                            astified_bindings.push((keyname, get_ast(&val)));
-                        }
-                        return SExpr::Let(astified_bindings, Box::new(get_ast(&body)));
+                        return Ok(SExpr::Let(try!(get_bindings(bindings)),
+                                             Box::new(try!(get_body(&elts[2..])))));
+                    },
+                &[SExpr::Symbol(ref k, _), SExpr::Symbol(ref name, _), SExpr::List(ref bindings), ref body]
+                    if k == "let" => {
+                        return get_named_let(name, bindings, body);
+                    },
+                &[SExpr::Symbol(ref k, _), SExpr::List(ref bindings), ref body]
+                    if k == "letrec" => {
+                        return get_letrec(bindings, body);
+                    },
+                &[SExpr::Symbol(ref k, _), SExpr::List(ref bindings), _, _..]
+                    if k == "let-values" => {
+                        return get_let_values(bindings, &elts[2..]);
+                    },
+                &[SExpr::Symbol(ref k, span), SExpr::List(ref bindings), ref body]
+                    if k == "parameterize" => {
+                        return Ok(SExpr::Parameterize(try!(get_bindings(bindings)),
+                                                      box try!(get_ast(&body)), span));
+                    },
+                &[SExpr::Symbol(ref k, _), SExpr::Symbol(ref name, span), ref val]
+                    if k == "set!" => {
+                        return Ok(SExpr::Set(name.clone(), span, box try!(get_ast(val))));
                     },
-                &[SExpr::Symbol(ref k), SExpr::List(ref args), ref body]
+                &[SExpr::Symbol(ref k, _), ref name, _]
+                    if k == "set!" => {
+                        return Err(ParseError::BadName(name.to_string()));
+                    },
+                &[SExpr::Symbol(ref k, _), SExpr::List(ref args), _, _..]
                     if k == "lambda" => {
-                        return SExpr::Lambda(get_arg_names(&args.to_vec()),
-                                             box get_ast(body));
-                    },
-                &[SExpr::Symbol(ref k), _..]
-                    if k == "tuple" => {
-                        let mut tuple_elts = elts[1..].to_vec();
-                        tuple_elts = tuple_elts.iter().map(|e| get_ast(e)).collect();
-                        return SExpr::Tuple(tuple_elts);
-                    },
-                &[SExpr::Symbol(ref cmp), ref left, ref right]
-                    if (cmp == ">" || cmp == "<" ||
-                        cmp == "<=" || cmp == ">=" ||
-                        cmp == "=") => {
-                        let cc = match &cmp[..] {
-                            ">" => CC::G,
-                            "<" => CC::L,
-                            ">=" => CC::GE,
-                            "<=" => CC::LE,
-                            "=" => CC::E,
-                            &_ => panic!("NYI"),
-                        };
-
-                        return SExpr::Cmp(cc,
-                                          box get_ast(left),
-                                          box get_ast(right));
+                        let (params, body) = try!(get_params(args, try!(get_body(&elts[2..]))));
+                        return Ok(SExpr::Lambda(params, box body));
+                    },
+                // several values are a tuple of them, see get_let_values
+                &[SExpr::Symbol(ref k, _), _..]
+                    if k == "tuple" || k == "values" => {
+                        let mut tuple_elts = vec![];
+                        for elt in elts[1..].iter() {
+                            tuple_elts.push(try!(get_ast(elt)));
+                        }
+                        return Ok(SExpr::Tuple(tuple_elts));
+                    },
+                &[SExpr::Symbol(ref k, _), ref datum]
+                    if k == "quote" => {
+                        return get_datum(datum);
+                    },
+                // the empty list is a constant
+                &[SExpr::Symbol(ref k, _), ref list]
+                    if k == "null?" => {
+                        return Ok(SExpr::Cmp(CC::E,
+                                             box try!(get_ast(list)),
+                                             box SExpr::List(vec![])));
+                    },
+                &[SExpr::Symbol(ref cmp, _), ref left, ref right]
+                    if comparison_cc(cmp).is_some() => {
+                        let cc = comparison_cc(cmp).unwrap();
+
+                        let left = try!(get_ast(left));
+                        let right = try!(get_ast(right));
+                        match (literal_type(&left), literal_type(&right)) {
+                            (Some(l), Some(r)) if l != r =>
+                                return Err(ParseError::MismatchedTypes(cmp.clone(),
+                                                                       l.to_string(),
+                                                                       r.to_string())),
+                            _ => (),
+                        }
+                        return Ok(SExpr::Cmp(cc, box left, box right));
+                    },
+                &[SExpr::Symbol(ref k, _), ref subject, _..]
+                    if k == "match" => {
+                        return get_match(subject, &elts[2..]);
+                    },
+                &[SExpr::Symbol(ref k, _), _..]
+                    if UNSUPPORTED_FORMS.contains(&&k[..]) => {
+                        return Err(ParseError::UnknownForm(k.clone()));
                     },
                 &[ref f, _..] => {
                     let mut astified_args = vec![];
                     for arg in elts[1..].to_vec() {
-                        astified_args.push(get_ast(&arg));
+                        astified_args.push(try!(get_ast(&arg)));
                     }
 
-                    return SExpr::App(Box::new(f.clone()), astified_args);
+                    return Ok(SExpr::App(Box::new(try!(get_ast(f))), astified_args));
                 },
-                &_ => panic!("NYI: {:?}", elts),
+                &[] => Err(ParseError::EmptyApplication),
             }
         ,
-        &_ => expr.clone(),
+        &_ => Ok(expr.clone()),
     }
 }
 
-pub fn read(ls: &mut LexerState) -> SExpr {
-    return get_ast(&get_expr(ls));
+// The compiler keeps the forms as read, so only the tests read
+// straight to the AST.
+#[cfg(test)]
+pub fn read(ls: &mut LexerState) -> Result<SExpr, ParseError> {
+    return get_toplevel(&try!(get_expr(ls)));
 }
 
 #[test]
 fn test_parser() {
-    let mut input = String::from("(if #f (+ 42 (foo 12)) 17)
-                                  (define (foo x y z) (+ x 10))
-                                  (+ 1 2)");
-    let mut lexer = LexerState {
-        s: input,
-        pos: 0,
-        col: 1,
-        line_num: 1,
-        tok_buf: None,
-    };
+    let mut lexer = lexer_for("(if #f (+ 42 (foo 12)) 17)
+                               (define (foo x y z) (+ x 10))
+                               (+ 1 2)");
     assert_eq!(SExpr::If(Box::new(SExpr::Bool(false)),
-                         Box::new(SExpr::App(Box::new(SExpr::Symbol("+".to_string())),
+                         Box::new(SExpr::App(Box::new(SExpr::Symbol("+".to_string(), NO_SPAN)),
                                              vec![SExpr::Number(42),
-                                                  SExpr::App(Box::new(SExpr::Symbol("foo".to_string())),
+                                                  SExpr::App(Box::new(SExpr::Symbol("foo".to_string(), NO_SPAN)),
                                                              vec![SExpr::Number(12)])])),
                          Box::new(SExpr::Number(17))),
-               read(&mut lexer));
+               read(&mut lexer).unwrap());
 
     // Second top-level s-expression
     assert_eq!(SExpr::Define("foo".to_string(), vec!["x".to_string(), "y".to_string(), "z".to_string()],
-                             Box::new(SExpr::App(Box::new(SExpr::Symbol("+".to_string())),
-                                        vec![SExpr::Symbol("x".to_string()), SExpr::Number(10)]))),
-               read(&mut lexer));
+                             Box::new(SExpr::App(Box::new(SExpr::Symbol("+".to_string(), NO_SPAN)),
+                                        vec![SExpr::Symbol("x".to_string(), NO_SPAN), SExpr::Number(10)]))),
+               read(&mut lexer).unwrap());
 
     // Third top-level s-expression
-    assert_eq!(SExpr::App(Box::new(SExpr::Symbol("+".to_string())),
+    assert_eq!(SExpr::App(Box::new(SExpr::Symbol("+".to_string(), NO_SPAN)),
                           vec![SExpr::Number(1),
                                SExpr::Number(2)]),
-               read(&mut lexer));
+               read(&mut lexer).unwrap());
     // nothing left in string
-    assert_eq!(SExpr::EOF, read(&mut lexer));
+    assert_eq!(SExpr::EOF, read(&mut lexer).unwrap());
+}
+
+#[test]
+fn test_if_without_else() {
+    let mut lexer = lexer_for("(if #t 5) (if #t)");
+    assert_eq!(SExpr::If(box SExpr::Bool(true), box SExpr::Number(5), box SExpr::Void),
+               read(&mut lexer).unwrap());
+    assert_eq!(Err(ParseError::BadArity("if".to_string())), read(&mut lexer));
+}
+
+#[test]
+fn test_number_too_large() {
+    let input = format!("{} {}", MAX_FIXNUM, MAX_FIXNUM + 1);
+    let mut lexer = lexer_for(&input);
+    assert_eq!(Ok(SExpr::Number(MAX_FIXNUM)), read(&mut lexer));
+    assert_eq!(Err(ParseError::NumberTooLarge(MAX_FIXNUM + 1)), read(&mut lexer));
+}
+
+#[test]
+fn test_body_with_several_expressions() {
+    let mut lexer = lexer_for("(let ((x 1)) (set! x 2) x) (lambda (y) y)");
+    let body = SExpr::Begin(vec![SExpr::Set("x".to_string(), NO_SPAN, box SExpr::Number(2)),
+                                 SExpr::Symbol("x".to_string(), NO_SPAN)]);
+    assert_eq!(SExpr::Let(vec![("x".to_string(), SExpr::Number(1))], box body),
+               read(&mut lexer).unwrap());
+    assert_eq!(SExpr::Lambda(vec!["y".to_string()], box SExpr::Symbol("y".to_string(), NO_SPAN)),
+               read(&mut lexer).unwrap());
+
+    // the expressions can't define functions, which are only defined
+    // at the top level
+    let mut lexer = lexer_for("(define (g x) (define (h y) y) (h x)) (lambda (x) (define (h) x) 1)");
+    assert_eq!(Err(ParseError::NotTopLevel("define".to_string())), read(&mut lexer));
+    assert_eq!(Err(ParseError::NotTopLevel("define".to_string())), read(&mut lexer));
+}
+
+#[test]
+fn test_mismatched_comparison() {
+    let mut lexer = lexer_for("(= #t 1) (< 1 2.5) (= x #t)");
+    assert_eq!(Err(ParseError::MismatchedTypes("=".to_string(),
+                                               "boolean".to_string(),
+                                               "number".to_string())),
+               read(&mut lexer));
+    // integers and floats are both numbers, and a variable could be
+    // anything
+    assert!(read(&mut lexer).is_ok());
+    assert!(read(&mut lexer).is_ok());
+}
+
+#[test]
+fn test_begin() {
+    let mut lexer = lexer_for("(begin (define a 1) a)");
+    assert_eq!(SExpr::Begin(vec![SExpr::DefineVal("a".to_string(), box SExpr::Number(1)),
+                                 SExpr::Symbol("a".to_string(), NO_SPAN)]),
+               read(&mut lexer).unwrap());
+}
+
+#[test]
+fn test_quote() {
+    let mut lexer = lexer_for("'(1 (2) #t ()) (quote ()) '(a 1) ('f x)");
+    assert_eq!(SExpr::List(vec![SExpr::Number(1),
+                                SExpr::List(vec![SExpr::Number(2)]),
+                                SExpr::Bool(true),
+                                SExpr::List(vec![])]),
+               read(&mut lexer).unwrap());
+    assert_eq!(SExpr::List(vec![]), read(&mut lexer).unwrap());
+    // a quoted symbol is a value, not a variable, even where a
+    // function would be
+    assert_eq!(SExpr::List(vec![SExpr::QuotedSymbol("a".to_string()), SExpr::Number(1)]),
+               read(&mut lexer).unwrap());
+    assert_eq!(SExpr::App(box SExpr::QuotedSymbol("f".to_string()),
+                          vec![SExpr::Symbol("x".to_string(), NO_SPAN)]),
+               read(&mut lexer).unwrap());
+}
+
+#[test]
+fn test_unknown_form() {
+    let mut lexer = lexer_for("(define-syntax swap (syntax-rules (a b) (b a)))");
+    assert_eq!(Err(ParseError::UnknownForm("define-syntax".to_string())),
+               read(&mut lexer));
+}
+
+#[test]
+fn test_bool_spellings() {
+    let mut lexer = lexer_for("(if true #t false) '(true #f)
+                               (let ((true 1)) true) (define (false) 0) (set! #t 2)");
+    assert_eq!(SExpr::If(box SExpr::Bool(true), box SExpr::Bool(true), box SExpr::Bool(false)),
+               read(&mut lexer).unwrap());
+    assert_eq!(SExpr::List(vec![SExpr::Bool(true), SExpr::Bool(false)]),
+               read(&mut lexer).unwrap());
+    // a boolean can't be bound
+    assert_eq!(Err(ParseError::BadName("#t".to_string())), read(&mut lexer));
+    assert_eq!(Err(ParseError::BadName("#f".to_string())), read(&mut lexer));
+    assert_eq!(Err(ParseError::BadName("#t".to_string())), read(&mut lexer));
+}
+
+#[test]
+fn test_let_values() {
+    let mut lexer = lexer_for("(let-values (((q r) (values 3 1)) ((s) (values 2))) (+ q s))
+                               (let-values (((a b c) (if #t (values 1 2 3) (values 4 5)))) a)");
+    let sym = |s: &str| SExpr::Symbol(s.to_string(), NO_SPAN);
+    let elt = |t: &str, i| SExpr::App(box sym("tuple-ref"), vec![sym(t), SExpr::Number(i)]);
+    let expr = read(&mut lexer).unwrap();
+    let (q, s) = match &expr {
+        &SExpr::Let(ref inits, _) => (inits[0].0.clone(), inits[1].0.clone()),
+        _ => panic!("expected a let, got {:?}", expr),
+    };
+    assert_eq!(SExpr::Let(vec![(q.clone(), SExpr::Tuple(vec![SExpr::Number(3),
+                                                              SExpr::Number(1)])),
+                               (s.clone(), SExpr::Tuple(vec![SExpr::Number(2)]))],
+                          box SExpr::Let(vec![("q".to_string(), elt(&q, 0)),
+                                              ("r".to_string(), elt(&q, 1)),
+                                              ("s".to_string(), elt(&s, 0))],
+                                         box SExpr::App(box sym("+"), vec![sym("q"), sym("s")]))),
+               expr);
+    assert_eq!(Err(ParseError::ValuesMismatch(3, 2)), read(&mut lexer));
+}
+
+#[test]
+fn test_datum_comments() {
+    let mut lexer = lexer_for("(+ 1 #;2 3) (+ #;(f (g 1) \")\") #; #; 4 5 6) #;(7)
+                               8 (+ 1 #;) #;");
+    let sym = |s: &str| SExpr::Symbol(s.to_string(), NO_SPAN);
+    assert_eq!(SExpr::App(box sym("+"), vec![SExpr::Number(1), SExpr::Number(3)]),
+               read(&mut lexer).unwrap());
+    assert_eq!(SExpr::App(box sym("+"), vec![SExpr::Number(6)]), read(&mut lexer).unwrap());
+    assert_eq!(SExpr::Number(8), read(&mut lexer).unwrap());
+    assert_eq!(Err(ParseError::EmptyDatumComment(2, 39)), read(&mut lexer));
+    assert_eq!(Err(ParseError::EmptyDatumComment(2, 43)), read(&mut lexer));
 }
@@ -0,0 +1,92 @@
+use anf::{Flat, FlatResult};
+use X86;
+
+// How big the program is after a pass, which `--stats` prints to stderr.
+// `spilled` is the number of variables kept on the stack, once homes
+// have been assigned.
+#[derive(Debug)]
+pub struct PassStats {
+    pub pass: &'static str,
+    pub instrs: usize,
+    pub spilled: Option<i64>,
+}
+
+impl PassStats {
+    pub fn of_flat(pass: &'static str, prog: &FlatResult) -> PassStats {
+        PassStats { pass: pass, instrs: count_flat(prog), spilled: None }
+    }
+
+    pub fn of_x86(pass: &'static str, prog: &X86) -> PassStats {
+        PassStats { pass: pass, instrs: count_x86(prog), spilled: count_spilled(prog) }
+    }
+}
+
+// An `if` or a loop counts as one instruction, besides the ones in it.
+fn count_flat_instrs(instrs: &[Flat]) -> usize {
+    instrs.iter().map(|i| match i {
+        &Flat::If(_, ref thn, ref els) => 1 + count_flat_instrs(thn) + count_flat_instrs(els),
+        &Flat::While(ref test, _, ref body) =>
+            1 + count_flat_instrs(test) + count_flat_instrs(body),
+        _ => 1,
+    }).sum()
+}
+
+fn count_flat(prog: &FlatResult) -> usize {
+    match prog {
+        &FlatResult::Prog(ref defs, ref instrs, _) =>
+            defs.iter().map(count_flat).sum::<usize>() + count_flat_instrs(instrs),
+        &FlatResult::Define(_, _, ref instrs, _) |
+        &FlatResult::Flat(_, ref instrs, _) => count_flat_instrs(instrs),
+    }
+}
+
+fn count_x86_instrs(instrs: &[X86]) -> usize {
+    instrs.iter().map(count_x86).sum()
+}
+
+fn count_x86(prog: &X86) -> usize {
+    match prog {
+        &X86::Prog(ref defs, ref instrs, _) |
+        &X86::ProgWithLives(ref defs, ref instrs, _, _) |
+        &X86::ProgWithStackSize(ref defs, ref instrs, _, _) =>
+            count_x86_instrs(defs) + count_x86_instrs(instrs),
+        &X86::Define(_, _, ref instrs) |
+        &X86::DefineWithLives(_, _, _, ref instrs) |
+        &X86::DefineWithStackSize(_, _, _, ref instrs) => count_x86_instrs(instrs),
+        &X86::If(_, ref thn, ref els) |
+        &X86::IfWithLives(_, ref thn, _, ref els, _) =>
+            1 + count_x86_instrs(thn) + count_x86_instrs(els),
+        &X86::While(ref test, _, ref body) |
+        &X86::WhileWithLives(ref test, _, _, ref body, _) =>
+            1 + count_x86_instrs(test) + count_x86_instrs(body),
+        _ => 1,
+    }
+}
+
+// The stack slots of main and every function, or None if homes
+// haven't been assigned yet.
+fn count_spilled(prog: &X86) -> Option<i64> {
+    match prog {
+        &X86::ProgWithStackSize(ref defs, _, stack_size, _) => {
+            let mut spilled = stack_size;
+            for def in defs {
+                spilled += count_spilled(def).unwrap_or(0);
+            }
+            Some(spilled)
+        },
+        &X86::DefineWithStackSize(_, stack_size, _, _) => Some(stack_size),
+        _ => None,
+    }
+}
+
+pub fn stats_table(stats: &[PassStats]) -> String {
+    let mut table = format!("{:<20} {:>8} {:>8}\n", "pass", "instrs", "spilled");
+    for s in stats {
+        let spilled = match s.spilled {
+            Some(n) => n.to_string(),
+            None => "-".to_string(),
+        };
+        table.push_str(&format!("{:<20} {:>8} {:>8}\n", s.pass, s.instrs, spilled));
+    }
+    return table;
+}
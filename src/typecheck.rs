@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use parser::SExpr;
+use error::CompileError;
+
+// What the type checker knows about a value. Dynamic is anything it
+// can't tell, eg. a function's argument or a string, and is accepted
+// wherever a type is expected, so that programs without a type error
+// that the checker can see still compile.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Int,
+    Bool,
+    Tuple,
+    Fn,
+    Symbol,
+    Dynamic,
+}
+
+fn type_name(t: Type) -> &'static str {
+    match t {
+        Type::Int => "number",
+        Type::Bool => "boolean",
+        Type::Tuple => "tuple",
+        Type::Fn => "function",
+        Type::Symbol => "symbol",
+        Type::Dynamic => "value",
+    }
+}
+
+// The type of a value that can be either `a` or `b`.
+fn join(a: Type, b: Type) -> Type {
+    if a == b { a } else { Type::Dynamic }
+}
+
+fn type_error(form: &SExpr, msg: String) -> CompileError {
+    CompileError::Type(msg, form.clone())
+}
+
+// Check that `arg`, the argument of `f` in `form`, can be a `t`.
+fn expect(form: &SExpr, f: &str, arg: Type, t: Type) -> Result<(), CompileError> {
+    if arg != t && arg != Type::Dynamic {
+        return Err(type_error(form, format!("`{}` expects a {}, not a {}",
+                                            f, type_name(t), type_name(arg))));
+    }
+    return Ok(());
+}
+
+// Variables assigned by `set!` somewhere, whose type can change.
+fn set_vars(expr: &SExpr, vars: &mut HashSet<String>) {
+    match expr {
+        &SExpr::Set(ref name, _, ref val) => {
+            vars.insert(name.clone());
+            set_vars(val, vars);
+        },
+        &SExpr::Define(_, _, ref e) | &SExpr::DefineVal(_, ref e) |
+        &SExpr::Lambda(_, ref e) | &SExpr::DefineDynamic(_, ref e) |
+        &SExpr::DynamicSet(_, ref e) => set_vars(e, vars),
+        &SExpr::Let(ref bindings, ref body) | &SExpr::Parameterize(ref bindings, ref body, _) => {
+            for &(_, ref v) in bindings {
+                set_vars(v, vars);
+            }
+            set_vars(body, vars);
+        },
+        &SExpr::If(ref cnd, ref thn, ref els) => {
+            set_vars(cnd, vars);
+            set_vars(thn, vars);
+            set_vars(els, vars);
+        },
+        &SExpr::Cmp(_, ref left, ref right) | &SExpr::While(ref left, ref right) => {
+            set_vars(left, vars);
+            set_vars(right, vars);
+        },
+        &SExpr::App(ref f, ref args) => {
+            set_vars(f, vars);
+            for a in args {
+                set_vars(a, vars);
+            }
+        },
+        &SExpr::Begin(ref es) | &SExpr::Tuple(ref es) => {
+            for e in es {
+                set_vars(e, vars);
+            }
+        },
+        &SExpr::Prog(ref defs, ref main) => {
+            for def in defs {
+                set_vars(def, vars);
+            }
+            set_vars(main, vars);
+        },
+        _ => (),
+    }
+}
+
+// The type of a call to the primitive `f`, whose arguments have types
+// `args`.
+fn prim_type(form: &SExpr, f: &str, args: &[Type]) -> Result<Type, CompileError> {
+    // flatten reports calls with the wrong number of arguments
+    let first = args.first().cloned().unwrap_or(Type::Dynamic);
+    match f {
+        "+" | "-" | "min" | "max" | "abs" | "quotient" | "remainder" | "modulo" => {
+            for &a in args {
+                try!(expect(form, f, a, Type::Int));
+            }
+            // floats are numbers too, but aren't told apart from
+            // other values
+            if args.iter().all(|&a| a == Type::Int) {
+                return Ok(Type::Int);
+            }
+            return Ok(Type::Dynamic);
+        },
+        "tuple-ref" | "car" | "cdr" => {
+            try!(expect(form, f, first, Type::Tuple));
+            return Ok(Type::Dynamic);
+        },
+        "tuple-length" => {
+            try!(expect(form, f, first, Type::Tuple));
+            return Ok(Type::Int);
+        },
+        "string-length" => Ok(Type::Int),
+        "tuple" | "cons" => Ok(Type::Tuple),
+        "symbol->string" => {
+            try!(expect(form, f, first, Type::Symbol));
+            return Ok(Type::Dynamic);
+        },
+        "pair?" | "%tuple?" | "eq?" | "equal?" => Ok(Type::Bool),
+        "exit" => {
+            try!(expect(form, f, first, Type::Int));
+            return Ok(Type::Dynamic);
+        },
+        "print_hex" | "print_binary" => {
+            try!(expect(form, f, first, Type::Int));
+            return Ok(Type::Int);
+        },
+        "assert" | "print" => Ok(first),
+        _ => Ok(Type::Dynamic),
+    }
+}
+
+// The type of `expr`, where `env` holds the types of the variables in
+// scope, or the first type error in it.
+fn type_of(expr: &SExpr, env: &HashMap<String, Type>, set: &HashSet<String>,
+           prims: &[&str]) -> Result<Type, CompileError> {
+    let check = |e: &SExpr| type_of(e, env, set, prims);
+    match expr {
+        &SExpr::Number(_) => Ok(Type::Int),
+        &SExpr::Bool(_) => Ok(Type::Bool),
+        &SExpr::QuotedSymbol(_) => Ok(Type::Symbol),
+        &SExpr::List(ref elts) if !elts.is_empty() => Ok(Type::Tuple),
+        &SExpr::Symbol(ref name, _) => Ok(env.get(name).cloned().unwrap_or(Type::Dynamic)),
+        &SExpr::Tuple(ref elts) => {
+            for e in elts {
+                try!(check(e));
+            }
+            Ok(Type::Tuple)
+        },
+        &SExpr::Lambda(ref args, ref body) | &SExpr::Define(_, ref args, ref body) => {
+            let mut body_env = env.clone();
+            for arg in args {
+                body_env.insert(arg.clone(), Type::Dynamic);
+            }
+            try!(type_of(body, &body_env, set, prims));
+            Ok(Type::Fn)
+        },
+        &SExpr::Let(ref bindings, ref body) => {
+            let mut body_env = env.clone();
+            for &(ref k, ref v) in bindings {
+                let t = try!(check(v));
+                body_env.insert(k.clone(), if set.contains(k) { Type::Dynamic } else { t });
+            }
+            type_of(body, &body_env, set, prims)
+        },
+        &SExpr::Parameterize(ref bindings, ref body, _) => {
+            for &(_, ref v) in bindings {
+                try!(check(v));
+            }
+            check(body)
+        },
+        &SExpr::If(ref cnd, ref thn, ref els) => {
+            let t = try!(check(cnd));
+            if t != Type::Bool && t != Type::Dynamic {
+                return Err(type_error(expr, format!("the test of `if` is a {}, not a boolean",
+                                                    type_name(t))));
+            }
+            Ok(join(try!(check(thn)), try!(check(els))))
+        },
+        &SExpr::Cmp(_, ref left, ref right) => {
+            try!(check(left));
+            try!(check(right));
+            Ok(Type::Bool)
+        },
+        &SExpr::Begin(ref body) => {
+            let mut t = Type::Dynamic;
+            for e in body {
+                t = try!(check(e));
+            }
+            Ok(t)
+        },
+        &SExpr::While(ref test, ref body) => {
+            try!(check(test));
+            try!(check(body));
+            Ok(Type::Dynamic)
+        },
+        &SExpr::Set(_, _, ref val) | &SExpr::DefineVal(_, ref val) |
+        &SExpr::DefineDynamic(_, ref val) | &SExpr::DynamicSet(_, ref val) => {
+            try!(check(val));
+            Ok(Type::Dynamic)
+        },
+        &SExpr::App(box SExpr::Symbol(ref f, _), ref args)
+            if !env.contains_key(f) && prims.contains(&&f[..]) => {
+                let mut arg_types = vec![];
+                for a in args {
+                    arg_types.push(try!(check(a)));
+                }
+                prim_type(expr, f, &arg_types)
+            },
+        &SExpr::App(ref f, ref args) => {
+            let t = try!(check(f));
+            if t != Type::Fn && t != Type::Dynamic {
+                return Err(type_error(expr, format!("`{}` is a {}, not a function",
+                                                    f, type_name(t))));
+            }
+            for a in args {
+                try!(check(a));
+            }
+            Ok(Type::Dynamic)
+        },
+        &SExpr::Prog(ref defs, ref main) => {
+            let mut prog_env = env.clone();
+            for def in defs {
+                if let &SExpr::Define(ref name, _, _) = def {
+                    prog_env.insert(name.clone(), Type::Fn);
+                }
+            }
+            for def in defs {
+                try!(type_of(def, &prog_env, set, prims));
+            }
+            type_of(main, &prog_env, set, prims)
+        },
+        _ => Ok(Type::Dynamic),
+    }
+}
+
+// Check `prog` for type errors that are certain to happen when it
+// runs, eg. `(+ #t 1)`, an `if` whose test is a number, or calling a
+// tuple. `prims` are the names of the primitives.
+pub fn typecheck(prog: &SExpr, prims: &[&str]) -> Result<(), CompileError> {
+    let mut set = HashSet::new();
+    set_vars(prog, &mut set);
+    try!(type_of(prog, &HashMap::new(), &set, prims));
+    return Ok(());
+}
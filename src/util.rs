@@ -1,7 +1,58 @@
-static mut VAR_COUNTER : i32 = 0;
+use std::cell::{Cell, RefCell};
+
+// Each thread has its own counter, so that compilations (and tests)
+// running in parallel don't affect each other's names.
+thread_local!(static VAR_COUNTER : Cell<i32> = Cell::new(0));
+thread_local!(static LABEL_COUNTER : Cell<i32> = Cell::new(0));
+// the names of the quoted symbols, in the order they were first seen
+thread_local!(static SYMBOLS : RefCell<Vec<String>> = RefCell::new(vec![]));
+
 pub fn get_unique_varname(stem: &str) -> String {
-    unsafe {
-        VAR_COUNTER += 1;
-        return stem.to_string() + &VAR_COUNTER.to_string();
-    }
-}    
+    VAR_COUNTER.with(|counter| {
+        counter.set(counter.get() + 1);
+        return stem.to_string() + &counter.get().to_string();
+    })
+}
+
+// A jump target, eg. `.Lthen1`. No identifier can start with a `.`,
+// so it can't be a function's name, and labels are numbered apart
+// from variables. NASM makes a label starting with `.` local to the
+// function before it, and GAS keeps `.L` labels out of the object's
+// symbol table.
+pub fn get_unique_label(stem: &str) -> String {
+    LABEL_COUNTER.with(|counter| {
+        counter.set(counter.get() + 1);
+        return format!(".L{}{}", stem, counter.get());
+    })
+}
+
+// Start numbering from 1 again, eg. so that a test can predict the
+// names it gets.
+pub fn reset_var_counter() {
+    VAR_COUNTER.with(|counter| counter.set(0));
+    LABEL_COUNTER.with(|counter| counter.set(0));
+}
+
+// The index of the symbol `name` in the symbol table, which is the
+// same every time it's asked for. A new name is added at the end.
+pub fn intern_symbol(name: &str) -> usize {
+    SYMBOLS.with(|symbols| {
+        let mut symbols = symbols.borrow_mut();
+        match symbols.iter().position(|s| s == name) {
+            Some(i) => i,
+            None => {
+                symbols.push(name.to_string());
+                symbols.len() - 1
+            },
+        }
+    })
+}
+
+pub fn interned_symbols() -> Vec<String> {
+    SYMBOLS.with(|symbols| symbols.borrow().clone())
+}
+
+// Empty the symbol table, before compiling another program.
+pub fn reset_symbols() {
+    SYMBOLS.with(|symbols| symbols.borrow_mut().clear());
+}
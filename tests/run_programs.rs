@@ -0,0 +1,363 @@
+// Compiles each program with rusl, assembles it with nasm, links it
+// with runtime.c and checks what it prints, or how it fails. The tests
+// pass without doing anything if nasm or gcc isn't installed.
+
+extern crate rusl;
+
+use rusl::Config;
+
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn toolchain_available() -> bool {
+    Command::new("nasm").arg("-v").output().is_ok() &&
+        Command::new("gcc").arg("--version").output().is_ok()
+}
+
+// A directory of its own for each program, since the tests run in
+// parallel.
+fn scratch_dir() -> PathBuf {
+    static COUNTER : AtomicUsize = ATOMIC_USIZE_INIT;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    let dir = env::temp_dir().join(format!("rusl-run-{}-{}-{}",
+                                           now.as_secs(), now.subsec_nanos(),
+                                           COUNTER.fetch_add(1, Ordering::SeqCst)));
+    fs::create_dir_all(&dir).unwrap();
+    return dir;
+}
+
+// Compile `source`, link it with the runtime and run it, and return
+// how it exited and what it printed.
+fn run_program(source: &str) -> Result<Output, String> {
+    let dir = scratch_dir();
+    let exe = dir.join("a.out");
+    let output = rusl::compile_to_executable(source, &Config::default(), &exe)
+        .map_err(|e| e.to_string())
+        .and_then(|_| Command::new(&exe).output().map_err(|e| e.to_string()));
+    let _ = fs::remove_dir_all(&dir);
+    return output;
+}
+
+// What `source` printed, without the trailing newline, or if it
+// failed, its exit status and what it printed to stderr.
+fn compile_and_run(source: &str) -> Result<String, String> {
+    let output = try!(run_program(source));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match output.status.code() {
+        Some(0) => Ok(String::from_utf8_lossy(&output.stdout).trim_right().to_string()),
+        Some(code) => Err(format!("exit status {}: {}", code, stderr)),
+        None => Err(format!("killed by a signal: {}", stderr)),
+    }
+}
+
+fn check_programs(programs: &[(&str, &str)]) {
+    if !toolchain_available() {
+        return;
+    }
+
+    for &(source, expected) in programs {
+        assert_eq!(Ok(expected.to_string()), compile_and_run(source), "running {}", source);
+    }
+}
+
+#[test]
+fn test_add() {
+    check_programs(&[
+        ("(+ 40 2)", "42"),
+        ("(+ (+ 1 2) (+ 3 (- 4)))", "2"),
+        ("(- (+ 5 5))", "-10"),
+        ("(+ 5000000000 1)", "5000000001"),
+    ]);
+}
+
+#[test]
+fn test_if() {
+    check_programs(&[
+        ("(if #t 1 2)", "1"),
+        ("(if (< 3 2) 1 2)", "2"),
+        ("(if (= 1 1) (if (> 1 2) 3 4) 5)", "4"),
+        ("(if #t 5)", "5"),
+        ("(if #f 5)", ""),
+    ]);
+}
+
+#[test]
+fn test_let() {
+    check_programs(&[
+        ("(let ((x 5)) (+ x x))", "10"),
+        ("(let ((x 1) (y 2)) (let ((x 10)) (+ x y)))", "12"),
+        ("(let ((t (tuple 1 #f))) (tuple-ref t 1))", "#f"),
+        ("(let ((x 1)) (set! x (+ x 41)) x)", "42"),
+        // the outer x is back in scope after the inner let's body
+        ("(let ((x 1)) (+ (let ((x 2)) x) x))", "3"),
+        ("(let ((x 1)) (+ ((lambda (x) x) 2) x))", "3"),
+        // the initializer reads the outer x
+        ("(let ((x 1)) (let ((x (+ x 1))) x))", "2"),
+    ]);
+}
+
+#[test]
+fn test_abs_min_max() {
+    check_programs(&[
+        ("(abs (- 5))", "5"),
+        ("(tuple (min 3 7) (max 3 7) (abs 4) (abs 0))", "(3, 7, 4, 0)"),
+        ("(define (dist a b) (abs (+ a (- b))))
+          (tuple (dist 3 10) (dist 10 3))", "(7, 7)"),
+    ]);
+}
+
+#[test]
+fn test_division() {
+    check_programs(&[
+        ("(tuple (quotient 7 2) (remainder 7 2) (modulo 7 2))", "(3, 1, 1)"),
+        ("(define (q a b) (quotient a b))
+          (define (r a b) (remainder a b))
+          (define (m a b) (modulo a b))
+          (tuple (q (- 7) 3) (r (- 7) 3) (m (- 7) 3)
+                 (q 7 (- 3)) (r 7 (- 3)) (m 7 (- 3)) (m (- 6) 3))",
+         "(-2, -1, 2, -2, 1, -2, 0)"),
+    ]);
+
+    if !toolchain_available() {
+        return;
+    }
+    let output = run_program("(define (f a b) (modulo a b)) (f 1 0)").unwrap();
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!("error: division by zero\n", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn test_let_values() {
+    check_programs(&[
+        ("(define (divmod a b)
+            (let loop ((q 0) (r a))
+              (if (< r b) (values q r) (loop (+ q 1) (+ r (- b))))))
+          (let-values (((q r) (divmod 17 5)))
+            (+ q r))", "5"),
+        ("(let-values (((a b) (values 1 2)) ((c) (values (tuple 3))))
+            (tuple b a c))", "(2, 1, (3))"),
+        ("(let ((x 1))
+            (let-values (((x y) (values 10 x))) (+ x y)))", "11"),
+    ]);
+}
+
+#[test]
+fn test_let_values_mismatch() {
+    if !toolchain_available() {
+        return;
+    }
+    // the values come from calls, so they are checked at run time
+    for source in &["(define (two) (values 1 2)) (let-values (((a b c) (two))) a)",
+                    "(define (one) 1) (let-values (((a) (one))) a)"] {
+        let output = run_program(source).unwrap();
+        assert_eq!(Some(1), output.status.code());
+        assert_eq!("error: let-values: wrong number of values\n",
+                   String::from_utf8_lossy(&output.stderr));
+    }
+}
+
+#[test]
+fn test_symbols() {
+    check_programs(&[
+        ("(tuple (eq? 'foo 'foo) (eq? 'foo 'bar))", "(#t, #f)"),
+        ("'(a b)", "(a, (b, ()))"),
+        ("(define (kind x) (match x ('apple 1) ('pear 2) (_ 3)))
+          (tuple (kind 'pear) (kind 'plum) (symbol->string 'apple))", "(2, 3, apple)"),
+        ("(string-length (symbol->string 'hello))", "5"),
+    ]);
+}
+
+#[test]
+fn test_chars() {
+    check_programs(&[
+        ("#\\a", "#\\a"),
+        ("(tuple #\\x41 #\\x3bb)", "(#\\A, #\\λ)"),
+        ("#\\x1f600", "#\\\u{1f600}"),
+    ]);
+}
+
+#[test]
+fn test_function_calls() {
+    check_programs(&[
+        ("(define (add2 x) (+ x 2))
+          (add2 40)", "42"),
+        ("(define (fibo x)
+            (if (< x 2)
+                1
+                (+ (fibo (+ x (- 2)))
+                   (fibo (+ x (- 1))))))
+          (fibo 6)", "13"),
+        ("(define (apply f x) (f x))
+          (apply (lambda (y) (+ y 1)) 41)", "42"),
+        ("(define (inc x) (+ x 1))
+          (define (dbl x) (+ x x))
+          (inc (dbl (inc 20)))", "43"),
+        ("(define (inc x) (+ x 1))
+          (define (twice f x) (f (f x)))
+          (twice inc 40)", "42"),
+        ("(let ((fs (tuple (lambda (x) (+ x 1)) (lambda (x) (+ x 2)))))
+            ((tuple-ref fs 1) 40))", "42"),
+        ("(define (bump c x)
+            (set! c (+ c 1))
+            (tuple c x))
+          (bump 0 42)", "(1, 42)"),
+        // the runtime's print tells the value's type from its tag
+        ("(define (neg? x) (< x 0))
+          (neg? (- 3))", "#t"),
+        ("(define (neg? x) (< x 0))
+          (neg? 3)", "#f"),
+    ]);
+}
+
+#[test]
+fn test_assert() {
+    check_programs(&[
+        ("(assert (= 1 1))", "#t"),
+        ("(+ (assert 41) 1)", "42"),
+    ]);
+
+    if !toolchain_available() {
+        return;
+    }
+    let output = run_program("(assert (= 1 2))").unwrap();
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!("error: assertion failed\n", String::from_utf8_lossy(&output.stderr));
+    assert_eq!("", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn test_exit() {
+    if !toolchain_available() {
+        return;
+    }
+    let output = run_program("(define (check x) (if (< x 0) (exit 3) x))
+                              (print (check 5))
+                              (print (check (- 1)))
+                              (print 7)").unwrap();
+    assert_eq!(Some(3), output.status.code());
+    assert_eq!("5\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn test_print() {
+    check_programs(&[
+        ("(+ (print 3) 4)", "3\n7"),
+        ("(let ((t (tuple 1 #t)))
+            (begin (print (tuple-ref t 1))
+                   (print t)
+                   (+ (print (tuple-ref t 0)) 1)))", "#t\n(1, #t)\n1\n2"),
+        ("(define (show x) (print x))
+          (print (show #\\a))", "#\\a\n#\\a\n#\\a"),
+        ("(+ (print_hex 255) (print_hex (- 16)))", "0xff\n-0x10\n239"),
+        ("(tuple (print_binary 5) (print_binary 0))", "0b101\n0b0\n(5, 0)"),
+    ]);
+}
+
+#[test]
+fn test_computed_tuple_index() {
+    check_programs(&[
+        ("(define (get t i) (tuple-ref t i))
+          (get (tuple 10 20 30) 2)", "30"),
+        ("(let ((t (tuple 1 2 3 4)) (i 0) (sum 0))
+            (begin (while (< i (tuple-length t))
+                     (begin (set! sum (+ sum (tuple-ref t i)))
+                            (set! i (+ i 1))))
+                   sum))", "10"),
+        ("(let ((t (tuple #t #f))) (tuple-ref t (+ 0 1)))", "#f"),
+    ]);
+
+    if !toolchain_available() {
+        return;
+    }
+    for source in &["(define (get t i) (tuple-ref t i)) (get (tuple 1 2) 2)",
+                    "(define (get t i) (tuple-ref t i)) (get (tuple 1 2) (- 1))",
+                    "(tuple-ref (tuple 1 2) 2)",
+                    "(let ((i 5)) (tuple-ref (tuple 1 2) i))"] {
+        let output = run_program(source).unwrap();
+        assert_eq!(Some(1), output.status.code());
+        assert_eq!("error: tuple-ref: index out of range\n",
+                   String::from_utf8_lossy(&output.stderr));
+    }
+}
+
+#[test]
+fn test_vectors() {
+    check_programs(&[
+        ("(let ((v (make-vector 2 #f)))
+            (begin (vector-set! v 0 (tuple 1 2))
+                   (vector-ref v 0)))", "(1, 2)"),
+        ("(let ((v (make-vector 3 7)) (i 1))
+            (begin (vector-set! v i 8)
+                   v))", "#(7, 8, 7)"),
+        ("(vector-length (make-vector 0 0))", "0"),
+    ]);
+
+    if !toolchain_available() {
+        return;
+    }
+    let output = run_program("(vector-ref (make-vector 2 0) 2)").unwrap();
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!("error: vector: index out of range\n", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn test_floats() {
+    check_programs(&[
+        ("(define (add1 x) (+ x 1)) (tuple (add1 1.5) (add1 1))", "(2.5, 2)"),
+        ("(define (smaller a b) (if (< a b) a b)) (smaller 2 1.5)", "1.5"),
+    ]);
+
+    if !toolchain_available() {
+        return;
+    }
+    let output = run_program("(define (add1 x) (+ x 1)) (tuple (add1 1.5) (add1 #t))").unwrap();
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!("error: arithmetic: argument is not a number\n",
+               String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn test_match_no_clause() {
+    if !toolchain_available() {
+        return;
+    }
+    // the last clause still checks that the value is a pair
+    let output = run_program("(define (sum p) (match p ((a b) (+ a b))))
+                              (tuple (sum (tuple 1 2)) (sum 5))").unwrap();
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!("error: match: no clause matches the value\n",
+               String::from_utf8_lossy(&output.stderr));
+}
+
+// The rusl binary, which cargo builds next to the directory the tests
+// run from.
+fn rusl_binary() -> PathBuf {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    return path.join("rusl");
+}
+
+#[test]
+fn test_run_flag() {
+    if !toolchain_available() {
+        return;
+    }
+    let dir = scratch_dir();
+    let input = dir.join("prog.rl");
+    File::create(&input).and_then(|mut f| f.write_all(b"(+ 40 2)")).unwrap();
+    let output = Command::new(rusl_binary()).arg("--run").arg(&input).output();
+    let _ = fs::remove_dir_all(&dir);
+    let output = output.unwrap();
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!("42\n", String::from_utf8_lossy(&output.stdout));
+}